@@ -0,0 +1,86 @@
+//! Measure how fast we can hash pieces, frame peer messages, and store pieces.
+//!
+//! Run with `cargo run --release --example throughput`. Debug builds are an order of magnitude
+//! slower, so their numbers don't mean much.
+
+use bittorrent_starter_rust::hash::{PieceHasher, Sha1Hasher};
+use bittorrent_starter_rust::mmap::MmapStorage;
+use bittorrent_starter_rust::peer::{Message, MessageFramer, MessageTag};
+use bittorrent_starter_rust::storage::{Layout, MemoryStorage, Storage};
+use bittorrent_starter_rust::torrent::{Info, Torrent};
+use bittorrent_starter_rust::BLOCK_MAX;
+use bytes::BytesMut;
+use std::time::{Duration, Instant};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// How much data each measurement pushes through.
+const TOTAL: usize = 256 << 20;
+
+fn main() {
+    let piece = vec![0xa5; 256 << 10];
+    let elapsed = time(|| {
+        for _ in 0..TOTAL / piece.len() {
+            std::hint::black_box(Sha1Hasher.hash(std::hint::black_box(&piece)));
+        }
+    });
+    report("hash 256 KiB pieces", elapsed);
+
+    let block = Message {
+        tag: MessageTag::Piece,
+        payload: vec![0x5a; 8 + BLOCK_MAX],
+    };
+    let nblocks = TOTAL / BLOCK_MAX;
+    let mut framer = MessageFramer::default();
+    let mut wire = BytesMut::new();
+    let elapsed = time(|| {
+        for _ in 0..nblocks {
+            framer
+                .encode(block.clone(), &mut wire)
+                .expect("block fits in a frame");
+        }
+    });
+    report("encode 16 KiB blocks", elapsed);
+
+    let elapsed = time(|| {
+        let mut decoded = 0;
+        while let Some(msg) = framer.decode(&mut wire).expect("we encoded it") {
+            std::hint::black_box(msg);
+            decoded += 1;
+        }
+        assert_eq!(decoded, nblocks);
+    });
+    report("decode 16 KiB blocks", elapsed);
+
+    let npieces = TOTAL / piece.len();
+    let memory = MemoryStorage::default();
+    let elapsed = time(|| store(&memory, &piece, npieces));
+    report("store in memory", elapsed);
+
+    let t = Torrent::new(
+        String::new(),
+        Info::single_file("throughput.bin", piece.len(), vec![[0; 20]; npieces], TOTAL),
+    );
+    let dir = tempfile::tempdir().expect("create temporary directory");
+    let mmap = MmapStorage::create(&t, dir.path(), &Layout::default()).expect("map output file");
+    let elapsed = time(|| store(&mmap, &piece, npieces));
+    report("store in mmap", elapsed);
+}
+
+fn store(storage: &dyn Storage, piece: &[u8], npieces: usize) {
+    for piece_i in 0..npieces {
+        storage.write_piece(piece_i, piece).expect("store piece");
+    }
+}
+
+fn time(f: impl FnOnce()) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+fn report(what: &str, elapsed: Duration) {
+    println!(
+        "{what:<24} {:>8.1} MiB/s",
+        TOTAL as f64 / (1 << 20) as f64 / elapsed.as_secs_f64()
+    );
+}
@@ -0,0 +1,308 @@
+//! Encoding JSON values as bencode.
+//!
+//! JSON strings can't hold arbitrary bytes, so strings are unescaped before encoding: `\xNN`
+//! (that is, a literal backslash followed by `x` and two hex digits) stands for the byte `0xNN`,
+//! and `\\` stands for a single backslash. Everything else is encoded as its UTF-8 bytes.
+//!
+//! There are also helpers for picking apart bencoded dictionaries without decoding them, for
+//! when the exact bytes of a value matter, as they do for a torrent's info dictionary.
+//!
+//! Bencode has exactly one encoding of every value, but plenty of torrents in the wild were made
+//! by encoders that didn't hold to it. How picky to be about that is a [`Mode`].
+
+use anyhow::Context;
+use serde_json::Value;
+
+/// How closely bencode has to follow the canonical encoding to be accepted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Reject anything that isn't canonical (see [`NonCanonical`]).
+    ///
+    /// Anything that's hashed as it's encoded and not as it's parsed, such as the info
+    /// dictionary of a v2 torrent, must be canonical, or other clients will hash a different
+    /// encoding of the same value.
+    Strict,
+    /// Accept the non-canonical encodings other encoders are known to produce.
+    #[default]
+    Lenient,
+}
+
+/// A way in which bencode isn't encoded canonically, which only [`Mode::Strict`] rejects.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum NonCanonical {
+    #[error("dictionary key {0:?} is out of order")]
+    UnsortedKey(String),
+    #[error("dictionary key {0:?} appears more than once")]
+    DuplicateKey(String),
+    #[error("number {0:?} has leading zeroes")]
+    LeadingZero(String),
+    #[error("integer is negative zero")]
+    NegativeZero,
+}
+
+/// Encode `value` as bencode.
+///
+/// Integers, strings, lists, and dictionaries map directly onto their bencode counterparts, with
+/// dictionary keys sorted by their raw bytes as the spec requires. Bencode has no representation
+/// for `null`, booleans, or non-integer numbers, so those are errors.
+pub fn to_bytes(value: &Value) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    encode(value, &mut out)?;
+    Ok(out)
+}
+
+fn encode(value: &Value, out: &mut Vec<u8>) -> anyhow::Result<()> {
+    match value {
+        Value::Null => anyhow::bail!("bencode cannot represent null"),
+        Value::Bool(b) => anyhow::bail!("bencode cannot represent booleans (got {b})"),
+        Value::Number(n) => {
+            let n = n
+                .as_i64()
+                .map(|n| n.to_string())
+                .or_else(|| n.as_u64().map(|n| n.to_string()))
+                .with_context(|| format!("bencode only supports integers (got {n})"))?;
+            out.push(b'i');
+            out.extend(n.as_bytes());
+            out.push(b'e');
+        }
+        Value::String(s) => encode_bytes(&unescape(s)?, out),
+        Value::Array(values) => {
+            out.push(b'l');
+            for v in values {
+                encode(v, out)?;
+            }
+            out.push(b'e');
+        }
+        Value::Object(dict) => {
+            let mut entries = dict
+                .iter()
+                .map(|(k, v)| Ok((unescape(k)?, v)))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            out.push(b'd');
+            for (k, v) in entries {
+                encode_bytes(&k, out);
+                encode(v, out).with_context(|| {
+                    format!("encode value of key {}", String::from_utf8_lossy(&k))
+                })?;
+            }
+            out.push(b'e');
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend(bytes);
+}
+
+/// Turn `\xNN` and `\\` escapes in `s` into the bytes they represent.
+pub fn unescape(s: &str) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        if b != b'\\' {
+            bytes.push(b);
+            continue;
+        }
+        match rest {
+            [b'\\', tail @ ..] => {
+                bytes.push(b'\\');
+                rest = tail;
+            }
+            [b'x', hi, lo, tail @ ..] => {
+                let byte = hex::decode([*hi, *lo])
+                    .with_context(|| format!("invalid hex escape in {s:?}"))?;
+                bytes.extend(byte);
+                rest = tail;
+            }
+            _ => anyhow::bail!("invalid escape in {s:?}: expected \\\\ or \\xNN"),
+        }
+    }
+    Ok(bytes)
+}
+
+/// The entries of the bencoded dictionary `dict`, in the order they're encoded in, with each
+/// value left as the bytes that encode it.
+///
+/// `dict` must be a single dictionary, with nothing after it.
+pub(crate) fn dict_entries(dict: &[u8]) -> anyhow::Result<Vec<(Vec<u8>, &[u8])>> {
+    let Some((b'd', mut rest)) = dict.split_first() else {
+        anyhow::bail!("not a dictionary");
+    };
+    let mut entries = Vec::new();
+    loop {
+        match rest.first() {
+            Some(b'e') if rest.len() == 1 => return Ok(entries),
+            Some(b'e') => anyhow::bail!("trailing data after the dictionary"),
+            None => anyhow::bail!("dictionary is not terminated"),
+            Some(b'0'..=b'9') => {}
+            Some(_) => anyhow::bail!("dictionary key is not a string"),
+        }
+        let key_length = value_length(rest).context("find dictionary key")?;
+        let colon = rest
+            .iter()
+            .position(|&b| b == b':')
+            .expect("strings have a colon");
+        let key = rest[colon + 1..key_length].to_vec();
+        rest = &rest[key_length..];
+        let value_length = value_length(rest)
+            .with_context(|| format!("find value of key {}", String::from_utf8_lossy(&key)))?;
+        let (value, tail) = rest.split_at(value_length);
+        rest = tail;
+        entries.push((key, value));
+    }
+}
+
+/// Check that `bytes` is a single bencoded value, with nothing after it, encoded as `mode`
+/// requires.
+///
+/// In [strict](Mode::Strict) mode, the error is a [`NonCanonical`] if that's all that's wrong.
+pub fn check(bytes: &[u8], mode: Mode) -> anyhow::Result<()> {
+    let length = walk(bytes, mode)?;
+    anyhow::ensure!(length == bytes.len(), "trailing data after the value");
+    Ok(())
+}
+
+/// The length of the bencoded value that `bytes` starts with.
+pub(crate) fn value_length(bytes: &[u8]) -> anyhow::Result<usize> {
+    walk(bytes, Mode::Lenient)
+}
+
+/// The length of the bencoded value that `bytes` starts with, after checking that it's encoded
+/// as `mode` requires.
+fn walk(bytes: &[u8], mode: Mode) -> anyhow::Result<usize> {
+    match bytes.first() {
+        Some(b'i') => {
+            let end = bytes
+                .iter()
+                .position(|&b| b == b'e')
+                .context("integer is not terminated")?;
+            let digits = &bytes[1..end];
+            let magnitude = digits.strip_prefix(b"-").unwrap_or(digits);
+            anyhow::ensure!(
+                !magnitude.is_empty() && magnitude.iter().all(u8::is_ascii_digit),
+                "integer {:?} is not a number",
+                String::from_utf8_lossy(digits)
+            );
+            if mode == Mode::Strict {
+                if magnitude.len() > 1 && magnitude[0] == b'0' {
+                    let number = String::from_utf8_lossy(digits).into_owned();
+                    return Err(NonCanonical::LeadingZero(number).into());
+                }
+                if digits == b"-0" {
+                    return Err(NonCanonical::NegativeZero.into());
+                }
+            }
+            Ok(end + 1)
+        }
+        Some(b'l') => {
+            let mut length = 1;
+            loop {
+                match bytes.get(length) {
+                    Some(b'e') => return Ok(length + 1),
+                    Some(_) => length += walk(&bytes[length..], mode)?,
+                    None => anyhow::bail!("list is not terminated"),
+                }
+            }
+        }
+        Some(b'd') => {
+            let mut length = 1;
+            let mut previous: Option<&[u8]> = None;
+            loop {
+                match bytes.get(length) {
+                    Some(b'e') => return Ok(length + 1),
+                    Some(b'0'..=b'9') => {}
+                    Some(_) => anyhow::bail!("dictionary key is not a string"),
+                    None => anyhow::bail!("dictionary is not terminated"),
+                }
+                let key_length = walk(&bytes[length..], mode).context("find dictionary key")?;
+                let key = &bytes[length..length + key_length];
+                let key = &key[key
+                    .iter()
+                    .position(|&b| b == b':')
+                    .expect("strings have a colon")
+                    + 1..];
+                if let (Mode::Strict, Some(previous)) = (mode, previous) {
+                    let name = || String::from_utf8_lossy(key).into_owned();
+                    if key == previous {
+                        return Err(NonCanonical::DuplicateKey(name()).into());
+                    } else if key < previous {
+                        return Err(NonCanonical::UnsortedKey(name()).into());
+                    }
+                }
+                previous = Some(key);
+                length += key_length;
+                length += walk(&bytes[length..], mode).with_context(|| {
+                    format!("find value of key {}", String::from_utf8_lossy(key))
+                })?;
+            }
+        }
+        Some(b'0'..=b'9') => {
+            let colon = bytes
+                .iter()
+                .position(|&b| b == b':')
+                .context("string has no length")?;
+            if mode == Mode::Strict && colon > 1 && bytes[0] == b'0' {
+                let number = String::from_utf8_lossy(&bytes[..colon]).into_owned();
+                return Err(NonCanonical::LeadingZero(number).into());
+            }
+            let length: usize = std::str::from_utf8(&bytes[..colon])?
+                .parse()
+                .context("string length is not a number")?;
+            let end = (colon + 1)
+                .checked_add(length)
+                .filter(|&end| end <= bytes.len())
+                .context("string is cut short")?;
+            Ok(end)
+        }
+        Some(&b) => anyhow::bail!("unexpected byte {b:#04x} at the start of a value"),
+        None => anyhow::bail!("expected a value, found the end of the input"),
+    }
+}
+
+#[test]
+fn encode_json() {
+    let value = serde_json::json!({
+        "peers": "\\x7f\\x00\\x00\\x01\\x1a\\xe1",
+        "interval": 60,
+        "list": [-1, "a\\\\b", {}],
+    });
+    assert_eq!(
+        to_bytes(&value).unwrap(),
+        b"d8:intervali60e4:listli-1e3:a\\bdee5:peers6:\x7f\x00\x00\x01\x1a\xe1e"
+    );
+
+    assert!(to_bytes(&serde_json::json!(null)).is_err());
+    assert!(to_bytes(&serde_json::json!(1.5)).is_err());
+    assert!(to_bytes(&serde_json::json!("\\xZZ")).is_err());
+    assert!(to_bytes(&serde_json::json!("\\n")).is_err());
+}
+
+#[test]
+fn canonical_encoding() {
+    let canonical = b"d1:ai-3e1:bli0e3:xyze1:cd0:i1eee";
+    check(canonical, Mode::Strict).unwrap();
+    for (bencode, problem) in [
+        (
+            &b"d1:bi1e1:ai2ee"[..],
+            NonCanonical::UnsortedKey("a".into()),
+        ),
+        (b"d1:ai1e1:ai2ee", NonCanonical::DuplicateKey("a".into())),
+        (b"li01ee", NonCanonical::LeadingZero("01".into())),
+        (b"i-007e", NonCanonical::LeadingZero("-007".into())),
+        (b"03:abc", NonCanonical::LeadingZero("03".into())),
+        (b"ld1:ai-0eee", NonCanonical::NegativeZero),
+    ] {
+        let e = check(bencode, Mode::Strict).unwrap_err();
+        assert_eq!(e.downcast_ref::<NonCanonical>(), Some(&problem), "{e:#}");
+        check(bencode, Mode::Lenient).expect("lenient mode accepts it");
+    }
+    // broken in either mode
+    for bencode in [&b"ie"[..], b"i1x2e", b"d1:ae", b"di1e1:ae", b"i1ei2e"] {
+        assert!(check(bencode, Mode::Lenient).is_err());
+    }
+}
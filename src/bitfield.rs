@@ -0,0 +1,260 @@
+//! Which pieces of a torrent someone has.
+//!
+//! A [`Bitfield`] keeps one bit per piece, laid out the way the peer wire protocol's `Bitfield`
+//! message does: the first piece is the highest bit of the first byte. Pieces past the end of what
+//! has been set are simply not there, so a bitfield doesn't need to know how many pieces the
+//! torrent has until it's turned into (or checked against) a message payload.
+
+/// The set of pieces someone has, such as a peer, or us.
+#[derive(Debug, Clone, Default)]
+pub struct Bitfield {
+    payload: Vec<u8>,
+}
+
+impl Bitfield {
+    /// A bitfield without any pieces.
+    pub fn empty() -> Self {
+        Self {
+            payload: Vec::new(),
+        }
+    }
+
+    /// A bitfield with every one of `npieces` pieces.
+    pub fn full(npieces: usize) -> Self {
+        (0..npieces).collect()
+    }
+
+    pub fn has_piece(&self, piece_i: usize) -> bool {
+        let (byte_i, mask) = locate(piece_i);
+        let Some(&byte) = self.payload.get(byte_i) else {
+            return false;
+        };
+        byte & mask != 0
+    }
+
+    /// The pieces in the bitfield, in increasing order.
+    pub fn pieces(&self) -> Pieces<'_> {
+        Pieces {
+            payload: &self.payload,
+            next: 0,
+        }
+    }
+
+    pub fn set_piece(&mut self, piece_i: usize) {
+        let (byte_i, mask) = locate(piece_i);
+        if self.payload.len() <= byte_i {
+            self.payload.resize(byte_i + 1, 0);
+        }
+        self.payload[byte_i] |= mask;
+    }
+
+    pub fn clear_piece(&mut self, piece_i: usize) {
+        let (byte_i, mask) = locate(piece_i);
+        if let Some(byte) = self.payload.get_mut(byte_i) {
+            *byte &= !mask;
+        }
+    }
+
+    /// The number of pieces in the bitfield.
+    pub fn count_ones(&self) -> usize {
+        self.payload
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    /// Whether the bitfield has every one of `npieces` pieces.
+    pub fn is_complete(&self, npieces: usize) -> bool {
+        self.pieces()
+            .take_while(|&piece_i| piece_i < npieces)
+            .count()
+            == npieces
+    }
+
+    /// The bitfield a peer sent in a `Bitfield` message, for a torrent with `npieces` pieces.
+    ///
+    /// The payload must have exactly one bit per piece, rounded up to whole bytes, and the spare
+    /// bits at the end must be zero.
+    pub fn from_payload(payload: Vec<u8>, npieces: usize) -> Result<Self, InvalidBitfield> {
+        let expected = npieces.div_ceil(u8::BITS as usize);
+        if payload.len() != expected {
+            return Err(InvalidBitfield::Length {
+                expected,
+                actual: payload.len(),
+            });
+        }
+        let spare = expected * (u8::BITS as usize) - npieces;
+        if let Some(&last) = payload.last() {
+            if spare > 0 && last & ((1u8 << spare) - 1) != 0 {
+                return Err(InvalidBitfield::SpareBitsSet);
+            }
+        }
+        Ok(Self { payload })
+    }
+
+    /// The payload of a `Bitfield` message for a torrent with `npieces` pieces.
+    ///
+    /// Any pieces at or past `npieces` are left out.
+    pub fn to_payload(&self, npieces: usize) -> Vec<u8> {
+        let mut payload = self.payload.clone();
+        payload.resize(npieces.div_ceil(u8::BITS as usize), 0);
+        let spare = payload.len() * (u8::BITS as usize) - npieces;
+        if let Some(last) = payload.last_mut() {
+            *last &= !((1u16 << spare) - 1) as u8;
+        }
+        payload
+    }
+}
+
+/// The byte that holds the bit of `piece_i`, and the mask for that bit within it.
+fn locate(piece_i: usize) -> (usize, u8) {
+    let byte_i = piece_i / (u8::BITS as usize);
+    let bit_i = (piece_i % (u8::BITS as usize)) as u32;
+    (byte_i, 1u8.rotate_right(bit_i + 1))
+}
+
+// two bitfields with the same pieces are equal, however far each one's payload happens to extend
+impl PartialEq for Bitfield {
+    fn eq(&self, other: &Self) -> bool {
+        let (short, long) = if self.payload.len() <= other.payload.len() {
+            (&self.payload, &other.payload)
+        } else {
+            (&other.payload, &self.payload)
+        };
+        long.starts_with(short) && long[short.len()..].iter().all(|&byte| byte == 0)
+    }
+}
+
+impl Eq for Bitfield {}
+
+impl FromIterator<usize> for Bitfield {
+    fn from_iter<I: IntoIterator<Item = usize>>(pieces: I) -> Self {
+        let mut bitfield = Self::empty();
+        bitfield.extend(pieces);
+        bitfield
+    }
+}
+
+impl Extend<usize> for Bitfield {
+    fn extend<I: IntoIterator<Item = usize>>(&mut self, pieces: I) {
+        for piece_i in pieces {
+            self.set_piece(piece_i);
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Bitfield {
+    type Item = usize;
+    type IntoIter = Pieces<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.pieces()
+    }
+}
+
+/// The pieces of a [`Bitfield`], from [`Bitfield::pieces`].
+#[derive(Debug, Clone)]
+pub struct Pieces<'a> {
+    payload: &'a [u8],
+    next: usize,
+}
+
+impl Iterator for Pieces<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (byte_i, _) = locate(self.next);
+            let byte = *self.payload.get(byte_i)?;
+            // skip the rest of the byte at once if it has no more pieces
+            let bit_i = self.next % (u8::BITS as usize);
+            let rest = byte << bit_i;
+            if rest == 0 {
+                self.next = (byte_i + 1) * (u8::BITS as usize);
+                continue;
+            }
+            let piece_i = self.next + rest.leading_zeros() as usize;
+            self.next = piece_i + 1;
+            return Some(piece_i);
+        }
+    }
+}
+
+/// Ways in which a peer's bitfield can disagree with the torrent.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidBitfield {
+    #[error("bitfield is {actual} bytes long, but the torrent's pieces need {expected}")]
+    Length { expected: usize, actual: usize },
+    #[error("bitfield has bits set past the last piece")]
+    SpareBitsSet,
+}
+
+#[test]
+fn bitfield_validation() {
+    assert!(Bitfield::from_payload(vec![0b11111111, 0b11000000], 10).is_ok());
+    assert!(Bitfield::from_payload(vec![0b11111111], 8).is_ok());
+    assert!(Bitfield::from_payload(Vec::new(), 0).is_ok());
+    assert_eq!(
+        Bitfield::from_payload(vec![0b11111111, 0b11100000], 10).unwrap_err(),
+        InvalidBitfield::SpareBitsSet
+    );
+    assert_eq!(
+        Bitfield::from_payload(vec![0b11111111], 10).unwrap_err(),
+        InvalidBitfield::Length {
+            expected: 2,
+            actual: 1
+        }
+    );
+    assert!(Bitfield::from_payload(vec![0; 3], 10).is_err());
+}
+
+#[test]
+fn bitfield_has() {
+    let bf = Bitfield {
+        payload: vec![0b10101010, 0b01010101],
+    };
+    assert!(bf.has_piece(0));
+    assert!(!bf.has_piece(1));
+    assert!(!bf.has_piece(7));
+    assert!(!bf.has_piece(8));
+    assert!(bf.has_piece(15));
+}
+
+#[test]
+fn bitfield_iter() {
+    let bf = Bitfield {
+        payload: vec![0b10101010, 0b01010101],
+    };
+    let mut pieces = bf.pieces();
+    assert_eq!(pieces.next(), Some(0));
+    assert_eq!(pieces.next(), Some(2));
+    assert_eq!(pieces.next(), Some(4));
+    assert_eq!(pieces.next(), Some(6));
+    assert_eq!(pieces.next(), Some(9));
+    assert_eq!(pieces.next(), Some(11));
+    assert_eq!(pieces.next(), Some(13));
+    assert_eq!(pieces.next(), Some(15));
+    assert_eq!(pieces.next(), None);
+}
+
+#[test]
+fn bitfield_mutation() {
+    let mut bf: Bitfield = [1, 3, 9].into_iter().collect();
+    assert_eq!(bf.count_ones(), 3);
+    assert_eq!(bf.to_payload(10), [0b01010000, 0b01000000]);
+    // pieces past the end of the torrent don't make it into the payload
+    assert_eq!(bf.to_payload(9), [0b01010000, 0b00000000]);
+
+    bf.clear_piece(9);
+    bf.clear_piece(100);
+    assert_eq!((&bf).into_iter().collect::<Vec<_>>(), [1, 3]);
+    assert_eq!(bf, [3, 1].into_iter().collect());
+    assert_ne!(bf, Bitfield::empty());
+
+    assert!(!bf.is_complete(4));
+    bf.extend([0, 2]);
+    assert!(bf.is_complete(4));
+    assert!(!bf.is_complete(5));
+    assert_eq!(bf, Bitfield::full(4));
+    assert!(Bitfield::empty().is_complete(0));
+}
@@ -0,0 +1,114 @@
+//! A least-recently-used cache of whole pieces, bounded by the number of bytes it holds.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+pub(crate) struct PieceCache {
+    /// The most bytes of piece data the cache may hold at once.
+    budget: usize,
+    inner: Mutex<Lru>,
+}
+
+#[derive(Default)]
+struct Lru {
+    /// The cached pieces, along with when they were last used.
+    pieces: HashMap<usize, (Arc<[u8]>, u64)>,
+    /// Cached piece indices ordered by when they were last used, least recent first.
+    by_use: BTreeMap<u64, usize>,
+    size: usize,
+    clock: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, piece_i: usize) -> Option<Arc<[u8]>> {
+        self.clock += 1;
+        let (piece, used) = self.pieces.get_mut(&piece_i)?;
+        self.by_use.remove(used);
+        *used = self.clock;
+        self.by_use.insert(self.clock, piece_i);
+        Some(Arc::clone(piece))
+    }
+}
+
+impl PieceCache {
+    pub(crate) fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            inner: Mutex::new(Lru::default()),
+        }
+    }
+
+    /// Get piece `piece_i` from the cache, or read it with `read` and cache it if it isn't there.
+    ///
+    /// The cache isn't locked while `read` runs, so two callers missing on the same piece at the
+    /// same time may both end up reading it.
+    pub(crate) fn get_or_insert_with(
+        &self,
+        piece_i: usize,
+        read: impl FnOnce() -> anyhow::Result<Arc<[u8]>>,
+    ) -> anyhow::Result<Arc<[u8]>> {
+        if let Some(piece) = self
+            .inner
+            .lock()
+            .expect("cache lock poisoned")
+            .touch(piece_i)
+        {
+            return Ok(piece);
+        }
+        let piece = read()?;
+        if piece.len() > self.budget {
+            return Ok(piece);
+        }
+
+        let mut lru = self.inner.lock().expect("cache lock poisoned");
+        if lru.touch(piece_i).is_some() {
+            // someone else read it in the meantime
+            return Ok(piece);
+        }
+        while lru.size + piece.len() > self.budget {
+            let (_, evict) = lru
+                .by_use
+                .pop_first()
+                .expect("cache is over budget, so it can't be empty");
+            let (evicted, _) = lru.pieces.remove(&evict).expect("by_use matches pieces");
+            lru.size -= evicted.len();
+        }
+        let used = lru.clock;
+        lru.size += piece.len();
+        lru.pieces.insert(piece_i, (Arc::clone(&piece), used));
+        lru.by_use.insert(used, piece_i);
+        Ok(piece)
+    }
+}
+
+#[test]
+fn lru_eviction() {
+    let cache = PieceCache::new(10);
+    let reads = std::cell::Cell::new(0);
+    let get = |piece_i: usize, len: usize| {
+        cache
+            .get_or_insert_with(piece_i, || {
+                reads.set(reads.get() + 1);
+                Ok(vec![piece_i as u8; len].into())
+            })
+            .unwrap()
+    };
+
+    get(0, 4);
+    get(1, 4);
+    assert_eq!(reads.get(), 2);
+    // a hit makes 0 the most recently used, so 1 is evicted to make room for 2
+    assert_eq!(&*get(0, 4), &[0; 4]);
+    assert_eq!(reads.get(), 2);
+    get(2, 4);
+    assert_eq!(reads.get(), 3);
+    get(0, 4);
+    assert_eq!(reads.get(), 3);
+    get(1, 4);
+    assert_eq!(reads.get(), 4);
+
+    // pieces that don't fit at all are never cached
+    get(3, 11);
+    get(3, 11);
+    assert_eq!(reads.get(), 6);
+}
@@ -0,0 +1,102 @@
+//! Running several downloads side by side.
+
+use crate::dedupe::{self, Duplicate};
+use crate::download::{self, DownloadConfig, DownloadHandle, PiecesSlot};
+use crate::torrent::Torrent;
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+
+/// Downloads any number of torrents at once.
+///
+/// Every download shares the client's peer id and rate limits, so a limit of, say, 1 MiB/s applies
+/// to all of them together rather than to each one.
+///
+/// With a [limit](Self::with_max_active_downloads) on how many download at once, the rest wait
+/// their turn in the order they were added.
+pub struct Client {
+    config: DownloadConfig,
+    stop: CancellationToken,
+    /// The turns of downloads to be active, if there's a limit to how many may be.
+    queue: Option<Arc<Semaphore>>,
+    /// Every download we've started, and where its pieces are for as long as its handle is around.
+    downloads: Mutex<Vec<(Torrent, Weak<PiecesSlot>)>>,
+}
+
+impl Client {
+    /// A client whose downloads all use `config`.
+    pub fn new(config: DownloadConfig) -> Self {
+        Self {
+            config,
+            stop: CancellationToken::new(),
+            queue: None,
+            downloads: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Only let `max` downloads be active at once, and queue up the rest until one of those
+    /// finishes (or is [force started](DownloadHandle::force_start)).
+    pub fn with_max_active_downloads(mut self, max: usize) -> Self {
+        self.queue = Some(Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    pub fn config(&self) -> &DownloadConfig {
+        &self.config
+    }
+
+    /// Start downloading `t` in the background, or queue it up if too many downloads are active
+    /// already.
+    pub fn add(&self, t: &Torrent) -> DownloadHandle {
+        let handle = download::start(
+            t.clone(),
+            self.config.clone(),
+            self.stop.child_token(),
+            self.queue.clone(),
+        );
+        let mut downloads = self.downloads.lock().expect("client lock poisoned");
+        downloads.retain(|(_, pieces)| pieces.strong_count() > 0);
+        downloads.push((t.clone(), handle.pieces()));
+        handle
+    }
+
+    /// Find the files that any two of our downloads have in common, and copy the pieces of them
+    /// that one download already has over to the other, so they aren't downloaded twice.
+    ///
+    /// Only downloads whose [`DownloadHandle`] is still around (and whose storage is open) are
+    /// considered, but that includes ones that have finished.
+    pub fn dedupe_scan(&self) -> anyhow::Result<Vec<Duplicate>> {
+        let downloads: Vec<_> = self
+            .downloads
+            .lock()
+            .expect("client lock poisoned")
+            .iter()
+            .filter_map(|(t, pieces)| Some((t.clone(), pieces.upgrade()?.get()?.clone())))
+            .collect();
+        let mut duplicates = Vec::new();
+        for (i, (a, pieces_a)) in downloads.iter().enumerate() {
+            for (b, pieces_b) in &downloads[i + 1..] {
+                for file in dedupe::shared_files(a, b) {
+                    let reused = dedupe::reuse(
+                        &file,
+                        pieces_a,
+                        pieces_b,
+                        &a.info.pieces.0,
+                        &*self.config.hasher,
+                    )?;
+                    duplicates.push(Duplicate {
+                        info_hashes: [a.info_hash(), b.info_hash()],
+                        file,
+                        reused,
+                    });
+                }
+            }
+        }
+        Ok(duplicates)
+    }
+
+    /// Gracefully [stop](DownloadHandle::stop) every download of this client.
+    pub fn stop(&self) {
+        self.stop.cancel();
+    }
+}
@@ -0,0 +1,246 @@
+//! Comparing the info dictionaries of two torrents, such as a re-created torrent and the original
+//! it's meant to match.
+//!
+//! Two torrents with the same info hash are the same torrent, but that's all the hash tells us.
+//! When they differ, [`compare`] says where: in the name, the piece length, which pieces hash
+//! differently, or which files were added, removed, or changed length.
+
+use crate::torrent::{Keys, Torrent};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// A way in which the info dictionaries of two torrents, `a` and `b`, differ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    Name {
+        a: String,
+        b: String,
+    },
+    /// One torrent is a single file, and the other a directory of files.
+    Kind {
+        a_single: bool,
+    },
+    PieceLength {
+        a: usize,
+        b: usize,
+    },
+    Private {
+        a: bool,
+        b: bool,
+    },
+    PieceCount {
+        a: usize,
+        b: usize,
+    },
+    /// The pieces (among those both torrents have) whose hashes differ.
+    Pieces(Vec<usize>),
+    /// A file only `a` has, with its length.
+    OnlyInA {
+        path: PathBuf,
+        length: usize,
+    },
+    /// A file only `b` has, with its length.
+    OnlyInB {
+        path: PathBuf,
+        length: usize,
+    },
+    FileLength {
+        path: PathBuf,
+        a: usize,
+        b: usize,
+    },
+    /// Both torrents have the same files, but in a different order, and so a different layout of
+    /// their data across pieces.
+    FileOrder,
+}
+
+/// Every way in which the info dictionaries of `a` and `b` differ, which is none if they have
+/// the same info hash.
+pub fn compare(a: &Torrent, b: &Torrent) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    if a.info_hash() == b.info_hash() {
+        return differences;
+    }
+
+    let (name_a, name_b) = (a.info.display_name(), b.info.display_name());
+    if name_a != name_b {
+        differences.push(Difference::Name {
+            a: name_a.into_owned(),
+            b: name_b.into_owned(),
+        });
+    }
+    let a_single = matches!(a.info.keys, Keys::SingleFile { .. });
+    if a_single != matches!(b.info.keys, Keys::SingleFile { .. }) {
+        differences.push(Difference::Kind { a_single });
+    }
+    if a.info.plength != b.info.plength {
+        differences.push(Difference::PieceLength {
+            a: a.info.plength,
+            b: b.info.plength,
+        });
+    }
+    if a.is_private() != b.is_private() {
+        differences.push(Difference::Private {
+            a: a.is_private(),
+            b: b.is_private(),
+        });
+    }
+
+    let (pieces_a, pieces_b) = (&a.info.pieces.0, &b.info.pieces.0);
+    if pieces_a.len() != pieces_b.len() {
+        differences.push(Difference::PieceCount {
+            a: pieces_a.len(),
+            b: pieces_b.len(),
+        });
+    }
+    let changed: Vec<_> = pieces_a
+        .iter()
+        .zip(pieces_b)
+        .enumerate()
+        .filter(|(_, (a, b))| a != b)
+        .map(|(i, _)| i)
+        .collect();
+    if !changed.is_empty() {
+        differences.push(Difference::Pieces(changed));
+    }
+
+    let (files_a, files_b) = (files(a), files(b));
+    let lengths_b: BTreeMap<_, _> = files_b.iter().cloned().collect();
+    let lengths_a: BTreeMap<_, _> = files_a.iter().cloned().collect();
+    for (path, length) in &files_a {
+        match lengths_b.get(path) {
+            None => differences.push(Difference::OnlyInA {
+                path: path.clone(),
+                length: *length,
+            }),
+            Some(&b) if b != *length => differences.push(Difference::FileLength {
+                path: path.clone(),
+                a: *length,
+                b,
+            }),
+            Some(_) => {}
+        }
+    }
+    for (path, length) in &files_b {
+        if !lengths_a.contains_key(path) {
+            differences.push(Difference::OnlyInB {
+                path: path.clone(),
+                length: *length,
+            });
+        }
+    }
+    if lengths_a == lengths_b && files_a != files_b {
+        differences.push(Difference::FileOrder);
+    }
+    differences
+}
+
+/// The path of every file of `t` (relative to its directory, for a multi-file torrent), along
+/// with its length, in the order the torrent lists them.
+fn files(t: &Torrent) -> Vec<(PathBuf, usize)> {
+    match &t.info.keys {
+        Keys::SingleFile { length } => vec![(t.info.local_name().into(), *length)],
+        Keys::MultiFile { files } => files
+            .iter()
+            .filter(|file| !file.is_dir())
+            .map(|file| (file.relative_path(), file.length))
+            .collect(),
+    }
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Name { a, b } => write!(f, "name: {a:?} vs {b:?}"),
+            Self::Kind { a_single: true } => f.write_str("a single file vs a directory of files"),
+            Self::Kind { a_single: false } => f.write_str("a directory of files vs a single file"),
+            Self::PieceLength { a, b } => write!(f, "piece length: {a} vs {b}"),
+            Self::Private { a, b } => write!(f, "private: {a} vs {b}"),
+            Self::PieceCount { a, b } => write!(f, "piece count: {a} vs {b}"),
+            Self::Pieces(pieces) => {
+                write!(f, "{} piece hashes differ: ", pieces.len())?;
+                // runs of consecutive pieces, as `first-last`
+                let mut runs = Vec::new();
+                for &i in pieces {
+                    match runs.last_mut() {
+                        Some((_, last)) if *last + 1 == i => *last = i,
+                        _ => runs.push((i, i)),
+                    }
+                }
+                for (n, (first, last)) in runs.into_iter().enumerate() {
+                    if n > 0 {
+                        f.write_str(", ")?;
+                    }
+                    if first == last {
+                        write!(f, "{first}")?;
+                    } else {
+                        write!(f, "{first}-{last}")?;
+                    }
+                }
+                Ok(())
+            }
+            Self::OnlyInA { path, length } => {
+                write!(f, "only in the first: {} ({length} bytes)", path.display())
+            }
+            Self::OnlyInB { path, length } => {
+                write!(f, "only in the second: {} ({length} bytes)", path.display())
+            }
+            Self::FileLength { path, a, b } => {
+                write!(f, "length of {}: {a} vs {b}", path.display())
+            }
+            Self::FileOrder => f.write_str("the same files, but in a different order"),
+        }
+    }
+}
+
+#[test]
+fn differences() {
+    use crate::torrent::File;
+    let (a, _) = crate::testing::generate_files(&[10, 20, 30], 16);
+    assert!(compare(&a, &a.clone()).is_empty());
+    let paths: Vec<_> = files(&a).into_iter().map(|(path, _)| path).collect();
+
+    let mut b = a.clone();
+    b.info.pieces.0[1] = [0; 20];
+    b.info.pieces.0[2] = [0; 20];
+    let Keys::MultiFile { files } = &mut b.info.keys else {
+        unreachable!("generated with several files");
+    };
+    files[1].length = 21;
+    files[2] = File {
+        length: 30,
+        path: vec!["other".into()],
+        path_utf8: None,
+    };
+    b.info.private = Some(1);
+    let differences = compare(&a, &b);
+    assert_eq!(
+        differences,
+        [
+            Difference::Private { a: false, b: true },
+            Difference::Pieces(vec![1, 2]),
+            Difference::FileLength {
+                path: paths[1].clone(),
+                a: 20,
+                b: 21
+            },
+            Difference::OnlyInA {
+                path: paths[2].clone(),
+                length: 30
+            },
+            Difference::OnlyInB {
+                path: "other".into(),
+                length: 30
+            },
+        ]
+    );
+    assert_eq!(differences[1].to_string(), "2 piece hashes differ: 1-2");
+
+    let mut swapped = a.clone();
+    let Keys::MultiFile { files } = &mut swapped.info.keys else {
+        unreachable!("generated with several files");
+    };
+    files.swap(0, 1);
+    assert_eq!(compare(&a, &swapped), [Difference::FileOrder]);
+}
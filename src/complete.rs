@@ -0,0 +1,172 @@
+//! What to do with a torrent's files once it has finished downloading.
+//!
+//! Like most clients, we can keep incomplete downloads apart from finished ones: the files are
+//! written somewhere first, and only moved to their final directory once every piece has been
+//! verified. After that, a command of the user's choosing can be run, to hand the files on to
+//! whatever comes next.
+
+use crate::hash::{PieceHasher, Sha1Hasher};
+use crate::torrent::{Keys, Torrent};
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+/// The hooks to run when a download completes.
+#[derive(Debug, Clone, Default)]
+pub struct OnComplete {
+    /// Move the torrent's files into this directory.
+    pub move_to: Option<PathBuf>,
+    /// Run this command with `sh -c` once the files are where they'll stay.
+    ///
+    /// The command finds out about the torrent from the environment: `TORRENT_NAME`,
+    /// `TORRENT_INFO_HASH` (in hex), and `TORRENT_PATH`, which is the file of a single-file
+    /// torrent, or the directory of a multi-file one.
+    pub exec: Option<String>,
+}
+
+impl OnComplete {
+    /// Run the hooks for `t`, whose data was written to `path`, and return where it is now.
+    ///
+    /// If the files can't simply be renamed into place, such as when `move_to` is on another
+    /// file system, they're copied instead. The originals are only removed once every piece of the
+    /// copy has been checked against the torrent.
+    pub async fn run(&self, t: &Torrent, path: &Path) -> anyhow::Result<PathBuf> {
+        let path = match &self.move_to {
+            Some(dir) => move_to(t, path, dir).await?,
+            None => path.to_path_buf(),
+        };
+        if let Some(command) = &self.exec {
+            let status = tokio::process::Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .env("TORRENT_NAME", &*t.info.display_name())
+                .env("TORRENT_INFO_HASH", hex::encode(t.info_hash()))
+                .env("TORRENT_PATH", &path)
+                .status()
+                .await
+                .with_context(|| format!("run {command:?}"))?;
+            anyhow::ensure!(status.success(), "{command:?} failed: {status}");
+        }
+        Ok(path)
+    }
+}
+
+async fn move_to(t: &Torrent, from: &Path, dir: &Path) -> anyhow::Result<PathBuf> {
+    let name = from
+        .file_name()
+        .with_context(|| format!("{} has no file name", from.display()))?;
+    let to = dir.join(name);
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("create {}", dir.display()))?;
+    anyhow::ensure!(
+        !tokio::fs::try_exists(&to).await.unwrap_or(false),
+        "{} already exists",
+        to.display()
+    );
+    if tokio::fs::rename(from, &to).await.is_ok() {
+        return Ok(to);
+    }
+
+    for (relative, is_dir) in files(t) {
+        let (from, to) = (join(from, &relative), join(&to, &relative));
+        if is_dir {
+            tokio::fs::create_dir_all(&to)
+                .await
+                .with_context(|| format!("create {}", to.display()))?;
+            continue;
+        }
+        if let Some(parent) = to.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("create {}", parent.display()))?;
+        }
+        tokio::fs::copy(&from, &to)
+            .await
+            .with_context(|| format!("copy {} to {}", from.display(), to.display()))?;
+    }
+    verify(t, &to, &Sha1Hasher)
+        .await
+        .with_context(|| format!("check the copy at {}", to.display()))?;
+    if matches!(t.info.keys, Keys::SingleFile { .. }) {
+        tokio::fs::remove_file(from).await
+    } else {
+        tokio::fs::remove_dir_all(from).await
+    }
+    .with_context(|| format!("remove {}", from.display()))?;
+    Ok(to)
+}
+
+/// The files of `t`, relative to where its data is, and whether each one is really a directory.
+///
+/// A single-file torrent's data is the file itself, so its one file has an empty path.
+fn files(t: &Torrent) -> Vec<(PathBuf, bool)> {
+    match &t.info.keys {
+        Keys::SingleFile { .. } => vec![(PathBuf::new(), false)],
+        Keys::MultiFile { files } => files
+            .iter()
+            .map(|file| (file.relative_path(), file.is_dir()))
+            .collect(),
+    }
+}
+
+fn join(base: &Path, relative: &Path) -> PathBuf {
+    if relative.as_os_str().is_empty() {
+        base.to_path_buf()
+    } else {
+        base.join(relative)
+    }
+}
+
+/// Check that the data of `t` at `path` matches every piece hash.
+async fn verify(t: &Torrent, path: &Path, hasher: &dyn PieceHasher) -> anyhow::Result<()> {
+    let mut data = Vec::with_capacity(t.length());
+    for (relative, is_dir) in files(t) {
+        if is_dir {
+            continue;
+        }
+        let file = join(path, &relative);
+        data.extend(
+            tokio::fs::read(&file)
+                .await
+                .with_context(|| format!("read {}", file.display()))?,
+        );
+    }
+    anyhow::ensure!(
+        data.len() == t.length(),
+        "found {} bytes, but the torrent has {}",
+        data.len(),
+        t.length()
+    );
+    let mut pieces = data.chunks(t.info.plength).zip(&t.info.pieces.0);
+    if let Some(piece_i) = pieces.position(|(piece, hash)| hasher.hash(piece) != *hash) {
+        anyhow::bail!("piece {piece_i} doesn't match the torrent");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn copy_and_verify() {
+    let (t, data) = crate::testing::generate(1000, 64);
+    let root = tempfile::tempdir().expect("create temporary directory");
+    let from = root.path().join("incomplete").join("generated.bin");
+    std::fs::create_dir_all(from.parent().unwrap()).unwrap();
+    std::fs::write(&from, &data).unwrap();
+    let done = root.path().join("done");
+    std::fs::create_dir_all(done.join("generated.bin")).unwrap();
+    // something is in the way, so nothing is moved
+    assert!(move_to(&t, &from, &done).await.is_err());
+    assert!(from.exists());
+
+    std::fs::remove_dir(done.join("generated.bin")).unwrap();
+    let hooks = OnComplete {
+        move_to: Some(done.clone()),
+        exec: Some("test \"$(cat \"$TORRENT_PATH\" | wc -c)\" -eq 1000".to_string()),
+    };
+    let path = hooks.run(&t, &from).await.expect("hooks succeed");
+    assert_eq!(path, done.join("generated.bin"));
+    assert!(!from.exists());
+    assert!(verify(&t, &path, &Sha1Hasher).await.is_ok());
+
+    std::fs::write(&path, &data[1..]).unwrap();
+    assert!(verify(&t, &path, &Sha1Hasher).await.is_err());
+}
@@ -0,0 +1,214 @@
+//! Finding the files that several torrents have in common, so that they're only downloaded once.
+//!
+//! A v1 torrent hashes pieces rather than files, and pieces straddle the boundaries between files,
+//! so the best we can do is compare the pieces that lie wholly within a file. A file is taken to
+//! be the same in two torrents if it's as long in both, starts at the same offset into a piece
+//! in both (with pieces of the same length), and every piece that lies wholly within it in both
+//! torrents has the same hash. The pieces that straddle its ends are left to be downloaded.
+//!
+//! v2 torrents hash every file on its own (see [`crate::merkle`]), which would make this a matter
+//! of comparing file roots, but we don't read those yet.
+
+use crate::hash::PieceHasher;
+use crate::storage::Pieces;
+use crate::torrent::Torrent;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// A file that is the same in two torrents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedFile {
+    /// The index of the file in the first torrent.
+    pub file_a: usize,
+    /// The index of the file in the second torrent.
+    pub file_b: usize,
+    pub length: usize,
+    /// The pieces that lie wholly within the file, as the index of the same piece in the first
+    /// torrent and in the second.
+    pub pieces: Vec<(usize, usize)>,
+}
+
+/// A file that two of a [`Client`](crate::client::Client)'s downloads have in common.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Duplicate {
+    /// The info hashes of the two torrents.
+    pub info_hashes: [[u8; 20]; 2],
+    pub file: SharedFile,
+    /// How many of the file's pieces one download got from the other, rather than from peers.
+    pub reused: usize,
+}
+
+/// The files that `a` and `b` have in common.
+pub fn shared_files(a: &Torrent, b: &Torrent) -> Vec<SharedFile> {
+    let plength = a.info.plength;
+    if plength != b.info.plength || plength == 0 {
+        return Vec::new();
+    }
+    let mut by_length: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+    for (file_b, (start, length)) in files(b).enumerate() {
+        if length > 0 {
+            by_length.entry(length).or_default().push((file_b, start));
+        }
+    }
+
+    let mut shared = Vec::new();
+    for (file_a, (start_a, length)) in files(a).enumerate() {
+        let Some(candidates) = by_length.get(&length) else {
+            continue;
+        };
+        let pieces_a = whole_pieces(a, start_a..start_a + length);
+        for &(file_b, start_b) in candidates {
+            if start_a % plength != start_b % plength {
+                continue;
+            }
+            let pieces_b = whole_pieces(b, start_b..start_b + length);
+            // the file may be followed by another in one torrent but not the other, so the last
+            // piece may only lie within the file in one of them
+            let pieces: Vec<_> = pieces_a.clone().zip(pieces_b).collect();
+            let same = pieces.iter().all(|&(piece_a, piece_b)| {
+                a.info.pieces.0[piece_a] == b.info.pieces.0[piece_b]
+                    && piece_length(a, piece_a) == piece_length(b, piece_b)
+            });
+            if same && !pieces.is_empty() {
+                shared.push(SharedFile {
+                    file_a,
+                    file_b,
+                    length,
+                    pieces,
+                });
+            }
+        }
+    }
+    shared
+}
+
+/// Copy the pieces of `shared` that one of `a` and `b` has and the other doesn't across, after
+/// checking them against `hashes_a` (the piece hashes of `a`) with `hasher`.
+///
+/// Returns how many pieces were copied.
+pub(crate) fn reuse(
+    shared: &SharedFile,
+    a: &Pieces,
+    b: &Pieces,
+    hashes_a: &[[u8; 20]],
+    hasher: &dyn PieceHasher,
+) -> anyhow::Result<usize> {
+    let mut reused = 0;
+    for &(piece_a, piece_b) in &shared.pieces {
+        let (from, from_i, to, to_i) = match (a.has_piece(piece_a), b.has_piece(piece_b)) {
+            (true, false) => (a, piece_a, b, piece_b),
+            (false, true) => (b, piece_b, a, piece_a),
+            _ => continue,
+        };
+        let data = from
+            .read_piece(from_i)
+            .with_context(|| format!("read piece {from_i}"))?;
+        // the pieces have the same hash in both torrents
+        if hasher.hash(&data) != hashes_a[piece_a] {
+            eprintln!("piece {from_i} no longer matches its hash, so not reusing it");
+            continue;
+        }
+        to.fill(to_i, &data)
+            .with_context(|| format!("fill in piece {to_i}"))?;
+        reused += 1;
+    }
+    Ok(reused)
+}
+
+/// Where each file of `t` starts in the torrent's data, along with its length.
+fn files(t: &Torrent) -> impl Iterator<Item = (usize, usize)> + '_ {
+    t.file_lengths().scan(0, |offset, length| {
+        let start = *offset;
+        *offset += length;
+        Some((start, length))
+    })
+}
+
+/// The pieces of `t` that lie wholly within the bytes `range` of its data.
+fn whole_pieces(t: &Torrent, range: Range<usize>) -> Range<usize> {
+    let plength = t.info.plength;
+    let first = range.start.div_ceil(plength);
+    // the last piece of the torrent is short, so it ends with the torrent rather than a whole
+    // piece length on
+    let end = if range.end == t.length() {
+        range.end.div_ceil(plength)
+    } else {
+        range.end / plength
+    };
+    first..end.max(first)
+}
+
+fn piece_length(t: &Torrent, piece_i: usize) -> usize {
+    t.info.plength.min(t.length() - piece_i * t.info.plength)
+}
+
+#[test]
+fn files_in_common() {
+    use crate::torrent::{File, Hashes, Info, Keys};
+    let file = |length, name: &str| File {
+        length,
+        path: vec![name.into()],
+        path_utf8: None,
+    };
+    let hash = |n: u8| [n; 20];
+    // pieces of 10 bytes: [10 a] [10 a] [5 a, 5 b] [10 b]
+    let a = Torrent::new(
+        String::new(),
+        Info {
+            name: "a".into(),
+            name_utf8: None,
+            plength: 10,
+            pieces: Hashes(vec![hash(1), hash(2), hash(3), hash(4)]),
+            private: None,
+            keys: Keys::MultiFile {
+                files: vec![file(25, "a"), file(15, "b")],
+            },
+        },
+    );
+    // pieces of 10 bytes: [10 x] [5 x, 5 b] [10 b] [5 b]
+    let b = Torrent::new(
+        String::new(),
+        Info {
+            name: "b".into(),
+            name_utf8: None,
+            plength: 10,
+            pieces: Hashes(vec![hash(9), hash(8), hash(4), hash(7)]),
+            private: None,
+            keys: Keys::MultiFile {
+                files: vec![file(15, "x"), file(15, "b"), file(5, "y")],
+            },
+        },
+    );
+    // b starts 5 bytes into a piece in both, and its one whole piece is the same in both
+    assert_eq!(
+        shared_files(&a, &b),
+        [SharedFile {
+            file_a: 1,
+            file_b: 1,
+            length: 15,
+            pieces: vec![(3, 2)],
+        }]
+    );
+
+    // a single-file torrent of just a's first file: its short last piece is wholly within the
+    // file, but only shares a hash with a's if that piece is wholly within the file there too
+    let single = |pieces| {
+        Torrent::new(
+            String::new(),
+            Info {
+                name: "a".into(),
+                name_utf8: None,
+                plength: 10,
+                pieces: Hashes(pieces),
+                private: None,
+                keys: Keys::SingleFile { length: 25 },
+            },
+        )
+    };
+    let shared = shared_files(&single(vec![hash(1), hash(2), hash(5)]), &a);
+    assert_eq!(shared.len(), 1);
+    assert_eq!(shared[0].pieces, [(0, 0), (1, 1)]);
+    assert!(shared_files(&single(vec![hash(1), hash(6), hash(5)]), &a).is_empty());
+    assert_eq!(shared_files(&a, &a).len(), 2);
+}
@@ -0,0 +1,259 @@
+//! Storage that writes pieces to the torrent's files with ordinary file writes, in batches.
+//!
+//! Pieces arrive in whatever order the swarm hands them to us, and with large pieces spread over
+//! a large file, writing each one as it's verified makes for a lot of seeking, which spinning
+//! disks are slow at. Instead, verified pieces are held in memory until they add up to a budget
+//! (or have been waiting for a while), and are then written out in order of where they go, with
+//! the pieces that are next to each other in the same file written in a single go.
+
+use crate::storage::{FileLayout, Layout, Storage};
+use crate::torrent::Torrent;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a written piece may wait in memory before it goes to disk, however few there are.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Keeps pieces in the torrent's files on disk, writing them out in batches.
+///
+/// The files are laid out the way [`Downloaded::write_to_dir`](crate::download::Downloaded)
+/// would write them with the same [`Layout`].
+pub struct DiskStorage {
+    files: FileLayout<Mutex<OpenFile>>,
+    /// How many bytes of pieces may wait to be written before they're flushed.
+    budget: usize,
+    dirty: Mutex<Dirty>,
+}
+
+struct OpenFile {
+    path: PathBuf,
+    file: File,
+}
+
+/// The pieces that have been written, but not yet flushed to disk.
+struct Dirty {
+    pieces: BTreeMap<usize, Arc<[u8]>>,
+    bytes: usize,
+    /// When the oldest of the pieces was written.
+    since: Option<Instant>,
+}
+
+impl DiskStorage {
+    /// Create (or truncate) the files of `t` in `dir`, laid out by `layout`, and keep up to
+    /// `budget` bytes of pieces in memory before writing them out.
+    pub fn create(t: &Torrent, dir: &Path, layout: &Layout, budget: usize) -> anyhow::Result<Self> {
+        Self::with_files(t, dir, layout, budget, true)
+    }
+
+    /// Like [`DiskStorage::create`], but keep whatever the files already hold, such as the pieces
+    /// of a download that's resuming.
+    pub fn open(t: &Torrent, dir: &Path, layout: &Layout, budget: usize) -> anyhow::Result<Self> {
+        Self::with_files(t, dir, layout, budget, false)
+    }
+
+    fn with_files(
+        t: &Torrent,
+        dir: &Path,
+        layout: &Layout,
+        budget: usize,
+        truncate: bool,
+    ) -> anyhow::Result<Self> {
+        let files = FileLayout::open(t, dir, layout, truncate, |path, file, _| {
+            Ok(Mutex::new(OpenFile { path, file }))
+        })?;
+        Ok(Self {
+            files,
+            budget,
+            dirty: Mutex::new(Dirty {
+                pieces: BTreeMap::new(),
+                bytes: 0,
+                since: None,
+            }),
+        })
+    }
+
+    /// Write out every piece that's waiting, in order, as one write per run of consecutive
+    /// pieces in each file.
+    ///
+    /// The pieces of a run only stop waiting once the run has been written, so if a write fails,
+    /// the pieces it didn't get to are still there to read, and to write out next time.
+    fn flush_dirty(&self, dirty: &mut Dirty) -> anyhow::Result<()> {
+        // each run as its first piece and the piece after its last
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        for &piece_i in dirty.pieces.keys() {
+            match runs.last_mut() {
+                Some((_, end)) if *end == piece_i => *end += 1,
+                _ => runs.push((piece_i, piece_i + 1)),
+            }
+        }
+        for (first, end) in runs {
+            let mut run = Vec::new();
+            for piece_i in first..end {
+                run.extend_from_slice(&dirty.pieces[&piece_i]);
+            }
+            let offset = first * self.files.plength();
+            for (file, at, from, n) in self.files.spans(offset, run.len()) {
+                let mut file = file.lock().expect("storage lock poisoned");
+                let OpenFile { path, file } = &mut *file;
+                file.seek(SeekFrom::Start(at as u64))
+                    .and_then(|_| file.write_all(&run[from..][..n]))
+                    .with_context(|| format!("write to {}", path.display()))?;
+            }
+            for piece_i in first..end {
+                let piece = dirty
+                    .pieces
+                    .remove(&piece_i)
+                    .expect("run is of waiting pieces");
+                dirty.bytes -= piece.len();
+            }
+        }
+        dirty.since = None;
+        Ok(())
+    }
+}
+
+impl Storage for DiskStorage {
+    fn write_piece(&self, piece_i: usize, data: &[u8]) -> anyhow::Result<()> {
+        let (_, len) = self.files.piece_range(piece_i)?;
+        anyhow::ensure!(
+            data.len() == len,
+            "piece {piece_i} is {} bytes, but should be {len}",
+            data.len()
+        );
+        let mut dirty = self.dirty.lock().expect("storage lock poisoned");
+        if let Some(previous) = dirty.pieces.insert(piece_i, data.into()) {
+            dirty.bytes -= previous.len();
+        }
+        dirty.bytes += len;
+        let since = *dirty.since.get_or_insert_with(Instant::now);
+        if dirty.bytes > self.budget || since.elapsed() >= FLUSH_INTERVAL {
+            self.flush_dirty(&mut dirty)?;
+        }
+        Ok(())
+    }
+
+    fn read_piece(&self, piece_i: usize) -> anyhow::Result<Arc<[u8]>> {
+        let (offset, len) = self.files.piece_range(piece_i)?;
+        if let Some(piece) = self
+            .dirty
+            .lock()
+            .expect("storage lock poisoned")
+            .pieces
+            .get(&piece_i)
+        {
+            return Ok(Arc::clone(piece));
+        }
+        let mut piece = vec![0; len];
+        for (file, at, from, n) in self.files.spans(offset, len) {
+            let mut file = file.lock().expect("storage lock poisoned");
+            let OpenFile { path, file } = &mut *file;
+            file.seek(SeekFrom::Start(at as u64))
+                .and_then(|_| file.read_exact(&mut piece[from..][..n]))
+                .with_context(|| format!("read from {}", path.display()))?;
+        }
+        Ok(piece.into())
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        self.flush_dirty(&mut self.dirty.lock().expect("storage lock poisoned"))
+    }
+
+    fn sync(&self) -> anyhow::Result<()> {
+        self.flush()?;
+        for file in self.files.files() {
+            let file = file.lock().expect("storage lock poisoned");
+            file.file
+                .sync_data()
+                .with_context(|| format!("sync {}", file.path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DiskStorage {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("failed to write out pieces: {e:?}");
+        }
+    }
+}
+
+#[test]
+fn coalesced_writes() {
+    use crate::torrent::{File as TorrentFile, Hashes, Info, Keys};
+    let t = Torrent::new(
+        String::new(),
+        Info {
+            name: "multi".into(),
+            name_utf8: None,
+            plength: 4,
+            pieces: Hashes(vec![[0; 20]; 3]),
+            keys: Keys::MultiFile {
+                files: vec![
+                    TorrentFile {
+                        length: 3,
+                        path: vec!["a".into()],
+                        path_utf8: None,
+                    },
+                    TorrentFile {
+                        length: 7,
+                        path: vec!["sub".into(), "b".into()],
+                        path_utf8: None,
+                    },
+                ],
+            },
+            private: None,
+        },
+    );
+    let dir = tempfile::tempdir().expect("create temporary directory");
+    let storage = DiskStorage::create(&t, dir.path(), &Layout::default(), 8).expect("create");
+    let root = dir.path().join("multi");
+    storage.write_piece(2, &[9, 10]).unwrap();
+    storage.write_piece(0, &[1, 2, 3, 4]).unwrap();
+    // nothing has gone to disk yet, but the pieces can be read back all the same
+    assert_eq!(std::fs::read(root.join("a")).unwrap(), [0, 0, 0]);
+    assert_eq!(&*storage.read_piece(2).unwrap(), [9, 10]);
+    assert!(storage.write_piece(2, &[9, 10, 11]).is_err());
+
+    // going over the budget writes out everything that's waiting
+    storage.write_piece(1, &[5, 6, 7, 8]).unwrap();
+    assert_eq!(std::fs::read(root.join("a")).unwrap(), [1, 2, 3]);
+    assert_eq!(
+        std::fs::read(root.join("sub").join("b")).unwrap(),
+        [4, 5, 6, 7, 8, 9, 10]
+    );
+    assert_eq!(&*storage.read_piece(1).unwrap(), [5, 6, 7, 8]);
+
+    storage.write_piece(0, &[0; 4]).unwrap();
+    drop(storage);
+    assert_eq!(std::fs::read(root.join("a")).unwrap(), [0, 0, 0]);
+}
+
+#[test]
+fn failed_flush_keeps_pieces() {
+    let (t, data) = crate::testing::generate(3 * 4, 4);
+    let dir = tempfile::tempdir().expect("create temporary directory");
+    let storage = DiskStorage::create(&t, dir.path(), &Layout::default(), 100).expect("create");
+    for (piece_i, piece) in data.chunks(4).enumerate() {
+        storage.write_piece(piece_i, piece).unwrap();
+    }
+
+    // swap the file for one that can't be written to
+    let path = dir.path().join("generated.bin");
+    let writable = {
+        let mut file = storage.files.files().next().unwrap().lock().unwrap();
+        std::mem::replace(&mut file.file, File::open(&path).unwrap())
+    };
+    assert!(storage.flush().is_err());
+    assert_eq!(&*storage.read_piece(1).unwrap(), &data[4..8]);
+
+    // the pieces go out once writing works again
+    storage.files.files().next().unwrap().lock().unwrap().file = writable;
+    storage.flush().unwrap();
+    assert!(std::fs::read(&path).unwrap() == data);
+}
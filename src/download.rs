@@ -1,183 +1,1211 @@
-use crate::peer::Peer;
-use crate::piece::Piece;
+use crate::bitfield::Bitfield;
+use crate::hash::{PieceHasher, Sha1Hasher};
+use crate::history::History;
+use crate::peer::{Peer, PeerTimeouts, DEFAULT_MAX_BLOCK};
+use crate::picker::{ByPriority, MostAvailable, PiecePicker};
+use crate::piece::PiecePolicy;
+use crate::pool::PeerPool;
+use crate::portmap::PortMapper;
+use crate::priority::{Priorities, Priority};
+use crate::proxy::{Proxy, Unavailable};
+use crate::ratelimit::RateLimits;
+use crate::resume::{ResumeFile, Resumed};
+use crate::scheduler::{Next, Scheduler, SharedScheduler};
+use crate::stats::Stats;
+use crate::storage::{FsyncPolicy, Layout, PartialPiece, Pieces, StorageBackend};
+use crate::swarm::{DuplicateConnection, PeerSource, Swarm};
 use crate::torrent::{File, Keys, Torrent};
-use crate::tracker::TrackerResponse;
-use crate::BLOCK_MAX;
+use crate::tracker::{
+    AnnounceSession, Event, TrackerConfig, TrackerFailure, TrackerResponse, UnsupportedTracker,
+};
+use crate::verify::{Checking, VerifyQueue};
+use crate::{peer_id, portmap, PORT};
 use anyhow::Context;
-use futures_util::stream::StreamExt;
-use sha1::{Digest, Sha1};
-use std::collections::BinaryHeap;
+use futures_util::stream::{FuturesUnordered, Stream, StreamExt};
+use std::collections::HashSet;
+use std::net::SocketAddrV4;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{watch, Notify, Semaphore};
+use tokio::task::JoinError;
+use tokio_util::sync::CancellationToken;
 
-pub(crate) async fn all(t: &Torrent) -> anyhow::Result<Downloaded> {
+pub use crate::stats::{BlockReceived, DownloadEvent};
+
+/// Knobs for how a download behaves.
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    /// Where to keep the pieces we've downloaded.
+    pub storage: StorageBackend,
+    /// Where the torrent's files go, both in the [storage](Self::storage) (if it's on disk) and
+    /// when [written out](Downloaded::write_to_dir).
+    pub layout: Layout,
+    /// How many bytes of recently read pieces to keep in memory for serving to other peers.
+    pub read_cache_size: usize,
+    /// How many bytes of verified pieces [disk storage](StorageBackend::Disk) may keep in memory
+    /// to write out together.
+    pub write_buffer_size: usize,
+    /// Read every piece back from storage after writing it, and check that it still hashes
+    /// correctly.
+    ///
+    /// This doubles the storage reads of a download, but catches data that gets silently corrupted
+    /// on its way to disk.
+    pub verify_writes: bool,
+    /// When to sync downloaded pieces to disk.
+    ///
+    /// With [storage](Self::storage) on disk, the download keeps a record of which pieces are
+    /// stored next to the torrent's files, which a later download of the torrent resumes from.
+    /// Pieces only make it into the record once they've been synced (or, with
+    /// [`FsyncPolicy::Never`], written out), so a crash never leaves it claiming a piece that
+    /// isn't there. Files [written out](Downloaded::write_to_dir) at the end are synced too,
+    /// unless this is `Never`.
+    pub fsync: FsyncPolicy,
+    /// How many peers to start downloading from.
+    ///
+    /// More peers are connected to in the background once the download has started, for as long
+    /// as they make it faster (see `max_peers`). The pool never shrinks below this.
+    pub bootstrap_peers: usize,
+    /// The most peers to download from at once.
+    ///
+    /// The pool of peers grows from `bootstrap_peers` while adding peers makes the download
+    /// faster, and shrinks back (disconnecting the slowest peers) when they stop adding anything.
+    pub max_peers: usize,
+    /// How many peers to be connecting to at the same time.
+    pub connect_concurrency: usize,
+    /// How many times to retry connecting to a peer before giving up on it.
+    pub connect_retries: usize,
+    /// How long to wait before retrying a failed connection. This doubles with every retry.
+    pub connect_backoff: Duration,
+    /// How long a peer gets for each step of connecting, before we give up on it (and retry).
+    pub peer_timeouts: PeerTimeouts,
+    /// The port our DHT node (BEP 5) listens on, if we run one.
+    ///
+    /// Peers that run a DHT node too are sent it in a `Port` message, so they can add ours to
+    /// their routing table. This crate doesn't come with a DHT node, so this is for one run
+    /// alongside it; with `None`, we don't claim to support the DHT at all. Private torrents never
+    /// advertise it.
+    pub dht_port: Option<u16>,
+    /// How to talk to the tracker.
+    pub tracker: TrackerConfig,
+    /// The peer id we identify ourselves with to peers and the tracker.
+    pub peer_id: [u8; 20],
+    /// Identify ourselves with a fresh peer id for every torrent instead of `peer_id`, so that
+    /// trackers and peers can't tell which torrents are being downloaded (or seeded) by the same
+    /// client.
+    pub privacy: bool,
+    /// Limits on how fast we download and upload.
+    ///
+    /// Downloads whose configurations share the same limits are limited together.
+    pub limits: Arc<RateLimits>,
+    /// What to check the hashes of pieces with.
+    pub hasher: Arc<dyn PieceHasher>,
+    /// Which order to download pieces of the same priority in.
+    pub piece_policy: PiecePolicy,
+    /// Which piece to download next.
+    ///
+    /// The default goes by priority (and then by round, see `piece_policy`), and then for the
+    /// piece the most peers have.
+    pub piece_picker: Arc<dyn PiecePicker>,
+    /// How many bytes to ask peers for at a time, at most [`BLOCK_MAX`](crate::BLOCK_MAX).
+    ///
+    /// Some peers (particularly ones running on small devices) would rather be asked for smaller
+    /// blocks. The last block of a piece may be shorter.
+    pub block_size: usize,
+    /// The largest block a peer may send us in a single message.
+    ///
+    /// We never ask for blocks over [`BLOCK_MAX`](crate::BLOCK_MAX) bytes, but some clients
+    /// have historically sent (or asked for) blocks of up to 128 KiB. Messages carrying larger
+    /// blocks than this are treated as an attack, and the peer is disconnected.
+    pub max_block_size: usize,
+    /// Accept a block that's larger than the one we requested, as long as it covers whole blocks
+    /// of the piece, instead of disconnecting the peer that sent it.
+    pub accept_oversized_blocks: bool,
+    /// Write each piece that fails its hash check to this directory, along with which peer sent
+    /// each of its blocks, so that the peer that sent bad data can be tracked down.
+    pub hash_failure_dir: Option<PathBuf>,
+    /// Fetch the blocks of each piece from at most this many peers, the fastest first.
+    ///
+    /// A piece can only be verified once its last block arrives, so one slow peer holding a
+    /// block of it holds up the whole piece. The other peers only step in if one of these goes
+    /// away. With `None`, every peer that has the piece fetches blocks of it.
+    pub piece_affinity: Option<usize>,
+    /// Where to keep what we've transferred of each torrent across runs.
+    ///
+    /// With this, announces report a torrent's totals over every run rather than only this one,
+    /// and each download (or seed) adds to them when it's done.
+    pub history: Option<Arc<History>>,
+    /// Make every connection, to peers and trackers alike, through this proxy, and leave out
+    /// whatever would go around it (see [`Unavailable`]).
+    ///
+    /// This overrides the tracker's own [`proxy`](TrackerConfig::proxy).
+    pub proxy: Option<Proxy>,
+    /// Stop seeding a torrent once we've uploaded this many times what we downloaded of it, over
+    /// every run we've [kept count](Self::history) of.
+    ///
+    /// A torrent we never downloaded (or only downloaded part of) counts from its whole length
+    /// instead, so that seeding data we already had still comes to an end.
+    pub seed_ratio_limit: Option<f64>,
+    /// Stop seeding a torrent after this long.
+    pub seed_time_limit: Option<Duration>,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            storage: StorageBackend::default(),
+            layout: Layout::default(),
+            read_cache_size: 16 << 20,
+            write_buffer_size: 16 << 20,
+            verify_writes: false,
+            fsync: FsyncPolicy::default(),
+            bootstrap_peers: 5,
+            max_peers: 50,
+            connect_concurrency: 5,
+            connect_retries: 3,
+            connect_backoff: Duration::from_secs(1),
+            peer_timeouts: PeerTimeouts::default(),
+            dht_port: None,
+            tracker: TrackerConfig::default(),
+            peer_id: peer_id::generate(),
+            privacy: false,
+            limits: Arc::default(),
+            hasher: Arc::new(Sha1Hasher),
+            piece_policy: PiecePolicy::default(),
+            piece_picker: Arc::new(ByPriority(MostAvailable)),
+            block_size: crate::BLOCK_MAX,
+            max_block_size: DEFAULT_MAX_BLOCK,
+            accept_oversized_blocks: false,
+            hash_failure_dir: None,
+            piece_affinity: None,
+            history: None,
+            proxy: None,
+            seed_ratio_limit: None,
+            seed_time_limit: None,
+        }
+    }
+}
+
+impl DownloadConfig {
+    /// The peer id to use for a torrent: `peer_id`, unless every torrent gets its own.
+    pub(crate) fn torrent_peer_id(&self) -> [u8; 20] {
+        if self.privacy {
+            peer_id::generate()
+        } else {
+            self.peer_id
+        }
+    }
+
+    /// How to talk to trackers, through our proxy if we have one.
+    pub(crate) fn tracker_config(&self) -> TrackerConfig {
+        TrackerConfig {
+            proxy: self.proxy.clone().or_else(|| self.tracker.proxy.clone()),
+            ..self.tracker.clone()
+        }
+    }
+
+    /// Keep `PORT` mapped on the router in the background, unless that would go around our proxy.
+    pub(crate) fn map_port(&self) -> Option<PortMapper> {
+        if self.proxy.is_some() {
+            eprintln!(
+                "could not map port {PORT} on the router: {}",
+                Unavailable::PortMapping
+            );
+            None
+        } else {
+            Some(portmap::keep(PORT))
+        }
+    }
+
+    /// Fresh transfer counters for `t`, carrying on from its totals in the history if we keep
+    /// one.
+    pub(crate) fn torrent_stats(&self, t: &Torrent, left: usize) -> Stats {
+        let stats = Stats::new(left);
+        match &self.history {
+            Some(history) => stats.with_previous(history.get(t.info_hash())),
+            None => stats,
+        }
+    }
+
+    /// Add what was transferred of `t` in this run to the history, if we keep one.
+    pub(crate) fn record_stats(&self, t: &Torrent, stats: &Stats) {
+        if let Some(history) = &self.history {
+            if let Err(e) = history.record(t.info_hash(), stats) {
+                eprintln!(
+                    "failed to record transfer totals of {}: {e:?}",
+                    t.info.display_name()
+                );
+            }
+        }
+    }
+}
+
+/// How long to wait for any peer to have a piece we need before giving up on the download.
+const NO_PEERS_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long to listen for haves while waiting for a peer to have a piece we need, before checking
+/// again.
+const HAVE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often a connection that's waiting for room in the pool of peers checks whether some peer
+/// has left.
+const ROOM_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where a download's pieces are, once its storage is open.
+pub(crate) type PiecesSlot = OnceLock<Arc<Pieces>>;
+
+/// A download running in the background.
+pub struct DownloadHandle {
+    priorities: watch::Sender<Priorities>,
+    paused: watch::Sender<bool>,
+    stop: CancellationToken,
+    stats: Arc<Stats>,
+    length: usize,
+    /// The download's pieces, once its storage is open.
+    ///
+    /// Kept for as long as the handle is, even once the download is done, so that the
+    /// [`Client`](crate::client::Client) can share them with other downloads of the same files.
+    pieces: Arc<PiecesSlot>,
+    /// Whether the download is still waiting for its turn to start.
+    queued: Arc<AtomicBool>,
+    /// Lets a queued download start without waiting for its turn.
+    force_start: Arc<Notify>,
+    task: tokio::task::JoinHandle<anyhow::Result<Downloaded>>,
+}
+
+impl DownloadHandle {
+    /// Change how eagerly the pieces of file `file_i` are downloaded.
+    ///
+    /// Pieces that span multiple files get the highest priority of any of their files, so
+    /// [`Priority::Skip`] only excludes the pieces that are unique to skipped files.
+    pub fn set_file_priority(&self, file_i: usize, priority: Priority) {
+        self.priorities
+            .send_modify(|priorities| priorities.set_file(file_i, priority));
+    }
+
+    /// Change how eagerly piece `piece_i` is downloaded, overriding the priority of its files.
+    pub fn set_piece_priority(&self, piece_i: usize, priority: Priority) {
+        self.priorities
+            .send_modify(|priorities| priorities.set_piece(piece_i, priority));
+    }
+
+    /// The transfer counters of the download.
+    pub fn stats(&self) -> Arc<Stats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Everything that happens to the download from now on, for reacting to progress as it's
+    /// made rather than polling [`stats`](Self::stats).
+    pub fn events(&self) -> impl Stream<Item = DownloadEvent> {
+        self.stats.events()
+    }
+
+    /// Every block that arrives from now on (see [`Stats::blocks`]).
+    pub fn blocks(&self) -> impl Stream<Item = BlockReceived> {
+        self.stats.blocks()
+    }
+
+    /// The length of the torrent being downloaded, in bytes.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Stop requesting new pieces until the download is [resumed](Self::resume).
+    ///
+    /// Pieces that are already in flight are still completed, and peers are still served.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Whether the download is waiting for other downloads of its
+    /// [`Client`](crate::client::Client) to finish before it starts.
+    pub fn is_queued(&self) -> bool {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Start the download now if it's [queued](Self::is_queued), even though that takes it past
+    /// the client's limit on active downloads.
+    pub fn force_start(&self) {
+        self.force_start.notify_one();
+    }
+
+    /// Whether the download has finished (or stopped), so that [`wait`](Self::wait) returns
+    /// immediately.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Stop the download gracefully.
+    ///
+    /// No new blocks are accepted after this, the tracker is told that we're going away, and
+    /// [`wait`](Self::wait) then returns whatever pieces were verified up to that point.
+    pub fn stop(&self) {
+        self.stop.cancel();
+    }
+
+    /// Wait for the download to finish (or [stop](Self::stop)).
+    pub async fn wait(&mut self) -> anyhow::Result<Downloaded> {
+        (&mut self.task).await.context("download task panicked")?
+    }
+
+    /// Where the download's pieces will be, for as long as the handle is around.
+    pub(crate) fn pieces(&self) -> Weak<PiecesSlot> {
+        Arc::downgrade(&self.pieces)
+    }
+}
+
+/// Dropping the handle [stops](DownloadHandle::stop) the download, since there'd be no way to get
+/// at what it downloaded.
+impl Drop for DownloadHandle {
+    fn drop(&mut self) {
+        self.stop.cancel();
+    }
+}
+
+/// Start downloading `t` in the background, once there's room for it in `queue` (if given).
+///
+/// Cancelling `stop` stops the download just like [`DownloadHandle::stop`] does.
+pub(crate) fn start(
+    t: Torrent,
+    config: DownloadConfig,
+    stop: CancellationToken,
+    queue: Option<Arc<Semaphore>>,
+) -> DownloadHandle {
+    let (priorities, priorities_rx) = watch::channel(Priorities::default());
+    let (paused, paused_rx) = watch::channel(false);
+    let stats = Arc::new(config.torrent_stats(&t, t.length()));
+    let length = t.length();
+    let pieces = Arc::new(OnceLock::new());
+    let queued = Arc::new(AtomicBool::new(queue.is_some()));
+    let force_start = Arc::new(Notify::new());
+    let task = tokio::spawn({
+        let stop = stop.clone();
+        let stats = Arc::clone(&stats);
+        let pieces = Arc::clone(&pieces);
+        let queued = Arc::clone(&queued);
+        let force_start = Arc::clone(&force_start);
+        async move {
+            // held until the download is done, so that the next one in the queue can start
+            let _turn = match queue {
+                Some(queue) => tokio::select! {
+                    turn = queue.acquire_owned() => Some(turn.expect("download queue is never closed")),
+                    _ = force_start.notified() => None,
+                    _ = stop.cancelled() => anyhow::bail!("download was stopped before it started"),
+                },
+                None => None,
+            };
+            queued.store(false, Ordering::Relaxed);
+            let result = all(
+                &t,
+                &config,
+                Arc::clone(&stats),
+                pieces,
+                priorities_rx,
+                paused_rx,
+                stop,
+            )
+            .await;
+            config.record_stats(&t, &stats);
+            result
+        }
+    });
+    DownloadHandle {
+        priorities,
+        paused,
+        stop,
+        stats,
+        length,
+        pieces,
+        queued,
+        force_start,
+        task,
+    }
+}
+
+async fn all(
+    t: &Torrent,
+    config: &DownloadConfig,
+    stats: Arc<Stats>,
+    pieces_slot: Arc<PiecesSlot>,
+    mut priorities: watch::Receiver<Priorities>,
+    mut paused: watch::Receiver<bool>,
+    stop: CancellationToken,
+) -> anyhow::Result<Downloaded> {
+    t.validate().context("invalid torrent")?;
+    anyhow::ensure!(
+        (1..=crate::BLOCK_MAX).contains(&config.block_size),
+        "block size must be between 1 and {} bytes",
+        crate::BLOCK_MAX
+    );
     let info_hash = t.info_hash();
-    let peer_info = TrackerResponse::query(t, info_hash)
+    let metadata: Arc<[u8]> = t.info_bytes().into();
+    // mapping the port on the router can take a while, so don't hold up the download for it
+    let portmap = config.map_port();
+    let tracker_config = config.tracker_config();
+    let tracker = tracker_config.announcer()?;
+    let peer_id = config.torrent_peer_id();
+    let npieces = t.info.pieces.0.len();
+    let (pieces, resumed) = open_pieces(t, config, &stats)?;
+    let (resumed, partial) = match resumed {
+        Some(Resumed { have, partial }) => (Some(have), partial),
+        None => (None, Vec::new()),
+    };
+    let _ = pieces_slot.set(Arc::clone(&pieces));
+    drop(pieces_slot);
+
+    // if anything goes wrong from here on, dropping the session tells the tracker we're gone
+    let complete_at_start = stats.left() == 0;
+    let mut session = AnnounceSession::new(tracker, t, peer_id, Arc::clone(&stats), tracker_config);
+    let peer_info = announce_start(&mut session, config)
         .await
         .context("query tracker for peer info")?;
 
-    let mut peer_list = Vec::new();
-    let mut peers = futures_util::stream::iter(peer_info.peers.0.iter())
-        .map(|&peer_addr| async move {
-            let peer = Peer::new(peer_addr, info_hash).await;
-            (peer_addr, peer)
-        })
-        .buffer_unordered(5 /* user config */);
-    while let Some((peer_addr, peer)) = peers.next().await {
-        match peer {
-            Ok(peer) => {
-                peer_list.push(peer);
-                if peer_list.len() >= 5
-                /* TODO: user config */
-                {
-                    break;
+    let (swarm, candidates) = Swarm::new(
+        Arc::clone(&stats),
+        t.is_private(),
+        pieces,
+        npieces,
+        peer_id,
+        config,
+    );
+    // how many peers to be connected to grows (and shrinks) with how fast we're going
+    let mut pool = PeerPool::new(
+        Arc::clone(&swarm),
+        candidates,
+        info_hash,
+        Arc::clone(&metadata),
+        config,
+    );
+    let mut connector = spawn_connect(
+        peer_info.peers.0.clone(),
+        info_hash,
+        &metadata,
+        &swarm,
+        config,
+        pool.size(),
+        pool.joiner(),
+    );
+    // the tracker is announced to as often as it asks from here on, and once more as we're done
+    let announced = CancellationToken::new();
+    let announcer = Background(tokio::spawn(reannounce(
+        session,
+        Arc::clone(&swarm),
+        announced.clone(),
+    )));
+
+    // start downloading as soon as we have a few peers, and let the rest join as they connect
+    pool.bootstrap(config.bootstrap_peers, &mut connector, &stop)
+        .await;
+    anyhow::ensure!(
+        !pool.peers().is_empty() || stop.is_cancelled(),
+        "could not connect to any peers"
+    );
+
+    let mut scheduler = Scheduler::new(
+        t,
+        config.piece_policy,
+        Arc::clone(&config.piece_picker),
+        config.block_size,
+        config.accept_oversized_blocks,
+        config.piece_affinity,
+    );
+    for partial in partial {
+        scheduler.restore_partial(partial);
+    }
+    let scheduler = SharedScheduler::new(scheduler);
+    // pieces are checked against their hashes (and written out) in the background, while the
+    // next ones download
+    let mut verifying = VerifyQueue::new(Checking {
+        info_hash,
+        swarm: Arc::clone(&swarm),
+        stats: Arc::clone(&stats),
+        hasher: Arc::clone(&config.hasher),
+        verify_writes: config.verify_writes,
+        hash_failure_dir: config.hash_failure_dir.clone(),
+        block_size: config.block_size,
+    });
+    // when we started waiting for a peer to announce any of the pieces no-one seemed to have
+    let mut waiting_since = None;
+    priorities.mark_changed();
+    while !stop.is_cancelled() {
+        if *paused.borrow() {
+            tokio::select! {
+                _ = paused.wait_for(|paused| !paused) => {}
+                _ = stop.cancelled() => break,
+            }
+            // nothing was downloaded while paused, which says nothing about the pool
+            pool.restart();
+        }
+
+        // no piece is being fetched between pieces, so the scheduler's peer indices can change
+        pool.refresh();
+        take_filled(t, &swarm, &scheduler, resumed.as_ref());
+        verifying.ready(&scheduler)?;
+
+        let next = {
+            let mut scheduler = scheduler.lock();
+            if priorities.has_changed().unwrap_or(false) {
+                scheduler.set_priorities(t, &priorities.borrow_and_update());
+            }
+            let bitfields: Vec<_> = pool.peers().iter().map(Peer::bitfield).collect();
+            scheduler.next_piece(&bitfields)
+        };
+        let piece_i = match next {
+            Next::Download { piece_i } => piece_i,
+            Next::Done => {
+                // unless one of the pieces still being checked turns out bad
+                if verifying.next(&scheduler).await? {
+                    continue;
+                }
+                stats.emit(DownloadEvent::Completed);
+                break;
+            }
+            Next::Wait { missing, others } => {
+                // none of our peers has any of the pieces we still need (yet), so listen to what
+                // they're telling us for a bit, in case that's about to change.
+                let waited = waiting_since.get_or_insert_with(tokio::time::Instant::now);
+                anyhow::ensure!(
+                    waited.elapsed() < NO_PEERS_TIMEOUT,
+                    "none of our peers has piece {missing} (or {others} other pieces)"
+                );
+                tokio::select! {
+                    _ = pool.listen(tokio::time::Instant::now() + HAVE_POLL_INTERVAL) => {}
+                    _ = swarm.pieces().filled() => {}
+                    _ = stop.cancelled() => break,
                 }
+                continue;
+            }
+        };
+        waiting_since = None;
+
+        let have_piece: HashSet<_> = scheduler.lock().current_peers().collect();
+        for peer_i in fetch_piece(piece_i, pool.peers_mut(), &have_piece, &scheduler, &stop).await {
+            pool.peer_failed(peer_i);
+        }
+
+        let Some((piece, data, sources)) = scheduler.lock().take_piece() else {
+            if stop.is_cancelled() {
+                break;
             }
-            Err(e) => {
-                eprintln!("failed to connect to peer {peer_addr:?}: {e:?}");
+            let retry_at = pool
+                .peers()
+                .iter()
+                .enumerate()
+                .filter(|(peer_i, _)| have_piece.contains(peer_i))
+                .filter_map(|(_, peer)| peer.sidelined_until())
+                .min();
+            if let Some(retry_at) = retry_at {
+                // the peers that have the piece are all choking us, so keep up with them until
+                // it's time to ask again
+                tokio::select! {
+                    _ = pool.listen(retry_at) => {}
+                    _ = stop.cancelled() => break,
+                }
+                continue;
             }
+            // we'll need to connect to more peers, and make sure that those additional peers also
+            // have this piece, and then download the blocks we _didn't_ get from them.
+            anyhow::bail!("no peers left to get piece {piece_i}");
+        };
+
+        // who sent each block only matters if the piece turns out bad, and we keep those
+        let blocks = config.hash_failure_dir.is_some().then(|| {
+            sources
+                .iter()
+                .map(|source| source.map(|peer_i| pool.peers()[peer_i].contributor()))
+                .collect()
+        });
+        verifying.push(piece, data, blocks, &scheduler).await?;
+    }
+
+    // the pieces we did get are worth keeping, even if we're stopping
+    while verifying.next(&scheduler).await? {}
+
+    // and so are the blocks of the ones we didn't finish
+    swarm
+        .pieces()
+        .keep_partial(scheduler.lock().partial_pieces().cloned().collect());
+    announced.cancel();
+    let mut session = announcer.await.context("announce to tracker")?;
+    // only a download that got the whole torrent in this run is one the tracker should count
+    if stats.left() == 0 && !complete_at_start {
+        if let Err(e) = session.announce(Some(Event::Completed)).await {
+            eprintln!("failed to tell tracker we're done: {e:?}");
         }
     }
-    drop(peers);
-    let mut peers = peer_list;
+    if let Err(e) = session.stop().await {
+        eprintln!("failed to tell tracker we're stopping: {e:?}");
+    }
 
-    let mut need_pieces = BinaryHeap::new();
-    let mut no_peers = Vec::new();
-    for piece_i in 0..t.info.pieces.0.len() {
-        let piece = Piece::new(piece_i, &t, &peers);
-        if piece.peers().is_empty() {
-            no_peers.push(piece);
+    drop(connector);
+    if let Some(portmap) = portmap {
+        portmap.shutdown().await;
+    }
+
+    swarm.pieces().flush()?;
+    if swarm.pieces().bitfield().is_complete(npieces) {
+        // nothing left to resume, and the files may yet be moved away from under the record
+        swarm.pieces().discard_resume()?;
+    }
+    // the lock has to be let go of before the scheduler is
+    let downloaded = Downloaded::new(t, config, &swarm, &scheduler.lock());
+    downloaded
+}
+
+/// Open the storage for `t`, picking up where the last run left off if there's a resume record
+/// for it. Returns the pieces, and what was resumed.
+///
+/// The resumed pieces are counted in `stats` as not left, so that the tracker hears how much is
+/// really left from the very first announce.
+fn open_pieces(
+    t: &Torrent,
+    config: &DownloadConfig,
+    stats: &Stats,
+) -> anyhow::Result<(Arc<Pieces>, Option<Resumed>)> {
+    let npieces = t.info.pieces.0.len();
+    let resume = config
+        .storage
+        .resume_path(t)
+        .map(|path| ResumeFile::new(path, t.info_hash(), npieces));
+    let resumed = match &resume {
+        Some(resume) => resume.load().context("read resume record")?,
+        None => None,
+    };
+    let storage = config
+        .storage
+        .open(t, config, resumed.is_some())
+        .context("open storage")?;
+    let mut pieces = Pieces::new(storage, config.read_cache_size);
+    if let Some(resume) = resume {
+        pieces = pieces.with_resume(resume, config.fsync);
+    }
+    if let Some(Resumed { have, partial }) = &resumed {
+        eprintln!(
+            "resuming with {} pieces already stored, and {} partly downloaded",
+            have.count_ones(),
+            partial.len()
+        );
+        pieces.restore(have);
+        for piece_i in have.pieces() {
+            stats.piece_verified(piece_i, piece_length(t, piece_i));
+        }
+        // until this run has anything newer to say about them
+        pieces.keep_partial(partial.clone());
+    }
+    Ok((Arc::new(pieces), resumed))
+}
+
+/// Tell the scheduler about the pieces that were stored without being fetched, such as ones
+/// copied in from another download of the same file, or that we stored before `resumed`.
+fn take_filled(
+    t: &Torrent,
+    swarm: &Swarm,
+    scheduler: &SharedScheduler,
+    resumed: Option<&Bitfield>,
+) {
+    for piece_i in swarm.pieces().take_filled() {
+        let mut scheduler = scheduler.lock();
+        if scheduler.piece_stored(piece_i) {
+            // the resumed pieces were counted before we first announced
+            if !resumed.is_some_and(|have| have.has_piece(piece_i)) {
+                swarm
+                    .stats()
+                    .piece_verified(piece_i, piece_length(t, piece_i));
+            }
+            swarm.announce_have(piece_i);
+            if let Some(pieces) = scheduler.verified_in_order() {
+                swarm
+                    .stats()
+                    .emit(DownloadEvent::VerifiedInOrder { pieces });
+            }
+        }
+    }
+}
+
+/// Fetch the blocks of the scheduler's current piece, `piece_i`, from those of `peers` that have
+/// it (`have_piece`), while the rest keep up with the swarm. Returns the peers that failed along the
+/// way, by index.
+///
+/// Peers that have snubbed us are only used if no-one else has the piece, and ones that left us
+/// choked aren't used until it's time to ask them again.
+async fn fetch_piece(
+    piece_i: usize,
+    peers: &mut [Peer],
+    have_piece: &HashSet<usize>,
+    scheduler: &SharedScheduler,
+    stop: &CancellationToken,
+) -> Vec<usize> {
+    let (mut responsive, mut snubbed, mut idle) = (Vec::new(), Vec::new(), Vec::new());
+    for (peer_i, peer) in peers.iter_mut().enumerate() {
+        if !have_piece.contains(&peer_i) || peer.sidelined_until().is_some() {
+            idle.push(peer);
+        } else if peer.is_snubbed() {
+            snubbed.push((peer_i, peer));
         } else {
-            need_pieces.push(piece);
-        }
-    }
-
-    // TODO
-    assert!(no_peers.is_empty());
-
-    // TODO: this is dumb because all the pieces for a given torrent may not fit in memory!
-    // should probably write every piece to disk so that we can also resume downloads, and seed
-    // later on.
-    let mut all_pieces = vec![0; t.length()];
-    while let Some(piece) = need_pieces.pop() {
-        // the + (BLOCK_MAX - 1) rounds up
-        let piece_size = piece.length();
-        let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
-        let peers: Vec<_> = peers
-            .iter_mut()
-            .enumerate()
-            .filter_map(|(peer_i, peer)| piece.peers().contains(&peer_i).then_some(peer))
-            .collect();
-
-        let (submit, tasks) = kanal::bounded_async(nblocks);
-        for block in 0..nblocks {
-            submit
-                .send(block)
-                .await
-                .expect("bound holds all these items");
-        }
-        let (finish, mut done) = tokio::sync::mpsc::channel(nblocks);
-        let mut participants = futures_util::stream::futures_unordered::FuturesUnordered::new();
-        for peer in peers {
-            participants.push(peer.participate(
-                piece.index(),
-                piece_size,
-                nblocks,
-                submit.clone(),
-                tasks.clone(),
-                finish.clone(),
-            ));
-        }
-        drop(submit);
-        drop(finish);
-        drop(tasks);
-
-        eprintln!("start receive loop");
-        let mut all_blocks = vec![0u8; piece_size];
-        let mut bytes_received = 0;
-        loop {
-            tokio::select! {
-                joined = participants.next(), if !participants.is_empty() => {
-                    // if a participant ends early, it's either slow or failed
-                    eprintln!("participant finished");
-                    match joined {
-                        None => {
-                            // there are no peers!
-                            // this must mean we are about to get None from done.recv(),
-                            // so we'll handle it there
-                        }
-                        Some(Ok(_)) => {
-                            // the peer gave up because it timed out
-                            // nothing to do, except maybe de-prioritize this peer for later
-                            // TODO
-                        }
-                        Some(Err(_)) => {
-                            // the peer failed and should be removed
-                            // it already isn't participating in this piece any more, so this is
-                            // more of an indicator that we shouldn't try this peer again, and
-                            // should remove it from the global peer list
-                            // TODO
-                        }
+            responsive.push((peer_i, peer));
+        }
+    }
+    let mut participating = if responsive.is_empty() {
+        snubbed
+    } else {
+        idle.extend(snubbed.into_iter().map(|(_, peer)| peer));
+        responsive
+    };
+    // the participants ask for blocks in this order, so with a piece affinity, the fastest
+    // peers are the ones that get them
+    participating.sort_by(|(_, a), (_, b)| b.bandwidth().total_cmp(&a.bandwidth()));
+    // the rest of the peers keep up with the swarm meanwhile: they get the haves for the
+    // pieces we verify, and are served what they ask us for.
+    let mut idle = Box::pin(futures_util::future::join_all(
+        idle.into_iter().map(|peer| peer.serve()),
+    ));
+    let mut idle_done = false;
+    let mut participants: FuturesUnordered<_> = participating
+        .into_iter()
+        .map(|(peer_i, peer)| async move { (peer_i, peer.participate(peer_i, scheduler).await) })
+        .collect();
+
+    let mut failed = Vec::new();
+    loop {
+        tokio::select! {
+            _ = stop.cancelled() => {
+                // the blocks we have so far of this piece can't be verified, so drop them
+                break;
+            }
+            _ = &mut idle, if !idle_done => {
+                // every idle peer has disconnected, which their next piece will find out
+                idle_done = true;
+            }
+            joined = participants.next() => {
+                match joined {
+                    None => {
+                        // every participant is done, either because the piece is complete, or
+                        // because they've all snubbed us or failed
+                        break;
+                    }
+                    Some((_, Ok(()))) => {
+                        // either the piece is complete, or the peer gave up because it snubbed
+                        // us and has handed its block back for the other participants. it'll
+                        // be avoided for later pieces.
+                    }
+                    Some((peer_i, Err(e))) => {
+                        // the peer failed, so whatever it was fetching is up for grabs, and
+                        // it's let go of before the next piece
+                        eprintln!("peer failed while downloading piece {piece_i}: {e:#}");
+                        scheduler.peer_lost(peer_i);
+                        failed.push(peer_i);
                     }
                 }
-                piece = done.recv() => {
-                    if let Some(piece) = piece {
-                        eprintln!("got piece");
-                        // keep track of the bytes in message
-                        let piece = crate::peer::Piece::ref_from_bytes(&piece.payload[..])
-                            .expect("always get all Piece response fields from peer");
-                        bytes_received += piece.block().len();
-                        all_blocks[piece.begin() as usize..][..piece.block().len()].copy_from_slice(piece.block());
-                        if bytes_received == piece_size {
-                            // have received every piece
-                            // this must mean that all participations have either exited or are
-                            // waiting for more work -- in either case, it is okay to drop all the
-                            // participant futures.
-                            break;
-                        }
-                    } else {
-                        eprintln!("got pieces end");
-                        // there are no peers left, so we can't progress!
+            }
+        }
+    }
+    failed
+}
+
+/// Tell the tracker that the download is starting, and get peers from it.
+///
+/// If the tracker can't be reached, this is retried like connecting to a peer is. A tracker that
+/// answers with a failure reason has actually refused us though, and one we can't speak the
+/// protocol of never will answer, so those are returned right away.
+async fn announce_start(
+    session: &mut AnnounceSession,
+    config: &DownloadConfig,
+) -> anyhow::Result<TrackerResponse> {
+    let mut backoff = config.connect_backoff;
+    let mut attempt = 0;
+    loop {
+        match session.announce(Some(Event::Started)).await {
+            Ok(response) => return Ok(response),
+            Err(e)
+                if attempt < config.connect_retries
+                    && !e.is::<TrackerFailure>()
+                    && !e.is::<UnsupportedTracker>() =>
+            {
+                eprintln!("failed to reach tracker, retrying in {backoff:?}: {e:#}");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The length of piece `piece_i` of `t`, which is shorter than the rest if it's the last one.
+fn piece_length(t: &Torrent, piece_i: usize) -> usize {
+    t.info.plength.min(t.length() - piece_i * t.info.plength)
+}
+
+/// Announce to the tracker again whenever it asks us to, and hand the peers it tells us about to
+/// the swarm, until `done` is cancelled. The session is handed back for the last announces.
+async fn reannounce(
+    mut session: AnnounceSession,
+    swarm: Arc<Swarm>,
+    done: CancellationToken,
+) -> AnnounceSession {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(session.next_announce()) => {}
+            _ = done.cancelled() => return session,
+        }
+        match session.announce(None).await {
+            Ok(response) => {
+                for addr in response.peers.0 {
+                    swarm.add_candidate(addr, PeerSource::Tracker);
+                }
+            }
+            Err(e) => eprintln!("failed to announce to tracker: {e:#}"),
+        }
+    }
+}
+
+/// Connect to the peers at `addrs` in the background, and send the ones we connect to on
+/// `joined`.
+///
+/// Only so many peers are connected to at once: a connection waits until we're connected to
+/// fewer peers than `pool_size`.
+///
+/// Peers we fail to connect to are retried with exponential backoff. If we can't reach a peer at
+/// all, it may be behind a NAT, in which case one of the peers we _did_ reach may be able to broker
+/// a connection to it.
+fn spawn_connect(
+    addrs: Vec<SocketAddrV4>,
+    info_hash: [u8; 20],
+    metadata: &Arc<[u8]>,
+    swarm: &Arc<Swarm>,
+    config: &DownloadConfig,
+    pool_size: watch::Receiver<usize>,
+    joined: tokio::sync::mpsc::UnboundedSender<Peer>,
+) -> Background<()> {
+    let metadata = Arc::clone(metadata);
+    let swarm = Arc::clone(swarm);
+    let (concurrency, retries, backoff, timeouts) = (
+        config.connect_concurrency,
+        config.connect_retries,
+        config.connect_backoff,
+        config.peer_timeouts,
+    );
+    Background(tokio::spawn(async move {
+        let mut attempts = futures_util::stream::iter(addrs)
+            .map(|peer_addr| {
+                let metadata = Arc::clone(&metadata);
+                let swarm = Arc::clone(&swarm);
+                let mut pool_size = pool_size.clone();
+                async move {
+                    wait_for_room(&swarm, &mut pool_size).await;
+                    let peer = connect_with_backoff(
+                        peer_addr, info_hash, metadata, swarm, timeouts, retries, backoff,
+                    )
+                    .await;
+                    (peer_addr, peer)
+                }
+            })
+            .buffer_unordered(concurrency.max(1));
+        while let Some((peer_addr, peer)) = attempts.next().await {
+            match peer {
+                Ok(peer) => {
+                    eprintln!(
+                        "connected to peer {peer_addr} ({})",
+                        peer.client().as_deref().unwrap_or("unknown client")
+                    );
+                    if joined.send(peer).is_err() {
+                        // the download is over
                         break;
                     }
                 }
+                Err(e) => {
+                    eprintln!("giving up on peer {peer_addr:?}: {e:?}");
+                    swarm.request_holepunch(peer_addr);
+                }
             }
         }
-        drop(participants);
+    }))
+}
 
-        if bytes_received == piece_size {
-            // great, we got all the bytes
-        } else {
-            // we'll need to connect to more peers, and make sure that those additional peers also
-            // have this piece, and then download the pieces we _didn't_ get from them.
-            // probably also stick this back onto the pieces_heap.
-            anyhow::bail!("no peers left to get piece {}", piece.index());
-        }
+/// A task that runs alongside a download, such as [connecting to peers](spawn_connect) or
+/// [announcing](reannounce).
+///
+/// It's stopped when dropped, so that it doesn't outlive the download however that ends.
+struct Background<T>(tokio::task::JoinHandle<T>);
 
-        let mut hasher = Sha1::new();
-        hasher.update(&all_blocks);
-        let hash: [u8; 20] = hasher
-            .finalize()
-            .try_into()
-            .expect("GenericArray<_, 20> == [_; 20]");
-        assert_eq!(hash, piece.hash());
+impl<T> std::future::Future for Background<T> {
+    type Output = Result<T, JoinError>;
 
-        all_pieces[piece.index() * t.info.plength..][..piece_size].copy_from_slice(&all_blocks);
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        std::pin::Pin::new(&mut self.0).poll(cx)
     }
+}
 
-    Ok(Downloaded {
-        bytes: all_pieces,
-        files: match &t.info.keys {
-            Keys::SingleFile { length } => vec![File {
-                length: *length,
-                path: vec![t.info.name.clone()],
-            }],
-            Keys::MultiFile { files } => files.clone(),
-        },
-    })
+impl<T> Drop for Background<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Wait until we're connected to fewer peers than `pool_size`.
+async fn wait_for_room(swarm: &Swarm, pool_size: &mut watch::Receiver<usize>) {
+    while swarm.peer_count() >= *pool_size.borrow_and_update() {
+        // peers leaving doesn't wake us up, so check again every so often
+        if let Ok(Err(_)) = tokio::time::timeout(ROOM_POLL_INTERVAL, pool_size.changed()).await {
+            // the download is over
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Connect to the peer at `peer_addr`, retrying up to `retries` times.
+///
+/// The first retry happens after `backoff`, and the wait doubles for each one after that.
+async fn connect_with_backoff(
+    peer_addr: SocketAddrV4,
+    info_hash: [u8; 20],
+    metadata: Arc<[u8]>,
+    swarm: Arc<Swarm>,
+    timeouts: PeerTimeouts,
+    retries: usize,
+    mut backoff: Duration,
+) -> anyhow::Result<Peer> {
+    let mut attempt = 0;
+    loop {
+        match Peer::new(
+            peer_addr,
+            info_hash,
+            Arc::clone(&metadata),
+            Arc::clone(&swarm),
+            timeouts,
+        )
+        .await
+        {
+            Ok(peer) => return Ok(peer),
+            Err(e) if attempt < retries && e.downcast_ref::<DuplicateConnection>().is_none() => {
+                eprintln!(
+                    "failed to connect to peer {peer_addr:?}, retrying in {backoff:?}: {e:#}"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Connect to (up to `max` of) the peers at `addrs`.
+///
+/// Peers are connected to the same way a download connects to them, so `config` decides how many
+/// to connect to at once, and how failed connections are retried.
+pub(crate) async fn connect(
+    addrs: &[SocketAddrV4],
+    info_hash: [u8; 20],
+    metadata: &Arc<[u8]>,
+    swarm: &Arc<Swarm>,
+    config: &DownloadConfig,
+    max: usize,
+) -> Vec<Peer> {
+    let (_pool_size, pool_size_rx) = watch::channel(max);
+    let (joined, mut joiner) = tokio::sync::mpsc::unbounded_channel();
+    let _connector = spawn_connect(
+        addrs.to_vec(),
+        info_hash,
+        metadata,
+        swarm,
+        config,
+        pool_size_rx,
+        joined,
+    );
+    let mut peers = Vec::new();
+    while peers.len() < max {
+        // once the connector has tried every peer, it drops its end of the channel
+        let Some(peer) = joiner.recv().await else {
+            break;
+        };
+        peers.push(peer);
+    }
+    peers
 }
 
 pub struct Downloaded {
-    bytes: Vec<u8>, // TODO: maybe Bytes?
+    /// All of the torrent's data, laid end to end.
+    ///
+    /// Storage that keeps the torrent's files on disk only has this read back out of it the
+    /// first time it's asked for, so that a download needn't fit in memory.
+    bytes: OnceLock<Vec<u8>>,
+    /// Where to read `bytes` from, if they haven't been read yet.
+    pieces: Option<Arc<Pieces>>,
+    plength: usize,
+    length: usize,
     files: Vec<File>,
+    /// Where the torrent goes relative to the directory it's written to, as the download's
+    /// [`Layout::root`] has it.
+    root: PathBuf,
+    multi_file: bool,
+    npieces: usize,
+    verified: usize,
+    partial: Vec<PartialPiece>,
+    /// Whether to sync the files to disk when [writing them out](Self::write_to_dir).
+    sync: bool,
+}
+
+impl Downloaded {
+    /// What was downloaded of `t` into the pieces of `swarm`, as far as `scheduler` got.
+    fn new(
+        t: &Torrent,
+        config: &DownloadConfig,
+        swarm: &Swarm,
+        scheduler: &Scheduler,
+    ) -> anyhow::Result<Self> {
+        let npieces = t.info.pieces.0.len();
+        let (bytes, pieces) = match config.storage {
+            // the pieces are all in memory already, so there's no point holding on to them
+            StorageBackend::Memory => (
+                OnceLock::from(
+                    swarm
+                        .pieces()
+                        .to_bytes(npieces, t.info.plength, t.length())?,
+                ),
+                None,
+            ),
+            StorageBackend::Mmap(_) | StorageBackend::Disk(_) => {
+                (OnceLock::new(), Some(Arc::clone(swarm.pieces())))
+            }
+        };
+        Ok(Self {
+            bytes,
+            pieces,
+            plength: t.info.plength,
+            length: t.length(),
+            npieces,
+            verified: scheduler.verified(),
+            partial: scheduler.partial_pieces().cloned().collect(),
+            sync: config.fsync != FsyncPolicy::Never,
+            root: config.layout.root(t, Path::new(""))?,
+            multi_file: matches!(t.info.keys, Keys::MultiFile { .. }),
+            files: match &t.info.keys {
+                Keys::SingleFile { length } => vec![match &config.layout.file_name {
+                    Some(name) => File {
+                        length: *length,
+                        path: vec![name.as_str().into()],
+                        path_utf8: None,
+                    },
+                    None => File {
+                        length: *length,
+                        path: vec![t.info.name.clone()],
+                        path_utf8: t.info.name_utf8.clone().map(|name| vec![name]),
+                    },
+                }],
+                Keys::MultiFile { files } => files.clone(),
+            },
+        })
+    }
+
+    /// Whether every piece of the torrent was downloaded and verified.
+    ///
+    /// This is not the case if the download was stopped early, or if some pieces were skipped.
+    /// Missing pieces are left as zeroes.
+    pub fn is_complete(&self) -> bool {
+        self.verified == self.npieces
+    }
+
+    /// The number of pieces that were downloaded and verified.
+    pub fn verified_pieces(&self) -> usize {
+        self.verified
+    }
+
+    /// The number of pieces in the torrent.
+    pub fn total_pieces(&self) -> usize {
+        self.npieces
+    }
+
+    /// The pieces that were started but not finished, along with the blocks we got of them.
+    ///
+    /// Their data isn't part of the downloaded files, since it hasn't been verified.
+    pub fn partial_pieces(&self) -> &[PartialPiece] {
+        &self.partial
+    }
+
+    /// All of the torrent's data, read out of storage if it hasn't been yet.
+    fn bytes(&self) -> anyhow::Result<&[u8]> {
+        if let Some(bytes) = self.bytes.get() {
+            return Ok(bytes);
+        }
+        let pieces = self
+            .pieces
+            .as_ref()
+            .expect("unread bytes are read from storage");
+        let bytes = pieces
+            .to_bytes(self.npieces, self.plength, self.length)
+            .context("read downloaded pieces")?;
+        Ok(self.bytes.get_or_init(|| bytes))
+    }
+
+    /// Where [`write_to_dir`](Self::write_to_dir) puts the torrent in `output`: the file of a
+    /// single-file torrent, or the directory of a multi-file one.
+    pub fn root(&self, output: &Path) -> PathBuf {
+        output.join(&self.root)
+    }
+
+    /// Write the downloaded files into the directory `output`, laid out by the download's
+    /// [`Layout`].
+    ///
+    /// Multi-file torrents get a directory of their own inside `output` (unless the layout says
+    /// otherwise), and any empty files and directories they list are created as well.
+    pub async fn write_to_dir(&self, output: &Path) -> anyhow::Result<()> {
+        // the storage may be the very files we're about to overwrite, so read it all first
+        self.bytes()?;
+        let base = if self.multi_file {
+            self.root(output)
+        } else {
+            output.to_path_buf()
+        };
+        for file in self {
+            let relative = file.path();
+            anyhow::ensure!(
+                relative
+                    .components()
+                    .all(|c| matches!(c, std::path::Component::Normal(_))),
+                "refusing to write {} outside of {}",
+                relative.display(),
+                base.display()
+            );
+            let path = base.join(relative);
+            if file.is_dir() {
+                tokio::fs::create_dir_all(&path)
+                    .await
+                    .with_context(|| format!("create {}", path.display()))?;
+                continue;
+            }
+            if let Some(dir) = path.parent() {
+                tokio::fs::create_dir_all(dir)
+                    .await
+                    .with_context(|| format!("create {}", dir.display()))?;
+            }
+            let mut out = tokio::fs::File::create(&path)
+                .await
+                .with_context(|| format!("create {}", path.display()))?;
+            file.write_to(&mut out)
+                .await
+                .with_context(|| format!("write {}", path.display()))?;
+            if self.sync {
+                out.sync_data()
+                    .await
+                    .with_context(|| format!("sync {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<'a> IntoIterator for &'a Downloaded {
@@ -209,23 +1237,45 @@ impl<'d> Iterator for DownloadedIter<'d> {
 
     fn next(&mut self) -> Option<Self::Item> {
         let file = self.file_iter.next()?;
-        let bytes = &self.downloaded.bytes[self.offset..][..file.length];
+        let offset = self.offset;
         self.offset += file.length;
-        Some(DownloadedFile { file, bytes })
+        Some(DownloadedFile {
+            downloaded: self.downloaded,
+            file,
+            offset,
+        })
     }
 }
 
 pub struct DownloadedFile<'d> {
+    downloaded: &'d Downloaded,
     file: &'d File,
-    bytes: &'d [u8],
+    offset: usize,
 }
 
 impl<'d> DownloadedFile<'d> {
-    pub fn path(&self) -> &'d [String] {
-        &self.file.path
+    /// Where the file goes, relative to the torrent's directory (if it has one).
+    pub fn path(&self) -> PathBuf {
+        self.file.relative_path()
+    }
+
+    /// Whether this is an empty directory rather than a file.
+    pub fn is_dir(&self) -> bool {
+        self.file.is_dir()
+    }
+
+    /// The contents of the file.
+    ///
+    /// With storage that keeps the torrent's files on disk, the first call reads all of the
+    /// torrent back into memory.
+    pub fn bytes(&self) -> anyhow::Result<&'d [u8]> {
+        Ok(&self.downloaded.bytes()?[self.offset..][..self.file.length])
     }
 
-    pub fn bytes(&self) -> &'d [u8] {
-        self.bytes
+    /// Write the contents of the file to `out`, and flush it.
+    pub async fn write_to(&self, mut out: impl AsyncWrite + Unpin) -> std::io::Result<()> {
+        let bytes = self.bytes().map_err(std::io::Error::other)?;
+        out.write_all(bytes).await?;
+        out.flush().await
     }
 }
@@ -0,0 +1,206 @@
+//! Changing the metadata of an existing .torrent file, such as when its tracker moves.
+//!
+//! Everything outside the info dictionary can be changed without affecting the torrent's info
+//! hash, so [`TorrentEditor`] keeps the info dictionary exactly as the file had it, byte for byte,
+//! rather than parsing and re-encoding it. That way even keys we don't know about, or a dictionary
+//! that isn't quite canonical, make it through unharmed.
+
+use crate::bencode::{self, encode_bytes, Mode};
+use crate::merkle;
+use anyhow::Context;
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// An open .torrent file, ready to be edited.
+#[derive(Debug, Clone)]
+pub struct TorrentEditor {
+    /// Every key of the top-level dictionary except `info`.
+    fields: BTreeMap<Vec<u8>, Value>,
+    /// The info dictionary, as it was encoded in the file.
+    info: Vec<u8>,
+}
+
+impl TorrentEditor {
+    pub fn from_bytes(dot_torrent: &[u8]) -> anyhow::Result<Self> {
+        let mut fields = BTreeMap::new();
+        let mut info = None;
+        for (key, value) in bencode::dict_entries(dot_torrent).context("torrent file")? {
+            if key == b"info" {
+                anyhow::ensure!(value[0] == b'd', "info is not a dictionary");
+                info = Some(value.to_vec());
+            } else {
+                let value = serde_bencode::from_bytes(value).with_context(|| {
+                    format!("parse value of key {}", String::from_utf8_lossy(&key))
+                })?;
+                fields.insert(key, value);
+            }
+        }
+        Ok(Self {
+            fields,
+            info: info.context("torrent has no info dictionary")?,
+        })
+    }
+
+    pub async fn read(file: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dot_torrent = tokio::fs::read(file).await.context("read torrent file")?;
+        Self::from_bytes(&dot_torrent).context("parse torrent file")
+    }
+
+    /// The info hash of the torrent as it stands.
+    pub fn info_hash(&self) -> [u8; 20] {
+        Sha1::digest(&self.info).into()
+    }
+
+    /// The v2 info hash (BEP 52) of the torrent as it stands: the SHA-256 of its info dictionary.
+    ///
+    /// That's only well-defined if the info dictionary is encoded canonically, so anything else is
+    /// an error.
+    pub fn info_hash_v2(&self) -> anyhow::Result<[u8; 32]> {
+        bencode::check(&self.info, Mode::Strict).context("info dictionary")?;
+        Ok(merkle::sha256(&self.info))
+    }
+
+    /// Set the URL of the tracker.
+    pub fn set_announce(&mut self, url: &str) {
+        self.set(b"announce", Some(Value::Bytes(url.as_bytes().to_vec())));
+    }
+
+    /// Replace the tiers of trackers (BEP 12), or remove them if there are none.
+    pub fn set_announce_list(&mut self, tiers: &[Vec<String>]) {
+        let list = tiers
+            .iter()
+            .map(|tier| {
+                Value::List(
+                    tier.iter()
+                        .map(|url| Value::Bytes(url.as_bytes().to_vec()))
+                        .collect(),
+                )
+            })
+            .collect();
+        self.set(
+            b"announce-list",
+            (!tiers.is_empty()).then_some(Value::List(list)),
+        );
+    }
+
+    /// Replace the web seeds (BEP 19), or remove them if there are none.
+    pub fn set_web_seeds(&mut self, urls: &[String]) {
+        let list = urls
+            .iter()
+            .map(|url| Value::Bytes(url.as_bytes().to_vec()))
+            .collect();
+        self.set(b"url-list", (!urls.is_empty()).then_some(Value::List(list)));
+    }
+
+    /// Set the free-form comment, or remove it.
+    pub fn set_comment(&mut self, comment: Option<&str>) {
+        self.set(
+            b"comment",
+            comment.map(|comment| Value::Bytes(comment.into())),
+        );
+    }
+
+    /// Mark the torrent as private (BEP 27) or not.
+    ///
+    /// Unlike everything else here, the private flag is part of the info dictionary, so changing
+    /// it changes the info hash: peers see the result as a different torrent altogether. The info
+    /// dictionary is re-encoded in canonical form when that happens.
+    pub fn set_private(&mut self, private: bool) -> anyhow::Result<()> {
+        let Value::Dict(mut info) =
+            serde_bencode::from_bytes(&self.info).context("parse info dictionary")?
+        else {
+            anyhow::bail!("info is not a dictionary");
+        };
+        let current = matches!(info.get(&b"private"[..]), Some(Value::Int(1)));
+        if current == private {
+            return Ok(());
+        }
+        if private {
+            info.insert(b"private".to_vec(), Value::Int(1));
+        } else {
+            info.remove(&b"private"[..]);
+        }
+        self.info =
+            serde_bencode::to_bytes(&Value::Dict(info)).context("re-encode info dictionary")?;
+        Ok(())
+    }
+
+    fn set(&mut self, key: &[u8], value: Option<Value>) {
+        match value {
+            Some(value) => self.fields.insert(key.to_vec(), value),
+            None => self.fields.remove(key),
+        };
+    }
+
+    /// Encode the edited torrent, ready to be written to a .torrent file.
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut out = vec![b'd'];
+        let info = (b"info".to_vec(), None);
+        let mut entries: Vec<_> = self
+            .fields
+            .iter()
+            .map(|(key, value)| (key.clone(), Some(value)))
+            .chain([info])
+            .collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (key, value) in entries {
+            encode_bytes(&key, &mut out);
+            match value {
+                Some(value) => out.extend(serde_bencode::to_bytes(value).with_context(|| {
+                    format!("encode value of key {}", String::from_utf8_lossy(&key))
+                })?),
+                None => out.extend(&self.info),
+            }
+        }
+        out.push(b'e');
+        Ok(out)
+    }
+}
+
+#[test]
+fn edit_torrent() {
+    // an info dictionary with its keys out of order, which re-encoding would fix (and so change
+    // the info hash)
+    let info = b"d4:name1:a6:lengthi1e12:piece lengthi1e6:pieces0:e";
+    let mut dot_torrent = b"d8:announce9:http://a/7:comment3:old4:info".to_vec();
+    dot_torrent.extend(info);
+    dot_torrent.push(b'e');
+
+    let mut editor = TorrentEditor::from_bytes(&dot_torrent).unwrap();
+    let info_hash = editor.info_hash();
+    assert_eq!(info_hash, <[u8; 20]>::from(Sha1::digest(info)));
+    assert_eq!(editor.to_bytes().unwrap(), dot_torrent);
+
+    editor.set_announce("http://b/");
+    editor.set_announce_list(&[vec!["http://b/".to_string()], vec!["http://c/".to_string()]]);
+    editor.set_web_seeds(&["http://d/".to_string()]);
+    editor.set_comment(None);
+    let mut expected =
+        b"d8:announce9:http://b/13:announce-listll9:http://b/el9:http://c/ee4:info".to_vec();
+    expected.extend(info);
+    expected.extend(b"8:url-listl9:http://d/ee");
+    assert_eq!(editor.to_bytes().unwrap(), expected);
+    assert_eq!(editor.info_hash(), info_hash);
+
+    // hashing with SHA-256 isn't enough to get a v2 info hash when the encoding isn't canonical
+    assert!(editor.info_hash_v2().is_err());
+
+    // making it private has to touch the info dictionary
+    editor.set_private(true).unwrap();
+    assert_ne!(editor.info_hash(), info_hash);
+    editor.set_private(false).unwrap();
+    let reencoded = TorrentEditor::from_bytes(&editor.to_bytes().unwrap()).unwrap();
+    assert_eq!(
+        reencoded.info,
+        b"d6:lengthi1e4:name1:a12:piece lengthi1e6:pieces0:e"
+    );
+    assert_eq!(
+        reencoded.info_hash_v2().unwrap(),
+        merkle::sha256(&reencoded.info)
+    );
+
+    assert!(TorrentEditor::from_bytes(b"d8:announce1:ae").is_err());
+    assert!(TorrentEditor::from_bytes(b"d4:infod").is_err());
+}
@@ -0,0 +1,416 @@
+//! The BitTorrent extension protocol (BEP 10) and the extensions we support on top of it.
+//!
+//! Every extension's messages arrive as `Extended` messages, under the extended message id we
+//! gave the extension in our extension handshake. Each connection has an [`Extensions`] registry
+//! of [handlers](ExtensionHandler), one per extension we support, which the connection hands
+//! those messages to. Supporting another extension is a matter of registering a handler for it.
+
+use crate::holepunch::HolepunchHandler;
+use crate::peer::{Message, MessageTag};
+use crate::swarm::Swarm;
+use crate::BLOCK_MAX;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddrV4;
+
+/// The extended message id reserved for the extension handshake itself.
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// The name of the metadata exchange extension (BEP 9).
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// The extended message id _we_ expect peers to use when sending us `ut_metadata` messages.
+pub const UT_METADATA_ID: u8 = 1;
+
+/// The name of the extension peers use to tell each other they've stopped downloading (BEP 21).
+pub const UPLOAD_ONLY: &str = "upload_only";
+
+/// The extended message id _we_ expect peers to use when sending us `upload_only` messages.
+pub const UPLOAD_ONLY_ID: u8 = 3;
+
+/// The payload of the extension handshake message.
+///
+/// Every field is optional, and peers are free to send keys we don't know about.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ExtensionHandshake {
+    /// Maps the name of each supported extension to the extended message id the sender wants to
+    /// receive that extension's messages on. An id of 0 means the extension is disabled.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+
+    /// The size of the info dictionary in bytes, if the sender has it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+
+    /// The client name and version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+
+    /// Non-zero if the sender isn't going to download anything, such as because it's a seed
+    /// (BEP 21).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_only: Option<u8>,
+}
+
+impl ExtensionHandshake {
+    /// The handshake we send to peers when we have `metadata_size` bytes of metadata to share,
+    /// and support the extensions in `m` (see [`Extensions::m`]). If we're `upload_only`, we say
+    /// so.
+    pub fn ours(metadata_size: usize, m: BTreeMap<String, u8>, upload_only: bool) -> Self {
+        Self {
+            m,
+            metadata_size: Some(metadata_size),
+            v: None,
+            upload_only: upload_only.then_some(1),
+        }
+    }
+
+    /// Whether the sender said it isn't going to download anything.
+    pub fn is_upload_only(&self) -> bool {
+        self.upload_only.is_some_and(|upload_only| upload_only != 0)
+    }
+
+    /// The names of the extensions the sender supports.
+    pub fn supported(&self) -> impl Iterator<Item = &str> + '_ {
+        self.m
+            .iter()
+            .filter(|(_, &id)| id != 0)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The extended message id the sender wants `extension` messages sent to, if it supports it.
+    pub fn id_for(&self, extension: &str) -> Option<u8> {
+        self.m.get(extension).copied().filter(|&id| id != 0)
+    }
+}
+
+/// Handles the messages of one extension on one connection.
+///
+/// Every connection gets handlers of its own, so a handler may keep state about its peer.
+pub(crate) trait ExtensionHandler: Send + Sync {
+    /// The name of the extension, as it appears in the `m` dictionary of extension handshakes.
+    fn name(&self) -> &'static str;
+
+    /// The extended message id we ask peers to send the extension's messages to us on.
+    fn id(&self) -> u8;
+
+    /// Handle a message of the extension from the peer, whose `body` follows the extended message
+    /// id. Replies go through `cx`.
+    ///
+    /// An error disconnects the peer.
+    fn handle(&mut self, body: &[u8], cx: &mut ExtensionContext<'_>) -> anyhow::Result<()>;
+}
+
+/// What an [`ExtensionHandler`] gets to work with while handling a message.
+pub(crate) struct ExtensionContext<'a> {
+    /// The peer the message came from.
+    pub(crate) addr: SocketAddrV4,
+    pub(crate) swarm: &'a Swarm,
+    /// The bencoded info dictionary of the torrent.
+    pub(crate) metadata: &'a [u8],
+    /// The peer's extension handshake, if it has sent one.
+    pub(crate) theirs: Option<&'a ExtensionHandshake>,
+    replies: Vec<Message>,
+}
+
+impl<'a> ExtensionContext<'a> {
+    pub(crate) fn new(
+        addr: SocketAddrV4,
+        swarm: &'a Swarm,
+        metadata: &'a [u8],
+        theirs: Option<&'a ExtensionHandshake>,
+    ) -> Self {
+        Self {
+            addr,
+            swarm,
+            metadata,
+            theirs,
+            replies: Vec::new(),
+        }
+    }
+
+    /// Send `body` to the peer as a message of `extension`, under the extended message id the
+    /// peer asked for it on.
+    ///
+    /// Returns `false`, and sends nothing, if the peer didn't tell us where to send `extension`
+    /// messages.
+    pub(crate) fn reply(&mut self, extension: &str, body: Vec<u8>) -> bool {
+        let Some(id) = self.theirs.and_then(|theirs| theirs.id_for(extension)) else {
+            return false;
+        };
+        let mut payload = vec![id];
+        payload.extend(body);
+        self.replies.push(Message {
+            tag: MessageTag::Extended,
+            payload,
+        });
+        true
+    }
+
+    /// The messages to send the peer, in the order they were replied with.
+    pub(crate) fn into_replies(self) -> Vec<Message> {
+        self.replies
+    }
+}
+
+/// The extensions a connection supports, each with the handler for its messages.
+#[derive(Default)]
+pub(crate) struct Extensions {
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+}
+
+impl Extensions {
+    /// The extensions we support for a torrent.
+    ///
+    /// For private torrents, we don't offer any extensions that let peers find each other.
+    pub(crate) fn for_torrent(private: bool) -> Self {
+        let mut extensions = Self::default();
+        extensions.register(MetadataHandler);
+        extensions.register(UploadOnlyHandler);
+        if !private {
+            extensions.register(HolepunchHandler);
+        }
+        extensions
+    }
+
+    /// Support another extension, whose messages go to `handler`.
+    ///
+    /// # Panics
+    ///
+    /// If the handler wants the handshake's id, or an id or name that's already taken.
+    pub(crate) fn register(&mut self, handler: impl ExtensionHandler + 'static) {
+        assert_ne!(
+            handler.id(),
+            HANDSHAKE_ID,
+            "{} took the handshake id",
+            handler.name()
+        );
+        assert!(
+            self.handlers
+                .iter()
+                .all(|other| other.id() != handler.id() && other.name() != handler.name()),
+            "{} is registered twice, or its id is taken",
+            handler.name()
+        );
+        self.handlers.push(Box::new(handler));
+    }
+
+    /// The `m` dictionary of our extension handshake.
+    pub(crate) fn m(&self) -> BTreeMap<String, u8> {
+        self.handlers
+            .iter()
+            .map(|handler| (handler.name().to_string(), handler.id()))
+            .collect()
+    }
+
+    /// The handler for the messages peers send us on extended message id `id`, if any.
+    pub(crate) fn get_mut(&mut self, id: u8) -> Option<&mut (dyn ExtensionHandler + 'static)> {
+        self.handlers
+            .iter_mut()
+            .find(|handler| handler.id() == id)
+            .map(|handler| &mut **handler)
+    }
+}
+
+/// Serves our metadata to peers that ask for it with `ut_metadata`.
+///
+/// We never ask for metadata ourselves, so data and rejects are ignored.
+struct MetadataHandler;
+
+impl ExtensionHandler for MetadataHandler {
+    fn name(&self) -> &'static str {
+        UT_METADATA
+    }
+
+    fn id(&self) -> u8 {
+        UT_METADATA_ID
+    }
+
+    fn handle(&mut self, body: &[u8], cx: &mut ExtensionContext<'_>) -> anyhow::Result<()> {
+        let msg: MetadataMessage =
+            serde_bencode::from_bytes(body).context("parse ut_metadata message")?;
+        if msg.kind() == Some(MetadataMessageType::Request) {
+            // a peer that didn't tell us where to send replies doesn't get any
+            cx.reply(UT_METADATA, serve_metadata(cx.metadata, msg.piece)?);
+        }
+        Ok(())
+    }
+}
+
+/// Keeps track of whether the peer is only uploading, for peers that tell us when that changes
+/// rather than only in their extension handshake.
+///
+/// The message is a single byte, which is non-zero if the peer has stopped downloading.
+struct UploadOnlyHandler;
+
+impl ExtensionHandler for UploadOnlyHandler {
+    fn name(&self) -> &'static str {
+        UPLOAD_ONLY
+    }
+
+    fn id(&self) -> u8 {
+        UPLOAD_ONLY_ID
+    }
+
+    fn handle(&mut self, body: &[u8], cx: &mut ExtensionContext<'_>) -> anyhow::Result<()> {
+        let &[upload_only] = body else {
+            anyhow::bail!("upload_only message is {} bytes, not 1", body.len());
+        };
+        cx.swarm
+            .update(cx.addr, |state| state.upload_only = upload_only != 0);
+        Ok(())
+    }
+}
+
+/// The `msg_type` values of `ut_metadata` messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MetadataMessageType {
+    Request = 0,
+    Data = 1,
+    Reject = 2,
+}
+
+/// The bencoded dictionary that leads every `ut_metadata` message.
+///
+/// For `Data` messages, the bytes of the metadata piece follow directly after the dictionary.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MetadataMessage {
+    pub msg_type: u8,
+    pub piece: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<usize>,
+}
+
+impl MetadataMessage {
+    pub fn kind(&self) -> Option<MetadataMessageType> {
+        match self.msg_type {
+            0 => Some(MetadataMessageType::Request),
+            1 => Some(MetadataMessageType::Data),
+            2 => Some(MetadataMessageType::Reject),
+            _ => None,
+        }
+    }
+}
+
+/// Build the payload of an extended message: the extended message id followed by `body`.
+pub fn payload(id: u8, body: &impl Serialize) -> anyhow::Result<Vec<u8>> {
+    let mut payload = vec![id];
+    payload.extend(serde_bencode::to_bytes(body).context("bencode extended message")?);
+    Ok(payload)
+}
+
+/// The number of 16 KiB pieces the metadata (info dictionary) is split into for `ut_metadata`.
+pub fn metadata_pieces(metadata: &[u8]) -> usize {
+    metadata.len().div_ceil(BLOCK_MAX)
+}
+
+/// Construct the reply to a `ut_metadata` request for `piece` of `metadata`.
+///
+/// The reply is a `Data` message carrying the requested piece (every piece is 16 KiB except
+/// possibly the last), or a `Reject` if no such piece exists. The returned bytes do _not_ include
+/// the leading extended message id.
+pub fn serve_metadata(metadata: &[u8], piece: usize) -> anyhow::Result<Vec<u8>> {
+    if piece >= metadata_pieces(metadata) {
+        let reject = MetadataMessage {
+            msg_type: MetadataMessageType::Reject as u8,
+            piece,
+            total_size: None,
+        };
+        return serde_bencode::to_bytes(&reject).context("bencode metadata reject");
+    }
+
+    let data = &metadata[piece * BLOCK_MAX..][..BLOCK_MAX.min(metadata.len() - piece * BLOCK_MAX)];
+    let header = MetadataMessage {
+        msg_type: MetadataMessageType::Data as u8,
+        piece,
+        total_size: Some(metadata.len()),
+    };
+    let mut reply = serde_bencode::to_bytes(&header).context("bencode metadata data")?;
+    reply.extend_from_slice(data);
+    Ok(reply)
+}
+
+#[test]
+fn metadata_chunking() {
+    let metadata = vec![7u8; BLOCK_MAX + 10];
+    assert_eq!(metadata_pieces(&metadata), 2);
+
+    let first = serve_metadata(&metadata, 0).unwrap();
+    let header = b"d8:msg_typei1e5:piecei0e10:total_sizei16394ee";
+    assert_eq!(&first[..header.len()], header);
+    assert_eq!(first.len(), header.len() + BLOCK_MAX);
+
+    let last = serve_metadata(&metadata, 1).unwrap();
+    let header = b"d8:msg_typei1e5:piecei1e10:total_sizei16394ee";
+    assert_eq!(&last[..header.len()], header);
+    assert_eq!(last.len(), header.len() + 10);
+
+    let reject = serve_metadata(&metadata, 2).unwrap();
+    assert_eq!(reject, b"d8:msg_typei2e5:piecei2ee");
+}
+
+#[test]
+fn extension_registry() {
+    use crate::download::DownloadConfig;
+    use crate::stats::Stats;
+    use crate::storage::{MemoryStorage, Pieces};
+    use std::sync::Arc;
+
+    /// Sends every message straight back.
+    struct Echo;
+    impl ExtensionHandler for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+        fn id(&self) -> u8 {
+            7
+        }
+        fn handle(&mut self, body: &[u8], cx: &mut ExtensionContext<'_>) -> anyhow::Result<()> {
+            cx.reply("echo", body.to_vec());
+            Ok(())
+        }
+    }
+
+    let mut extensions = Extensions::for_torrent(true);
+    extensions.register(Echo);
+    assert_eq!(
+        extensions.m(),
+        BTreeMap::from([
+            ("echo".to_string(), 7),
+            (UPLOAD_ONLY.to_string(), 3),
+            (UT_METADATA.to_string(), 1),
+        ])
+    );
+    assert!(
+        extensions.get_mut(2).is_none(),
+        "no holepunching when private"
+    );
+
+    let (swarm, _candidates) = Swarm::new(
+        Arc::new(Stats::new(0)),
+        true,
+        Arc::new(Pieces::new(Arc::new(MemoryStorage::default()), 0)),
+        1,
+        [0; 20],
+        &DownloadConfig::default(),
+    );
+    let addr = "127.0.0.1:6881".parse().unwrap();
+    let theirs = ExtensionHandshake {
+        m: BTreeMap::from([("echo".to_string(), 3)]),
+        ..ExtensionHandshake::default()
+    };
+    let mut cx = ExtensionContext::new(addr, &swarm, b"d4:name1:xe", Some(&theirs));
+    let echo = extensions.get_mut(7).expect("registered");
+    echo.handle(b"hi", &mut cx).unwrap();
+    // the peer didn't ask for ut_metadata messages, so it gets no metadata
+    extensions
+        .get_mut(UT_METADATA_ID)
+        .expect("registered")
+        .handle(b"d8:msg_typei0e5:piecei0ee", &mut cx)
+        .unwrap();
+    let replies = cx.into_replies();
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].payload, b"\x03hi");
+}
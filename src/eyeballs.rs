@@ -0,0 +1,121 @@
+//! Connecting to a peer that can be reached at more than one address, the Happy Eyeballs way
+//! (RFC 8305).
+//!
+//! Rather than trying each address in turn, and waiting out a full connect timeout for every one
+//! that doesn't answer, attempts are started a short delay apart, without waiting for the earlier
+//! ones to give up. Whichever connects first is used, and the rest are abandoned. Addresses
+//! alternate between IPv6 and IPv4, so that a broken path over one family costs no more than the
+//! delay.
+
+use anyhow::Context;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// How long to give one connection attempt before starting the next, as recommended by RFC 8305.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect to whichever of `addrs` answers first, starting a new attempt every `delay` (or as
+/// soon as an earlier attempt fails).
+///
+/// Returns the connection along with the address it's to. If every attempt fails, the error is
+/// that of the last one.
+pub async fn connect(
+    addrs: &[SocketAddr],
+    delay: Duration,
+) -> anyhow::Result<(TcpStream, SocketAddr)> {
+    let mut pending = interleave(addrs).into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        if attempts.is_empty() {
+            let Some(addr) = pending.next() else {
+                return Err(
+                    last_error.unwrap_or_else(|| anyhow::anyhow!("no address to connect to"))
+                );
+            };
+            attempts.push(attempt(addr));
+        }
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(stream) => return Ok((stream, addr)),
+                Err(e) => {
+                    last_error = Some(e);
+                    // no point waiting out the delay for an attempt that has already failed
+                    if let Some(addr) = pending.next() {
+                        attempts.push(attempt(addr));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(delay), if pending.peek().is_some() => {
+                attempts.push(attempt(pending.next().expect("just peeked")));
+            }
+        }
+    }
+}
+
+async fn attempt(addr: SocketAddr) -> (SocketAddr, anyhow::Result<TcpStream>) {
+    let stream = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("connect to {addr}"));
+    (addr, stream)
+}
+
+/// The order to try `addrs` in: alternating between address families, starting with the family
+/// of the first address, and otherwise keeping the order they were given in.
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return Vec::new();
+    };
+    let (same, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .iter()
+        .partition(|addr| addr.is_ipv6() == first.is_ipv6());
+    let mut order = Vec::with_capacity(addrs.len());
+    let (mut same, mut other) = (same.into_iter(), other.into_iter());
+    loop {
+        match (same.next(), other.next()) {
+            (None, None) => return order,
+            (a, b) => order.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+#[test]
+fn interleaves_families() {
+    let addrs: Vec<SocketAddr> = [
+        "[::1]:1",
+        "[::1]:2",
+        "[::1]:3",
+        "127.0.0.1:4",
+        "127.0.0.1:5",
+    ]
+    .iter()
+    .map(|addr| addr.parse().unwrap())
+    .collect();
+    let ports: Vec<_> = interleave(&addrs).iter().map(|addr| addr.port()).collect();
+    assert_eq!(ports, [1, 4, 2, 5, 3]);
+    let ports: Vec<_> = interleave(&[addrs[4], addrs[0], addrs[3]])
+        .iter()
+        .map(|addr| addr.port())
+        .collect();
+    assert_eq!(ports, [5, 1, 4]);
+}
+
+#[tokio::test]
+async fn first_to_connect_wins() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let open = listener.local_addr().unwrap();
+    // nothing listens on this one, so it fails right away
+    let closed = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    };
+
+    let (_, addr) = connect(&[closed, open], Duration::from_secs(60))
+        .await
+        .expect("falls back to the open address without waiting out the delay");
+    assert_eq!(addr, open);
+    assert!(connect(&[closed], CONNECTION_ATTEMPT_DELAY).await.is_err());
+    assert!(connect(&[], CONNECTION_ATTEMPT_DELAY).await.is_err());
+}
@@ -0,0 +1,270 @@
+//! Injecting faults into a peer's side of a connection, to test how downloads recover from them.
+//!
+//! A [`FaultyStream`] wraps the stream a (test) peer talks to us over, and messes with the
+//! messages it sends: it hangs up instead of sending some, sends only part of others, corrupts the
+//! data of blocks, and holds messages back for a while. Which messages are hit is random, but
+//! follows from [`Faults::seed`], so a failing test can be re-run with the same faults.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// The length of the handshake that starts every connection, which is left alone.
+const HANDSHAKE_LEN: usize = 68;
+
+/// Which faults to inject, and how often.
+///
+/// Each chance is per message, and the default injects none at all.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Faults {
+    pub(crate) seed: u64,
+    /// The chance of hanging up instead of sending a message.
+    pub(crate) drop: f64,
+    /// The chance of sending only part of a message, and then hanging up.
+    pub(crate) truncate: f64,
+    /// The chance of flipping a byte of the data of a block.
+    pub(crate) corrupt: f64,
+    /// Hold each message back for a random time of up to this long.
+    pub(crate) delay: Option<Duration>,
+}
+
+/// A stream that passes what's read from it through untouched, but injects [`Faults`] into the
+/// peer wire messages written to it.
+///
+/// Each message is written out as a whole before the write that completed it returns, so a write
+/// that's left pending must be retried with the same data, as `write_all` does.
+pub(crate) struct FaultyStream<S> {
+    inner: S,
+    faults: Faults,
+    rng: u64,
+    /// The bytes we have so far of the handshake or message currently being written.
+    unit: Vec<u8>,
+    handshake_sent: bool,
+    out: Option<Outgoing>,
+    hung_up: bool,
+}
+
+/// A handshake or message on its way out.
+struct Outgoing {
+    bytes: Vec<u8>,
+    written: usize,
+    delay: Option<Pin<Box<Sleep>>>,
+    /// Whether to hang up once it's out.
+    hang_up: bool,
+    /// How many bytes of the caller's write went into it.
+    consumed: usize,
+}
+
+impl<S> FaultyStream<S> {
+    pub(crate) fn new(inner: S, faults: Faults) -> Self {
+        Self {
+            inner,
+            // scramble the seed, since xorshift takes a while to get going from small ones (and
+            // gets stuck at zero)
+            rng: faults.seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1,
+            faults,
+            unit: Vec::new(),
+            handshake_sent: false,
+            out: None,
+            hung_up: false,
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// A random number in `[0, 1)`.
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// How many more bytes the handshake or message being written needs to be complete.
+    fn missing(&self) -> usize {
+        if !self.handshake_sent {
+            return HANDSHAKE_LEN - self.unit.len();
+        }
+        match self.unit.get(..4) {
+            Some(length) => {
+                let length = u32::from_be_bytes(length.try_into().expect("4 bytes")) as usize;
+                4 + length - self.unit.len()
+            }
+            None => 4 - self.unit.len(),
+        }
+    }
+
+    /// Decide what happens to the complete handshake or message in `unit`.
+    fn inject(&mut self, consumed: usize) -> io::Result<()> {
+        let mut bytes = std::mem::take(&mut self.unit);
+        if !self.handshake_sent {
+            self.handshake_sent = true;
+            self.out = Some(Outgoing {
+                bytes,
+                written: 0,
+                delay: None,
+                hang_up: false,
+                consumed,
+            });
+            return Ok(());
+        }
+
+        if self.random() < self.faults.drop {
+            self.hung_up = true;
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "injected connection drop",
+            ));
+        }
+        // a piece message, with the block after its tag, index, and begin
+        if bytes.get(4) == Some(&7) && bytes.len() > 13 && self.random() < self.faults.corrupt {
+            let at = 13 + (self.random() * (bytes.len() - 13) as f64) as usize;
+            bytes[at] ^= 0xff;
+        }
+        let mut hang_up = false;
+        if self.random() < self.faults.truncate {
+            bytes.truncate((self.random() * bytes.len() as f64) as usize);
+            hang_up = true;
+        }
+        let delay = self
+            .faults
+            .delay
+            .map(|max| Box::pin(tokio::time::sleep(max.mul_f64(self.random()))));
+        self.out = Some(Outgoing {
+            bytes,
+            written: 0,
+            delay,
+            hang_up,
+            consumed,
+        });
+        Ok(())
+    }
+}
+
+impl<S: AsyncWrite + Unpin> FaultyStream<S> {
+    /// Write out what's on its way out, and return how many bytes of the caller's write that was.
+    fn poll_out(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let Some(out) = &mut self.out else {
+            return Poll::Ready(Ok(0));
+        };
+        if let Some(delay) = &mut out.delay {
+            ready!(delay.as_mut().poll(cx));
+            out.delay = None;
+        }
+        while out.written < out.bytes.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &out.bytes[out.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            out.written += n;
+        }
+        let out = self.out.take().expect("checked above");
+        if out.hang_up {
+            self.hung_up = true;
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "injected truncated message",
+            )));
+        }
+        Poll::Ready(Ok(out.consumed))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for FaultyStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FaultyStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.out.is_some() {
+            // the rest of a write we left pending
+            return this.poll_out(cx);
+        }
+        if this.hung_up {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let mut consumed = 0;
+        while consumed < buf.len() {
+            let take = this.missing().min(buf.len() - consumed);
+            this.unit.extend_from_slice(&buf[consumed..][..take]);
+            consumed += take;
+            if this.missing() == 0 {
+                this.inject(consumed)?;
+                return this.poll_out(cx);
+            }
+        }
+        Poll::Ready(Ok(consumed))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_out(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn inject_faults() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let message = |tag: u8, payload: &[u8]| {
+        let mut msg = (1 + payload.len() as u32).to_be_bytes().to_vec();
+        msg.push(tag);
+        msg.extend(payload);
+        msg
+    };
+    let block = message(7, &[[0; 8].as_slice(), &[1; 100]].concat());
+
+    // the handshake is left alone, and with every block corrupted, each differs in one byte
+    let (a, mut b) = tokio::io::duplex(1 << 16);
+    let faults = Faults {
+        corrupt: 1.0,
+        ..Faults::default()
+    };
+    let mut a = FaultyStream::new(a, faults);
+    a.write_all(&[0x13; HANDSHAKE_LEN]).await.unwrap();
+    // written in bits, the way a message can be
+    a.write_all(&block[..3]).await.unwrap();
+    a.write_all(&block[3..]).await.unwrap();
+    let mut received = vec![0; HANDSHAKE_LEN + block.len()];
+    b.read_exact(&mut received).await.unwrap();
+    assert_eq!(received[..HANDSHAKE_LEN], [0x13; HANDSHAKE_LEN]);
+    let received = &received[HANDSHAKE_LEN..];
+    assert_eq!(received[..13], block[..13]);
+    let differ = received.iter().zip(&block).filter(|(a, b)| a != b).count();
+    assert_eq!(differ, 1);
+
+    // a truncated message goes out in part, and then the stream refuses to go on
+    let (a, mut b) = tokio::io::duplex(1 << 16);
+    let faults = Faults {
+        truncate: 1.0,
+        ..Faults::default()
+    };
+    let mut a = FaultyStream::new(a, faults);
+    a.write_all(&[0x13; HANDSHAKE_LEN]).await.unwrap();
+    assert!(a.write_all(&block).await.is_err());
+    assert!(a.write_all(&block).await.is_err());
+    drop(a);
+    let mut received = Vec::new();
+    b.read_to_end(&mut received).await.unwrap();
+    assert!(received.len() < HANDSHAKE_LEN + block.len());
+}
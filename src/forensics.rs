@@ -0,0 +1,164 @@
+//! Keeping the evidence when a downloaded piece fails its hash check.
+//!
+//! A piece that doesn't match its hash is thrown away and fetched again, which is all the download
+//! needs. But it leaves no trace of who sent the bad data, so with a dump directory configured,
+//! the piece as we received it is written there, along with a map of which peer sent each of its
+//! blocks. Comparing the dumped piece with a good copy then points at the block (and so the peer)
+//! that corrupted it.
+
+use anyhow::Context;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::SocketAddrV4;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A peer that sent us blocks of a piece.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contributor {
+    pub addr: SocketAddrV4,
+    pub peer_id: [u8; 20],
+    /// The client the peer is running, if we could tell.
+    pub client: Option<String>,
+}
+
+/// A piece that failed its hash check, as it was received.
+#[derive(Debug)]
+pub struct HashFailure<'a> {
+    pub info_hash: [u8; 20],
+    pub piece_i: usize,
+    pub expected: [u8; 20],
+    pub actual: [u8; 20],
+    pub data: &'a [u8],
+    /// The length of every block but the last.
+    pub block_size: usize,
+    /// Who sent each block, or `None` for blocks kept from an earlier attempt at the piece.
+    pub blocks: Vec<Option<Contributor>>,
+}
+
+impl HashFailure<'_> {
+    /// Every peer that sent blocks of the piece, in the order their first block is in, along with
+    /// how many blocks each sent.
+    ///
+    /// One (or more) of these sent bad data.
+    pub fn contributors(&self) -> Vec<(&Contributor, usize)> {
+        let mut contributors: Vec<(&Contributor, usize)> = Vec::new();
+        for contributor in self.blocks.iter().flatten() {
+            match contributors.iter_mut().find(|(c, _)| *c == contributor) {
+                Some((_, n)) => *n += 1,
+                None => contributors.push((contributor, 1)),
+            }
+        }
+        contributors
+    }
+
+    /// The block map, as it's written next to the dumped piece.
+    fn map(&self) -> String {
+        let mut map = String::new();
+        let describe = |c: &Contributor| {
+            format!(
+                "{} {} ({})",
+                c.addr,
+                hex::encode(c.peer_id),
+                c.client.as_deref().unwrap_or("unknown client")
+            )
+        };
+        // writing to a string can't fail
+        let _ = writeln!(map, "info hash: {}", hex::encode(self.info_hash));
+        let _ = writeln!(map, "piece: {}", self.piece_i);
+        let _ = writeln!(map, "length: {}", self.data.len());
+        let _ = writeln!(map, "expected hash: {}", hex::encode(self.expected));
+        let _ = writeln!(map, "actual hash: {}", hex::encode(self.actual));
+        let _ = writeln!(map);
+        let _ = writeln!(map, "blocks (begin, length, peer):");
+        for (block_i, contributor) in self.blocks.iter().enumerate() {
+            let begin = block_i * self.block_size;
+            let length = self.block_size.min(self.data.len() - begin);
+            let peer = match contributor {
+                Some(c) => describe(c),
+                None => "kept from an earlier attempt".into(),
+            };
+            let _ = writeln!(map, "{begin} {length} {peer}");
+        }
+        let _ = writeln!(map);
+        let _ = writeln!(map, "peers (blocks, peer):");
+        for (contributor, n) in self.contributors() {
+            let _ = writeln!(map, "{n} {}", describe(contributor));
+        }
+        map
+    }
+
+    /// Write the piece and its block map to `dir`, as `<info hash>-<piece>-<time>.piece` and
+    /// `.blocks` next to it, and return the path of the piece.
+    ///
+    /// Dumps never overwrite each other, even of the same piece failing twice in a millisecond.
+    pub fn dump(&self, dir: &Path) -> anyhow::Result<PathBuf> {
+        std::fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+        // the same piece can fail more than once, so tell the attempts apart
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let name = format!("{}-{}-{millis}", hex::encode(self.info_hash), self.piece_i);
+        let mut piece = dir.join(format!("{name}.piece"));
+        for n in 1.. {
+            match OpenOptions::new().write(true).create_new(true).open(&piece) {
+                Ok(mut file) => {
+                    file.write_all(self.data)
+                        .with_context(|| format!("write {}", piece.display()))?;
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    piece = dir.join(format!("{name}-{n}.piece"));
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("create {}", piece.display()));
+                }
+            }
+        }
+        let map = piece.with_extension("blocks");
+        std::fs::write(&map, self.map()).with_context(|| format!("write {}", map.display()))?;
+        Ok(piece)
+    }
+}
+
+#[test]
+fn dump_failure() {
+    let peer = |n: u8, client: Option<&str>| Contributor {
+        addr: SocketAddrV4::new([127, 0, 0, n].into(), 6881),
+        peer_id: [n; 20],
+        client: client.map(Into::into),
+    };
+    let (a, b) = (peer(1, Some("Test 1.0")), peer(2, None));
+    let data = [7; 10];
+    let failure = HashFailure {
+        info_hash: [0xab; 20],
+        piece_i: 3,
+        expected: [1; 20],
+        actual: [2; 20],
+        data: &data,
+        block_size: 3,
+        blocks: vec![Some(b.clone()), None, Some(a.clone()), Some(b.clone())],
+    };
+    assert_eq!(failure.contributors(), [(&b, 2), (&a, 1)]);
+
+    let dir = tempfile::tempdir().expect("create temporary directory");
+    let piece = failure.dump(&dir.path().join("dumps")).expect("dump");
+    assert_eq!(std::fs::read(&piece).unwrap(), data);
+    let name = piece.file_name().unwrap().to_str().unwrap();
+    assert!(name.starts_with(&format!("{}-3-", "ab".repeat(20))));
+    let again = failure.dump(&dir.path().join("dumps")).expect("dump again");
+    assert_ne!(again, piece);
+    let map = std::fs::read_to_string(piece.with_extension("blocks")).unwrap();
+    assert!(map.contains(&format!("expected hash: {}\n", "01".repeat(20))));
+    let b = format!("127.0.0.2:6881 {} (unknown client)", "02".repeat(20));
+    assert!(map.contains(&format!("0 3 {b}\n")));
+    assert!(map.contains("3 3 kept from an earlier attempt\n"));
+    assert!(map.contains(&format!(
+        "6 3 127.0.0.1:6881 {} (Test 1.0)\n",
+        "01".repeat(20)
+    )));
+    assert!(map.contains(&format!("9 1 {b}\n")));
+    assert!(map.contains(&format!("2 {b}\n")));
+}
@@ -0,0 +1,34 @@
+//! Hashing pieces to check them against the torrent.
+//!
+//! Hashing is where a fast download spends most of its CPU time, so the hasher is pluggable. The
+//! default one uses the `sha1` crate, which already picks the SHA extensions of x86 CPUs at
+//! runtime where they're available. Run `cargo run --release --example throughput` to see how
+//! fast hashing (and message framing) is on a given machine. On an x86 machine with SHA extensions
+//! it measured about 2.4 GiB/s for hashing, 3.6 GiB/s for encoding blocks, and much faster still for
+//! decoding them, so neither should hold back a download.
+
+use sha1::{Digest, Sha1};
+use std::fmt;
+
+/// Computes the SHA-1 hashes that pieces are checked against.
+pub trait PieceHasher: fmt::Debug + Send + Sync {
+    fn hash(&self, data: &[u8]) -> [u8; 20];
+}
+
+/// Hashes with the `sha1` crate.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha1Hasher;
+
+impl PieceHasher for Sha1Hasher {
+    fn hash(&self, data: &[u8]) -> [u8; 20] {
+        Sha1::digest(data).into()
+    }
+}
+
+#[test]
+fn sha1_hasher() {
+    assert_eq!(
+        hex::encode(Sha1Hasher.hash(b"abc")),
+        "a9993e364706816aba3e25717850c26c9cd0d89d"
+    );
+}
@@ -0,0 +1,117 @@
+//! Keeping count of what we've transferred of each torrent across runs.
+//!
+//! Private trackers hold us to a ratio of uploaded to downloaded data, and a torrent is usually
+//! downloaded in one run and seeded over many more. So the totals of every torrent are kept in a
+//! file in a state directory, announces report those rather than only what this run has done, and
+//! the totals grow with every run that records its [`Stats`].
+
+use crate::stats::{Stats, Totals};
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The name of the file in the state directory that the totals are kept in.
+const FILE_NAME: &str = "stats.json";
+
+/// The transfer totals of every torrent we've recorded, kept in sync with a file on disk.
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+    /// By hex-encoded info hash.
+    torrents: Mutex<BTreeMap<String, Totals>>,
+}
+
+impl History {
+    /// Read the totals kept in `state_dir`, which is created if it doesn't exist yet.
+    pub fn open(state_dir: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(state_dir)
+            .with_context(|| format!("create state directory {}", state_dir.display()))?;
+        let path = state_dir.join(FILE_NAME);
+        let torrents = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("parse {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+        };
+        Ok(Self {
+            path,
+            torrents: Mutex::new(torrents),
+        })
+    }
+
+    /// What we've transferred of the torrent with `info_hash` in the runs recorded so far.
+    pub fn get(&self, info_hash: [u8; 20]) -> Totals {
+        self.torrents
+            .lock()
+            .expect("history lock poisoned")
+            .get(&hex::encode(info_hash))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// What we've transferred of all torrents together.
+    pub fn total(&self) -> Totals {
+        self.torrents
+            .lock()
+            .expect("history lock poisoned")
+            .values()
+            .fold(Totals::default(), |sum, &totals| sum + totals)
+    }
+
+    /// Record the [totals](Stats::totals) of the torrent with `info_hash`, and write them out.
+    ///
+    /// `stats` should have carried on from [`get`](Self::get), or the earlier runs are forgotten.
+    pub fn record(&self, info_hash: [u8; 20], stats: &Stats) -> anyhow::Result<()> {
+        let mut torrents = self.torrents.lock().expect("history lock poisoned");
+        torrents.insert(hex::encode(info_hash), stats.totals());
+        let json = serde_json::to_vec_pretty(&*torrents).context("encode transfer totals")?;
+        // write the new file next to the old one and swap it in, so that a crash midway leaves
+        // one or the other rather than half of each
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, json).with_context(|| format!("write {}", tmp.display()))?;
+        std::fs::rename(&tmp, &self.path)
+            .with_context(|| format!("replace {}", self.path.display()))
+    }
+}
+
+#[test]
+fn totals_across_runs() {
+    let dir = tempfile::tempdir().expect("create temporary directory");
+    let (a, b) = ([1; 20], [2; 20]);
+
+    let history = History::open(&dir.path().join("state")).unwrap();
+    assert_eq!(history.get(a), Totals::default());
+    let stats = Stats::new(100).with_previous(history.get(a));
+    stats.add_downloaded(100);
+    stats.add_uploaded(30);
+    history.record(a, &stats).unwrap();
+    drop(history);
+
+    let history = History::open(&dir.path().join("state")).unwrap();
+    let stats = Stats::new(0).with_previous(history.get(a));
+    stats.add_uploaded(120);
+    assert_eq!(
+        stats.totals(),
+        Totals {
+            uploaded: 150,
+            downloaded: 100
+        }
+    );
+    assert_eq!(stats.totals().ratio(), Some(1.5));
+    history.record(a, &stats).unwrap();
+    let other = Stats::new(0);
+    other.add_downloaded(50);
+    history.record(b, &other).unwrap();
+
+    let history = History::open(&dir.path().join("state")).unwrap();
+    assert_eq!(history.get(a).uploaded, 150);
+    assert_eq!(
+        history.total(),
+        Totals {
+            uploaded: 150,
+            downloaded: 150
+        }
+    );
+    assert_eq!(history.get([3; 20]).ratio(), None);
+}
@@ -0,0 +1,215 @@
+//! The holepunch extension (BEP 55), which lets a peer we are connected to broker a connection
+//! between us and a peer that neither side could reach directly because of NAT.
+
+use crate::extension::{ExtensionContext, ExtensionHandler};
+use crate::swarm::PeerSource;
+use anyhow::Context;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// The name of the holepunch extension in the extension handshake.
+pub const UT_HOLEPUNCH: &str = "ut_holepunch";
+
+/// The extended message id _we_ expect peers to use when sending us `ut_holepunch` messages.
+pub const UT_HOLEPUNCH_ID: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HolepunchType {
+    /// Sent by the initiator to a relay, asking it to connect the initiator with the target.
+    Rendezvous = 0,
+    /// Sent by the relay to both the initiator and the target, telling them to connect to each
+    /// other.
+    Connect = 1,
+    /// Sent by the relay to the initiator if the rendezvous could not be arranged.
+    Error = 2,
+}
+
+/// The reasons a relay may give for failing a rendezvous.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchError {
+    /// The target endpoint is invalid.
+    NoSuchPeer,
+    /// The relay is not connected to the target peer.
+    NotConnected,
+    /// The target peer does not support the holepunch extension.
+    NoSupport,
+    /// The target endpoint belongs to the relay itself.
+    NoSelf,
+    /// An error code BEP 55 doesn't define, which is no reason to drop the peer that sent it.
+    Unknown(u32),
+}
+
+impl HolepunchError {
+    fn from_code(code: u32) -> Self {
+        match code {
+            1 => Self::NoSuchPeer,
+            2 => Self::NotConnected,
+            3 => Self::NoSupport,
+            4 => Self::NoSelf,
+            code => Self::Unknown(code),
+        }
+    }
+
+    fn code(self) -> u32 {
+        match self {
+            Self::NoSuchPeer => 1,
+            Self::NotConnected => 2,
+            Self::NoSupport => 3,
+            Self::NoSelf => 4,
+            Self::Unknown(code) => code,
+        }
+    }
+}
+
+/// A `ut_holepunch` message.
+///
+/// On the wire, this is `msg_type`, an address type (0 for IPv4, 1 for IPv6), the address, the
+/// big-endian port, and a big-endian error code (which is 0 for anything but `Error` messages).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HolepunchMessage {
+    pub kind: HolepunchType,
+    pub addr: SocketAddr,
+    pub error: Option<HolepunchError>,
+}
+
+impl HolepunchMessage {
+    pub fn rendezvous(target: SocketAddr) -> Self {
+        Self {
+            kind: HolepunchType::Rendezvous,
+            addr: target,
+            error: None,
+        }
+    }
+
+    pub fn connect(addr: SocketAddr) -> Self {
+        Self {
+            kind: HolepunchType::Connect,
+            addr,
+            error: None,
+        }
+    }
+
+    pub fn error(target: SocketAddr, error: HolepunchError) -> Self {
+        Self {
+            kind: HolepunchType::Error,
+            addr: target,
+            error: Some(error),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(24);
+        bytes.push(self.kind as u8);
+        match self.addr.ip() {
+            IpAddr::V4(ip) => {
+                bytes.push(0);
+                bytes.extend(ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                bytes.push(1);
+                bytes.extend(ip.octets());
+            }
+        }
+        bytes.extend(self.addr.port().to_be_bytes());
+        bytes.extend(self.error.map_or(0, HolepunchError::code).to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let [kind, addr_type, rest @ ..] = bytes else {
+            anyhow::bail!("holepunch message is too short");
+        };
+        let kind = match kind {
+            0 => HolepunchType::Rendezvous,
+            1 => HolepunchType::Connect,
+            2 => HolepunchType::Error,
+            kind => anyhow::bail!("unknown holepunch message type {kind}"),
+        };
+        let (ip, rest) = match addr_type {
+            0 if rest.len() == 4 + 2 + 4 => {
+                let octets: [u8; 4] = rest[..4].try_into().expect("length checked above");
+                (IpAddr::from(Ipv4Addr::from(octets)), &rest[4..])
+            }
+            1 if rest.len() == 16 + 2 + 4 => {
+                let octets: [u8; 16] = rest[..16].try_into().expect("length checked above");
+                (IpAddr::from(Ipv6Addr::from(octets)), &rest[16..])
+            }
+            _ => anyhow::bail!("invalid holepunch address (type {addr_type})"),
+        };
+        let port = u16::from_be_bytes([rest[0], rest[1]]);
+        let code = u32::from_be_bytes(rest[2..].try_into().expect("length checked above"));
+        let error = match kind {
+            HolepunchType::Error => Some(HolepunchError::from_code(code)),
+            _ => None,
+        };
+        Ok(Self {
+            kind,
+            addr: SocketAddr::new(ip, port),
+            error,
+        })
+    }
+}
+
+/// Brokers connections for the peer, and connects to the peers it brokers connections to.
+pub(crate) struct HolepunchHandler;
+
+impl ExtensionHandler for HolepunchHandler {
+    fn name(&self) -> &'static str {
+        UT_HOLEPUNCH
+    }
+
+    fn id(&self) -> u8 {
+        UT_HOLEPUNCH_ID
+    }
+
+    fn handle(&mut self, body: &[u8], cx: &mut ExtensionContext<'_>) -> anyhow::Result<()> {
+        let msg = HolepunchMessage::from_bytes(body).context("parse ut_holepunch message")?;
+        match msg.kind {
+            HolepunchType::Rendezvous => {
+                let reply = if msg.addr == SocketAddr::V4(cx.addr) {
+                    HolepunchMessage::error(msg.addr, HolepunchError::NoSelf)
+                } else {
+                    cx.swarm.relay_holepunch(cx.addr, msg.addr)
+                };
+                cx.reply(UT_HOLEPUNCH, reply.to_bytes());
+            }
+            HolepunchType::Connect => {
+                // the other side will be connecting to us at the same time, which is what gets
+                // us through both NATs.
+                if let SocketAddr::V4(addr) = msg.addr {
+                    cx.swarm.add_candidate(addr, PeerSource::Holepunch);
+                }
+            }
+            HolepunchType::Error => {
+                eprintln!(
+                    "peer {} could not broker a connection to {}: {:?}",
+                    cx.addr, msg.addr, msg.error
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn holepunch_roundtrip() {
+    let v4 = HolepunchMessage::rendezvous("1.2.3.4:6881".parse().unwrap());
+    let bytes = v4.to_bytes();
+    assert_eq!(bytes, [0, 0, 1, 2, 3, 4, 0x1a, 0xe1, 0, 0, 0, 0]);
+    assert_eq!(HolepunchMessage::from_bytes(&bytes).unwrap(), v4);
+
+    let v6 = HolepunchMessage::error("[::1]:80".parse().unwrap(), HolepunchError::NotConnected);
+    let bytes = v6.to_bytes();
+    assert_eq!(bytes.len(), 2 + 16 + 2 + 4);
+    assert_eq!(HolepunchMessage::from_bytes(&bytes).unwrap(), v6);
+
+    assert!(HolepunchMessage::from_bytes(&bytes[..10]).is_err());
+
+    // an error code from a later revision of the spec still parses
+    let mut bytes = bytes;
+    bytes[2 + 16 + 2..].copy_from_slice(&42u32.to_be_bytes());
+    assert_eq!(
+        HolepunchMessage::from_bytes(&bytes).unwrap().error,
+        Some(HolepunchError::Unknown(42))
+    );
+}
@@ -1,7 +1,51 @@
 pub const BLOCK_MAX: usize = 1 << 14;
 
+/// The port we tell trackers (and map on the router) for peers to reach us on.
+pub const PORT: u16 = 6881;
+
+pub mod bencode;
+pub mod bitfield;
+mod cache;
+pub mod client;
+pub mod compare;
+pub mod complete;
+pub mod dedupe;
+pub mod disk;
 pub mod download;
+pub mod edit;
+pub mod extension;
+pub mod eyeballs;
+#[cfg(test)]
+mod faults;
+pub mod forensics;
+pub mod hash;
+pub mod history;
+pub mod holepunch;
+pub mod lookup;
+pub mod merkle;
+pub mod metrics;
+pub mod mmap;
 pub mod peer;
+pub mod peer_id;
+pub mod picker;
 pub mod piece;
+pub mod pipeline;
+mod pool;
+pub mod portmap;
+pub mod priority;
+pub mod proxy;
+pub mod ratelimit;
+mod resume;
+pub mod rpc;
+mod scheduler;
+pub mod seed;
+pub mod stats;
+pub mod storage;
+pub mod swarm;
+#[cfg(test)]
+mod testing;
 pub mod torrent;
+pub mod trace;
 pub mod tracker;
+pub mod udp_tracker;
+mod verify;
@@ -0,0 +1,253 @@
+//! Finding out more about a peer's address: its reverse DNS name, and roughly where it is.
+
+use anyhow::Context;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// How long to wait for the name server to answer a reverse lookup.
+const DNS_TIMEOUT: Duration = Duration::from_secs(2);
+
+const TYPE_PTR: u16 = 12;
+const CLASS_IN: u16 = 1;
+
+/// Look up the host name of `ip` (its PTR record) with the system's name server.
+///
+/// Returns `None` if the address has no name.
+pub async fn reverse_dns(ip: Ipv4Addr) -> anyhow::Result<Option<String>> {
+    let resolv_conf =
+        std::fs::read_to_string("/etc/resolv.conf").context("read /etc/resolv.conf")?;
+    let server = nameserver(&resolv_conf).context("no name server in /etc/resolv.conf")?;
+
+    let socket = UdpSocket::bind(match server {
+        IpAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        IpAddr::V6(_) => SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, 0)),
+    })
+    .await
+    .context("bind DNS socket")?;
+    socket
+        .connect((server, 53))
+        .await
+        .context("connect to name server")?;
+    // the id only has to tell our queries apart, and we only ever have one in flight per socket
+    let id = std::process::id() as u16 ^ u32::from(ip) as u16;
+    socket
+        .send(&ptr_query(id, ip))
+        .await
+        .context("send DNS query")?;
+
+    let mut buf = [0; 512];
+    let n = tokio::time::timeout(DNS_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .context("name server did not answer")?
+        .context("receive DNS response")?;
+    parse_ptr_response(id, &buf[..n])
+}
+
+/// The first name server listed in the contents of `resolv.conf`.
+fn nameserver(resolv_conf: &str) -> Option<IpAddr> {
+    resolv_conf.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        (words.next() == Some("nameserver"))
+            .then(|| words.next()?.parse().ok())
+            .flatten()
+    })
+}
+
+fn ptr_query(id: u16, ip: Ipv4Addr) -> Vec<u8> {
+    let mut query = Vec::with_capacity(64);
+    query.extend(id.to_be_bytes());
+    // a standard query that asks for recursion
+    query.extend(0x0100u16.to_be_bytes());
+    // one question, and no answer, authority, or additional records
+    query.extend([0, 1, 0, 0, 0, 0, 0, 0]);
+    let [a, b, c, d] = ip.octets();
+    for label in [
+        d.to_string(),
+        c.to_string(),
+        b.to_string(),
+        a.to_string(),
+        "in-addr".to_string(),
+        "arpa".to_string(),
+    ] {
+        query.push(label.len() as u8);
+        query.extend(label.as_bytes());
+    }
+    query.push(0);
+    query.extend(TYPE_PTR.to_be_bytes());
+    query.extend(CLASS_IN.to_be_bytes());
+    query
+}
+
+fn parse_ptr_response(id: u16, response: &[u8]) -> anyhow::Result<Option<String>> {
+    let u16_at = |i: usize| -> anyhow::Result<u16> {
+        let bytes = response
+            .get(i..i + 2)
+            .context("DNS response is truncated")?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    anyhow::ensure!(u16_at(0)? == id, "DNS response is for a different query");
+    let flags = u16_at(2)?;
+    match flags & 0xf {
+        0 => {}
+        // NXDOMAIN: the address has no name
+        3 => return Ok(None),
+        rcode => anyhow::bail!("name server failed with response code {rcode}"),
+    }
+    let (questions, answers) = (u16_at(4)?, u16_at(6)?);
+
+    let mut at = 12;
+    for _ in 0..questions {
+        at = read_name(response, at)?.1 + 4;
+    }
+    for _ in 0..answers {
+        at = read_name(response, at)?.1;
+        let (kind, length) = (u16_at(at)?, u16_at(at + 8)? as usize);
+        let data = at + 10;
+        if kind == TYPE_PTR {
+            return Ok(Some(read_name(response, data)?.0));
+        }
+        at = data + length;
+    }
+    Ok(None)
+}
+
+/// Read the (possibly compressed) domain name at offset `at` of a DNS message.
+///
+/// Returns the name, and the offset just past it.
+fn read_name(msg: &[u8], mut at: usize) -> anyhow::Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // every pointer must go backwards, so following more of them than there are bytes means
+    // there's a loop
+    for _ in 0..msg.len() {
+        let &len = msg.get(at).context("DNS name is truncated")?;
+        match len {
+            0 => {
+                let end = end.unwrap_or(at + 1);
+                return Ok((labels.join("."), end));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let &low = msg.get(at + 1).context("DNS name is truncated")?;
+                end.get_or_insert(at + 2);
+                let target = usize::from(len & 0x3f) << 8 | usize::from(low);
+                anyhow::ensure!(target < at, "DNS name pointer does not point backwards");
+                at = target;
+            }
+            len => {
+                let label = msg
+                    .get(at + 1..at + 1 + usize::from(len))
+                    .context("DNS name is truncated")?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                at += 1 + usize::from(len);
+            }
+        }
+    }
+    anyhow::bail!("DNS name is too long")
+}
+
+/// A database mapping IPv4 address ranges to locations.
+///
+/// The database is a CSV file with one range per line, `first,last,location`, where `first` and
+/// `last` are the (inclusive) ends of the range. This is the layout of the free "IP to country"
+/// databases that several providers publish. Fields may be quoted, and the location may contain
+/// further commas (for example `US,California`).
+#[derive(Debug, Clone, Default)]
+pub struct GeoIp {
+    /// Non-overlapping ranges, sorted by their first address.
+    ranges: Vec<(u32, u32, String)>,
+}
+
+impl GeoIp {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let csv = std::fs::read_to_string(path)
+            .with_context(|| format!("read GeoIP database {}", path.display()))?;
+        Self::parse(&csv).with_context(|| format!("parse GeoIP database {}", path.display()))
+    }
+
+    fn parse(csv: &str) -> anyhow::Result<Self> {
+        let mut ranges = Vec::new();
+        for (line_i, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.splitn(3, ',').map(|f| f.trim().trim_matches('"'));
+            let (Some(first), Some(last), Some(location)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                anyhow::bail!("line {} has fewer than three fields", line_i + 1);
+            };
+            let parse = |ip: &str| {
+                ip.parse::<Ipv4Addr>()
+                    .map(u32::from)
+                    .or_else(|_| ip.parse::<u32>())
+                    .with_context(|| format!("line {}: {ip:?} is not an address", line_i + 1))
+            };
+            let location = location
+                .split(',')
+                .map(|part| part.trim().trim_matches('"'))
+                .collect::<Vec<_>>()
+                .join(",");
+            ranges.push((parse(first)?, parse(last)?, location));
+        }
+        ranges.sort_by_key(|&(first, _, _)| first);
+        Ok(Self { ranges })
+    }
+
+    /// The location of `ip`, if it's in the database.
+    pub fn lookup(&self, ip: Ipv4Addr) -> Option<&str> {
+        let ip = u32::from(ip);
+        let i = self.ranges.partition_point(|&(first, _, _)| first <= ip);
+        let (_, last, location) = self.ranges.get(i.checked_sub(1)?)?;
+        (ip <= *last).then_some(location.as_str())
+    }
+}
+
+#[test]
+fn ptr_roundtrip() {
+    let ip = Ipv4Addr::new(1, 2, 3, 4);
+    let query = ptr_query(7, ip);
+    assert_eq!(read_name(&query, 12).unwrap().0, "4.3.2.1.in-addr.arpa");
+
+    // the name server's answer repeats the question, and points back into it for the answer's name
+    let mut response = query.clone();
+    response[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+    response[6..8].copy_from_slice(&1u16.to_be_bytes());
+    response.extend([0xc0, 12]);
+    response.extend(TYPE_PTR.to_be_bytes());
+    response.extend(CLASS_IN.to_be_bytes());
+    response.extend(3600u32.to_be_bytes());
+    let name = b"\x04peer\x07example\x03com\x00";
+    response.extend((name.len() as u16).to_be_bytes());
+    response.extend(name);
+    assert_eq!(
+        parse_ptr_response(7, &response).unwrap().as_deref(),
+        Some("peer.example.com")
+    );
+    assert!(parse_ptr_response(8, &response).is_err());
+
+    response[3] = 0x83;
+    assert_eq!(parse_ptr_response(7, &response).unwrap(), None);
+
+    assert_eq!(
+        nameserver("# comment\nsearch lan\nnameserver 10.0.0.1\nnameserver 10.0.0.2\n"),
+        Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))
+    );
+}
+
+#[test]
+fn geoip_ranges() {
+    let db = GeoIp::parse(
+        "\"16777216\",\"16777471\",\"AU\"\n\
+         # comment\n\
+         2.0.0.0,2.15.255.255,\"FR\",\"Paris\"\n",
+    )
+    .unwrap();
+    assert_eq!(db.lookup(Ipv4Addr::new(1, 0, 0, 7)), Some("AU"));
+    assert_eq!(db.lookup(Ipv4Addr::new(1, 0, 1, 0)), None);
+    assert_eq!(db.lookup(Ipv4Addr::new(2, 3, 4, 5)), Some("FR,Paris"));
+    assert_eq!(db.lookup(Ipv4Addr::new(0, 0, 0, 1)), None);
+    assert!(GeoIp::parse("1.2.3.4,nope,US").is_err());
+}
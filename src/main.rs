@@ -1,21 +1,52 @@
 use anyhow::Context;
+use bittorrent_starter_rust::bencode;
+use bittorrent_starter_rust::client::Client;
+use bittorrent_starter_rust::compare;
+use bittorrent_starter_rust::complete::OnComplete;
+use bittorrent_starter_rust::download::DownloadConfig;
+use bittorrent_starter_rust::edit::TorrentEditor;
+use bittorrent_starter_rust::history::History;
+use bittorrent_starter_rust::lookup::{self, GeoIp};
+use bittorrent_starter_rust::metrics;
+use bittorrent_starter_rust::proxy::{Proxy, Unavailable};
+use bittorrent_starter_rust::ratelimit::{InFlightLimit, RateLimit, RateLimits};
+use bittorrent_starter_rust::rpc::Daemon;
+use bittorrent_starter_rust::seed::{self, Seed};
+use bittorrent_starter_rust::stats::{Stats, Totals};
+use bittorrent_starter_rust::storage::{FsyncPolicy, Layout};
+use bittorrent_starter_rust::swarm::SwarmState;
 use bittorrent_starter_rust::torrent::{self, Torrent};
+use bittorrent_starter_rust::trace;
 use bittorrent_starter_rust::tracker::*;
-use bittorrent_starter_rust::{peer::*, BLOCK_MAX};
+use bittorrent_starter_rust::{peer::*, BLOCK_MAX, PORT};
 use clap::{Parser, Subcommand};
-use futures_util::{SinkExt, StreamExt};
-use serde_bencode;
-use serde_json;
+use futures_util::StreamExt;
 use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::io::Write;
 use std::net::SocketAddrV4;
-use std::path::PathBuf;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[command(subcommand)]
     command: Command,
+    /// Log every message exchanged with peers to stderr, or, with `--trace-wire=FILE`, as JSON
+    /// lines to FILE.
+    #[arg(long, global = true, value_name = "FILE", num_args = 0..=1, require_equals = true)]
+    trace_wire: Option<Option<PathBuf>>,
+    /// Keep what we've uploaded and downloaded of each torrent across runs in this directory, and
+    /// report those totals to trackers.
+    #[arg(long, global = true, value_name = "DIR")]
+    state_dir: Option<PathBuf>,
+    /// Download and seed through this SOCKS5 proxy only (such as Tor's, at
+    /// socks5://127.0.0.1:9050), and turn off everything that uses UDP.
+    #[arg(long, global = true, value_name = "URL")]
+    proxy: Option<Proxy>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -24,63 +55,368 @@ enum Command {
     Decode {
         value: String,
     },
+    /// Encode a JSON value as bencode, writing the raw bytes to stdout.
+    ///
+    /// Within JSON strings, `\xNN` stands for the byte 0xNN, and `\\` for a backslash.
+    Encode {
+        value: String,
+    },
     Info {
         torrent: PathBuf,
+        /// Refuse torrent files that aren't encoded canonically, as some encoders get wrong.
+        #[arg(long)]
+        strict: bool,
+    },
+    /// Write out the piece hashes of a torrent, one hex-encoded hash per line.
+    Hashes {
+        torrent: PathBuf,
+        /// Write the raw 20-byte hashes back to back instead.
+        #[arg(long)]
+        binary: bool,
     },
+    /// Show how the info dictionaries of two torrents differ, such as a re-created torrent and
+    /// its original. Exits with an error if they do.
+    Compare {
+        a: PathBuf,
+        b: PathBuf,
+    },
+    /// List the peers the tracker knows about.
     Peers {
         torrent: PathBuf,
+        /// Only list each peer once, even if the tracker repeats it.
+        #[arg(long)]
+        unique: bool,
+        /// List peers in address order rather than in the order the tracker gave them.
+        #[arg(long)]
+        sort: bool,
+        /// Also show the host name of each peer.
+        #[arg(long)]
+        resolve: bool,
+        /// Also show the location of each peer, from a CSV database of `first,last,location` IP
+        /// ranges.
+        #[arg(long)]
+        geoip: Option<PathBuf>,
     },
     Handshake {
         torrent: PathBuf,
         peer: String,
     },
+    /// Change the trackers, web seeds, comment, or private flag of a torrent file.
+    ///
+    /// Everything but the private flag leaves the info hash as it was.
+    #[command(rename_all = "kebab-case", alias = "edit_torrent")]
+    EditTorrent {
+        torrent: PathBuf,
+        /// Where to write the edited torrent file, instead of over the original.
+        #[arg(short)]
+        output: Option<PathBuf>,
+        /// The URL of the tracker.
+        #[arg(long)]
+        announce: Option<String>,
+        /// A tier of tracker URLs, separated by commas. Replaces all existing tiers; give it
+        /// several times for several tiers.
+        #[arg(long)]
+        tier: Vec<String>,
+        /// Remove all tiers of trackers.
+        #[arg(long, conflicts_with = "tier")]
+        clear_tiers: bool,
+        /// A web seed URL. Replaces all existing web seeds; give it several times for several.
+        #[arg(long)]
+        web_seed: Vec<String>,
+        /// Remove all web seeds.
+        #[arg(long, conflicts_with = "web_seed")]
+        clear_web_seeds: bool,
+        /// The comment to set, or an empty one to remove it.
+        #[arg(long)]
+        comment: Option<String>,
+        /// Whether the torrent is private. Changing this changes the info hash.
+        #[arg(long)]
+        private: Option<bool>,
+    },
     DownloadPiece {
         #[arg(short)]
         output: PathBuf,
         torrent: PathBuf,
         piece: usize,
     },
+    /// Download one or more torrents.
+    ///
+    /// With a single torrent file, `output` is the file to write. With several (or a directory of
+    /// them), or when `--output-name` or `--output-dir` is given, `output` is the directory to
+    /// write each torrent's files into.
+    #[command(rename_all = "kebab-case")]
     Download {
         #[arg(short)]
         output: PathBuf,
+        #[arg(required = true)]
+        torrents: Vec<PathBuf>,
+        /// Save the file of a single-file torrent under this name rather than the torrent's.
+        #[arg(long)]
+        output_name: Option<String>,
+        /// Put the files of a multi-file torrent in this directory (relative to `output`) rather
+        /// than one named after the torrent. An empty path puts them straight into `output`.
+        #[arg(long)]
+        output_dir: Option<PathBuf>,
+        /// Read each piece back after writing it, and check that it still hashes correctly.
+        #[arg(long)]
+        verify_writes: bool,
+        /// When to sync what's downloaded to disk: `never` (leave it to the operating system),
+        /// `periodic`, or after every `piece`. Unless it's `never`, the files written out are
+        /// synced before the download counts as done.
+        #[arg(long, default_value = "periodic")]
+        fsync: FsyncPolicy,
+        /// The user agent to announce to the tracker with.
+        #[arg(long)]
+        user_agent: Option<String>,
+        /// A PEM file of extra root certificates to trust for an HTTPS tracker.
+        #[arg(long)]
+        tracker_ca: Option<PathBuf>,
+        /// Limit the combined download rate of all torrents to this many KiB/s.
+        #[arg(long)]
+        max_download_rate: Option<usize>,
+        /// Limit the combined upload rate of all torrents to this many KiB/s.
+        #[arg(long)]
+        max_upload_rate: Option<usize>,
+        /// Limit how many KiB of blocks all torrents together may have requested but not yet
+        /// received.
+        #[arg(long)]
+        max_in_flight: Option<usize>,
+        /// Use a different peer id for every torrent, so that trackers and peers can't tell
+        /// they're all coming from us.
+        #[arg(long)]
+        privacy: bool,
+        /// The address to tell the tracker peers should connect to, instead of the one it sees.
+        #[arg(long)]
+        announce_ip: Option<std::net::IpAddr>,
+        /// Our IPv4 address, for the tracker to list us under (detected if publicly routable).
+        #[arg(long)]
+        announce_ipv4: Option<std::net::Ipv4Addr>,
+        /// Our IPv6 address, for the tracker to list us under (detected if publicly routable).
+        #[arg(long)]
+        announce_ipv6: Option<std::net::Ipv6Addr>,
+        /// Move the files of each completed torrent into this directory.
+        #[arg(long)]
+        move_completed: Option<PathBuf>,
+        /// Run this shell command for each completed torrent, with `TORRENT_NAME`,
+        /// `TORRENT_INFO_HASH` and `TORRENT_PATH` set.
+        #[arg(long)]
+        exec_on_complete: Option<String>,
+        /// Download at most this many torrents at once, and queue up the rest.
+        #[arg(long)]
+        max_active: Option<usize>,
+        /// Fetch the blocks of each piece from at most this many peers at once.
+        #[arg(long)]
+        piece_affinity: Option<usize>,
+        /// Ask peers for blocks of this many KiB at a time (at most 16).
+        #[arg(long, default_value_t = 16)]
+        block_size: usize,
+        /// Download from at most this many peers at once.
+        #[arg(long, default_value_t = 50)]
+        max_peers: usize,
+        /// Keep each piece that fails its hash check in this directory, along with which peer
+        /// sent each of its blocks.
+        #[arg(long)]
+        dump_bad_pieces: Option<PathBuf>,
+    },
+    /// Watch a directory for new torrent files, and download each one as it appears.
+    ///
+    /// Downloaded files are written to `output`, and the torrent files of completed downloads are
+    /// moved into a `done` directory inside the watched directory.
+    Watch {
+        dir: PathBuf,
+        #[arg(short)]
+        output: PathBuf,
+        /// How often to look for new torrent files, in seconds.
+        #[arg(long, default_value_t = 5)]
+        interval: u64,
+        /// Move the files of each completed torrent into this directory.
+        #[arg(long)]
+        move_completed: Option<PathBuf>,
+        /// Run this shell command for each completed torrent, with `TORRENT_NAME`,
+        /// `TORRENT_INFO_HASH` and `TORRENT_PATH` set.
+        #[arg(long)]
+        exec_on_complete: Option<String>,
+    },
+    /// Run in the background, taking commands over a local HTTP API.
+    ///
+    /// See the documentation of the `rpc` module for the endpoints.
+    Daemon {
+        /// Where to write the files of completed torrents.
+        #[arg(short)]
+        output: PathBuf,
+        /// The address to serve the API on.
+        #[arg(long, default_value = "127.0.0.1:6880")]
+        listen: std::net::SocketAddr,
+        /// Torrents to start downloading right away.
+        torrents: Vec<PathBuf>,
+        /// Download at most this many torrents at once, and queue up the rest.
+        #[arg(long)]
+        max_active: Option<usize>,
+    },
+    /// Verify local data against one or more torrents, and then seed them until stopped.
+    ///
+    /// The data for each torrent is expected where `download` with several torrents would have put
+    /// it in `dir`.
+    #[command(rename_all = "kebab-case")]
+    Seed {
+        #[arg(short)]
+        dir: PathBuf,
+        #[arg(required = true)]
+        torrents: Vec<PathBuf>,
+        /// Limit the combined upload rate of all torrents to this many KiB/s.
+        #[arg(long)]
+        max_upload_rate: Option<usize>,
+        /// How often to report upload stats, in seconds.
+        #[arg(long, default_value_t = 30)]
+        report_interval: u64,
+        /// Serve Prometheus metrics for every torrent at `/metrics` on this address.
+        #[arg(long)]
+        metrics: Option<std::net::SocketAddr>,
+        /// Use a different peer id for every torrent, so that trackers and peers can't tell
+        /// they're all coming from us.
+        #[arg(long)]
+        privacy: bool,
+        /// The address to tell the tracker peers should connect to, instead of the one it sees.
+        #[arg(long)]
+        announce_ip: Option<std::net::IpAddr>,
+        /// Our IPv4 address, for the tracker to list us under (detected if publicly routable).
+        #[arg(long)]
+        announce_ipv4: Option<std::net::Ipv4Addr>,
+        /// Our IPv6 address, for the tracker to list us under (detected if publicly routable).
+        #[arg(long)]
+        announce_ipv6: Option<std::net::Ipv6Addr>,
+        /// Stop seeding a torrent once we've uploaded this many times its size (or what we
+        /// downloaded of it, if more).
+        #[arg(long)]
+        ratio_limit: Option<f64>,
+        /// Stop seeding a torrent after this many minutes.
+        #[arg(long)]
+        seed_time_limit: Option<u64>,
+        /// Also seed torrents we only downloaded some of the files of, as a partial seed.
+        #[arg(long)]
+        partial: bool,
+    },
+    /// Show which pieces the peers in the swarm have, and how they're treating us.
+    Swarm {
         torrent: PathBuf,
+        /// Keep watching the swarm, refreshing every this many seconds.
+        #[arg(long)]
+        watch: Option<u64>,
+    },
+    /// Tools for looking into why a download isn't going the way it should.
+    Debug {
+        #[command(subcommand)]
+        command: DebugCommand,
     },
 }
 
+#[derive(Subcommand, Debug)]
+enum DebugCommand {
+    /// Connect to the peers the tracker knows about, without downloading anything, and print how
+    /// many of them have each piece, as one `piece<TAB>peers` line per piece.
+    ///
+    /// Pieces none of them have are listed on stderr, since a download stalls on those until a
+    /// peer that has them joins.
+    Availability { torrent: PathBuf },
+}
+
 // Usage: your_bittorrent.sh decode "<encoded_value>"
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    if let Some(path) = &args.trace_wire {
+        trace::enable(path.as_deref()).context("enable wire tracing")?;
+    }
+    // only opened by the commands that transfer anything
+    let history = || -> anyhow::Result<Option<Arc<History>>> {
+        match &args.state_dir {
+            Some(dir) => Ok(Some(Arc::new(History::open(dir)?))),
+            None => Ok(None),
+        }
+    };
+    // likewise, and tells the user what they're doing without
+    let proxy = || {
+        if let Some(proxy) = &args.proxy {
+            eprintln!("connecting through {proxy}, so:");
+            for unavailable in Unavailable::ALL {
+                eprintln!("  {unavailable}");
+            }
+        }
+        args.proxy.clone()
+    };
 
     match args.command {
         Command::Decode { value } => {
             let v = decode_bencoded_value(&value).0;
             println!("{v}");
         }
-        Command::Info { torrent } => {
+        Command::Encode { value } => {
+            let value: serde_json::Value =
+                serde_json::from_str(&value).context("parse JSON value")?;
+            let encoded = bencode::to_bytes(&value)?;
+            std::io::stdout()
+                .write_all(&encoded)
+                .context("write bencoded value")?;
+        }
+        Command::Info { torrent, strict } => {
             let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+            if strict {
+                bencode::check(&dot_torrent, bencode::Mode::Strict)
+                    .context("torrent file is not canonical bencode")?;
+            }
+            let t = Torrent::from_bytes(&dot_torrent)?;
             // eprintln!("{t:?}");
             println!("Tracker URL: {}", t.announce);
-            let length = if let torrent::Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                todo!();
-            };
-            println!("Length: {length}");
+            println!("Length: {}", t.length());
             let info_hash = t.info_hash();
-            println!("Info Hash: {}", hex::encode(&info_hash));
+            println!("Info Hash: {}", hex::encode(info_hash));
             println!("Piece Length: {}", t.info.plength);
+            println!("Piece Count: {}", t.info.pieces.0.len());
             println!("Piece Hashes:");
-            for hash in t.info.pieces.0 {
-                println!("{}", hex::encode(&hash));
+            for hash in &t.info.pieces.0 {
+                println!("{}", hex::encode(hash));
+            }
+            println!("Magnet Link: {}", t.to_magnet());
+            if let torrent::Keys::MultiFile { .. } = t.info.keys {
+                print!("Files:\n{}", t.file_tree());
+            }
+        }
+        Command::Hashes { torrent, binary } => {
+            let t = Torrent::read(&torrent).await?;
+            let mut stdout = std::io::stdout().lock();
+            for hash in &t.info.pieces.0 {
+                if binary {
+                    stdout.write_all(hash)
+                } else {
+                    writeln!(stdout, "{}", hex::encode(hash))
+                }
+                .context("write piece hash")?;
+            }
+            stdout.flush().context("write piece hashes")?;
+        }
+        Command::Compare { a, b } => {
+            let (ta, tb) = (Torrent::read(&a).await?, Torrent::read(&b).await?);
+            let differences = compare::compare(&ta, &tb);
+            if differences.is_empty() {
+                println!("same info hash: {}", hex::encode(ta.info_hash()));
+            } else {
+                println!("{}: {}", a.display(), hex::encode(ta.info_hash()));
+                println!("{}: {}", b.display(), hex::encode(tb.info_hash()));
+                for difference in &differences {
+                    println!("{difference}");
+                }
+                anyhow::bail!("{} and {} differ", a.display(), b.display());
             }
         }
-        Command::Peers { torrent } => {
+        Command::Peers {
+            torrent,
+            unique,
+            sort,
+            resolve,
+            geoip,
+        } => {
             let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+            let t = Torrent::from_bytes(&dot_torrent)?;
             let length = if let torrent::Keys::SingleFile { length } = t.info.keys {
                 length
             } else {
@@ -89,57 +425,134 @@ async fn main() -> anyhow::Result<()> {
 
             let info_hash = t.info_hash();
             let request = TrackerRequest {
-                peer_id: String::from("00112233445566778899"),
+                peer_id: *b"00112233445566778899",
+                key: None,
                 port: 6881,
                 uploaded: 0,
                 downloaded: 0,
                 left: length,
                 compact: 1,
+                event: None,
+                ip: None,
+                ipv4: None,
+                ipv6: None,
             };
 
-            let url_params =
-                serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                url_params,
-                &urlencode(&info_hash)
-            );
+            let tracker_url = request.url(&t.announce, &info_hash)?;
             let response = reqwest::get(tracker_url).await.context("query tracker")?;
             let response = response.bytes().await.context("fetch tracker response")?;
-            let response: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("parse tracker response")?;
-            for peer in &response.peers.0 {
-                println!("{}:{}", peer.ip(), peer.port());
+            let response = TrackerResponse::from_bytes(&response)?;
+            if let Some(warning) = &response.warning_message {
+                eprintln!("tracker warns: {warning}");
+            }
+            let mut peers = response.peers.0;
+            if sort {
+                peers.sort();
+            }
+            if unique {
+                let mut seen = HashSet::new();
+                peers.retain(|peer| seen.insert(*peer));
+            }
+            let geoip = geoip.as_deref().map(GeoIp::load).transpose()?;
+            let names: Vec<_> = if resolve {
+                futures_util::stream::iter(&peers)
+                    .map(|peer| async move {
+                        match lookup::reverse_dns(*peer.ip()).await {
+                            Ok(name) => name,
+                            Err(e) => {
+                                eprintln!("failed to resolve {}: {e:#}", peer.ip());
+                                None
+                            }
+                        }
+                    })
+                    .buffered(16)
+                    .collect()
+                    .await
+            } else {
+                vec![None; peers.len()]
+            };
+            for (peer, name) in peers.iter().zip(names) {
+                let mut line = format!("{}:{}", peer.ip(), peer.port());
+                if resolve {
+                    line.push('\t');
+                    line.push_str(name.as_deref().unwrap_or("-"));
+                }
+                if let Some(geoip) = &geoip {
+                    line.push('\t');
+                    line.push_str(geoip.lookup(*peer.ip()).unwrap_or("-"));
+                }
+                println!("{line}");
             }
         }
         Command::Handshake { torrent, peer } => {
             let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+            let t = Torrent::from_bytes(&dot_torrent)?;
 
             let info_hash = t.info_hash();
             let peer = peer.parse::<SocketAddrV4>().context("parse peer address")?;
-            let mut peer = tokio::net::TcpStream::connect(peer)
+            let probe = probe(peer, info_hash, t.info.pieces.0.len()).await?;
+            println!("Peer ID: {}", hex::encode(probe.peer_id));
+            if let Some(client) = probe.client() {
+                eprintln!("Client: {client}");
+            }
+            match &probe.extensions {
+                Some(extensions) => {
+                    let supported: Vec<_> = extensions.supported().collect();
+                    eprintln!("Extensions: {}", supported.join(", "));
+                }
+                None if probe.extension_protocol => {
+                    eprintln!("Extensions: supported, but no extension handshake received");
+                }
+                None => eprintln!("Extensions: not supported"),
+            }
+            eprintln!(
+                "Has: {:.1}% of pieces",
+                probe.availability(t.info.pieces.0.len()) * 100.0
+            );
+        }
+        Command::EditTorrent {
+            torrent,
+            output,
+            announce,
+            tier,
+            clear_tiers,
+            web_seed,
+            clear_web_seeds,
+            comment,
+            private,
+        } => {
+            let mut editor = TorrentEditor::read(&torrent).await?;
+            let info_hash = editor.info_hash();
+            if let Some(announce) = announce {
+                editor.set_announce(&announce);
+            }
+            if !tier.is_empty() || clear_tiers {
+                let tiers: Vec<Vec<String>> = tier
+                    .iter()
+                    .map(|tier| tier.split(',').map(str::to_string).collect())
+                    .collect();
+                editor.set_announce_list(&tiers);
+            }
+            if !web_seed.is_empty() || clear_web_seeds {
+                editor.set_web_seeds(&web_seed);
+            }
+            if let Some(comment) = comment {
+                editor.set_comment((!comment.is_empty()).then_some(&*comment));
+            }
+            if let Some(private) = private {
+                editor.set_private(private)?;
+            }
+            let output = output.unwrap_or(torrent);
+            tokio::fs::write(&output, editor.to_bytes()?)
                 .await
-                .context("connect to peer")?;
-            let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-            {
-                let handshake_bytes =
-                    &mut handshake as *mut Handshake as *mut [u8; std::mem::size_of::<Handshake>()];
-                // Safety: Handshake is a POD with repr(c) and repr(packed)
-                let handshake_bytes: &mut [u8; std::mem::size_of::<Handshake>()] =
-                    unsafe { &mut *handshake_bytes };
-                peer.write_all(handshake_bytes)
-                    .await
-                    .context("write handshake")?;
-                peer.read_exact(handshake_bytes)
-                    .await
-                    .context("read handshake")?;
+                .with_context(|| format!("write {}", output.display()))?;
+            if editor.info_hash() != info_hash {
+                eprintln!(
+                    "info hash changed from {} to {}",
+                    hex::encode(info_hash),
+                    hex::encode(editor.info_hash())
+                );
             }
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
-            println!("Peer ID: {}", hex::encode(&handshake.peer_id));
         }
         Command::DownloadPiece {
             output,
@@ -147,8 +560,8 @@ async fn main() -> anyhow::Result<()> {
             piece: piece_i,
         } => {
             let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+            let t = Torrent::from_bytes(&dot_torrent)?;
+            t.validate().context("invalid torrent")?;
             let length = if let torrent::Keys::SingleFile { length } = t.info.keys {
                 length
             } else {
@@ -158,50 +571,29 @@ async fn main() -> anyhow::Result<()> {
 
             let info_hash = t.info_hash();
             let request = TrackerRequest {
-                peer_id: String::from("00112233445566778899"),
+                peer_id: *b"00112233445566778899",
+                key: None,
                 port: 6881,
                 uploaded: 0,
                 downloaded: 0,
                 left: length,
                 compact: 1,
+                event: None,
+                ip: None,
+                ipv4: None,
+                ipv6: None,
             };
 
-            let url_params =
-                serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                url_params,
-                &urlencode(&info_hash)
-            );
+            let tracker_url = request.url(&t.announce, &info_hash)?;
             let response = reqwest::get(tracker_url).await.context("query tracker")?;
             let response = response.bytes().await.context("fetch tracker response")?;
-            let tracker_info: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("parse tracker response")?;
+            let tracker_info = TrackerResponse::from_bytes(&response)?;
 
-            let peer = &tracker_info.peers.0[0];
-            let mut peer = tokio::net::TcpStream::connect(peer)
-                .await
-                .context("connect to peer")?;
-            let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-            {
-                let handshake_bytes = handshake.as_bytes_mut();
-                peer.write_all(handshake_bytes)
-                    .await
-                    .context("write handshake")?;
-                peer.read_exact(handshake_bytes)
-                    .await
-                    .context("read handshake")?;
-            }
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
-
-            let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
-            let bitfield = peer
-                .next()
-                .await
-                .expect("peer always sends a bitfields")
-                .context("peer message was invalid")?;
+            let handshake = Handshake::new(info_hash, *b"00112233445566778899");
+            let mut peer =
+                Connection::connect(tracker_info.peers.0[0], handshake, t.info.pieces.0.len())
+                    .await?;
+            let bitfield = peer.recv().await?;
             assert_eq!(bitfield.tag, MessageTag::Bitfield);
             // NOTE: we assume that the bitfield covers all pieces
 
@@ -209,16 +601,11 @@ async fn main() -> anyhow::Result<()> {
                 tag: MessageTag::Interested,
                 payload: Vec::new(),
             })
-            .await
-            .context("send interested message")?;
+            .await?;
 
-            let unchoke = peer
-                .next()
-                .await
-                .expect("peer always sends an unchoke")
-                .context("peer message was invalid")?;
+            let unchoke = peer.recv().await?;
             assert_eq!(unchoke.tag, MessageTag::Unchoke);
-            assert!(unchoke.payload.is_empty());
+            assert!(!peer.is_choked());
 
             let piece_hash = &t.info.pieces.0[piece_i];
             let piece_size = if piece_i == t.info.pieces.0.len() - 1 {
@@ -231,8 +618,7 @@ async fn main() -> anyhow::Result<()> {
             } else {
                 t.info.plength
             };
-            // the + (BLOCK_MAX - 1) rounds up
-            let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
+            let nblocks = piece_size.div_ceil(BLOCK_MAX);
             let mut all_blocks = Vec::with_capacity(piece_size);
             for block in 0..nblocks {
                 let block_size = if block == nblocks - 1 {
@@ -245,24 +631,15 @@ async fn main() -> anyhow::Result<()> {
                 } else {
                     BLOCK_MAX
                 };
-                let mut request = Request::new(
+                peer.request_block(
                     piece_i as u32,
                     (block * BLOCK_MAX) as u32,
                     block_size as u32,
-                );
-                let request_bytes = Vec::from(request.as_bytes_mut());
-                peer.send(Message {
-                    tag: MessageTag::Request,
-                    payload: request_bytes,
-                })
+                )
                 .await
                 .with_context(|| format!("send request for block {block}"))?;
 
-                let piece = peer
-                    .next()
-                    .await
-                    .expect("peer always sends a piece")
-                    .context("peer message was invalid")?;
+                let piece = peer.recv().await?;
                 assert_eq!(piece.tag, MessageTag::Piece);
                 assert!(!piece.payload.is_empty());
 
@@ -277,10 +654,7 @@ async fn main() -> anyhow::Result<()> {
 
             let mut hasher = Sha1::new();
             hasher.update(&all_blocks);
-            let hash: [u8; 20] = hasher
-                .finalize()
-                .try_into()
-                .expect("GenericArray<_, 20> == [_; 20]");
+            let hash: [u8; 20] = hasher.finalize().into();
             assert_eq!(&hash, piece_hash);
 
             tokio::fs::write(&output, all_blocks)
@@ -288,22 +662,666 @@ async fn main() -> anyhow::Result<()> {
                 .context("write out downloaded piece")?;
             println!("Piece {piece_i} downloaded to {}.", output.display());
         }
-        Command::Download { output, torrent } => {
-            let torrent = Torrent::read(torrent).await?;
-            torrent.print_tree();
-            // torrent.download_all_to_file(output).await?;
-            let files = torrent.download_all().await?;
-            tokio::fs::write(
-                output,
-                files.into_iter().next().expect("always one file").bytes(),
+        Command::Download {
+            output,
+            torrents,
+            output_name,
+            output_dir,
+            verify_writes,
+            fsync,
+            user_agent,
+            tracker_ca,
+            max_download_rate,
+            max_upload_rate,
+            max_in_flight,
+            privacy,
+            announce_ip,
+            announce_ipv4,
+            announce_ipv6,
+            move_completed,
+            exec_on_complete,
+            max_active,
+            piece_affinity,
+            block_size,
+            max_peers,
+            dump_bad_pieces,
+        } => {
+            let on_complete = OnComplete {
+                move_to: move_completed,
+                exec: exec_on_complete,
+            };
+            let root_certificates = match tracker_ca {
+                Some(path) => vec![std::fs::read(path).context("read tracker CA certificates")?],
+                None => Vec::new(),
+            };
+            let layout = Layout {
+                file_name: output_name,
+                dir: output_dir,
+            };
+            let config = DownloadConfig {
+                verify_writes,
+                fsync,
+                layout: layout.clone(),
+                piece_affinity,
+                block_size: block_size * 1024,
+                max_peers,
+                hash_failure_dir: dump_bad_pieces,
+                tracker: TrackerConfig {
+                    user_agent,
+                    root_certificates,
+                    addrs: AnnounceAddrs {
+                        ip: announce_ip,
+                        ipv4: announce_ipv4,
+                        ipv6: announce_ipv6,
+                    },
+                    ..TrackerConfig::default()
+                },
+                privacy,
+                limits: Arc::new(RateLimits {
+                    download: max_download_rate.map(|kib| RateLimit::new(kib * 1024)),
+                    upload: max_upload_rate.map(|kib| RateLimit::new(kib * 1024)),
+                    in_flight: max_in_flight.map(|kib| InFlightLimit::new(kib * 1024)),
+                }),
+                history: history()?,
+                proxy: proxy(),
+                ..DownloadConfig::default()
+            };
+            let mut client = Client::new(config);
+            if let Some(max) = max_active {
+                client = client.with_max_active_downloads(max);
+            }
+
+            // a single torrent file is downloaded straight to `output`, but for anything more (or
+            // with a layout of its own), `output` is the directory to put each torrent's files in.
+            let single =
+                torrents.len() == 1 && !torrents[0].is_dir() && layout == Layout::default();
+            let mut paths = Vec::new();
+            for path in torrents {
+                if path.is_dir() {
+                    let mut found = Vec::new();
+                    for entry in std::fs::read_dir(&path)
+                        .with_context(|| format!("list torrents in {}", path.display()))?
+                    {
+                        let entry_path = entry.context("read directory entry")?.path();
+                        if entry_path.extension().is_some_and(|ext| ext == "torrent") {
+                            found.push(entry_path);
+                        }
+                    }
+                    found.sort();
+                    paths.extend(found);
+                } else {
+                    paths.push(path);
+                }
+            }
+            anyhow::ensure!(!paths.is_empty(), "no torrent files to download");
+            anyhow::ensure!(
+                layout.file_name.is_none() || paths.len() == 1,
+                "--output-name only works with a single torrent"
+            );
+
+            let mut downloads = Vec::new();
+            for path in &paths {
+                let torrent = Torrent::read(path).await?;
+                torrent.print_tree();
+                let handle = client.add(&torrent);
+                downloads.push((torrent, handle));
+            }
+
+            let progress = (!single).then(|| {
+                let rows: Vec<_> = downloads
+                    .iter()
+                    .map(|(t, handle)| {
+                        (
+                            t.info.display_name().into_owned(),
+                            handle.length(),
+                            handle.stats(),
+                        )
+                    })
+                    .collect();
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(Duration::from_secs(1));
+                    loop {
+                        interval.tick().await;
+                        print_progress(&rows);
+                    }
+                })
+            });
+
+            let results = {
+                let waits = futures_util::future::join_all(
+                    downloads.iter_mut().map(|(_, handle)| handle.wait()),
+                );
+                tokio::pin!(waits);
+                tokio::select! {
+                    results = &mut waits => results,
+                    _ = shutdown_signal() => {
+                        eprintln!("stopping downloads...");
+                        client.stop();
+                        waits.await
+                    }
+                }
+            };
+            if let Some(progress) = progress {
+                progress.abort();
+            }
+
+            let mut failed = 0;
+            for ((torrent, _), files) in downloads.iter().zip(results) {
+                let files = match files {
+                    Ok(files) => files,
+                    Err(e) => {
+                        eprintln!("failed to download {}: {e:?}", torrent.info.display_name());
+                        failed += 1;
+                        continue;
+                    }
+                };
+                let written = if single {
+                    let mut out = tokio::fs::File::create(&output)
+                        .await
+                        .with_context(|| format!("create {}", output.display()))?;
+                    files
+                        .into_iter()
+                        .next()
+                        .expect("always one file")
+                        .write_to(&mut out)
+                        .await
+                        .with_context(|| format!("write {}", output.display()))?;
+                    if fsync != FsyncPolicy::Never {
+                        out.sync_data()
+                            .await
+                            .with_context(|| format!("sync {}", output.display()))?;
+                    }
+                    output.clone()
+                } else {
+                    files.write_to_dir(&output).await?;
+                    files.root(&output)
+                };
+                if !files.is_complete() {
+                    eprintln!(
+                        "partial download of {}: {} of {} pieces verified (missing pieces are zeroed)",
+                        torrent.info.display_name(),
+                        files.verified_pieces(),
+                        files.total_pieces()
+                    );
+                } else if let Err(e) = on_complete.run(torrent, &written).await {
+                    eprintln!("failed to finish {}: {e:?}", torrent.info.display_name());
+                    failed += 1;
+                }
+            }
+            if let Some(history) = &client.config().history {
+                print_all_time(history);
+            }
+            anyhow::ensure!(failed == 0, "{failed} of {} downloads failed", paths.len());
+        }
+        Command::Watch {
+            dir,
+            output,
+            interval,
+            move_completed,
+            exec_on_complete,
+        } => {
+            let on_complete = OnComplete {
+                move_to: move_completed,
+                exec: exec_on_complete,
+            };
+            let config = DownloadConfig {
+                history: history()?,
+                proxy: proxy(),
+                ..DownloadConfig::default()
+            };
+            watch(
+                &dir,
+                &output,
+                Duration::from_secs(interval),
+                config,
+                &on_complete,
             )
-            .await?;
+            .await?
+        }
+        Command::Daemon {
+            output,
+            listen,
+            torrents,
+            max_active,
+        } => {
+            let config = DownloadConfig {
+                history: history()?,
+                proxy: proxy(),
+                ..DownloadConfig::default()
+            };
+            let mut client = Client::new(config);
+            if let Some(max) = max_active {
+                client = client.with_max_active_downloads(max);
+            }
+            let daemon = Daemon::new(client, output);
+            for path in torrents {
+                let torrent = Torrent::read(&path).await?;
+                daemon.add(&torrent);
+            }
+            let listener = tokio::net::TcpListener::bind(listen)
+                .await
+                .with_context(|| format!("listen on {listen}"))?;
+            eprintln!("listening on http://{listen}");
+            tokio::select! {
+                result = Arc::clone(&daemon).serve(listener) => result?,
+                _ = shutdown_signal() => {
+                    eprintln!("stopping downloads...");
+                    daemon.stop();
+                    // give the downloads a moment to tell their trackers, and write out what
+                    // finished
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    daemon.reap().await;
+                }
+            }
+        }
+        Command::Seed {
+            dir,
+            torrents,
+            max_upload_rate,
+            report_interval,
+            metrics,
+            privacy,
+            announce_ip,
+            announce_ipv4,
+            announce_ipv6,
+            ratio_limit,
+            seed_time_limit,
+            partial,
+        } => {
+            let config = DownloadConfig {
+                seed_ratio_limit: ratio_limit,
+                seed_time_limit: seed_time_limit.map(|mins| Duration::from_secs(mins * 60)),
+                tracker: TrackerConfig {
+                    addrs: AnnounceAddrs {
+                        ip: announce_ip,
+                        ipv4: announce_ipv4,
+                        ipv6: announce_ipv6,
+                    },
+                    ..TrackerConfig::default()
+                },
+                privacy,
+                limits: Arc::new(RateLimits {
+                    upload: max_upload_rate.map(|kib| RateLimit::new(kib * 1024)),
+                    ..RateLimits::default()
+                }),
+                history: history()?,
+                proxy: proxy(),
+                ..DownloadConfig::default()
+            };
+            let mut seeds = Vec::new();
+            for path in torrents {
+                let torrent = Torrent::read(&path).await?;
+                let seed = if partial {
+                    Seed::verify_partial(&torrent, &dir, &config).await
+                } else {
+                    Seed::verify(&torrent, &dir, &config).await
+                };
+                let seed = seed.with_context(|| format!("verify data for {}", path.display()))?;
+                if seed.is_partial() {
+                    eprintln!("verified part of {}", torrent.info.display_name());
+                } else {
+                    eprintln!("verified {}", torrent.info.display_name());
+                }
+                seeds.push(Arc::new(seed));
+            }
+
+            let listener = tokio::net::TcpListener::bind(("0.0.0.0", PORT))
+                .await
+                .with_context(|| format!("listen on port {PORT}"))?;
+            eprintln!("seeding {} torrents on port {PORT}", seeds.len());
+            let report = tokio::spawn({
+                let seeds = seeds.clone();
+                async move {
+                    let mut interval = tokio::time::interval(Duration::from_secs(report_interval));
+                    interval.tick().await;
+                    loop {
+                        interval.tick().await;
+                        print_seeding(&seeds);
+                    }
+                }
+            });
+
+            let metrics = match metrics {
+                Some(addr) => {
+                    let listener = tokio::net::TcpListener::bind(addr)
+                        .await
+                        .with_context(|| format!("serve metrics on {addr}"))?;
+                    let seeds = seeds.clone();
+                    Some(tokio::spawn(metrics::serve(listener, move || {
+                        seeds
+                            .iter()
+                            .map(|seed| {
+                                (
+                                    seed.torrent().info.display_name().into_owned(),
+                                    seed.stats(),
+                                )
+                            })
+                            .collect()
+                    })))
+                }
+                None => None,
+            };
+
+            let stop = CancellationToken::new();
+            let seeding = seed::run(seeds.clone(), listener, &config, stop.clone());
+            tokio::pin!(seeding);
+            tokio::select! {
+                result = &mut seeding => result?,
+                _ = shutdown_signal() => {
+                    eprintln!("stopping seeding...");
+                    stop.cancel();
+                    seeding.await?;
+                }
+            }
+            report.abort();
+            if let Some(metrics) = metrics {
+                metrics.abort();
+            }
+            print_seeding(&seeds);
+            if let Some(history) = &config.history {
+                print_all_time(history);
+            }
+        }
+        Command::Swarm { torrent, watch } => {
+            let torrent = Torrent::read(torrent).await?;
+            match watch {
+                None => print_swarm(&torrent.swarm_state().await?),
+                Some(secs) => {
+                    torrent
+                        .watch_swarm(Duration::from_secs(secs), |state| {
+                            print_swarm(state);
+                            println!();
+                            true
+                        })
+                        .await?;
+                }
+            }
+        }
+        Command::Debug {
+            command: DebugCommand::Availability { torrent },
+        } => {
+            let torrent = Torrent::read(torrent).await?;
+            let state = torrent.swarm_state().await?;
+            let mut stdout = std::io::stdout().lock();
+            for (piece_i, peers) in state.availability().into_iter().enumerate() {
+                writeln!(stdout, "{piece_i}\t{peers}").context("write availability")?;
+            }
+            eprintln!("{} peers told us what they have", state.peers.len());
+            let missing = state.missing_pieces();
+            if missing.is_empty() {
+                eprintln!(
+                    "every piece is available (the rarest from {} peers), so a stall isn't for \
+                     lack of pieces",
+                    state.min_availability()
+                );
+            } else {
+                eprintln!(
+                    "{} of {} pieces are missing from the swarm, and a download can't finish \
+                     until a peer with them joins: {}",
+                    missing.len(),
+                    state.npieces,
+                    piece_ranges(&missing)
+                );
+            }
         }
     }
 
     Ok(())
 }
 
+/// Write out sorted piece indices as ranges, such as `0-3, 7, 9-10`.
+fn piece_ranges(pieces: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for &piece_i in pieces {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == piece_i => *last = piece_i,
+            _ => ranges.push((piece_i, piece_i)),
+        }
+    }
+    ranges
+        .into_iter()
+        .map(|(first, last)| {
+            if first == last {
+                first.to_string()
+            } else {
+                format!("{first}-{last}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Resolves once the user asks us to shut down, with either Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Download every torrent file that shows up in `dir` into `output` with `config` until we're told
+/// to stop, and run `on_complete` for each one that finishes.
+async fn watch(
+    dir: &Path,
+    output: &Path,
+    interval: Duration,
+    config: DownloadConfig,
+    on_complete: &OnComplete,
+) -> anyhow::Result<()> {
+    let done = dir.join("done");
+    tokio::fs::create_dir_all(&done)
+        .await
+        .with_context(|| format!("create {}", done.display()))?;
+    let client = Client::new(config);
+    let mut seen = HashSet::new();
+    let mut downloads = tokio::task::JoinSet::new();
+    let mut scan = tokio::time::interval(interval);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut stopping = false;
+    loop {
+        tokio::select! {
+            _ = scan.tick(), if !stopping => {
+                let mut entries = tokio::fs::read_dir(dir)
+                    .await
+                    .with_context(|| format!("list {}", dir.display()))?;
+                while let Some(entry) = entries.next_entry().await.context("read directory entry")? {
+                    let path = entry.path();
+                    if !path.is_file() || seen.contains(&path) {
+                        continue;
+                    }
+                    match path.extension().and_then(|ext| ext.to_str()) {
+                        Some("torrent") => {}
+                        Some("magnet") => {
+                            eprintln!(
+                                "skipping {}: magnet links aren't supported yet",
+                                path.display()
+                            );
+                            seen.insert(path);
+                            continue;
+                        }
+                        _ => continue,
+                    }
+                    seen.insert(path.clone());
+                    let torrent = match Torrent::read(&path).await {
+                        Ok(torrent) => torrent,
+                        Err(e) => {
+                            eprintln!("skipping {}: {e:?}", path.display());
+                            continue;
+                        }
+                    };
+                    eprintln!("starting download of {}", torrent.info.display_name());
+                    let mut handle = client.add(&torrent);
+                    downloads.spawn(async move {
+                        let files = handle.wait().await;
+                        (path, torrent, files)
+                    });
+                }
+            }
+            Some(finished) = downloads.join_next() => {
+                let (path, torrent, files) = finished.context("download task panicked")?;
+                let files = match files {
+                    Ok(files) => files,
+                    Err(e) => {
+                        // leave the torrent file where it is, but don't keep retrying it
+                        eprintln!("failed to download {}: {e:?}", torrent.info.display_name());
+                        continue;
+                    }
+                };
+                if !files.is_complete() {
+                    eprintln!(
+                        "stopped {} with {} of {} pieces verified",
+                        torrent.info.display_name(),
+                        files.verified_pieces(),
+                        files.total_pieces()
+                    );
+                    continue;
+                }
+                files.write_to_dir(output).await?;
+                if let Err(e) = on_complete
+                    .run(&torrent, &output.join(torrent.info.local_name()))
+                    .await
+                {
+                    eprintln!("failed to finish {}: {e:?}", torrent.info.display_name());
+                }
+                let file_name = path.file_name().expect("read_dir entries have a file name");
+                tokio::fs::rename(&path, done.join(file_name))
+                    .await
+                    .with_context(|| format!("move {} to {}", path.display(), done.display()))?;
+                seen.remove(&path);
+                eprintln!("finished {}", torrent.info.display_name());
+            }
+            _ = &mut shutdown, if !stopping => {
+                eprintln!("stopping downloads...");
+                client.stop();
+                stopping = true;
+            }
+            else => break,
+        }
+    }
+    Ok(())
+}
+
+fn print_progress(rows: &[(String, usize, Arc<Stats>)]) {
+    eprintln!(
+        "{:<32} {:>7} {:>12} {:>12} {:>6}",
+        "torrent", "done", "down", "up", "ratio"
+    );
+    for (name, length, stats) in rows {
+        let done = if *length == 0 {
+            1.0
+        } else {
+            1.0 - stats.left() as f64 / *length as f64
+        };
+        eprintln!(
+            "{:<32} {:>6.1}% {:>8.1} MiB {:>8.1} MiB {:>6}",
+            name,
+            done * 100.0,
+            stats.downloaded() as f64 / (1 << 20) as f64,
+            stats.uploaded() as f64 / (1 << 20) as f64,
+            ratio(stats.totals()),
+        );
+    }
+}
+
+fn print_seeding(seeds: &[Arc<Seed>]) {
+    eprintln!(
+        "{:<32} {:>5} {:>12} {:>6}",
+        "torrent", "peers", "up", "ratio"
+    );
+    for seed in seeds {
+        let peers = if seed.is_finished() {
+            "done".to_string()
+        } else {
+            seed.swarm_state().peers.len().to_string()
+        };
+        eprintln!(
+            "{:<32} {:>5} {:>8.1} MiB {:>6}",
+            seed.torrent().info.display_name(),
+            peers,
+            seed.stats().uploaded() as f64 / (1 << 20) as f64,
+            ratio(seed.stats().totals()),
+        );
+    }
+}
+
+/// What we've transferred of every torrent we've kept count of, over all runs.
+fn print_all_time(history: &History) {
+    let total = history.total();
+    eprintln!(
+        "all time: {:.1} MiB down, {:.1} MiB up, ratio {}",
+        total.downloaded as f64 / (1 << 20) as f64,
+        total.uploaded as f64 / (1 << 20) as f64,
+        ratio(total),
+    );
+}
+
+/// The ratio of `totals`, or a dash if nothing was downloaded.
+fn ratio(totals: Totals) -> String {
+    match totals.ratio() {
+        Some(ratio) => format!("{ratio:.2}"),
+        None => "-".to_string(),
+    }
+}
+
+fn print_swarm(state: &SwarmState) {
+    for peer in &state.peers {
+        let have = peer
+            .bitfield
+            .pieces()
+            .filter(|&p| p < state.npieces)
+            .count();
+        println!(
+            "{:<21} {:<24} {:>5.1}% {:<11} {:<9} {:>8.1} KiB/s down {:>8.1} KiB/s up {:>6} rtt {:>3} queued",
+            peer.addr,
+            peer.client_name().as_deref().unwrap_or("?"),
+            100.0 * have as f64 / state.npieces as f64,
+            if peer.is_seed(state.npieces) {
+                "seed"
+            } else if peer.upload_only {
+                "upload-only"
+            } else {
+                ""
+            },
+            if peer.choked { "choked" } else { "unchoked" },
+            peer.download_rate() / 1024.0,
+            peer.upload_rate() / 1024.0,
+            peer.rtt
+                .map_or_else(|| "?".to_string(), |rtt| format!("{}ms", rtt.as_millis())),
+            peer.pipeline_depth,
+        );
+    }
+    println!("availability: [{}]", state.availability_map(64));
+    let histogram = state
+        .availability_histogram()
+        .iter()
+        .enumerate()
+        .filter(|&(_, &pieces)| pieces != 0)
+        .map(|(peers, pieces)| format!("{peers}: {pieces}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    println!("pieces by number of peers: {histogram}");
+    println!(
+        "distributed copies: {:.3} ({})",
+        state.distributed_copies(),
+        if state.has_full_copy() {
+            "complete"
+        } else {
+            "incomplete: some pieces are missing from the swarm"
+        }
+    );
+}
+
 // serde_bencode -> serde_json::Value is borked, so keep our manual impl too
 fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
     match encoded_value.chars().next() {
@@ -360,12 +1378,3 @@ fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
 
     panic!("Unhandled encoded value: {}", encoded_value)
 }
-
-fn urlencode(t: &[u8; 20]) -> String {
-    let mut encoded = String::with_capacity(3 * t.len());
-    for &byte in t {
-        encoded.push('%');
-        encoded.push_str(&hex::encode(&[byte]));
-    }
-    encoded
-}
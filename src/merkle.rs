@@ -0,0 +1,238 @@
+//! The per-file merkle trees of BitTorrent v2 (BEP 52).
+//!
+//! v2 torrents hash each file on its own, as a binary tree of SHA-256 hashes whose leaves are the
+//! file's 16 KiB blocks. A torrent only carries each file's root (and the "piece layer", the
+//! hashes of whole pieces), but a peer can send the hashes along a block's path up the tree, and
+//! with those a single block can be checked the moment it arrives, rather than once the whole
+//! piece is in.
+//!
+//! The tree is padded out to a power of two leaves with hashes of all zeroes, so a tree (and every
+//! proof in it) has the same shape however long the last block of the file is.
+
+/// The size of the blocks at the leaves of the tree. The last one of a file may be shorter.
+pub const LEAF_SIZE: usize = 16 << 10;
+
+/// A file's complete merkle tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    /// Every layer of the tree, from the (padded) leaves up to the root.
+    layers: Vec<Vec<[u8; 32]>>,
+}
+
+impl MerkleTree {
+    /// Build the tree of a file's contents.
+    pub fn from_data(data: &[u8]) -> Self {
+        Self::from_leaves(data.chunks(LEAF_SIZE).map(sha256).collect())
+    }
+
+    /// Build the tree above the hashes of a file's blocks.
+    pub fn from_leaves(mut leaves: Vec<[u8; 32]>) -> Self {
+        leaves.resize(leaves.len().max(1).next_power_of_two(), [0; 32]);
+        let mut layers = vec![leaves];
+        while layers.last().expect("there's always a layer").len() > 1 {
+            let below = layers.last().expect("there's always a layer");
+            let layer = below
+                .chunks(2)
+                .map(|pair| parent(&pair[0], &pair[1]))
+                .collect();
+            layers.push(layer);
+        }
+        Self { layers }
+    }
+
+    /// The hash at the top of the tree, which is what a v2 torrent lists as the file's
+    /// `pieces root`.
+    pub fn root(&self) -> [u8; 32] {
+        self.layers.last().expect("there's always a layer")[0]
+    }
+
+    /// The hashes of the pieces of `plength` bytes (which must be a power of two multiple of
+    /// [`LEAF_SIZE`]) of the file of `file_len` bytes, which is what a v2 torrent lists in its
+    /// `piece layers`.
+    ///
+    /// That's one hash per piece the file covers: the hashes of the padding past the end of the
+    /// file are left out, though the last piece's hash does cover the padding leaves below it.
+    /// Returns `None` if the tree is smaller than a single piece.
+    pub fn piece_layer(&self, plength: usize, file_len: usize) -> Option<&[[u8; 32]]> {
+        let blocks_per_piece = plength / LEAF_SIZE;
+        if !blocks_per_piece.is_power_of_two() || blocks_per_piece * LEAF_SIZE != plength {
+            return None;
+        }
+        let height = blocks_per_piece.trailing_zeros() as usize;
+        let layer = self.layers.get(height)?;
+        Some(&layer[..file_len.div_ceil(plength).min(layer.len())])
+    }
+
+    /// The hashes needed to check leaf `leaf_i` against the root: its sibling, then its parent's
+    /// sibling, and so on up the tree.
+    pub fn proof(&self, leaf_i: usize) -> Option<Vec<[u8; 32]>> {
+        if leaf_i >= self.layers[0].len() {
+            return None;
+        }
+        let layers = &self.layers[..self.layers.len() - 1];
+        Some(
+            layers
+                .iter()
+                .enumerate()
+                .map(|(height, layer)| layer[(leaf_i >> height) ^ 1])
+                .collect(),
+        )
+    }
+}
+
+/// Check that `block` is leaf `leaf_i` of the (sub)tree whose top hash is `root`, using the
+/// sibling hashes in `proof` (as from [`MerkleTree::proof`]).
+///
+/// `root` is usually a hash from the piece layer, in which case `leaf_i` counts blocks from the
+/// start of that piece, and `proof` only goes as far up as the piece.
+pub fn verify_block(root: &[u8; 32], leaf_i: usize, block: &[u8], proof: &[[u8; 32]]) -> bool {
+    if block.len() > LEAF_SIZE || leaf_i >> proof.len() != 0 {
+        return false;
+    }
+    let mut hash = sha256(block);
+    for (height, sibling) in proof.iter().enumerate() {
+        hash = if (leaf_i >> height) & 1 == 0 {
+            parent(&hash, sibling)
+        } else {
+            parent(sibling, &hash)
+        };
+    }
+    hash == *root
+}
+
+fn parent(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut pair = [0; 64];
+    pair[..32].copy_from_slice(left);
+    pair[32..].copy_from_slice(right);
+    sha256(&pair)
+}
+
+/// SHA-256, which v2 torrents hash with.
+///
+/// The `sha1` crate is the only hashing crate we depend on, so this is a plain implementation of
+/// FIPS 180-4.
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    // the message, a 1 bit, zeroes up to 8 bytes short of a whole block, and the length in bits
+    let mut tail = Vec::with_capacity(128);
+    let whole = data.len() - data.len() % 64;
+    tail.extend(&data[whole..]);
+    tail.push(0x80);
+    while tail.len() % 64 != 56 {
+        tail.push(0);
+    }
+    tail.extend((data.len() as u64 * 8).to_be_bytes());
+
+    for block in data[..whole].chunks_exact(64).chain(tail.chunks_exact(64)) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().expect("4 bytes"));
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 32];
+    for (out, s) in digest.chunks_exact_mut(4).zip(state) {
+        out.copy_from_slice(&s.to_be_bytes());
+    }
+    digest
+}
+
+#[test]
+fn sha256_vectors() {
+    let cases: [(&[u8], &str); 3] = [
+        (
+            b"",
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        ),
+        (
+            b"abc",
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        ),
+        (
+            b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+        ),
+    ];
+    for (data, digest) in cases {
+        assert_eq!(hex::encode(sha256(data)), digest);
+    }
+    assert_eq!(
+        hex::encode(sha256(&[b'a'; 1_000_000])),
+        "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+    );
+}
+
+#[test]
+fn block_proofs() {
+    // five blocks, the last of them short, so the tree is padded out to eight leaves
+    let data: Vec<u8> = (0..4 * LEAF_SIZE + 100).map(|i| (i % 251) as u8).collect();
+    let tree = MerkleTree::from_data(&data);
+    let blocks: Vec<_> = data.chunks(LEAF_SIZE).collect();
+    let root = tree.root();
+    for (leaf_i, block) in blocks.iter().enumerate() {
+        let proof = tree.proof(leaf_i).expect("leaf is in the tree");
+        assert_eq!(proof.len(), 3);
+        assert!(verify_block(&root, leaf_i, block, &proof));
+        assert!(!verify_block(&root, leaf_i ^ 1, block, &proof));
+        let mut bad = block.to_vec();
+        bad[0] ^= 1;
+        assert!(!verify_block(&root, leaf_i, &bad, &proof));
+    }
+    assert!(tree.proof(8).is_none());
+
+    // pieces of two blocks: check a block against its piece's hash with a shorter proof
+    let layer = tree
+        .piece_layer(2 * LEAF_SIZE, data.len())
+        .expect("tree spans several pieces");
+    // three pieces, and not the fourth that's only padding
+    assert_eq!(layer.len(), 3);
+    let proof = tree.proof(3).unwrap();
+    assert!(verify_block(&layer[1], 1, blocks[3], &proof[..1]));
+    assert!(tree.piece_layer(3 * LEAF_SIZE, data.len()).is_none());
+    assert!(tree.piece_layer(16 * LEAF_SIZE, data.len()).is_none());
+
+    // a file of a single block is its own root
+    assert_eq!(MerkleTree::from_data(b"tiny").root(), sha256(b"tiny"));
+}
@@ -0,0 +1,190 @@
+//! Exposing the [`Stats`] of running torrents in the Prometheus text format, so that a
+//! long-running seeder or daemon can be monitored.
+//!
+//! Every metric is labelled with the name of the torrent it's for. The daemon serves them at
+//! `GET /metrics` next to its API; [`serve`] serves nothing else, for when there's no API.
+
+use crate::rpc;
+use crate::stats::Stats;
+use anyhow::Context;
+use std::fmt::Write;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+/// The content type of the Prometheus text format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+struct Metric {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&Stats) -> usize,
+}
+
+const METRICS: &[Metric] = &[
+    Metric {
+        name: "bittorrent_downloaded_bytes_total",
+        kind: "counter",
+        help: "Bytes of piece data received from peers.",
+        value: Stats::downloaded,
+    },
+    Metric {
+        name: "bittorrent_uploaded_bytes_total",
+        kind: "counter",
+        help: "Bytes of piece data sent to peers.",
+        value: Stats::uploaded,
+    },
+    Metric {
+        name: "bittorrent_left_bytes",
+        kind: "gauge",
+        help: "Bytes still needed to complete the torrent.",
+        value: Stats::left,
+    },
+    Metric {
+        name: "bittorrent_pieces_verified_total",
+        kind: "counter",
+        help: "Pieces that matched their hash.",
+        value: Stats::pieces_verified,
+    },
+    Metric {
+        name: "bittorrent_hash_failures_total",
+        kind: "counter",
+        help: "Pieces that did not match their hash.",
+        value: Stats::hash_failures,
+    },
+    Metric {
+        name: "bittorrent_corrupt_bytes_total",
+        kind: "counter",
+        help: "Bytes of downloaded pieces that did not match their hash.",
+        value: Stats::corrupt,
+    },
+    Metric {
+        name: "bittorrent_redundant_bytes_total",
+        kind: "counter",
+        help: "Bytes of blocks received after we already had them.",
+        value: Stats::redundant,
+    },
+    Metric {
+        name: "bittorrent_wasted_bytes_total",
+        kind: "counter",
+        help: "Bytes of piece data received for nothing: corrupt, redundant, or discarded.",
+        value: Stats::wasted,
+    },
+    Metric {
+        name: "bittorrent_tracker_errors_total",
+        kind: "counter",
+        help: "Announces that failed.",
+        value: Stats::tracker_errors,
+    },
+    Metric {
+        name: "bittorrent_peers",
+        kind: "gauge",
+        help: "Peers currently connected.",
+        value: Stats::peers,
+    },
+    Metric {
+        name: "bittorrent_snubs_total",
+        kind: "counter",
+        help: "Requests that peers did not answer in time.",
+        value: Stats::snubs,
+    },
+];
+
+/// Render the stats of each named torrent in the Prometheus text format.
+pub fn render<'a>(torrents: impl IntoIterator<Item = (&'a str, &'a Stats)> + Clone) -> String {
+    let mut out = String::new();
+    for &Metric {
+        name,
+        kind,
+        help,
+        value,
+    } in METRICS
+    {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (torrent, stats) in torrents.clone() {
+            let _ = writeln!(
+                out,
+                "{name}{{torrent=\"{}\"}} {}",
+                escape(torrent),
+                value(stats)
+            );
+        }
+    }
+    out
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answer `GET /metrics` on `listener` forever, with the stats `torrents` returns at the time.
+pub async fn serve(
+    listener: TcpListener,
+    torrents: impl Fn() -> Vec<(String, Arc<Stats>)> + Send + Sync + 'static,
+) -> anyhow::Result<()> {
+    let torrents = Arc::new(torrents);
+    loop {
+        let (stream, addr) = listener
+            .accept()
+            .await
+            .context("accept metrics connection")?;
+        let torrents = Arc::clone(&torrents);
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &*torrents).await {
+                eprintln!("metrics request from {addr} failed: {e:?}");
+            }
+        });
+    }
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    torrents: &(dyn Fn() -> Vec<(String, Arc<Stats>)> + Sync),
+) -> anyhow::Result<()> {
+    let request = rpc::read_request(&mut stream).await?;
+    let response = if request.method == "GET" && request.path == "/metrics" {
+        let torrents = torrents();
+        let body = render(
+            torrents
+                .iter()
+                .map(|(name, stats)| (name.as_str(), &**stats)),
+        );
+        format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: {CONTENT_TYPE}\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            body.len()
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .context("write metrics response")?;
+    Ok(())
+}
+
+#[test]
+fn render_metrics() {
+    let stats = Stats::new(100);
+    stats.add_downloaded(40);
+    stats.piece_verified(0, 40);
+    stats.add_hash_failure(0);
+    stats.add_corrupt(40);
+    stats.add_redundant(5);
+    let out = render([("a \"b\"", &stats)]);
+    assert!(out.contains("# TYPE bittorrent_downloaded_bytes_total counter\n"));
+    assert!(out.contains("bittorrent_downloaded_bytes_total{torrent=\"a \\\"b\\\"\"} 40\n"));
+    assert!(out.contains("bittorrent_left_bytes{torrent=\"a \\\"b\\\"\"} 60\n"));
+    assert!(out.contains("bittorrent_pieces_verified_total{torrent=\"a \\\"b\\\"\"} 1\n"));
+    assert!(out.contains("bittorrent_hash_failures_total{torrent=\"a \\\"b\\\"\"} 1\n"));
+    assert!(out.contains("bittorrent_wasted_bytes_total{torrent=\"a \\\"b\\\"\"} 45\n"));
+    assert!(out.contains("bittorrent_peers{torrent=\"a \\\"b\\\"\"} 0\n"));
+}
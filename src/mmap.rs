@@ -0,0 +1,272 @@
+//! Storage that writes pieces straight into memory-mapped output files.
+//!
+//! Every file of the torrent is created at its full length up front and mapped into memory, so
+//! storing a piece is just a copy into the mapping: no system call per piece (or block), and the
+//! kernel writes the pages back to disk in its own time. Pieces that span files are split across
+//! their mappings.
+
+use crate::storage::{FileLayout, Layout, Storage};
+use crate::torrent::Torrent;
+use anyhow::Context;
+use std::fs::File;
+use std::path::Path;
+use std::ptr::NonNull;
+use std::sync::{Arc, RwLock};
+
+/// Keeps pieces in the torrent's files on disk, through memory mappings of them.
+///
+/// The files are laid out the way [`Downloaded::write_to_dir`](crate::download::Downloaded)
+/// would write them with the same [`Layout`]. Only available on 64-bit Linux, macOS,
+/// and FreeBSD.
+pub struct MmapStorage {
+    /// The mapping of each (non-empty) file.
+    files: FileLayout<Mapping>,
+    /// Pieces are only read after they've been written, but nothing stops the same piece from
+    /// being written twice, so writes are kept from overlapping with anything else.
+    lock: RwLock<()>,
+}
+
+impl MmapStorage {
+    /// Create (or truncate) the files of `t` in `dir`, laid out by `layout`, and map them.
+    pub fn create(t: &Torrent, dir: &Path, layout: &Layout) -> anyhow::Result<Self> {
+        Self::with_files(t, dir, layout, true)
+    }
+
+    /// Like [`MmapStorage::create`], but keep whatever the files already hold, such as the pieces
+    /// of a download that's resuming.
+    pub fn open(t: &Torrent, dir: &Path, layout: &Layout) -> anyhow::Result<Self> {
+        Self::with_files(t, dir, layout, false)
+    }
+
+    fn with_files(
+        t: &Torrent,
+        dir: &Path,
+        layout: &Layout,
+        truncate: bool,
+    ) -> anyhow::Result<Self> {
+        let files = FileLayout::open(t, dir, layout, truncate, |path, file, length| {
+            Mapping::new(file, length)
+                .with_context(|| format!("map {} into memory", path.display()))
+        })?;
+        Ok(Self {
+            files,
+            lock: RwLock::new(()),
+        })
+    }
+}
+
+impl Storage for MmapStorage {
+    fn write_piece(&self, piece_i: usize, data: &[u8]) -> anyhow::Result<()> {
+        let (offset, len) = self.files.piece_range(piece_i)?;
+        anyhow::ensure!(
+            data.len() == len,
+            "piece {piece_i} is {} bytes, but should be {len}",
+            data.len()
+        );
+        let _guard = self.lock.write().expect("storage lock poisoned");
+        for (mapping, at, from, n) in self.files.spans(offset, len) {
+            // Safety: `spans` keeps `at + n` within the mapping, and the write lock means nothing
+            // else is reading or writing it
+            unsafe {
+                std::ptr::copy_nonoverlapping(data[from..].as_ptr(), mapping.ptr().add(at), n)
+            };
+        }
+        Ok(())
+    }
+
+    fn read_piece(&self, piece_i: usize) -> anyhow::Result<Arc<[u8]>> {
+        let (offset, len) = self.files.piece_range(piece_i)?;
+        let mut piece = vec![0; len];
+        let _guard = self.lock.read().expect("storage lock poisoned");
+        for (mapping, at, from, n) in self.files.spans(offset, len) {
+            // Safety: as for writes, and the read lock keeps writers out
+            unsafe {
+                std::ptr::copy_nonoverlapping(mapping.ptr().add(at), piece[from..].as_mut_ptr(), n)
+            };
+        }
+        Ok(piece.into())
+    }
+
+    fn sync(&self) -> anyhow::Result<()> {
+        // not every system shares its page cache between mappings and files, so the pages
+        // dirtied through the mapping are written back to the file before the file is synced
+        for mapping in self.files.files() {
+            sys::sync(mapping.ptr, mapping.len).context("write back mapped file")?;
+            mapping.file.sync_data().context("sync mapped file")?;
+        }
+        Ok(())
+    }
+}
+
+/// A shared, writable mapping of a whole file.
+struct Mapping {
+    ptr: NonNull<u8>,
+    len: usize,
+    /// Kept open for as long as it's mapped (though the mapping itself doesn't need it), for
+    /// syncing it to disk.
+    file: File,
+}
+
+// Safety: the mapping is plain memory, and MmapStorage synchronizes access to it
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(file: File, len: usize) -> std::io::Result<Self> {
+        let ptr = sys::map(&file, len)?;
+        Ok(Self { ptr, len, file })
+    }
+
+    fn ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        sys::unmap(self.ptr, self.len);
+    }
+}
+
+#[cfg(all(
+    target_pointer_width = "64",
+    any(target_os = "linux", target_os = "macos", target_os = "freebsd")
+))]
+mod sys {
+    use std::ffi::{c_int, c_void};
+    use std::fs::File;
+    use std::os::fd::AsRawFd;
+    use std::ptr::NonNull;
+
+    // the same on Linux and the BSDs (including macOS)
+    const PROT_READ: c_int = 1;
+    const PROT_WRITE: c_int = 2;
+    const MAP_SHARED: c_int = 1;
+    // but this isn't
+    #[cfg(target_os = "linux")]
+    const MS_SYNC: c_int = 4;
+    #[cfg(target_os = "macos")]
+    const MS_SYNC: c_int = 0x10;
+    #[cfg(target_os = "freebsd")]
+    const MS_SYNC: c_int = 0;
+
+    extern "C" {
+        fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: i64,
+        ) -> *mut c_void;
+        fn munmap(addr: *mut c_void, len: usize) -> c_int;
+        fn msync(addr: *mut c_void, len: usize, flags: c_int) -> c_int;
+    }
+
+    pub(super) fn map(file: &File, len: usize) -> std::io::Result<NonNull<u8>> {
+        // Safety: we ask for a fresh mapping, which can't alias anything else
+        let ptr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        // MAP_FAILED is -1
+        if ptr as isize == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(NonNull::new(ptr.cast()).expect("mmap never returns null on success"))
+    }
+
+    pub(super) fn unmap(ptr: NonNull<u8>, len: usize) {
+        // Safety: only called once, when the mapping is dropped
+        unsafe { munmap(ptr.as_ptr().cast(), len) };
+    }
+
+    /// Write the pages dirtied through the mapping back to the file, and wait for that to finish.
+    pub(super) fn sync(ptr: NonNull<u8>, len: usize) -> std::io::Result<()> {
+        // Safety: the mapping is still there, since it's only unmapped when dropped
+        if unsafe { msync(ptr.as_ptr().cast(), len, MS_SYNC) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(all(
+    target_pointer_width = "64",
+    any(target_os = "linux", target_os = "macos", target_os = "freebsd")
+)))]
+mod sys {
+    use std::fs::File;
+    use std::ptr::NonNull;
+
+    pub(super) fn map(_: &File, _: usize) -> std::io::Result<NonNull<u8>> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "memory-mapped storage is only supported on 64-bit Linux, macOS, and FreeBSD",
+        ))
+    }
+
+    pub(super) fn unmap(_: NonNull<u8>, _: usize) {}
+
+    pub(super) fn sync(_: NonNull<u8>, _: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn pieces_across_files() {
+    use crate::torrent::{File as TorrentFile, Hashes, Info, Keys};
+    let t = Torrent::new(
+        String::new(),
+        Info {
+            name: "multi".into(),
+            name_utf8: None,
+            plength: 4,
+            pieces: Hashes(vec![[0; 20]; 3]),
+            keys: Keys::MultiFile {
+                files: vec![
+                    TorrentFile {
+                        length: 3,
+                        path: vec!["a".into()],
+                        path_utf8: None,
+                    },
+                    TorrentFile {
+                        length: 0,
+                        path: vec!["empty".into()],
+                        path_utf8: None,
+                    },
+                    TorrentFile {
+                        length: 7,
+                        path: vec!["sub".into(), "b".into()],
+                        path_utf8: None,
+                    },
+                ],
+            },
+            private: None,
+        },
+    );
+    let dir = tempfile::tempdir().expect("create temporary directory");
+    let storage = MmapStorage::create(&t, dir.path(), &Layout::default()).expect("map files");
+    storage.write_piece(0, &[1, 2, 3, 4]).unwrap();
+    storage.write_piece(2, &[9, 10]).unwrap();
+    storage.write_piece(1, &[5, 6, 7, 8]).unwrap();
+    assert!(storage.write_piece(2, &[9, 10, 11]).is_err());
+    assert!(storage.write_piece(3, &[0]).is_err());
+    assert_eq!(&*storage.read_piece(1).unwrap(), [5, 6, 7, 8]);
+    assert_eq!(&*storage.read_piece(2).unwrap(), [9, 10]);
+    drop(storage);
+
+    let root = dir.path().join("multi");
+    assert_eq!(std::fs::read(root.join("a")).unwrap(), [1, 2, 3]);
+    assert!(std::fs::read(root.join("empty")).unwrap().is_empty());
+    assert_eq!(
+        std::fs::read(root.join("sub").join("b")).unwrap(),
+        [4, 5, 6, 7, 8, 9, 10]
+    );
+}
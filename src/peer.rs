@@ -1,72 +1,821 @@
-use crate::BLOCK_MAX;
+use crate::extension::{self, ExtensionContext, ExtensionHandshake, Extensions};
+use crate::eyeballs;
+use crate::forensics::Contributor;
+use crate::holepunch;
+use crate::peer_id;
+use crate::pipeline::{Pipeline, Sent};
+use crate::proxy::Proxy;
+use crate::scheduler::{Assignment, Block, Received, SharedScheduler};
+use crate::stats::BlockReceived;
+use crate::swarm::Swarm;
+use crate::trace::Tap;
 use anyhow::Context;
 use bytes::{Buf, BufMut, BytesMut};
 use futures_util::{SinkExt, StreamExt};
-use std::net::SocketAddrV4;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::collections::VecDeque;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::{mpsc, OwnedSemaphorePermit};
 use tokio_util::codec::Decoder;
 use tokio_util::codec::Encoder;
 use tokio_util::codec::Framed;
 
-// TODO: ideally, Peer should keep track of what pieces we have downloaded (and references to them)
-// so that we can respond to Requests from the other side. also, choking/unchoking the other side.
-pub(crate) struct Peer {
+pub use crate::bitfield::{Bitfield, InvalidBitfield};
+
+/// A connection to a single peer that speaks the peer wire protocol.
+///
+/// This is what the rest of the crate downloads with, but it can also be used on its own to talk
+/// to a peer one message at a time. As messages go back and forth, the connection keeps track of
+/// the state they imply: which pieces the peer has, and who is choking and interested in whom.
+///
+/// The protocol itself runs over any byte stream `S`, such as one half of a
+/// [`tokio::io::duplex`] pipe, or a socket from another runtime wrapped to implement tokio's I/O
+/// traits. Only [`connect`](Self::connect) and its kin, which open the TCP connection themselves,
+/// are tied to [`TcpStream`].
+pub struct Connection<S = TcpStream> {
     addr: SocketAddrV4,
-    stream: Framed<TcpStream, MessageFramer>,
+    peer_id: [u8; 20],
+    reserved: [u8; 8],
+    stream: Framed<S, Tap>,
+    /// The number of pieces in the torrent, which the peer's bitfield and haves must agree with.
+    npieces: usize,
     bitfield: Bitfield,
+    /// Whether the peer is choking us.
     choked: bool,
+    /// Whether we are choking the peer.
+    choking: bool,
+    /// Whether we've told the peer we're interested in its pieces.
+    interested: bool,
+    /// Whether the peer has told us it's interested in our pieces.
+    peer_interested: bool,
 }
 
-impl Peer {
-    pub async fn new(peer_addr: SocketAddrV4, info_hash: [u8; 20]) -> anyhow::Result<Self> {
-        let mut peer = tokio::net::TcpStream::connect(peer_addr)
-            .await
-            .context("connect to peer")?;
-        let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
+impl Connection {
+    /// Connect to the peer at `addr`, and exchange `handshake` with it.
+    ///
+    /// Fails if the peer's handshake isn't for the same torrent as ours. The torrent has `npieces`
+    /// pieces, and a peer that claims to have pieces past the end is disconnected.
+    pub async fn connect(
+        addr: SocketAddrV4,
+        handshake: Handshake,
+        npieces: usize,
+    ) -> anyhow::Result<Self> {
+        Self::connect_any(&[addr], handshake, npieces, PeerTimeouts::default()).await
+    }
+
+    /// Like [`connect`](Self::connect), but for a peer that can be reached at any of `addrs`, and
+    /// giving up on it after `timeouts`.
+    ///
+    /// The addresses are raced against each other (see [`eyeballs`]), and the handshake happens
+    /// over whichever connects first.
+    pub async fn connect_any(
+        addrs: &[SocketAddrV4],
+        handshake: Handshake,
+        npieces: usize,
+        timeouts: PeerTimeouts,
+    ) -> anyhow::Result<Self> {
+        let addrs: Vec<_> = addrs.iter().copied().map(SocketAddr::V4).collect();
+        let (stream, addr) = tokio::time::timeout(
+            timeouts.connect,
+            eyeballs::connect(&addrs, eyeballs::CONNECTION_ATTEMPT_DELAY),
+        )
+        .await
+        .map_err(|_| PeerTimeout::Connect)?
+        .context("connect to peer")?;
+        let SocketAddr::V4(addr) = addr else {
+            unreachable!("only given IPv4 addresses");
+        };
+        tokio::time::timeout(
+            timeouts.handshake,
+            Connection::handshake(stream, addr, handshake, npieces),
+        )
+        .await
+        .map_err(|_| PeerTimeout::Handshake)?
+    }
+
+    /// Like [`connect_any`](Self::connect_any), but for a single address, and through `proxy`.
+    pub async fn connect_via(
+        proxy: &Proxy,
+        addr: SocketAddrV4,
+        handshake: Handshake,
+        npieces: usize,
+        timeouts: PeerTimeouts,
+    ) -> anyhow::Result<Self> {
+        let stream = tokio::time::timeout(
+            timeouts.connect,
+            proxy.connect(&addr.ip().to_string(), addr.port()),
+        )
+        .await
+        .map_err(|_| PeerTimeout::Connect)?
+        .context("connect to peer")?;
+        tokio::time::timeout(
+            timeouts.handshake,
+            Connection::handshake(stream, addr, handshake, npieces),
+        )
+        .await
+        .map_err(|_| PeerTimeout::Handshake)?
+    }
+}
+
+impl<S> Connection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Exchange `handshake` with the peer at `addr`, which is at the other end of `stream`.
+    ///
+    /// This is [`connect`](Connection::connect) for a stream that's already open.
+    pub async fn handshake(
+        mut stream: S,
+        addr: SocketAddrV4,
+        mut handshake: Handshake,
+        npieces: usize,
+    ) -> anyhow::Result<Self> {
+        let info_hash = handshake.info_hash;
         {
             let handshake_bytes = handshake.as_bytes_mut();
-            peer.write_all(handshake_bytes)
+            stream
+                .write_all(handshake_bytes)
                 .await
                 .context("write handshake")?;
-            peer.read_exact(handshake_bytes)
+            stream
+                .read_exact(handshake_bytes)
                 .await
                 .context("read handshake")?;
         }
         anyhow::ensure!(handshake.length == 19);
         anyhow::ensure!(&handshake.bittorrent == b"BitTorrent protocol");
-        let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
-        let bitfield = peer
+        anyhow::ensure!(
+            handshake.info_hash == info_hash,
+            "peer is serving a different torrent"
+        );
+        Ok(Self::new(addr, stream, &handshake, npieces))
+    }
+
+    /// Finish accepting a connection from a peer at `addr` that has already sent us `theirs` (see
+    /// [`read_handshake`]), by replying with `ours`.
+    ///
+    /// As with [`connect`](Connection::connect), the torrent has `npieces` pieces.
+    pub async fn accept(
+        mut stream: S,
+        addr: SocketAddrV4,
+        theirs: Handshake,
+        mut ours: Handshake,
+        npieces: usize,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            theirs.info_hash == ours.info_hash,
+            "peer asked for a different torrent"
+        );
+        stream
+            .write_all(ours.as_bytes_mut())
+            .await
+            .context("write handshake")?;
+        Ok(Self::new(addr, stream, &theirs, npieces))
+    }
+
+    fn new(addr: SocketAddrV4, stream: S, theirs: &Handshake, npieces: usize) -> Self {
+        Self {
+            addr,
+            peer_id: theirs.peer_id,
+            reserved: theirs.reserved,
+            stream: Framed::new(stream, Tap::new(MessageFramer::default(), addr)),
+            npieces,
+            bitfield: Bitfield::empty(),
+            choked: true,
+            choking: true,
+            interested: false,
+            peer_interested: false,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    /// The peer id the peer sent in its handshake.
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    /// Whether the peer supports the extension protocol (BEP 10).
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    /// Whether the peer runs a DHT node (BEP 5).
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[7] & 0x01 != 0
+    }
+
+    /// Accept blocks of up to `max_block` bytes from the peer, rather than [`DEFAULT_MAX_BLOCK`].
+    pub fn set_max_block(&mut self, max_block: usize) {
+        *self.stream.codec_mut() = Tap::new(MessageFramer::new(max_block), self.addr);
+    }
+
+    /// The pieces the peer has told us it has.
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    /// Whether the peer is choking us, in which case it won't answer our requests.
+    pub fn is_choked(&self) -> bool {
+        self.choked
+    }
+
+    /// Whether we are choking the peer.
+    pub fn is_choking(&self) -> bool {
+        self.choking
+    }
+
+    /// Whether we've told the peer that we're interested in its pieces.
+    pub fn is_interested(&self) -> bool {
+        self.interested
+    }
+
+    /// Whether the peer has told us that it's interested in our pieces.
+    pub fn is_peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+
+    /// Send `msg` to the peer.
+    pub async fn send(&mut self, msg: Message) -> anyhow::Result<()> {
+        let tag = msg.tag;
+        self.stream
+            .send(msg)
+            .await
+            .with_context(|| format!("send {tag:?} message"))?;
+        match tag {
+            MessageTag::Choke => self.choking = true,
+            MessageTag::Unchoke => self.choking = false,
+            MessageTag::Interested => self.interested = true,
+            MessageTag::NotInterested => self.interested = false,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Receive the next message from the peer.
+    ///
+    /// This is cancellation safe.
+    pub async fn recv(&mut self) -> anyhow::Result<Message> {
+        let msg = self
+            .stream
             .next()
             .await
-            .expect("peer always sends a bitfields")
+            .context("peer closed the connection")?
             .context("peer message was invalid")?;
-        anyhow::ensure!(bitfield.tag == MessageTag::Bitfield);
+        match msg.tag {
+            MessageTag::Choke => self.choked = true,
+            MessageTag::Unchoke => self.choked = false,
+            MessageTag::Interested => self.peer_interested = true,
+            MessageTag::NotInterested => self.peer_interested = false,
+            MessageTag::Have => {
+                let piece_i = have_index(&msg.payload)?;
+                anyhow::ensure!(
+                    piece_i < self.npieces,
+                    "peer has piece {piece_i}, but there are only {} pieces",
+                    self.npieces
+                );
+                self.bitfield.set_piece(piece_i);
+            }
+            MessageTag::Bitfield => {
+                self.bitfield = Bitfield::from_payload(msg.payload.clone(), self.npieces)
+                    .context("peer sent invalid bitfield")?;
+            }
+            _ => {}
+        }
+        Ok(msg)
+    }
+
+    /// Ask the peer for the `length` bytes at offset `begin` of piece `index`.
+    ///
+    /// The block arrives later as a `Piece` message from [`recv`](Self::recv).
+    pub async fn request_block(
+        &mut self,
+        index: u32,
+        begin: u32,
+        length: u32,
+    ) -> anyhow::Result<()> {
+        let mut request = Request::new(index, begin, length);
+        self.send(Message {
+            tag: MessageTag::Request,
+            payload: Vec::from(request.as_bytes_mut()),
+        })
+        .await
+    }
+}
 
+/// Read the handshake that a peer sends when it connects to us.
+///
+/// The handshake says which torrent the peer wants, so this comes before replying with our own
+/// handshake through [`Connection::accept`].
+pub async fn read_handshake(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Handshake> {
+    let mut handshake = Handshake::new([0; 20], [0; 20]);
+    stream
+        .read_exact(handshake.as_bytes_mut())
+        .await
+        .context("read handshake")?;
+    anyhow::ensure!(handshake.length == 19);
+    anyhow::ensure!(&handshake.bittorrent == b"BitTorrent protocol");
+    Ok(handshake)
+}
+
+/// How long a peer that has unchoked us can go without sending a block we asked for before we
+/// consider ourselves snubbed by it.
+const SNUB_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The most blocks a peer may have asked us for without having been sent them yet.
+///
+/// Clients keep a few hundred requests outstanding at most (we keep up to
+/// [`MAX_DEPTH`](crate::pipeline::MAX_DEPTH)), so a peer that goes past this is up to no good.
+const MAX_QUEUED_UPLOADS: usize = 1024;
+
+// TODO: a real choking algorithm, rather than unchoking everyone who asks.
+pub(crate) struct Peer {
+    conn: Connection,
+    snubbed: bool,
+    /// When we last gave up on the peer unchoking us, unless it has since.
+    sidelined: Option<tokio::time::Instant>,
+    /// The peer's extension handshake, if it supports the extension protocol and has sent one.
+    extensions: Option<ExtensionHandshake>,
+    /// The extensions we support on this connection, which handle the peer's messages for them.
+    handlers: Extensions,
+    /// The bencoded info dictionary, which we serve to peers that ask for it via `ut_metadata`.
+    metadata: Arc<[u8]>,
+    swarm: Arc<Swarm>,
+    /// Messages other parts of the swarm want sent to this peer.
+    outbox: mpsc::UnboundedReceiver<Message>,
+    /// How the peer's been keeping up with our requests, which decides how many we send at once.
+    pipeline: Pipeline,
+    /// The blocks the peer has asked us for that we've yet to send, oldest first.
+    ///
+    /// These only go out while nothing else is waiting to, so that a peer downloading heavily
+    /// from us (or our upload limit) can't hold up our control messages.
+    uploads: VecDeque<Block>,
+}
+
+impl Peer {
+    /// Connect to the peer at `peer_addr`, and wait for it to tell us which pieces it has.
+    ///
+    /// A peer that doesn't get that far within `timeouts` fails with a [`PeerTimeout`].
+    pub async fn new(
+        peer_addr: SocketAddrV4,
+        info_hash: [u8; 20],
+        metadata: Arc<[u8]>,
+        swarm: Arc<Swarm>,
+        timeouts: PeerTimeouts,
+    ) -> anyhow::Result<Self> {
+        let mut handshake = Handshake::new(info_hash, swarm.peer_id());
+        handshake.set_extension_protocol();
+        if swarm.dht_port().is_some() {
+            handshake.set_dht();
+        }
+        let mut conn = match swarm.proxy() {
+            Some(proxy) => {
+                Connection::connect_via(proxy, peer_addr, handshake, swarm.npieces(), timeouts)
+                    .await?
+            }
+            None => {
+                Connection::connect_any(&[peer_addr], handshake, swarm.npieces(), timeouts).await?
+            }
+        };
+        conn.set_max_block(swarm.max_block());
+        let handlers = Extensions::for_torrent(swarm.is_private());
+        send_extension_handshake(&mut conn, &metadata, &handlers, &swarm).await?;
+        send_dht_port(&mut conn, &swarm).await?;
+
+        let outbox = swarm.join(peer_addr, conn.peer_id(), true)?;
+        let mut this = Self {
+            conn,
+            snubbed: false,
+            sidelined: None,
+            extensions: None,
+            handlers,
+            metadata,
+            swarm,
+            outbox,
+            pipeline: Pipeline::default(),
+            uploads: VecDeque::new(),
+        };
+
+        // the extension handshake may arrive on either side of the bitfield
+        loop {
+            let msg = tokio::time::timeout(timeouts.first_message, this.recv())
+                .await
+                .map_err(|_| PeerTimeout::FirstMessage)??;
+            match msg.tag {
+                MessageTag::Bitfield => {
+                    let bitfield = this.conn.bitfield().clone();
+                    this.swarm
+                        .update(peer_addr, |state| state.bitfield = bitfield);
+                    break;
+                }
+                MessageTag::Extended => this.handle_extended(msg.payload).await?,
+                MessageTag::Port => this.handle_port(&msg.payload),
+                tag => anyhow::bail!("peer sent {tag:?} before bitfield"),
+            }
+        }
+
+        Ok(this)
+    }
+
+    /// Start talking to a peer that connected to us.
+    ///
+    /// Unlike when we connect out, we go first: the peer is told about every piece we have of the
+    /// torrent's `npieces`. Peers that have nothing may never send a bitfield, so we don't wait
+    /// for theirs.
+    pub(crate) async fn accept(
+        mut conn: Connection,
+        metadata: Arc<[u8]>,
+        swarm: Arc<Swarm>,
+        npieces: usize,
+    ) -> anyhow::Result<Self> {
+        let handlers = Extensions::for_torrent(swarm.is_private());
+        send_extension_handshake(&mut conn, &metadata, &handlers, &swarm).await?;
+        send_dht_port(&mut conn, &swarm).await?;
+        conn.send(Message {
+            tag: MessageTag::Bitfield,
+            payload: swarm.pieces().bitfield().to_payload(npieces),
+        })
+        .await
+        .context("send bitfield")?;
+
+        let outbox = swarm.join(conn.addr(), conn.peer_id(), false)?;
         Ok(Self {
-            addr: peer_addr,
-            stream: peer,
-            bitfield: Bitfield::from_payload(bitfield.payload),
-            choked: true,
+            conn,
+            snubbed: false,
+            sidelined: None,
+            extensions: None,
+            handlers,
+            metadata,
+            swarm,
+            outbox,
+            pipeline: Pipeline::default(),
+            uploads: VecDeque::new(),
         })
     }
 
-    pub(crate) fn has_piece(&self, piece_i: usize) -> bool {
-        self.bitfield.has_piece(piece_i)
+    pub(crate) fn bitfield(&self) -> &Bitfield {
+        self.conn.bitfield()
+    }
+
+    /// The client the peer is running, if we can tell.
+    pub(crate) fn client(&self) -> Option<String> {
+        self.extensions
+            .as_ref()
+            .and_then(|ext| ext.v.clone())
+            .or_else(|| peer_id::identify(&self.conn.peer_id()).map(|client| client.to_string()))
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddrV4 {
+        self.conn.addr()
+    }
+
+    /// The peer, as it's named in the record of a piece that failed its hash check.
+    pub(crate) fn contributor(&self) -> Contributor {
+        Contributor {
+            addr: self.conn.addr(),
+            peer_id: self.conn.peer_id(),
+            client: self.client(),
+        }
+    }
+
+    /// Receive the next message from the peer.
+    ///
+    /// While waiting, any messages that the rest of the swarm has queued up for this peer are sent
+    /// along, and then any blocks the peer asked for, as the upload limit allows.
+    async fn recv(&mut self) -> anyhow::Result<Message> {
+        loop {
+            while let Ok(msg) = self.outbox.try_recv() {
+                self.conn
+                    .send(msg)
+                    .await
+                    .context("send message queued for peer")?;
+            }
+            let upload = self.uploads.front().map_or(0, |block| block.length);
+            tokio::select! {
+                msg = self.conn.recv() => return msg,
+                msg = self.outbox.recv() => {
+                    // the swarm closes our outbox if it picked another connection to this peer
+                    let msg = msg.context("closed in favour of another connection to the peer")?;
+                    self.conn
+                        .send(msg)
+                        .await
+                        .context("send message queued for peer")?;
+                }
+                () = self.swarm.limits().upload(upload), if !self.uploads.is_empty() => {
+                    let block = self.uploads.pop_front().expect("only enabled if there's one");
+                    self.send_block(block).await?;
+                }
+            }
+        }
+    }
+
+    /// Note the port of the peer's DHT node, which makes it a node to bootstrap a DHT from.
+    fn handle_port(&mut self, payload: &[u8]) {
+        let port = u16::from_be_bytes([payload[0], payload[1]]);
+        self.swarm
+            .update(self.conn.addr(), |state| state.dht_port = Some(port));
+    }
+
+    /// Handle an extended message (BEP 10) received from the peer.
+    async fn handle_extended(&mut self, payload: Vec<u8>) -> anyhow::Result<()> {
+        let Some((&id, body)) = payload.split_first() else {
+            anyhow::bail!("peer sent extended message without an extended message id");
+        };
+        match id {
+            extension::HANDSHAKE_ID => {
+                let handshake: ExtensionHandshake =
+                    serde_bencode::from_bytes(body).context("parse extension handshake")?;
+                self.swarm
+                    .set_holepunch_id(self.conn.addr(), handshake.id_for(holepunch::UT_HOLEPUNCH));
+                let (client, upload_only) = (handshake.v.clone(), handshake.is_upload_only());
+                self.swarm.update(self.conn.addr(), |state| {
+                    state.client = client;
+                    state.upload_only = upload_only;
+                });
+                self.extensions = Some(handshake);
+            }
+            id => {
+                let Some(handler) = self.handlers.get_mut(id) else {
+                    // an extension we didn't advertise, so the peer shouldn't be sending it
+                    return Ok(());
+                };
+                let mut cx = ExtensionContext::new(
+                    self.conn.addr(),
+                    &self.swarm,
+                    &self.metadata,
+                    self.extensions.as_ref(),
+                );
+                handler
+                    .handle(body, &mut cx)
+                    .with_context(|| format!("handle {} message", handler.name()))?;
+                let name = handler.name();
+                for reply in cx.into_replies() {
+                    self.conn
+                        .send(reply)
+                        .await
+                        .with_context(|| format!("send {name} reply"))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a message about the peer downloading from us.
+    ///
+    /// We unchoke any peer that's interested, and then queue up whatever blocks it asks for to be
+    /// sent (see [`recv`](Self::recv)) until it cancels them. A request we can't serve is an
+    /// [`InvalidRequest`], and ends the connection: we don't support the fast extension, so
+    /// there's no way to reject just the one request.
+    async fn handle_upload(&mut self, msg: &Message) -> anyhow::Result<()> {
+        match msg.tag {
+            MessageTag::Interested if self.conn.is_choking() => {
+                self.conn
+                    .send(Message {
+                        tag: MessageTag::Unchoke,
+                        payload: Vec::new(),
+                    })
+                    .await
+                    .context("send unchoke message")?;
+            }
+            MessageTag::Request if !self.conn.is_choking() => {
+                let request =
+                    Request::from_bytes(&msg.payload).context("request payload is 12 bytes")?;
+                let (index, begin, length) = (request.index(), request.begin(), request.length());
+                let max = self.swarm.max_block();
+                if length == 0 {
+                    return Err(InvalidRequest::Empty.into());
+                } else if length as usize > max {
+                    return Err(InvalidRequest::TooLong {
+                        length: length as usize,
+                        max,
+                    }
+                    .into());
+                } else if self.uploads.len() >= MAX_QUEUED_UPLOADS {
+                    return Err(InvalidRequest::TooMany {
+                        max: MAX_QUEUED_UPLOADS,
+                    }
+                    .into());
+                }
+                self.uploads.push_back(Block {
+                    piece_i: index as usize,
+                    begin: begin as usize,
+                    length: length as usize,
+                });
+            }
+            MessageTag::Cancel => {
+                let cancel =
+                    Request::from_bytes(&msg.payload).context("cancel payload is 12 bytes")?;
+                let block = Block {
+                    piece_i: cancel.index() as usize,
+                    begin: cancel.begin() as usize,
+                    length: cancel.length() as usize,
+                };
+                self.uploads.retain(|&queued| queued != block);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Send the peer `block`, which it asked for.
+    async fn send_block(&mut self, block: Block) -> anyhow::Result<()> {
+        let Block {
+            piece_i,
+            begin,
+            length,
+        } = block;
+        let data = self.swarm.pieces().read_block(piece_i, begin, length)?;
+        let mut payload = Vec::with_capacity(8 + data.len());
+        payload.extend((piece_i as u32).to_be_bytes());
+        payload.extend((begin as u32).to_be_bytes());
+        payload.extend(data);
+        self.conn
+            .send(Message {
+                tag: MessageTag::Piece,
+                payload,
+            })
+            .await
+            .with_context(|| format!("send block {begin} of piece {piece_i}"))?;
+        self.swarm.stats().add_uploaded(length);
+        self.swarm
+            .update(self.conn.addr(), |state| state.uploaded += length);
+        Ok(())
     }
 
+    /// Record that the peer now has `piece_i`.
+    fn have(&mut self, piece_i: usize) {
+        self.swarm
+            .update(self.conn.addr(), |state| state.bitfield.set_piece(piece_i));
+    }
+
+    /// The rate (in bytes per second) at which the peer has been sending us blocks.
+    pub(crate) fn bandwidth(&self) -> f64 {
+        self.pipeline.bandwidth()
+    }
+
+    /// Whether the peer has recently left a request of ours unanswered while it had us unchoked.
+    pub(crate) fn is_snubbed(&self) -> bool {
+        self.snubbed
+    }
+
+    fn set_snubbed(&mut self, snubbed: bool) {
+        if snubbed && !self.snubbed {
+            self.swarm.stats().add_snub();
+        }
+        self.snubbed = snubbed;
+        self.swarm
+            .update(self.conn.addr(), |state| state.snubbed = snubbed);
+    }
+
+    /// Count a block of `length` bytes at `begin` of piece `piece_i` as downloaded from the peer.
+    fn block_received(&mut self, piece_i: usize, begin: usize, length: usize) {
+        self.swarm.stats().block_received(BlockReceived {
+            piece_i,
+            begin,
+            length,
+            peer: self.conn.addr(),
+        });
+        let (rtt, depth) = (
+            self.pipeline.rtt(),
+            self.pipeline.depth(self.swarm.block_size()),
+        );
+        self.swarm.update(self.conn.addr(), |state| {
+            state.downloaded += length;
+            state.rtt = rtt;
+            state.pipeline_depth = depth;
+        });
+    }
+
+    /// Take a block the peer sent us that isn't among our outstanding requests, because it
+    /// choked us (and so we gave those up) after already sending it.
+    ///
+    /// The block has gone back to the scheduler, but if it's still missing, it's just as good as
+    /// one we asked for, and saves asking again.
+    fn unrequested_block(&mut self, peer_i: usize, scheduler: &SharedScheduler, payload: &[u8]) {
+        let Some(piece) = Piece::ref_from_bytes(payload) else {
+            return;
+        };
+        let (piece_i, begin) = (piece.index() as usize, piece.begin() as usize);
+        let received = scheduler.block_received(peer_i, piece_i, begin, piece.block());
+        self.count_unneeded(received, piece.block().len());
+        if received == Received::Needed {
+            self.block_received(piece_i, begin, piece.block().len());
+        }
+    }
+
+    /// Count a block of `length` bytes that we didn't need towards what was wasted.
+    fn count_unneeded(&self, received: Received, length: usize) {
+        match received {
+            Received::Needed => {}
+            Received::Redundant => self.swarm.stats().add_redundant(length),
+            Received::Unusable => self.swarm.stats().add_discarded(length),
+        }
+    }
+
+    /// Until when the peer is sidelined for leaving us choked, if it is.
+    ///
+    /// Sidelined peers aren't asked for pieces, since they wouldn't send them, but once this
+    /// passes they're asked again.
+    pub(crate) fn sidelined_until(&self) -> Option<tokio::time::Instant> {
+        let until = self.sidelined? + self.swarm.peer_timeouts().unchoke_retry;
+        (until > tokio::time::Instant::now()).then_some(until)
+    }
+
+    fn set_choked(&mut self, choked: bool) {
+        if !choked {
+            self.sidelined = None;
+        }
+        self.swarm
+            .update(self.conn.addr(), |state| state.choked = choked);
+    }
+
+    /// Tell the peer we're interested, and then just keep track of what it tells us without ever
+    /// requesting anything.
+    pub(crate) async fn observe(&mut self) -> anyhow::Result<()> {
+        self.conn
+            .send(Message {
+                tag: MessageTag::Interested,
+                payload: Vec::new(),
+            })
+            .await
+            .context("send interested message")?;
+
+        loop {
+            let msg = self.recv().await?;
+            match msg.tag {
+                MessageTag::Choke => self.set_choked(true),
+                MessageTag::Unchoke => self.set_choked(false),
+                MessageTag::Have => {
+                    let piece_i = have_index(&msg.payload)?;
+                    self.have(piece_i);
+                }
+                MessageTag::Extended => self.handle_extended(msg.payload).await?,
+                MessageTag::Port => self.handle_port(&msg.payload),
+                MessageTag::Bitfield => {
+                    anyhow::bail!("peer sent bitfield after handshake has been completed");
+                }
+                MessageTag::Interested
+                | MessageTag::NotInterested
+                | MessageTag::Request
+                | MessageTag::Cancel => self.handle_upload(&msg).await?,
+                MessageTag::Piece => {}
+            }
+        }
+    }
+
+    /// Serve the peer whatever it asks for, without ever requesting anything from it.
+    ///
+    /// Returns once the peer disconnects, or if we're only uploading and it turns out the peer is
+    /// too, since then neither of us has anything to give the other.
+    pub(crate) async fn serve(&mut self) -> anyhow::Result<()> {
+        loop {
+            if self.swarm.is_upload_only() && self.swarm.is_upload_only_peer(self.conn.addr()) {
+                eprintln!("peer {} only uploads, as do we", self.conn.addr());
+                return Ok(());
+            }
+            let msg = self.recv().await?;
+            match msg.tag {
+                MessageTag::Bitfield => {
+                    let bitfield = self.conn.bitfield().clone();
+                    self.swarm
+                        .update(self.conn.addr(), |state| state.bitfield = bitfield);
+                }
+                MessageTag::Have => {
+                    let piece_i = have_index(&msg.payload)?;
+                    self.have(piece_i);
+                }
+                MessageTag::Choke => self.set_choked(true),
+                MessageTag::Unchoke => self.set_choked(false),
+                MessageTag::Extended => self.handle_extended(msg.payload).await?,
+                MessageTag::Port => self.handle_port(&msg.payload),
+                MessageTag::Interested
+                | MessageTag::NotInterested
+                | MessageTag::Request
+                | MessageTag::Cancel => self.handle_upload(&msg).await?,
+                MessageTag::Piece => {
+                    // we never ask for anything
+                }
+            }
+        }
+    }
+
+    /// Fetch blocks of the scheduler's current piece from the peer until none are left.
+    ///
+    /// As many requests are kept outstanding as the peer's [`Pipeline`] calls for.
+    ///
+    /// Returns early (with `Ok`) if the peer snubs us, after handing its blocks back, or if it
+    /// leaves us choked for longer than the [unchoke timeout](PeerTimeouts::unchoke).
     pub(crate) async fn participate(
         &mut self,
-        piece_i: usize,
-        piece_size: usize,
-        nblocks: usize,
-        submit: kanal::AsyncSender<usize>,
-        tasks: kanal::AsyncReceiver<usize>,
-        finish: tokio::sync::mpsc::Sender<Message>,
+        peer_i: usize,
+        scheduler: &SharedScheduler,
     ) -> anyhow::Result<()> {
-        anyhow::ensure!(self.bitfield.has_piece(piece_i));
-
-        self.stream
+        self.conn
             .send(Message {
                 tag: MessageTag::Interested,
                 payload: Vec::new(),
@@ -74,110 +823,174 @@ impl Peer {
             .await
             .context("send interested message")?;
 
-        // TODO: timeout, error, and return block to submit if .next() timed out
+        // the blocks we've asked the peer for, oldest first
+        let mut requested: VecDeque<Requested> = VecDeque::new();
         'task: loop {
-            while self.choked {
-                let unchoke = self
-                    .stream
-                    .next()
-                    .await
-                    .expect("peer always sends an unchoke")
-                    .context("peer message was invalid")?;
+            let deadline = tokio::time::Instant::now() + self.swarm.peer_timeouts().unchoke;
+            while self.conn.is_choked() {
+                let Ok(unchoke) = tokio::time::timeout_at(deadline, self.recv()).await else {
+                    // we hold none of the piece's blocks while choked, but the piece isn't done
+                    // until every participant is, so stop counting on this one for a while
+                    eprintln!("peer {} never unchoked us", self.conn.addr());
+                    self.sidelined = Some(tokio::time::Instant::now());
+                    return Ok(());
+                };
+                let unchoke = unchoke?;
                 match unchoke.tag {
                     MessageTag::Unchoke => {
-                        self.choked = false;
+                        self.set_choked(false);
                         assert!(unchoke.payload.is_empty());
                         break;
                     }
                     MessageTag::Have => {
-                        // TODO: update bitfield
-                        // TODO: add to list of peers for relevant piece
+                        let piece_i = have_index(&unchoke.payload)?;
+                        self.have(piece_i);
                     }
                     MessageTag::Interested
                     | MessageTag::NotInterested
                     | MessageTag::Request
-                    | MessageTag::Cancel => {
-                        // not allowing requests for now
-                    }
+                    | MessageTag::Cancel => self.handle_upload(&unchoke).await?,
+                    // most likely a block that was already on its way when the peer choked us
                     MessageTag::Piece => {
-                        // piece that we no longer need/are responsible for
-                    }
-                    MessageTag::Choke => {
-                        anyhow::bail!("peer sent unchoke while unchoked");
+                        self.unrequested_block(peer_i, scheduler, &unchoke.payload)
                     }
+                    MessageTag::Extended => self.handle_extended(unchoke.payload).await?,
+                    MessageTag::Port => self.handle_port(&unchoke.payload),
+                    // redundant, but harmless
+                    MessageTag::Choke => {}
                     MessageTag::Bitfield => {
                         anyhow::bail!("peer sent bitfield after handshake has been completed");
                     }
                 }
             }
-            let Ok(block) = tasks.recv().await else {
-                break;
-            };
 
-            let block_size = if block == nblocks - 1 {
-                let md = piece_size % BLOCK_MAX;
-                if md == 0 {
-                    BLOCK_MAX
+            while requested.len() < self.pipeline.depth(self.swarm.block_size()) {
+                // take our place in the in-flight budget before claiming a block, so that a block
+                // isn't held up (and kept from other peers) while we wait for room. the permit
+                // lasts until the block arrives or we give it back. with requests already out we
+                // can't wait for room, since it may be our own blocks that are taking it up.
+                let swarm = Arc::clone(&self.swarm);
+                let in_flight = if requested.is_empty() {
+                    swarm.limits().request(swarm.block_size()).await
                 } else {
-                    md
-                }
-            } else {
-                BLOCK_MAX
-            };
+                    match swarm.limits().try_request(swarm.block_size()) {
+                        Ok(permit) => permit,
+                        Err(_) => break,
+                    }
+                };
+                let block = if requested.is_empty() {
+                    match scheduler.assign_block(peer_i).await {
+                        Some(block) => block,
+                        None => break 'task,
+                    }
+                } else {
+                    // nor wait for another peer to hand back a block while ours are arriving
+                    match scheduler.try_assign_block(peer_i) {
+                        Assignment::Fetch(block) => block,
+                        Assignment::Wait => break,
+                        Assignment::Done => break 'task,
+                    }
+                };
+                anyhow::ensure!(
+                    self.conn.bitfield().has_piece(block.piece_i),
+                    "peer was asked for piece {} it doesn't have",
+                    block.piece_i
+                );
 
-            let mut request = Request::new(
-                piece_i as u32,
-                (block * BLOCK_MAX) as u32,
-                block_size as u32,
-            );
-            let request_bytes = Vec::from(request.as_bytes_mut());
-            self.stream
-                .send(Message {
-                    tag: MessageTag::Request,
-                    payload: request_bytes,
-                })
-                .await
-                .with_context(|| format!("send request for block {block}"))?;
+                self.swarm.limits().download(block.length).await;
+                self.conn
+                    .request_block(
+                        block.piece_i as u32,
+                        block.begin as u32,
+                        block.length as u32,
+                    )
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "send request for block at {} to {}",
+                            block.begin,
+                            self.conn.addr()
+                        )
+                    })?;
+                requested.push_back(Requested {
+                    block,
+                    sent: self.pipeline.sent(Instant::now()),
+                    _in_flight: in_flight,
+                });
+            }
 
-            let mut msg;
+            // wait for any one of the blocks we've asked for
+            let deadline = tokio::time::Instant::now() + SNUB_TIMEOUT;
             loop {
-                msg = self
-                    .stream
-                    .next()
-                    .await
-                    .expect("peer always sends a piece")
-                    .context("peer message was invalid")?;
+                let Ok(next) = tokio::time::timeout_at(deadline, self.recv()).await else {
+                    // the peer has unchoked us, but isn't sending us anything, so give the blocks
+                    // to someone else and let the download know not to pick this peer if it can
+                    // avoid it.
+                    eprintln!("peer {} snubbed us", self.conn.addr());
+                    self.set_snubbed(true);
+                    scheduler.peer_lost(peer_i);
+                    return Ok(());
+                };
+                let mut msg = next?;
 
                 match msg.tag {
                     MessageTag::Choke => {
                         assert!(msg.payload.is_empty());
-                        self.choked = true;
-                        submit.send(block).await.expect("we still have a receiver");
+                        self.set_choked(true);
+                        // the peer drops all our outstanding requests when it chokes us, so they
+                        // go back to the scheduler (and their places in the in-flight budget with
+                        // them), to be asked for again once we're unchoked, by us or anyone else
+                        requested.clear();
+                        scheduler.peer_lost(peer_i);
                         continue 'task;
                     }
                     MessageTag::Piece => {
                         let piece = Piece::ref_from_bytes(&msg.payload[..])
                             .expect("always get all Piece response fields from peer");
+                        let (piece_i, begin) = (piece.index() as usize, piece.begin() as usize);
 
-                        if piece.index() as usize != piece_i
-                            || piece.begin() as usize != block * BLOCK_MAX
-                        {
-                            // piece that we no longer need/are responsible for
-                        } else {
-                            assert_eq!(piece.block().len(), block_size);
-                            break;
-                        }
+                        let Some(request_i) = requested
+                            .iter()
+                            .position(|r| r.block.piece_i == piece_i && r.block.begin == begin)
+                        else {
+                            // one we asked for before the peer last choked us
+                            self.unrequested_block(peer_i, scheduler, &msg.payload);
+                            continue;
+                        };
+                        let request = requested.remove(request_i).expect("just found it");
+                        let length = piece.block().len();
+                        let received =
+                            scheduler.block_received(peer_i, piece_i, begin, piece.block());
+                        anyhow::ensure!(
+                            received == Received::Needed || length == request.block.length,
+                            "peer sent {length} bytes for a block of {}",
+                            request.block.length
+                        );
+                        // an oversized block may have covered some of our later requests too
+                        requested.retain(|r| {
+                            r.block.piece_i != piece_i
+                                || r.block.begin < begin
+                                || r.block.begin + r.block.length > begin + length
+                        });
+                        self.pipeline.received(request.sent, length, Instant::now());
+                        self.set_snubbed(false);
+                        self.count_unneeded(received, length);
+                        self.block_received(piece_i, begin, length);
+                        break;
                     }
                     MessageTag::Have => {
-                        // TODO: update bitfield
-                        // TODO: add to list of peers for relevant piece
+                        let piece_i = have_index(&msg.payload)?;
+                        self.have(piece_i);
                     }
                     MessageTag::Interested
                     | MessageTag::NotInterested
                     | MessageTag::Request
-                    | MessageTag::Cancel => {
-                        // not allowing requests for now
+                    | MessageTag::Cancel => self.handle_upload(&msg).await?,
+                    MessageTag::Extended => {
+                        self.handle_extended(std::mem::take(&mut msg.payload))
+                            .await?
                     }
+                    MessageTag::Port => self.handle_port(&msg.payload),
                     MessageTag::Unchoke => {
                         anyhow::bail!("peer sent unchoke while unchoked");
                     }
@@ -186,70 +999,152 @@ impl Peer {
                     }
                 }
             }
-
-            finish.send(msg).await.expect("receiver should not go away while there are active peers (us) and missing blocks (this one)");
         }
 
         Ok(())
     }
 }
 
-pub struct Bitfield {
-    payload: Vec<u8>,
+/// A block we've asked a peer for, and are waiting to receive.
+struct Requested {
+    block: Block,
+    sent: Sent,
+    /// Holds the block's place in the in-flight budget.
+    _in_flight: Option<OwnedSemaphorePermit>,
 }
 
-impl Bitfield {
-    pub(crate) fn has_piece(&self, piece_i: usize) -> bool {
-        let byte_i = piece_i / (u8::BITS as usize);
-        let bit_i = (piece_i % (u8::BITS as usize)) as u32;
-        let Some(&byte) = self.payload.get(byte_i) else {
-            return false;
-        };
-        byte & 1u8.rotate_right(bit_i + 1) != 0
+/// Tell the peer which extensions we support, and whether we're only uploading, if it supports
+/// the extension protocol at all.
+async fn send_extension_handshake(
+    conn: &mut Connection,
+    metadata: &[u8],
+    handlers: &Extensions,
+    swarm: &Swarm,
+) -> anyhow::Result<()> {
+    if !conn.supports_extension_protocol() {
+        return Ok(());
     }
+    conn.send(Message {
+        tag: MessageTag::Extended,
+        payload: extension::payload(
+            extension::HANDSHAKE_ID,
+            &ExtensionHandshake::ours(metadata.len(), handlers.m(), swarm.is_upload_only()),
+        )?,
+    })
+    .await
+    .context("send extension handshake")
+}
 
-    pub(crate) fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
-        self.payload.iter().enumerate().flat_map(|(byte_i, byte)| {
-            (0..u8::BITS).filter_map(move |bit_i| {
-                let piece_i = byte_i * (u8::BITS as usize) + (bit_i as usize);
-                let mask = 1u8.rotate_right(bit_i + 1);
-                (byte & mask != 0).then_some(piece_i)
-            })
-        })
+/// Tell the peer which port our DHT node listens on, if we run one and the peer does too.
+async fn send_dht_port(conn: &mut Connection, swarm: &Swarm) -> anyhow::Result<()> {
+    let Some(port) = swarm.dht_port() else {
+        return Ok(());
+    };
+    if !conn.supports_dht() {
+        return Ok(());
     }
+    conn.send(Message {
+        tag: MessageTag::Port,
+        payload: port.to_be_bytes().to_vec(),
+    })
+    .await
+    .context("send DHT port")
+}
 
-    fn from_payload(payload: Vec<u8>) -> Bitfield {
-        Self { payload }
+impl Drop for Peer {
+    fn drop(&mut self) {
+        self.swarm.leave(self.conn.addr());
     }
 }
 
-#[test]
-fn bitfield_has() {
-    let bf = Bitfield {
-        payload: vec![0b10101010, 0b01010101],
-    };
-    assert!(bf.has_piece(0));
-    assert!(!bf.has_piece(1));
-    assert!(!bf.has_piece(7));
-    assert!(!bf.has_piece(8));
-    assert!(bf.has_piece(15));
+/// What a peer told us about itself when we [probed](probe) it.
+#[derive(Debug, Clone)]
+pub struct PeerProbe {
+    pub peer_id: [u8; 20],
+    /// Whether the peer supports the extension protocol (BEP 10).
+    pub extension_protocol: bool,
+    /// The peer's extension handshake, if it sent one.
+    pub extensions: Option<ExtensionHandshake>,
+    /// The pieces the peer has told us it has.
+    pub bitfield: Bitfield,
 }
 
-#[test]
-fn bitfield_iter() {
-    let bf = Bitfield {
-        payload: vec![0b10101010, 0b01010101],
-    };
-    let mut pieces = bf.pieces();
-    assert_eq!(pieces.next(), Some(0));
-    assert_eq!(pieces.next(), Some(2));
-    assert_eq!(pieces.next(), Some(4));
-    assert_eq!(pieces.next(), Some(6));
-    assert_eq!(pieces.next(), Some(9));
-    assert_eq!(pieces.next(), Some(11));
-    assert_eq!(pieces.next(), Some(13));
-    assert_eq!(pieces.next(), Some(15));
-    assert_eq!(pieces.next(), None);
+impl PeerProbe {
+    /// The client the peer is running, if we can tell.
+    pub fn client(&self) -> Option<String> {
+        self.extensions
+            .as_ref()
+            .and_then(|ext| ext.v.clone())
+            .or_else(|| peer_id::identify(&self.peer_id).map(|client| client.to_string()))
+    }
+
+    /// The fraction of a torrent's `npieces` pieces that the peer has.
+    pub fn availability(&self, npieces: usize) -> f64 {
+        if npieces == 0 {
+            return 1.0;
+        }
+        let have = self.bitfield.pieces().filter(|&p| p < npieces).count();
+        have as f64 / npieces as f64
+    }
+}
+
+/// Connect to the peer at `addr`, and find out what it has of the torrent with `info_hash` (which
+/// has `npieces` pieces) without downloading anything.
+///
+/// Peers that have no pieces are allowed to not send a bitfield at all, so we only wait a few
+/// seconds for the peer to tell us about itself before giving up and reporting what we know.
+pub async fn probe(
+    addr: SocketAddrV4,
+    info_hash: [u8; 20],
+    npieces: usize,
+) -> anyhow::Result<PeerProbe> {
+    let mut handshake = Handshake::new(info_hash, peer_id::generate());
+    handshake.set_extension_protocol();
+    let mut conn = Connection::connect(addr, handshake, npieces).await?;
+    let extension_protocol = conn.supports_extension_protocol();
+    if extension_protocol {
+        // we don't offer any extensions, but peers only send their handshake if we send ours
+        conn.send(Message {
+            tag: MessageTag::Extended,
+            payload: extension::payload(extension::HANDSHAKE_ID, &ExtensionHandshake::default())?,
+        })
+        .await?;
+    }
+
+    let mut extensions = None;
+    let mut got_bitfield = false;
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(5);
+    while !got_bitfield || (extension_protocol && extensions.is_none()) {
+        let Ok(msg) = tokio::time::timeout_at(deadline, conn.recv()).await else {
+            break;
+        };
+        let msg = msg?;
+        match msg.tag {
+            MessageTag::Bitfield => got_bitfield = true,
+            MessageTag::Extended if msg.payload.first() == Some(&extension::HANDSHAKE_ID) => {
+                extensions = Some(
+                    serde_bencode::from_bytes(&msg.payload[1..])
+                        .context("parse extension handshake")?,
+                );
+            }
+            _ => {}
+        }
+    }
+
+    Ok(PeerProbe {
+        peer_id: conn.peer_id(),
+        extension_protocol,
+        extensions,
+        bitfield: conn.bitfield().clone(),
+    })
+}
+
+/// The piece index carried by a `Have` message.
+fn have_index(payload: &[u8]) -> anyhow::Result<usize> {
+    let index: [u8; 4] = payload
+        .try_into()
+        .context("have message payload should be 4 bytes")?;
+    Ok(u32::from_be_bytes(index) as usize)
 }
 
 #[repr(C)]
@@ -273,6 +1168,26 @@ impl Handshake {
         }
     }
 
+    /// Advertise support for the extension protocol (BEP 10).
+    pub fn set_extension_protocol(&mut self) {
+        self.reserved[5] |= 0x10;
+    }
+
+    /// Whether the sender supports the extension protocol (BEP 10).
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    /// Advertise that we run a DHT node (BEP 5), whose port we send in a `Port` message.
+    pub fn set_dht(&mut self) {
+        self.reserved[7] |= 0x01;
+    }
+
+    /// Whether the sender runs a DHT node (BEP 5).
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[7] & 0x01 != 0
+    }
+
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
         // Safety: Self is a POD with repr(c) and repr(packed)
@@ -281,6 +1196,73 @@ impl Handshake {
     }
 }
 
+/// How long to wait for each step of connecting to a peer.
+///
+/// Without these, a peer that accepts the TCP connection but then says nothing would keep us
+/// waiting forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerTimeouts {
+    /// For the TCP connection to be established.
+    pub connect: Duration,
+    /// For the peer to answer our handshake with its own.
+    pub handshake: Duration,
+    /// For each message after the handshake, until the peer has sent its bitfield.
+    pub first_message: Duration,
+    /// For the peer to unchoke us once we've told it we're interested.
+    ///
+    /// A peer that doesn't is sidelined: it stays connected, but isn't counted on for any pieces
+    /// until `unchoke_retry` has passed (or it unchokes us after all).
+    pub unchoke: Duration,
+    /// How long a peer that never unchoked us is sidelined for, before we tell it we're
+    /// interested again.
+    pub unchoke_retry: Duration,
+}
+
+impl Default for PeerTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            handshake: Duration::from_secs(10),
+            first_message: Duration::from_secs(30),
+            unchoke: Duration::from_secs(60),
+            unchoke_retry: Duration::from_secs(120),
+        }
+    }
+}
+
+/// A peer took too long to get through one of the [`PeerTimeouts`].
+///
+/// The peer may just be busy, so unlike it breaking the protocol, this is worth retrying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum PeerTimeout {
+    #[error("timed out connecting to peer")]
+    Connect,
+    #[error("timed out waiting for the peer's handshake")]
+    Handshake,
+    #[error("timed out waiting for the peer's bitfield")]
+    FirstMessage,
+}
+
+/// Ways in which a block a peer asked us for can't be served.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidRequest {
+    #[error("request for an empty block")]
+    Empty,
+    #[error("more than {max} requests outstanding")]
+    TooMany { max: usize },
+    #[error("request for a block of {length} bytes, but we serve at most {max}")]
+    TooLong { length: usize, max: usize },
+    #[error("request for piece {index}, which we don't have")]
+    Missing { index: usize },
+    #[error("request for block {begin}+{length} outside piece {index} of length {piece_length}")]
+    OutOfBounds {
+        index: usize,
+        begin: usize,
+        length: usize,
+        piece_length: usize,
+    },
+}
+
 #[repr(C)]
 #[repr(packed)]
 pub struct Request {
@@ -310,6 +1292,17 @@ impl Request {
         u32::from_be_bytes(self.length)
     }
 
+    /// Parse the payload of a `Request` (or `Cancel`) message.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        let (index, rest) = data.split_first_chunk()?;
+        let (begin, length) = rest.split_first_chunk()?;
+        Some(Self {
+            index: *index,
+            begin: *begin,
+            length: length.try_into().ok()?,
+        })
+    }
+
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
         // Safety: Self is a POD with repr(c) and repr(packed)
@@ -372,94 +1365,171 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    /// The port the peer's DHT node listens on (BEP 5).
+    Port = 9,
+    Extended = 20,
 }
 
-#[derive(Debug, Clone)]
+impl MessageTag {
+    /// Whether a payload of `n` bytes is well-formed for this kind of message.
+    ///
+    /// Checking this when decoding means the rest of the code can assume, for example, that a
+    /// `Piece` message always has room for its index and begin fields.
+    pub fn valid_payload_length(self, n: usize) -> bool {
+        match self {
+            MessageTag::Choke
+            | MessageTag::Unchoke
+            | MessageTag::Interested
+            | MessageTag::NotInterested => n == 0,
+            MessageTag::Have => n == 4,
+            MessageTag::Request | MessageTag::Cancel => n == 12,
+            MessageTag::Piece => n >= 8,
+            MessageTag::Port => n == 2,
+            MessageTag::Extended => n >= 1,
+            MessageTag::Bitfield => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Message {
     pub tag: MessageTag,
     pub payload: Vec<u8>,
 }
 
-pub struct MessageFramer;
+/// Splits a peer connection into [`Message`]s, and back.
+///
+/// Frames are limited in size so that a hostile peer can't make us buffer arbitrary amounts of
+/// data. `Piece` messages get a limit of their own, since some (mostly older) clients send blocks
+/// much larger than the 16 KiB everyone asks for these days.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageFramer {
+    max_block: usize,
+}
 
+/// The largest frame of any message other than a `Piece`.
 const MAX: usize = 1 << 16;
 
+/// The largest block a peer may send us in a `Piece` message, unless configured otherwise.
+pub const DEFAULT_MAX_BLOCK: usize = 1 << 17;
+
+impl MessageFramer {
+    /// A framer that accepts `Piece` messages carrying blocks of up to `max_block` bytes.
+    pub fn new(max_block: usize) -> Self {
+        Self { max_block }
+    }
+
+    /// The longest frame (tag included) we accept for messages tagged `tag`.
+    fn max_length(&self, tag: u8) -> usize {
+        if tag == MessageTag::Piece as u8 {
+            // tag, index, begin, and then the block
+            MAX.max(1 + 8 + self.max_block)
+        } else {
+            MAX
+        }
+    }
+}
+
+impl Default for MessageFramer {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_BLOCK)
+    }
+}
+
 impl Decoder for MessageFramer {
     type Item = Message;
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 4 {
-            // Not enough data to read length marker.
-            return Ok(None);
-        }
+        // NOTE: this is a loop rather than recursion so that a long run of heartbeats can't blow
+        // the stack.
+        loop {
+            if src.len() < 4 {
+                // Not enough data to read length marker.
+                return Ok(None);
+            }
 
-        // Read length marker.
-        let mut length_bytes = [0u8; 4];
-        length_bytes.copy_from_slice(&src[..4]);
-        let length = u32::from_be_bytes(length_bytes) as usize;
-
-        if length == 0 {
-            // this is a heartbeat message.
-            // discard it.
-            src.advance(4);
-            // and then try again in case the buffer has more messages
-            return self.decode(src);
-        }
+            // Read length marker.
+            let mut length_bytes = [0u8; 4];
+            length_bytes.copy_from_slice(&src[..4]);
+            let length = u32::from_be_bytes(length_bytes) as usize;
 
-        if src.len() < 5 {
-            // Not enough data to read tag marker.
-            return Ok(None);
-        }
+            if length == 0 {
+                // this is a heartbeat message.
+                // discard it, and then try again in case the buffer has more messages.
+                src.advance(4);
+                continue;
+            }
 
-        // Check that the length is not too large to avoid a denial of
-        // service attack where the server runs out of memory.
-        if length > MAX {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", length),
-            ));
-        }
+            // Check that the length is not too large to avoid a denial of
+            // service attack where the server runs out of memory. We do this before waiting for
+            // any more data so that we never reserve space for a frame we'll end up rejecting.
+            // Only piece frames may be longer than MAX, so between the two we need the tag to
+            // decide.
+            let too_long = if length > self.max_length(MessageTag::Piece as u8) {
+                true
+            } else if length > MAX {
+                let Some(&tag) = src.get(4) else {
+                    return Ok(None);
+                };
+                length > self.max_length(tag)
+            } else {
+                false
+            };
+            if too_long {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Frame of length {} is too large.", length),
+                ));
+            }
 
-        if src.len() < 4 + length {
-            // The full string has not yet arrived.
-            //
-            // We reserve more space in the buffer. This is not strictly
-            // necessary, but is a good idea performance-wise.
-            src.reserve(4 + length - src.len());
+            if src.len() < 4 + length {
+                // The full string has not yet arrived.
+                //
+                // We reserve more space in the buffer. This is not strictly
+                // necessary, but is a good idea performance-wise.
+                src.reserve(4 + length - src.len());
 
-            // We inform the Framed that we need more bytes to form the next
-            // frame.
-            return Ok(None);
-        }
+                // We inform the Framed that we need more bytes to form the next
+                // frame.
+                return Ok(None);
+            }
 
-        // Use advance to modify src such that it no longer contains
-        // this frame.
-        let tag = match src[4] {
-            0 => MessageTag::Choke,
-            1 => MessageTag::Unchoke,
-            2 => MessageTag::Interested,
-            3 => MessageTag::NotInterested,
-            4 => MessageTag::Have,
-            5 => MessageTag::Bitfield,
-            6 => MessageTag::Request,
-            7 => MessageTag::Piece,
-            8 => MessageTag::Cancel,
-            tag => {
+            let tag = match src[4] {
+                0 => MessageTag::Choke,
+                1 => MessageTag::Unchoke,
+                2 => MessageTag::Interested,
+                3 => MessageTag::NotInterested,
+                4 => MessageTag::Have,
+                5 => MessageTag::Bitfield,
+                6 => MessageTag::Request,
+                7 => MessageTag::Piece,
+                8 => MessageTag::Cancel,
+                9 => MessageTag::Port,
+                20 => MessageTag::Extended,
+                tag => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Unknown message type {}.", tag),
+                    ))
+                }
+            };
+            // the length includes the tag byte
+            let payload_length = length - 1;
+            if !tag.valid_payload_length(payload_length) {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
-                    format!("Unknown message type {}.", tag),
-                ))
+                    format!("{tag:?} message has invalid payload length {payload_length}."),
+                ));
             }
-        };
-        let data = if src.len() > 5 {
-            src[5..4 + length].to_vec()
-        } else {
-            Vec::new()
-        };
-        src.advance(4 + length);
 
-        Ok(Some(Message { tag, payload: data }))
+            let data = src[5..4 + length].to_vec();
+            // Use advance to modify src such that it no longer contains
+            // this frame.
+            src.advance(4 + length);
+
+            return Ok(Some(Message { tag, payload: data }));
+        }
     }
 }
 
@@ -469,7 +1539,7 @@ impl Encoder<Message> for MessageFramer {
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
         // Don't send a message if it is longer than the other end will
         // accept.
-        if item.payload.len() + 1 > MAX {
+        if item.payload.len() + 1 > self.max_length(item.tag as u8) {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 format!("Frame of length {} is too large.", item.payload.len()),
@@ -489,3 +1559,467 @@ impl Encoder<Message> for MessageFramer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+fn decode_all(framer: &mut MessageFramer, bytes: &[u8], chunk: usize) -> Vec<Message> {
+    let mut buf = BytesMut::new();
+    let mut msgs = Vec::new();
+    for chunk in bytes.chunks(chunk) {
+        buf.extend_from_slice(chunk);
+        while let Some(msg) = framer.decode(&mut buf).unwrap() {
+            msgs.push(msg);
+        }
+    }
+    assert!(buf.is_empty(), "trailing bytes left in buffer");
+    msgs
+}
+
+#[test]
+fn framer_real_sequence() {
+    // what a typical seeder sends right after the handshake: extension handshake, bitfield,
+    // its DHT port, a keep-alive, unchoke, a have, and then a piece in response to our request.
+    let frame = |tag: u8, payload: &[u8]| {
+        let mut frame = (payload.len() as u32 + 1).to_be_bytes().to_vec();
+        frame.push(tag);
+        frame.extend(payload);
+        frame
+    };
+    let mut piece = vec![0, 0, 0, 2, 0, 0, 0x40, 0];
+    piece.extend(std::iter::repeat_n(0xab, 1 << 14));
+    let wire = [
+        frame(20, b"\x00d1:md11:ut_metadatai3eee"),
+        frame(5, &[0xff, 0xff, 0xe0]),
+        frame(9, &[0x1a, 0xe1]),
+        vec![0, 0, 0, 0],
+        frame(1, &[]),
+        frame(4, &[0, 0, 0, 17]),
+        frame(7, &piece),
+    ]
+    .concat();
+
+    let expected = decode_all(&mut MessageFramer::default(), &wire, wire.len());
+    let tags: Vec<_> = expected.iter().map(|m| m.tag).collect();
+    assert_eq!(
+        tags,
+        [
+            MessageTag::Extended,
+            MessageTag::Bitfield,
+            MessageTag::Port,
+            MessageTag::Unchoke,
+            MessageTag::Have,
+            MessageTag::Piece
+        ]
+    );
+    let block = Piece::ref_from_bytes(&expected[5].payload).unwrap();
+    assert_eq!(block.index(), 2);
+    assert_eq!(block.begin(), 1 << 14);
+    assert_eq!(block.block().len(), 1 << 14);
+
+    // however the bytes get split up on the way, we should see the same messages
+    for chunk in [1, 2, 3, 5, 7, 4096] {
+        assert_eq!(
+            decode_all(&mut MessageFramer::default(), &wire, chunk),
+            expected
+        );
+    }
+}
+
+#[test]
+fn framer_rejects_malformed() {
+    let reject = |bytes: &[u8]| {
+        let mut buf = BytesMut::from(bytes);
+        assert!(
+            MessageFramer::default().decode(&mut buf).is_err(),
+            "{bytes:?}"
+        );
+    };
+    // have with a short index
+    reject(&[0, 0, 0, 3, 4, 0, 1]);
+    // piece without room for index and begin
+    reject(&[0, 0, 0, 5, 7, 0, 0, 0, 1]);
+    // choke with a payload
+    reject(&[0, 0, 0, 2, 0, 0]);
+    // extended message without an extended message id
+    reject(&[0, 0, 0, 1, 20]);
+    // unknown tag
+    reject(&[0, 0, 0, 1, 99]);
+    // a frame longer than we allow should be rejected before we wait for (or reserve) the rest
+    let mut buf = BytesMut::from(&u32::MAX.to_be_bytes()[..]);
+    assert!(MessageFramer::default().decode(&mut buf).is_err());
+    assert!(buf.capacity() < 1024);
+
+    // a tag-only frame is fine for tags that don't carry a payload
+    let mut buf = BytesMut::from(&[0, 0, 0, 1, 1][..]);
+    assert_eq!(
+        MessageFramer::default().decode(&mut buf).unwrap(),
+        Some(Message {
+            tag: MessageTag::Unchoke,
+            payload: Vec::new()
+        })
+    );
+
+    // lots of heartbeats shouldn't recurse
+    let mut buf = BytesMut::zeroed(4 * 1_000_000);
+    assert_eq!(MessageFramer::default().decode(&mut buf).unwrap(), None);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn framer_piece_limit() {
+    let frame = |tag: u8, payload: usize| {
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&(1 + payload as u32).to_be_bytes());
+        bytes.put_u8(tag);
+        bytes.extend_from_slice(&vec![0; payload]);
+        bytes
+    };
+    let mut framer = MessageFramer::new(1 << 17);
+    // a 128 KiB block is fine, but anything else that large isn't
+    let piece = framer
+        .decode(&mut frame(7, 8 + (1 << 17)))
+        .unwrap()
+        .unwrap();
+    assert_eq!(piece.payload.len(), 8 + (1 << 17));
+    assert!(framer.decode(&mut frame(5, 1 << 17)).is_err());
+    assert!(framer.decode(&mut frame(7, 9 + (1 << 17))).is_err());
+    // with the limit lowered, so are piece frames
+    assert!(MessageFramer::new(1 << 14)
+        .decode(&mut frame(7, 8 + (1 << 17)))
+        .is_err());
+
+    // the length alone is enough to wait for the tag before deciding
+    let mut partial = BytesMut::from(&(1u32 << 17).to_be_bytes()[..]);
+    assert_eq!(framer.decode(&mut partial).unwrap(), None);
+}
+
+#[test]
+fn framer_roundtrip() {
+    // a small xorshift generator, so that the "random" messages are the same on every run
+    let mut state = 0x2545f4914f6cdd1du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let tags = [
+        MessageTag::Choke,
+        MessageTag::Unchoke,
+        MessageTag::Interested,
+        MessageTag::NotInterested,
+        MessageTag::Have,
+        MessageTag::Bitfield,
+        MessageTag::Request,
+        MessageTag::Piece,
+        MessageTag::Cancel,
+        MessageTag::Port,
+        MessageTag::Extended,
+    ];
+
+    let mut msgs = Vec::new();
+    let mut wire = BytesMut::new();
+    for _ in 0..500 {
+        let tag = tags[next() as usize % tags.len()];
+        let len = loop {
+            let len = next() as usize % 300;
+            if tag.valid_payload_length(len) {
+                break len;
+            }
+        };
+        let payload: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+        let msg = Message { tag, payload };
+        MessageFramer::default()
+            .encode(msg.clone(), &mut wire)
+            .unwrap();
+        msgs.push(msg);
+    }
+
+    let chunk = 1 + next() as usize % 64;
+    assert_eq!(
+        decode_all(&mut MessageFramer::default(), &wire, chunk),
+        msgs
+    );
+}
+
+#[tokio::test]
+async fn silent_peer_times_out() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+        unreachable!("bound to an IPv4 address");
+    };
+    // accept the connection, but never say a word
+    let server = tokio::spawn(async move {
+        let (_stream, _) = listener.accept().await.unwrap();
+        std::future::pending::<()>().await;
+    });
+    let timeouts = PeerTimeouts {
+        handshake: Duration::from_millis(50),
+        ..PeerTimeouts::default()
+    };
+    let handshake = Handshake::new([0; 20], [0; 20]);
+    let e = Connection::connect_any(&[addr], handshake, 1, timeouts)
+        .await
+        .err()
+        .expect("peer never sends its handshake");
+    assert_eq!(
+        e.downcast_ref::<PeerTimeout>(),
+        Some(&PeerTimeout::Handshake)
+    );
+    server.abort();
+}
+
+#[tokio::test]
+async fn connection_over_duplex() {
+    let (ours, theirs) = tokio::io::duplex(1 << 16);
+    let addr = "127.0.0.1:6881".parse().unwrap();
+    let accept = tokio::spawn(async move {
+        let mut theirs = theirs;
+        let handshake = read_handshake(&mut theirs).await.unwrap();
+        let reply = Handshake::new(handshake.info_hash, *b"-SEED01-000000000000");
+        let mut conn = Connection::accept(theirs, addr, handshake, reply, 8)
+            .await
+            .unwrap();
+        conn.send(Message {
+            tag: MessageTag::Have,
+            payload: 3u32.to_be_bytes().to_vec(),
+        })
+        .await
+        .unwrap();
+        conn
+    });
+
+    let handshake = Handshake::new([7; 20], *b"-LEECH1-000000000000");
+    let mut conn = Connection::handshake(ours, addr, handshake, 8)
+        .await
+        .expect("handshake over a pipe");
+    assert_eq!(&conn.peer_id(), b"-SEED01-000000000000");
+    let msg = conn.recv().await.unwrap();
+    assert_eq!(msg.tag, MessageTag::Have);
+    assert!(conn.bitfield().has_piece(3));
+    accept.await.unwrap();
+}
+
+#[tokio::test]
+async fn control_messages_go_ahead_of_blocks() {
+    use crate::download::DownloadConfig;
+    use crate::ratelimit::{RateLimit, RateLimits};
+    use crate::stats::Stats;
+    use crate::storage::{MemoryStorage, Pieces};
+    use crate::BLOCK_MAX;
+
+    let pieces = Arc::new(Pieces::new(Arc::new(MemoryStorage::default()), 0));
+    pieces.write_verified(0, &[7; 4 * BLOCK_MAX]).unwrap();
+    // a quarter of a second for each block
+    let config = DownloadConfig {
+        limits: Arc::new(RateLimits {
+            upload: Some(RateLimit::new(4 * BLOCK_MAX)),
+            ..RateLimits::default()
+        }),
+        ..DownloadConfig::default()
+    };
+    let (swarm, _candidates) =
+        Swarm::new(Arc::new(Stats::new(0)), false, pieces, 2, [1; 20], &config);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+        unreachable!("bound to an IPv4 address");
+    };
+    let server = {
+        let swarm = Arc::clone(&swarm);
+        tokio::spawn(async move {
+            let (mut stream, SocketAddr::V4(from)) = listener.accept().await.unwrap() else {
+                unreachable!("connected from an IPv4 address");
+            };
+            let theirs = read_handshake(&mut stream).await.unwrap();
+            let ours = Handshake::new(theirs.info_hash, [1; 20]);
+            let conn = Connection::accept(stream, from, theirs, ours, 2)
+                .await
+                .unwrap();
+            let mut peer = Peer::accept(conn, Arc::from(&b""[..]), swarm, 2)
+                .await
+                .unwrap();
+            let _ = peer.serve().await;
+        })
+    };
+
+    let mut conn = Connection::connect(addr, Handshake::new([0; 20], [2; 20]), 2)
+        .await
+        .unwrap();
+    assert_eq!(conn.recv().await.unwrap().tag, MessageTag::Bitfield);
+    conn.send(Message {
+        tag: MessageTag::Interested,
+        payload: Vec::new(),
+    })
+    .await
+    .unwrap();
+    assert_eq!(conn.recv().await.unwrap().tag, MessageTag::Unchoke);
+    for begin in 0..3 {
+        conn.request_block(0, begin * BLOCK_MAX as u32, BLOCK_MAX as u32)
+            .await
+            .unwrap();
+    }
+    // the have goes out right away, rather than after the blocks already asked for
+    swarm.announce_have(1);
+    // and the last block can still be cancelled, since it hasn't been sent yet
+    let mut cancel = Request::new(0, 2 * BLOCK_MAX as u32, BLOCK_MAX as u32);
+    conn.send(Message {
+        tag: MessageTag::Cancel,
+        payload: cancel.as_bytes_mut().to_vec(),
+    })
+    .await
+    .unwrap();
+    conn.request_block(0, 3 * BLOCK_MAX as u32, BLOCK_MAX as u32)
+        .await
+        .unwrap();
+
+    assert_eq!(conn.recv().await.unwrap().tag, MessageTag::Have);
+    let mut sent = Vec::new();
+    for _ in 0..3 {
+        let msg = conn.recv().await.unwrap();
+        assert_eq!(msg.tag, MessageTag::Piece);
+        sent.push(Piece::ref_from_bytes(&msg.payload).unwrap().begin() as usize / BLOCK_MAX);
+    }
+    assert_eq!(sent, [0, 1, 3]);
+    server.abort();
+}
+
+#[tokio::test]
+async fn exchange_dht_ports() {
+    use crate::download::DownloadConfig;
+    use crate::stats::Stats;
+    use crate::storage::{MemoryStorage, Pieces};
+
+    let pieces = Arc::new(Pieces::new(Arc::new(MemoryStorage::default()), 0));
+    let config = DownloadConfig {
+        dht_port: Some(7000),
+        ..DownloadConfig::default()
+    };
+    let (swarm, _candidates) =
+        Swarm::new(Arc::new(Stats::new(0)), false, pieces, 2, [1; 20], &config);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+        unreachable!("bound to an IPv4 address");
+    };
+    let server = {
+        let swarm = Arc::clone(&swarm);
+        tokio::spawn(async move {
+            let (mut stream, SocketAddr::V4(from)) = listener.accept().await.unwrap() else {
+                unreachable!("connected from an IPv4 address");
+            };
+            let theirs = read_handshake(&mut stream).await.unwrap();
+            let mut ours = Handshake::new(theirs.info_hash, [1; 20]);
+            ours.set_dht();
+            let conn = Connection::accept(stream, from, theirs, ours, 2)
+                .await
+                .unwrap();
+            let mut peer = Peer::accept(conn, Arc::from(&b""[..]), swarm, 2)
+                .await
+                .unwrap();
+            let _ = peer.serve().await;
+        })
+    };
+
+    let mut handshake = Handshake::new([0; 20], [2; 20]);
+    handshake.set_dht();
+    let mut conn = Connection::connect(addr, handshake, 2).await.unwrap();
+    assert!(conn.supports_dht());
+    let port = conn.recv().await.unwrap();
+    assert_eq!(port.tag, MessageTag::Port);
+    assert_eq!(port.payload, 7000u16.to_be_bytes());
+    assert_eq!(conn.recv().await.unwrap().tag, MessageTag::Bitfield);
+
+    // our DHT node becomes one to bootstrap from
+    conn.send(Message {
+        tag: MessageTag::Port,
+        payload: 7001u16.to_be_bytes().to_vec(),
+    })
+    .await
+    .unwrap();
+    conn.send(Message {
+        tag: MessageTag::Interested,
+        payload: Vec::new(),
+    })
+    .await
+    .unwrap();
+    assert_eq!(conn.recv().await.unwrap().tag, MessageTag::Unchoke);
+    let SocketAddr::V4(ours) = conn.stream.get_ref().local_addr().unwrap() else {
+        unreachable!("connected from an IPv4 address");
+    };
+    assert_eq!(
+        swarm.state(2).dht_nodes(),
+        [SocketAddrV4::new(*ours.ip(), 7001)]
+    );
+    server.abort();
+}
+
+#[tokio::test]
+async fn seeds_part_ways() {
+    use crate::download::DownloadConfig;
+    use crate::stats::Stats;
+    use crate::storage::{MemoryStorage, Pieces};
+
+    let pieces = Arc::new(Pieces::new(Arc::new(MemoryStorage::default()), 0));
+    let (swarm, _candidates) = Swarm::new(
+        Arc::new(Stats::new(0)),
+        false,
+        pieces,
+        2,
+        [1; 20],
+        &DownloadConfig::default(),
+    );
+    swarm.set_upload_only();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+        unreachable!("bound to an IPv4 address");
+    };
+    let server = {
+        let swarm = Arc::clone(&swarm);
+        tokio::spawn(async move {
+            let (mut stream, SocketAddr::V4(from)) = listener.accept().await.unwrap() else {
+                unreachable!("connected from an IPv4 address");
+            };
+            let theirs = read_handshake(&mut stream).await.unwrap();
+            let mut ours = Handshake::new(theirs.info_hash, [1; 20]);
+            ours.set_extension_protocol();
+            let conn = Connection::accept(stream, from, theirs, ours, 2)
+                .await
+                .unwrap();
+            let mut peer = Peer::accept(conn, Arc::from(&b""[..]), swarm, 2)
+                .await
+                .unwrap();
+            peer.serve().await
+        })
+    };
+
+    let mut handshake = Handshake::new([0; 20], [2; 20]);
+    handshake.set_extension_protocol();
+    let mut conn = Connection::connect(addr, handshake, 2).await.unwrap();
+    let extended = conn.recv().await.unwrap();
+    assert_eq!(extended.tag, MessageTag::Extended);
+    let theirs: ExtensionHandshake = serde_bencode::from_bytes(&extended.payload[1..]).unwrap();
+    assert!(theirs.is_upload_only());
+    assert_eq!(
+        theirs.id_for(extension::UPLOAD_ONLY),
+        Some(extension::UPLOAD_ONLY_ID)
+    );
+    assert_eq!(conn.recv().await.unwrap().tag, MessageTag::Bitfield);
+
+    // a seed, like us, but one with only the first piece
+    conn.send(Message {
+        tag: MessageTag::Bitfield,
+        payload: vec![0b1000_0000],
+    })
+    .await
+    .unwrap();
+    conn.send(Message {
+        tag: MessageTag::Extended,
+        payload: vec![extension::UPLOAD_ONLY_ID, 1],
+    })
+    .await
+    .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("seeds have nothing to say to each other")
+        .unwrap()
+        .unwrap();
+    assert!(conn.recv().await.is_err(), "connection was closed");
+}
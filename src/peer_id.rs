@@ -0,0 +1,219 @@
+//! Figuring out which client a peer is running from its peer id.
+//!
+//! There is no standard for this, but most clients follow one of a few conventions:
+//!
+//! - Azureus-style: `-XXVVVV-` followed by random bytes, where `XX` identifies the client and
+//!   `VVVV` is its version (e.g. `-qB4250-` for qBittorrent 4.2.5).
+//! - Shadow-style: a single character identifying the client, followed by up to five version
+//!   characters and padded with `-` (e.g. `T03I-----` for BitTornado 0.3.18).
+//! - Mainline-style: `M` followed by a `-`-separated version (e.g. `M4-3-6--` for BitTorrent 4.3.6).
+
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The client software a peer is running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Client {
+    pub name: String,
+    pub version: String,
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.version.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} {}", self.name, self.version)
+        }
+    }
+}
+
+const AZUREUS: &[(&[u8; 2], &str)] = &[
+    (b"AG", "Ares"),
+    (b"AZ", "Vuze"),
+    (b"BB", "BitBuddy"),
+    (b"BC", "BitComet"),
+    (b"BI", "BiglyBT"),
+    (b"BT", "BitTorrent"),
+    (b"DE", "Deluge"),
+    (b"FD", "Free Download Manager"),
+    (b"FW", "FrostWire"),
+    (b"KT", "KTorrent"),
+    (b"LT", "libtorrent"),
+    (b"lt", "libTorrent (rakshasa)"),
+    (b"PI", "PicoTorrent"),
+    (b"qB", "qBittorrent"),
+    (b"SD", "Thunder"),
+    (b"TR", "Transmission"),
+    (b"TX", "Tixati"),
+    (b"UM", "µTorrent Mac"),
+    (b"UT", "µTorrent"),
+    (b"UW", "µTorrent Web"),
+    (b"WD", "WebTorrent Desktop"),
+    (b"WW", "WebTorrent"),
+    (b"XL", "Xunlei"),
+];
+
+const SHADOW: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow's client"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// The Azureus-style prefix of the peer ids we generate.
+const PREFIX: &[u8; 8] = b"-BS0001-";
+
+/// Generate a fresh peer id for this client.
+///
+/// The id follows the Azureus-style convention, with the random part made up of printable
+/// characters so that it can be sent anywhere a string is expected.
+pub fn generate() -> [u8; 20] {
+    const CHARSET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+    let mut peer_id = [0; 20];
+    peer_id[..PREFIX.len()].copy_from_slice(PREFIX);
+    for (c, r) in peer_id[PREFIX.len()..].iter_mut().zip(entropy()) {
+        *c = CHARSET[usize::from(r) % CHARSET.len()];
+    }
+    peer_id
+}
+
+/// Bytes that differ on every call, for made-up identifiers.
+pub(crate) fn entropy() -> [u8; 20] {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    // there's no need for cryptographic randomness here, just for ids that won't collide
+    let mut hasher = Sha1::new();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    hasher.update(now.as_nanos().to_le_bytes());
+    hasher.update(std::process::id().to_le_bytes());
+    hasher.update(COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    hasher.finalize().into()
+}
+
+/// Identify the client that generated `peer_id`, if it follows a convention we know about.
+pub fn identify(peer_id: &[u8; 20]) -> Option<Client> {
+    azureus(peer_id)
+        .or_else(|| mainline(peer_id))
+        .or_else(|| shadow(peer_id))
+}
+
+fn azureus(peer_id: &[u8; 20]) -> Option<Client> {
+    let [b'-', c1, c2, version @ .., b'-'] = &peer_id[..8] else {
+        return None;
+    };
+    if !version.iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+    let code = [*c1, *c2];
+    let name = AZUREUS
+        .iter()
+        .find(|(known, _)| **known == code)
+        .map_or_else(
+            || format!("unknown ({})", String::from_utf8_lossy(&code)),
+            |(_, name)| name.to_string(),
+        );
+    let version = if &code == b"TR" {
+        // Transmission uses one digit for the major version and two for the minor, with the last
+        // character marking development or beta builds.
+        let minor = std::str::from_utf8(&version[1..3]).ok()?;
+        let suffix = match version[3] {
+            b'Z' => "+",
+            b'X' => " beta",
+            _ => "",
+        };
+        format!("{}.{minor}{suffix}", version[0] as char)
+    } else {
+        let mut parts: Vec<_> = version.iter().map(|&c| version_digit(c)).collect();
+        // most clients only use the last digit for builds, and leave it as 0
+        if parts.last() == Some(&0) {
+            parts.pop();
+        }
+        join(&parts)
+    };
+    Some(Client { name, version })
+}
+
+fn mainline(peer_id: &[u8; 20]) -> Option<Client> {
+    let re = regex::bytes::Regex::new(r"^M(\d+)-(\d+)-(\d+)-").expect("valid regex");
+    let captures = re.captures(peer_id)?;
+    let part = |i| String::from_utf8_lossy(&captures[i]).into_owned();
+    Some(Client {
+        name: "BitTorrent (mainline)".to_string(),
+        version: format!("{}.{}.{}", part(1), part(2), part(3)),
+    })
+}
+
+fn shadow(peer_id: &[u8; 20]) -> Option<Client> {
+    let &(_, name) = SHADOW.iter().find(|(c, _)| *c == peer_id[0])?;
+    // the version is at most five characters, and is padded with dashes
+    let version = &peer_id[1..6];
+    let end = version
+        .iter()
+        .position(|&c| c == b'-')
+        .unwrap_or(version.len());
+    if peer_id[1 + end..9].iter().any(|&c| c != b'-') {
+        return None;
+    }
+    let version = &version[..end];
+    if !version
+        .iter()
+        .all(|c| c.is_ascii_alphanumeric() || *c == b'.')
+    {
+        return None;
+    }
+    let parts: Vec<_> = version.iter().map(|&c| version_digit(c)).collect();
+    Some(Client {
+        name: name.to_string(),
+        version: join(&parts),
+    })
+}
+
+/// Decode a version character, where `0-9` are themselves, and letters continue after that
+/// (`A-Z` being 10-35 and `a-z` being 36-61).
+fn version_digit(c: u8) -> u32 {
+    match c {
+        b'0'..=b'9' => u32::from(c - b'0'),
+        b'A'..=b'Z' => u32::from(c - b'A') + 10,
+        b'a'..=b'z' => u32::from(c - b'a') + 36,
+        b'.' => 62,
+        _ => 63,
+    }
+}
+
+fn join(parts: &[u32]) -> String {
+    parts
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[test]
+fn identify_clients() {
+    let id = |s: &[u8]| -> [u8; 20] {
+        let mut id = [b'x'; 20];
+        id[..s.len()].copy_from_slice(s);
+        id
+    };
+    let name = |s: &[u8]| identify(&id(s)).map(|c| c.to_string());
+
+    assert_eq!(name(b"-qB4250-").as_deref(), Some("qBittorrent 4.2.5"));
+    assert_eq!(name(b"-TR2940-").as_deref(), Some("Transmission 2.94"));
+    assert_eq!(name(b"-TR300Z-").as_deref(), Some("Transmission 3.00+"));
+    assert_eq!(name(b"-LT1234-").as_deref(), Some("libtorrent 1.2.3.4"));
+    assert_eq!(name(b"-ZZ1000-").as_deref(), Some("unknown (ZZ) 1.0.0"));
+    assert_eq!(
+        name(b"M4-3-6--").as_deref(),
+        Some("BitTorrent (mainline) 4.3.6")
+    );
+    assert_eq!(name(b"T03I-----").as_deref(), Some("BitTornado 0.3.18"));
+    assert_eq!(name(b"00112233445566778899"), None);
+}
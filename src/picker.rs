@@ -0,0 +1,140 @@
+//! Choosing which piece to download next.
+//!
+//! Every time a piece is done, the scheduler lists the pieces we still need that at least one of
+//! our peers has, and asks a [`PiecePicker`] which of them to go for. The pickers here can be
+//! combined: [`ByPriority`] and [`RandomFirst`] narrow down or override the choice, and leave the
+//! rest to the picker they wrap. A streaming player, say, could supply its own picker that prefers
+//! the pieces just past the playback position.
+
+use crate::priority::Priority;
+use std::fmt;
+
+/// A piece that could be downloaded next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub piece_i: usize,
+    pub priority: Priority,
+    /// Which round the piece is in under [`PiecePolicy::FileRoundRobin`], or 0 for every piece
+    /// under any other policy.
+    ///
+    /// [`PiecePolicy::FileRoundRobin`]: crate::piece::PiecePolicy::FileRoundRobin
+    pub round: usize,
+    /// How many of our peers have the piece. This is never 0.
+    pub peers: usize,
+}
+
+/// Decides which piece to download next.
+pub trait PiecePicker: fmt::Debug + Send + Sync {
+    /// Pick one of `candidates`, and return its index in `candidates`.
+    ///
+    /// `candidates` is never empty, but is in no particular order. `verified` is how many pieces
+    /// of the torrent we already have.
+    fn pick(&self, candidates: &[Candidate], verified: usize) -> usize;
+}
+
+/// The index of the candidate that `key` is smallest for, preferring lower piece indices on ties.
+fn min_by_key<K: Ord>(candidates: &[Candidate], key: impl Fn(&Candidate) -> K) -> usize {
+    candidates
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, c)| (key(c), c.piece_i))
+        .map(|(i, _)| i)
+        .expect("there is always a candidate")
+}
+
+/// Go for the piece the most peers have, since that's the one most likely to arrive quickly.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MostAvailable;
+
+impl PiecePicker for MostAvailable {
+    fn pick(&self, candidates: &[Candidate], _: usize) -> usize {
+        min_by_key(candidates, |c| std::cmp::Reverse(c.peers))
+    }
+}
+
+/// Go for the piece the fewest peers have, so that rare pieces are spread before the peers that
+/// have them leave.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RarestFirst;
+
+impl PiecePicker for RarestFirst {
+    fn pick(&self, candidates: &[Candidate], _: usize) -> usize {
+        min_by_key(candidates, |c| c.peers)
+    }
+}
+
+/// Go through the pieces in order, for consuming the data as it's downloaded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn pick(&self, candidates: &[Candidate], _: usize) -> usize {
+        min_by_key(candidates, |_| ())
+    }
+}
+
+/// Pick pieces at random until we have `pieces` of them, and then leave it to `then`.
+///
+/// Rare pieces are slow to get, so a new download is better off with any few pieces quickly, to
+/// have something to trade with.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomFirst<P> {
+    pub pieces: usize,
+    pub then: P,
+}
+
+impl<P: PiecePicker> PiecePicker for RandomFirst<P> {
+    fn pick(&self, candidates: &[Candidate], verified: usize) -> usize {
+        if verified >= self.pieces {
+            return self.then.pick(candidates, verified);
+        }
+        let [a, b, c, d, e, f, g, h, ..] = crate::peer_id::entropy();
+        (u64::from_le_bytes([a, b, c, d, e, f, g, h]) % candidates.len() as u64) as usize
+    }
+}
+
+/// Only consider the pieces with the highest [`Priority`] (and, among those, the earliest round),
+/// and leave the choice between them to the wrapped picker.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ByPriority<P>(pub P);
+
+impl<P: PiecePicker> PiecePicker for ByPriority<P> {
+    fn pick(&self, candidates: &[Candidate], verified: usize) -> usize {
+        let best = min_by_key(candidates, |c| (std::cmp::Reverse(c.priority), c.round));
+        let (priority, round) = (candidates[best].priority, candidates[best].round);
+        let (indices, best): (Vec<_>, Vec<_>) = candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.priority == priority && c.round == round)
+            .unzip();
+        indices[self.0.pick(&best, verified)]
+    }
+}
+
+#[test]
+fn pickers() {
+    let candidate = |piece_i, priority, peers| Candidate {
+        piece_i,
+        priority,
+        round: 0,
+        peers,
+    };
+    let candidates = [
+        candidate(3, Priority::Normal, 2),
+        candidate(1, Priority::Normal, 5),
+        candidate(2, Priority::High, 1),
+        candidate(0, Priority::Normal, 1),
+    ];
+    assert_eq!(MostAvailable.pick(&candidates, 0), 1);
+    assert_eq!(RarestFirst.pick(&candidates, 0), 3);
+    assert_eq!(Sequential.pick(&candidates, 0), 3);
+    assert_eq!(ByPriority(MostAvailable).pick(&candidates, 0), 2);
+    assert_eq!(ByPriority(Sequential).pick(&candidates[..2], 0), 1);
+
+    let random = RandomFirst {
+        pieces: 2,
+        then: Sequential,
+    };
+    assert!(random.pick(&candidates, 1) < candidates.len());
+    assert_eq!(random.pick(&candidates, 2), 3);
+}
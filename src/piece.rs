@@ -1,35 +1,35 @@
-use crate::{peer::Peer, torrent::Torrent};
+use crate::priority::{self, Priority};
+use crate::{bitfield::Bitfield, torrent::Torrent};
 use std::collections::HashSet;
 
+/// How to order the pieces of a download that have the same priority.
+///
+/// The policy puts pieces into rounds, which [`ByPriority`](crate::picker::ByPriority) goes
+/// through in order; within a round, the order is up to the picker it wraps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PiecePolicy {
+    /// Every piece is in the same round, so it's all up to the picker.
+    #[default]
+    Availability,
+    /// Take turns between files, so that every file being downloaded makes progress rather than
+    /// some sitting at 0% until others are done.
+    FileRoundRobin,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Piece {
+    priority: Priority,
+    /// See [`PiecePolicy`].
+    round: usize,
     peers: HashSet<usize>,
     piece_i: usize,
     length: usize,
     hash: [u8; 20],
 }
 
-impl Ord for Piece {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.peers
-            .len()
-            .cmp(&other.peers.len())
-            // tie-break by _random_ ordering of HashSet to avoid deterministic contention
-            .then(self.peers.iter().cmp(other.peers.iter()))
-            .then(self.hash.cmp(&other.hash))
-            .then(self.length.cmp(&other.length))
-            .then(self.piece_i.cmp(&other.piece_i))
-    }
-}
-
-impl PartialOrd for Piece {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
 impl Piece {
-    pub(crate) fn new(piece_i: usize, t: &Torrent, peers: &[Peer]) -> Self {
+    /// Piece `piece_i` of `t`, which no peers are known to have yet.
+    pub(crate) fn new(piece_i: usize, t: &Torrent) -> Self {
         let piece_hash = t.info.pieces.0[piece_i];
         let piece_size = if piece_i == t.info.pieces.0.len() - 1 {
             let md = t.length() % t.info.plength;
@@ -42,24 +42,45 @@ impl Piece {
             t.info.plength
         };
 
-        let peers = peers
-            .iter()
-            .enumerate()
-            .filter_map(|(peer_i, peer)| peer.has_piece(piece_i).then_some(peer_i))
-            .collect();
-
         Self {
-            peers,
+            priority: Priority::default(),
+            round: 0,
+            peers: HashSet::new(),
             piece_i,
             length: piece_size,
             hash: piece_hash,
         }
     }
 
+    /// Recompute which peers have this piece, given the bitfield of each peer.
+    pub(crate) fn refresh_peers(&mut self, peers: &[&Bitfield]) {
+        self.peers = peers
+            .iter()
+            .enumerate()
+            .filter_map(|(peer_i, peer)| peer.has_piece(self.piece_i).then_some(peer_i))
+            .collect();
+    }
+
     pub(crate) fn peers(&self) -> &HashSet<usize> {
         &self.peers
     }
 
+    pub(crate) fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub(crate) fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    pub(crate) fn round(&self) -> usize {
+        self.round
+    }
+
+    pub(crate) fn set_round(&mut self, round: usize) {
+        self.round = round;
+    }
+
     pub(crate) fn index(&self) -> usize {
         self.piece_i
     }
@@ -72,3 +93,64 @@ impl Piece {
         self.length
     }
 }
+
+/// The round in which each piece of `t` should be downloaded under [`PiecePolicy::FileRoundRobin`].
+///
+/// The `n`th piece of every file is in round `n`. A piece that spans several files counts towards
+/// the first of them.
+pub(crate) fn file_rounds(t: &Torrent) -> Vec<usize> {
+    let mut next_round = vec![0; t.file_lengths().count()];
+    (0..t.info.pieces.0.len())
+        .map(|piece_i| {
+            let file_i = priority::piece_files(t, piece_i).start;
+            let round = next_round[file_i];
+            next_round[file_i] += 1;
+            round
+        })
+        .collect()
+}
+
+#[test]
+fn round_robin_between_files() {
+    use crate::torrent::{File, Hashes, Info, Keys};
+    let file = |length, name: &str| File {
+        length,
+        path: vec![name.into()],
+        path_utf8: None,
+    };
+    // pieces of 10 bytes: [10 a] [5 a, 5 b] [10 b] [10 b] [10 b] [10 c]
+    let t = Torrent::new(
+        String::new(),
+        Info {
+            name: "dir".into(),
+            name_utf8: None,
+            plength: 10,
+            pieces: Hashes(vec![[0; 20]; 6]),
+            private: None,
+            keys: Keys::MultiFile {
+                files: vec![file(15, "a"), file(35, "b"), file(10, "c")],
+            },
+        },
+    );
+    let rounds = file_rounds(&t);
+    assert_eq!(rounds, [0, 1, 0, 1, 2, 0]);
+
+    use crate::picker::{ByPriority, Candidate, PiecePicker, Sequential};
+    let mut candidates: Vec<_> = rounds
+        .iter()
+        .enumerate()
+        .map(|(piece_i, &round)| Candidate {
+            piece_i,
+            priority: Priority::Normal,
+            round,
+            peers: 1,
+        })
+        .collect();
+    let first_three: HashSet<_> = std::iter::from_fn(|| {
+        let pick = ByPriority(Sequential).pick(&candidates, 0);
+        Some(candidates.swap_remove(pick).piece_i)
+    })
+    .take(3)
+    .collect();
+    assert_eq!(first_three, HashSet::from([0, 2, 5]));
+}
@@ -0,0 +1,138 @@
+//! How many block requests to keep outstanding with a peer at once.
+//!
+//! A peer can only send us blocks as fast as we ask for them: with a single request in flight,
+//! every block costs a full round trip, so a peer 200ms away never gets past 80 KiB/s however fat
+//! the pipe. To keep the pipe full we need a bandwidth-delay product's worth of requests
+//! outstanding, and both halves of that differ wildly from peer to peer, so they're measured per
+//! peer rather than fixed.
+//!
+//! The latency is the shortest round trip of a request we've seen, since longer ones have been
+//! queued behind other blocks (most likely our own). The bandwidth is the rate at which blocks were
+//! delivered while each request was in flight. While the pipe isn't full, every block that
+//! arrives shows a little more bandwidth than the depth accounted for, so the depth keeps growing
+//! until the peer (or the link) can't go any faster.
+
+use std::time::{Duration, Instant};
+
+/// How many requests to keep outstanding before we've measured anything.
+pub const INITIAL_DEPTH: usize = 4;
+
+/// The fewest requests we keep outstanding, so that the peer always has the next one in hand
+/// when it finishes sending a block.
+pub const MIN_DEPTH: usize = 2;
+
+/// The most requests we keep outstanding with any one peer (most clients drop requests beyond a
+/// few hundred anyway).
+pub const MAX_DEPTH: usize = 256;
+
+/// Latency and bandwidth measurements of a peer, and the request depth they call for.
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    /// The smoothed round-trip time of a request.
+    rtt: Option<Duration>,
+    /// The shortest round trip, which is the latency without any queueing.
+    ///
+    /// Once the pipe is full there's always a block or so queued, so a longer round trip later on
+    /// can't tell a slower route from our own queue; the shortest one is kept for the lifetime of
+    /// the connection instead.
+    min_rtt: Option<Duration>,
+    /// The delivery rate in bytes per second. Jumps up to new peaks right away, and decays slowly
+    /// from them.
+    bandwidth: f64,
+    /// Bytes of blocks delivered in total.
+    delivered: usize,
+}
+
+/// When a request was sent, for timing it once its block arrives.
+#[derive(Debug, Clone, Copy)]
+pub struct Sent {
+    at: Instant,
+    /// How much had been delivered by then.
+    delivered: usize,
+}
+
+impl Pipeline {
+    /// Note a request going out at `now`.
+    pub fn sent(&self, now: Instant) -> Sent {
+        Sent {
+            at: now,
+            delivered: self.delivered,
+        }
+    }
+
+    /// Note that the `length` bytes of the request that was `sent` arrived at `now`.
+    pub fn received(&mut self, sent: Sent, length: usize, now: Instant) {
+        self.delivered += length;
+        let rtt = now.saturating_duration_since(sent.at);
+        if rtt.is_zero() {
+            return;
+        }
+        self.rtt = Some(match self.rtt {
+            // the same smoothing as TCP's (RFC 6298)
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+
+        // everything that was delivered while this request was in flight
+        let rate = (self.delivered - sent.delivered) as f64 / rtt.as_secs_f64();
+        if rate > self.bandwidth {
+            self.bandwidth = rate;
+        } else {
+            self.bandwidth += (rate - self.bandwidth) / 8.0;
+        }
+    }
+
+    /// The smoothed round-trip time of a request to the peer, once we've timed one.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// The rate (in bytes per second) at which the peer has been delivering blocks.
+    pub fn bandwidth(&self) -> f64 {
+        self.bandwidth
+    }
+
+    /// How many requests for blocks of `block_size` bytes to keep outstanding with the peer:
+    /// enough blocks to cover the latency at the bandwidth we've seen, and one more to find out
+    /// whether it can go faster.
+    pub fn depth(&self, block_size: usize) -> usize {
+        let Some(latency) = self.min_rtt else {
+            return INITIAL_DEPTH;
+        };
+        let in_flight = (latency.as_secs_f64() * self.bandwidth / block_size as f64).ceil();
+        (in_flight as usize + 1).clamp(MIN_DEPTH, MAX_DEPTH)
+    }
+}
+
+#[test]
+fn depth_follows_bandwidth_delay_product() {
+    use crate::BLOCK_MAX;
+    let mut pipeline = Pipeline::default();
+    assert_eq!(pipeline.depth(BLOCK_MAX), INITIAL_DEPTH);
+    let start = Instant::now();
+    let latency = Duration::from_millis(100);
+    // a peer that sends a block every 10ms at most, 100ms away: 10 blocks fill the pipe
+    let spacing = Duration::from_millis(10);
+    let mut in_flight = std::collections::VecDeque::new();
+    let mut now = start;
+    let mut next_delivery = start;
+    for _ in 0..5000 {
+        while in_flight.len() < pipeline.depth(BLOCK_MAX) {
+            in_flight.push_back(pipeline.sent(now));
+        }
+        let sent = in_flight.pop_front().unwrap();
+        next_delivery = (next_delivery + spacing).max(sent.at + latency);
+        now = next_delivery;
+        pipeline.received(sent, BLOCK_MAX, now);
+    }
+    assert!(
+        (10..=12).contains(&pipeline.depth(BLOCK_MAX)),
+        "depth settled at {}",
+        pipeline.depth(BLOCK_MAX)
+    );
+    let bandwidth = BLOCK_MAX as f64 / spacing.as_secs_f64();
+    assert!((pipeline.bandwidth() - bandwidth).abs() < bandwidth / 10.0);
+    // the queueing behind our own requests shows in the round trip, but not in the latency
+    assert!(pipeline.rtt().unwrap() >= latency);
+}
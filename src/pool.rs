@@ -0,0 +1,340 @@
+//! Deciding how many peers a download should be connected to.
+//!
+//! A handful of peers is plenty in a small swarm, or when our own connection is the bottleneck,
+//! and every peer past that only costs connections and upload slots. In a large swarm of slow
+//! peers, on the other hand, a handful leaves most of our bandwidth unused. So instead of a fixed
+//! number, [`PoolSizer`] feels its way: it lets the pool grow for as long as that makes the
+//! download faster, and shrinks it back once more peers stop adding bandwidth. [`PeerPool`] keeps
+//! the peers themselves, and connects to more of them as there's room.
+
+use crate::download::DownloadConfig;
+use crate::peer::{Peer, PeerTimeouts};
+use crate::swarm::Swarm;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+
+/// How often to measure the download's throughput and reconsider the size of the pool.
+pub(crate) const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How much faster (as a fraction) the download has to get for a bigger pool to be worth it.
+const MIN_GAIN: f64 = 0.1;
+
+/// How many samples to leave the pool alone for after shrinking it, before trying more peers
+/// again.
+const HOLD_SAMPLES: usize = 6;
+
+/// Keeps track of how many peers a download should be connected to, between `min` and `max`.
+#[derive(Debug)]
+pub(crate) struct PoolSizer {
+    min: usize,
+    max: usize,
+    target: usize,
+    /// How much had been downloaded at the last sample, and when that was.
+    downloaded: usize,
+    at: Instant,
+    /// The throughput over the previous interval, and how many peers we had for it.
+    last: Option<(f64, usize)>,
+    /// How many more samples to hold the pool at its size for.
+    hold: usize,
+}
+
+impl PoolSizer {
+    /// Start out at `min` peers, with `downloaded` bytes downloaded so far as of `now`.
+    pub(crate) fn new(min: usize, max: usize, downloaded: usize, now: Instant) -> Self {
+        let min = min.clamp(1, max.max(1));
+        Self {
+            min,
+            max: max.max(min),
+            target: min,
+            downloaded,
+            at: now,
+            last: None,
+            hold: 0,
+        }
+    }
+
+    /// Start measuring afresh from `downloaded` bytes as of `now`, after a stretch (such as a
+    /// pause) that says nothing about how many peers are worth having.
+    pub(crate) fn restart(&mut self, downloaded: usize, now: Instant) {
+        self.downloaded = downloaded;
+        self.at = now;
+        self.last = None;
+    }
+
+    /// How many peers to be connected to.
+    pub(crate) fn target(&self) -> usize {
+        self.target
+    }
+
+    /// Whether it's time for another [sample](Self::sample).
+    pub(crate) fn is_due(&self, now: Instant) -> bool {
+        now.duration_since(self.at) >= SAMPLE_INTERVAL
+    }
+
+    /// Record that `downloaded` bytes have been downloaded by `now`, with `peers` peers connected,
+    /// and return how many peers to be connected to from here on.
+    ///
+    /// If the pool grew since the last sample and the download got faster for it, it's grown
+    /// again; if it didn't get faster, the pool goes back to the size it was. A pool that hasn't
+    /// filled up to its target yet is left be, since there's nothing to judge.
+    pub(crate) fn sample(&mut self, peers: usize, downloaded: usize, now: Instant) -> usize {
+        let elapsed = now.duration_since(self.at).as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            downloaded.saturating_sub(self.downloaded) as f64 / elapsed
+        } else {
+            0.0
+        };
+        self.downloaded = downloaded;
+        self.at = now;
+        if peers < self.target {
+            // not enough candidates (yet), or still connecting to them
+            self.last = Some((rate, peers));
+            return self.target;
+        }
+
+        let grow = (self.target / 4).max(2);
+        match self.last {
+            Some((last_rate, last_peers)) if peers > last_peers => {
+                if rate > last_rate * (1.0 + MIN_GAIN) {
+                    self.target += grow;
+                } else {
+                    // the extra peers didn't add anything
+                    self.target = last_peers;
+                    self.hold = HOLD_SAMPLES;
+                }
+            }
+            _ if self.hold > 0 => self.hold -= 1,
+            // see whether more peers would help
+            _ => self.target += grow,
+        }
+        self.target = self.target.clamp(self.min, self.max);
+        self.last = Some((rate, peers.min(self.target)));
+        self.target
+    }
+}
+
+/// The peers a download is connected to, along with the ones it has yet to connect to.
+///
+/// The peers are indexed by their place in the pool, which is how the scheduler knows them too.
+/// Peers only come and go [between pieces](Self::refresh), so the indices hold for as long as a
+/// piece is being fetched.
+pub(crate) struct PeerPool {
+    peers: Vec<Peer>,
+    /// The peers that failed while fetching the last piece, by their index.
+    failed: Vec<usize>,
+    /// Peers we've learned about but haven't made room for yet, and how many we're connecting to.
+    backlog: VecDeque<SocketAddrV4>,
+    connecting: Arc<AtomicUsize>,
+    candidates: mpsc::UnboundedReceiver<SocketAddrV4>,
+    /// The peers we've connected to, on their way into the pool.
+    joined: mpsc::UnboundedSender<Peer>,
+    new_peers: mpsc::UnboundedReceiver<Peer>,
+    sizer: PoolSizer,
+    size: watch::Sender<usize>,
+    swarm: Arc<Swarm>,
+    info_hash: [u8; 20],
+    metadata: Arc<[u8]>,
+    timeouts: PeerTimeouts,
+}
+
+impl PeerPool {
+    /// An empty pool for the download in `swarm`, which learns about peers to connect to from
+    /// `candidates`.
+    pub(crate) fn new(
+        swarm: Arc<Swarm>,
+        candidates: mpsc::UnboundedReceiver<SocketAddrV4>,
+        info_hash: [u8; 20],
+        metadata: Arc<[u8]>,
+        config: &DownloadConfig,
+    ) -> Self {
+        let (joined, new_peers) = mpsc::unbounded_channel();
+        let sizer = PoolSizer::new(
+            config.bootstrap_peers,
+            config.max_peers,
+            swarm.stats().downloaded(),
+            Instant::now(),
+        );
+        let (size, _) = watch::channel(sizer.target());
+        Self {
+            peers: Vec::new(),
+            failed: Vec::new(),
+            backlog: VecDeque::new(),
+            connecting: Arc::new(AtomicUsize::new(0)),
+            candidates,
+            joined,
+            new_peers,
+            sizer,
+            size,
+            swarm,
+            info_hash,
+            metadata,
+            timeouts: config.peer_timeouts,
+        }
+    }
+
+    /// How many peers the pool should have, as it changes.
+    pub(crate) fn size(&self) -> watch::Receiver<usize> {
+        self.size.subscribe()
+    }
+
+    /// Where peers connected to elsewhere go to join the pool.
+    pub(crate) fn joiner(&self) -> mpsc::UnboundedSender<Peer> {
+        self.joined.clone()
+    }
+
+    pub(crate) fn peers(&self) -> &[Peer] {
+        &self.peers
+    }
+
+    pub(crate) fn peers_mut(&mut self) -> &mut [Peer] {
+        &mut self.peers
+    }
+
+    /// Wait for `n` peers to join, or for `gave_up` to say that no more are coming.
+    pub(crate) async fn bootstrap(
+        &mut self,
+        n: usize,
+        mut gave_up: impl Future + Unpin,
+        stop: &CancellationToken,
+    ) {
+        while self.peers.len() < n {
+            tokio::select! {
+                Some(peer) = self.new_peers.recv() => self.peers.push(peer),
+                _ = &mut gave_up => break,
+                _ = stop.cancelled() => break,
+            }
+        }
+        while let Ok(peer) = self.new_peers.try_recv() {
+            self.peers.push(peer);
+        }
+    }
+
+    /// Note that the peer at `peer_i` failed, so that it's let go of before the next piece.
+    pub(crate) fn peer_failed(&mut self, peer_i: usize) {
+        self.failed.push(peer_i);
+    }
+
+    /// Start measuring the pool afresh, after a stretch (such as a pause) that says nothing about
+    /// how many peers are worth having.
+    pub(crate) fn restart(&mut self) {
+        self.sizer
+            .restart(self.swarm.stats().downloaded(), Instant::now());
+    }
+
+    /// Bring the pool up to date between pieces, which changes the peers' indices.
+    ///
+    /// We connect to any peers we've learned about in the meantime in the background (if
+    /// there's room for them), and take in the ones that have connected since the last piece.
+    /// The ones that failed are let go of, and if the pool is bigger than it's worth, so are the
+    /// slowest peers.
+    pub(crate) fn refresh(&mut self) {
+        self.backlog
+            .extend(std::iter::from_fn(|| self.candidates.try_recv().ok()));
+        while self.swarm.peer_count() + self.connecting.load(Ordering::Relaxed)
+            < self.sizer.target()
+        {
+            let Some(peer_addr) = self.backlog.pop_front() else {
+                break;
+            };
+            if self.swarm.is_connected(peer_addr) {
+                continue;
+            }
+            let (info_hash, timeouts) = (self.info_hash, self.timeouts);
+            let metadata = Arc::clone(&self.metadata);
+            let swarm = Arc::clone(&self.swarm);
+            let joined = self.joined.clone();
+            let connecting = Arc::clone(&self.connecting);
+            connecting.fetch_add(1, Ordering::Relaxed);
+            tokio::spawn(async move {
+                match Peer::new(peer_addr, info_hash, metadata, swarm, timeouts).await {
+                    Ok(peer) => {
+                        let _ = joined.send(peer);
+                    }
+                    Err(e) => {
+                        eprintln!("failed to connect to peer {peer_addr:?}: {e:?}");
+                    }
+                }
+                connecting.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+        while let Ok(peer) = self.new_peers.try_recv() {
+            self.peers.push(peer);
+        }
+        self.failed.sort_unstable();
+        for peer_i in self.failed.drain(..).rev() {
+            self.peers.remove(peer_i);
+        }
+
+        // see whether the peers we added since last time made a difference
+        let now = Instant::now();
+        if self.sizer.is_due(now) {
+            let downloaded = self.swarm.stats().downloaded();
+            let target = self.sizer.sample(self.peers.len(), downloaded, now);
+            self.size
+                .send_if_modified(|size| std::mem::replace(size, target) != target);
+            if self.peers.len() > target {
+                self.peers
+                    .sort_by(|a, b| b.bandwidth().total_cmp(&a.bandwidth()));
+                for peer in self.peers.drain(target..) {
+                    eprintln!("disconnecting from peer {} to shrink the pool", peer.addr());
+                }
+            }
+        }
+    }
+
+    /// Keep up with what every peer tells us (and serve what they ask for) until `until`, or
+    /// until another peer joins the pool.
+    pub(crate) async fn listen(&mut self, until: tokio::time::Instant) {
+        let Self {
+            peers, new_peers, ..
+        } = self;
+        let listen = futures_util::future::join_all(peers.iter_mut().map(Peer::serve));
+        tokio::select! {
+            _ = tokio::time::timeout_at(until, listen) => {}
+            Some(peer) = new_peers.recv() => peers.push(peer),
+        }
+    }
+}
+
+#[test]
+fn feel_for_pool_size() {
+    let start = Instant::now();
+    let mut sizer = PoolSizer::new(5, 12, 0, start);
+    assert_eq!(sizer.target(), 5);
+    let mut now = start;
+    let mut downloaded = 0;
+    // each peer brings 100 bytes a second, up to a total of 750
+    let mut sample = |sizer: &mut PoolSizer, peers: usize| {
+        now += SAMPLE_INTERVAL;
+        downloaded += (100 * peers).min(750) * SAMPLE_INTERVAL.as_secs() as usize;
+        sizer.sample(peers, downloaded, now)
+    };
+
+    assert!(!sizer.is_due(start));
+    assert!(sizer.is_due(start + SAMPLE_INTERVAL));
+    // a pool that isn't full yet can't be judged
+    assert_eq!(sample(&mut sizer, 3), 5);
+    assert_eq!(sample(&mut sizer, 5), 7);
+    assert_eq!(sample(&mut sizer, 7), 9);
+    // the two extra peers only added 50 bytes a second
+    assert_eq!(sample(&mut sizer, 9), 7);
+    for _ in 0..HOLD_SAMPLES {
+        assert_eq!(sample(&mut sizer, 7), 7);
+    }
+    assert_eq!(sample(&mut sizer, 7), 9);
+
+    // never more than the maximum, however much faster things get
+    let mut sizer = PoolSizer::new(10, 12, 0, start);
+    let mut downloaded = 0;
+    for (i, peers) in [10, 12].into_iter().enumerate() {
+        downloaded += 1000 * peers * (i + 1);
+        sizer.sample(peers, downloaded, start + SAMPLE_INTERVAL * (i as u32 + 1));
+    }
+    assert_eq!(sizer.target(), 12);
+}
@@ -0,0 +1,419 @@
+//! Mapping our listening port on the user's router so that peers can connect to us.
+//!
+//! We first try NAT-PMP (which is a single UDP round-trip to the default gateway), and fall back
+//! to UPnP IGD (SSDP discovery, followed by a SOAP request to the gateway's control URL).
+//!
+//! Either way, the mapping is only leased for so long, and is renewed for as long as we [keep
+//! it](keep), so that it goes away by itself if we never get to remove it.
+
+use anyhow::Context;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+const NAT_PMP_PORT: u16 = 5351;
+const SSDP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+const DESCRIPTION: &str = "bittorrent-starter-rust";
+
+/// How long to ask for a mapping for, in seconds.
+const LEASE: u32 = 3600;
+
+/// How long to wait before trying again when renewing a mapping fails.
+const RENEW_RETRY: Duration = Duration::from_secs(60);
+
+/// The UPnP services that can map ports, in order of preference.
+const UPNP_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// A TCP port mapping we established on the router, which should be [removed](Self::remove) again
+/// on shutdown.
+#[derive(Debug)]
+pub struct PortMapping {
+    pub port: u16,
+    pub external_port: u16,
+    /// How long the router holds on to the mapping unless we [renew](Self::renew) it.
+    pub lifetime: Duration,
+    method: Method,
+}
+
+#[derive(Debug)]
+enum Method {
+    NatPmp {
+        gateway: Ipv4Addr,
+    },
+    Upnp {
+        control_url: String,
+        service: &'static str,
+    },
+}
+
+/// Map TCP `port` on the router to the same port on this machine.
+pub async fn map(port: u16) -> anyhow::Result<PortMapping> {
+    let nat_pmp = match default_gateway() {
+        Some(gateway) => match nat_pmp(gateway, port, LEASE).await {
+            Ok((external_port, lifetime)) => {
+                return Ok(PortMapping {
+                    port,
+                    external_port,
+                    lifetime,
+                    method: Method::NatPmp { gateway },
+                })
+            }
+            Err(e) => e,
+        },
+        None => anyhow::anyhow!("no default gateway"),
+    };
+
+    let (control_url, service) = upnp_discover()
+        .await
+        .with_context(|| format!("NAT-PMP failed ({nat_pmp:#}), and so did UPnP"))?;
+    upnp_add(&control_url, service, port).await?;
+    Ok(PortMapping {
+        port,
+        external_port: port,
+        lifetime: Duration::from_secs(LEASE.into()),
+        method: Method::Upnp {
+            control_url,
+            service,
+        },
+    })
+}
+
+impl PortMapping {
+    /// Ask the router to hold on to the mapping for another lease.
+    pub async fn renew(&mut self) -> anyhow::Result<()> {
+        match &self.method {
+            Method::NatPmp { gateway } => {
+                let (external_port, lifetime) = nat_pmp(*gateway, self.port, LEASE).await?;
+                self.external_port = external_port;
+                self.lifetime = lifetime;
+            }
+            Method::Upnp {
+                control_url,
+                service,
+            } => upnp_add(control_url, service, self.port).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn remove(self) -> anyhow::Result<()> {
+        match self.method {
+            Method::NatPmp { gateway } => {
+                // a lifetime of zero deletes the mapping
+                nat_pmp(gateway, self.port, 0).await?;
+            }
+            Method::Upnp {
+                control_url,
+                service,
+            } => {
+                let args = format!(
+                    "<NewRemoteHost></NewRemoteHost>\
+                     <NewExternalPort>{}</NewExternalPort>\
+                     <NewProtocol>TCP</NewProtocol>",
+                    self.external_port
+                );
+                soap(&control_url, service, "DeletePortMapping", &args).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keeps a port mapped on the router in the background, renewing the mapping before its lease
+/// runs out.
+///
+/// Dropping it removes the mapping in the background; [`shutdown`](Self::shutdown) does so and
+/// waits for it.
+#[derive(Debug)]
+pub struct PortMapper {
+    task: Option<JoinHandle<()>>,
+    stop: CancellationToken,
+}
+
+/// Map TCP `port` on the router to the same port on this machine, and keep it mapped until the
+/// returned [`PortMapper`] goes away.
+pub fn keep(port: u16) -> PortMapper {
+    let stop = CancellationToken::new();
+    PortMapper {
+        task: Some(tokio::spawn(maintain(port, stop.clone()))),
+        stop,
+    }
+}
+
+impl PortMapper {
+    /// Remove the mapping, if we got one.
+    pub async fn shutdown(mut self) {
+        self.stop.cancel();
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Drop for PortMapper {
+    fn drop(&mut self) {
+        // the task removes the mapping on its way out
+        self.stop.cancel();
+    }
+}
+
+async fn maintain(port: u16, stop: CancellationToken) {
+    let mapping = tokio::select! {
+        mapping = map(port) => mapping,
+        // a mapping we were in the middle of making goes away with its lease
+        _ = stop.cancelled() => return,
+    };
+    let mut mapping = match mapping {
+        Ok(mapping) => mapping,
+        Err(e) => {
+            eprintln!("could not map port {port} on the router: {e:?}");
+            return;
+        }
+    };
+    // renew halfway through the lease, but don't hammer a router that hands out tiny ones
+    let renew_in = |mapping: &PortMapping| (mapping.lifetime / 2).max(Duration::from_secs(1));
+    let mut wait = renew_in(&mapping);
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = stop.cancelled() => break,
+        }
+        wait = match mapping.renew().await {
+            Ok(()) => renew_in(&mapping),
+            Err(e) => {
+                eprintln!("failed to renew port mapping: {e:?}");
+                RENEW_RETRY
+            }
+        };
+    }
+    if let Err(e) = mapping.remove().await {
+        eprintln!("failed to remove port mapping: {e:?}");
+    }
+}
+
+/// Find the default IPv4 gateway by looking at the kernel routing table.
+fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    parse_default_gateway(&routes)
+}
+
+fn parse_default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let mut fields = line.split_whitespace().skip(1);
+        let (destination, gateway) = (fields.next()?, fields.next()?);
+        if destination != "00000000" {
+            return None;
+        }
+        // the kernel prints the address as it's laid out in memory, so in host byte order
+        let gateway = u32::from_str_radix(gateway, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+/// Ask the gateway to map TCP `port` for `lifetime` seconds, returning the external port and how
+/// long the gateway actually mapped it for.
+async fn nat_pmp(gateway: Ipv4Addr, port: u16, lifetime: u32) -> anyhow::Result<(u16, Duration)> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .context("bind NAT-PMP socket")?;
+    socket
+        .connect((gateway, NAT_PMP_PORT))
+        .await
+        .context("connect to NAT-PMP gateway")?;
+
+    let mut request = vec![0 /* version */, 2 /* map TCP */, 0, 0];
+    request.extend(port.to_be_bytes());
+    request.extend(if lifetime == 0 { 0 } else { port }.to_be_bytes());
+    request.extend(lifetime.to_be_bytes());
+
+    // the spec says to start at 250ms and double on every retry
+    let mut wait = Duration::from_millis(250);
+    let mut response = [0u8; 16];
+    for _ in 0..4 {
+        socket
+            .send(&request)
+            .await
+            .context("send NAT-PMP request")?;
+        match tokio::time::timeout(wait, socket.recv(&mut response)).await {
+            Ok(n) => {
+                let n = n.context("receive NAT-PMP response")?;
+                anyhow::ensure!(n == 16, "NAT-PMP response was {n} bytes");
+                anyhow::ensure!(response[1] == 128 + 2, "unexpected NAT-PMP opcode");
+                let result = u16::from_be_bytes([response[2], response[3]]);
+                anyhow::ensure!(result == 0, "NAT-PMP gateway returned error {result}");
+                let external_port = u16::from_be_bytes([response[10], response[11]]);
+                let lifetime =
+                    u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+                return Ok((external_port, Duration::from_secs(lifetime.into())));
+            }
+            Err(_) => wait *= 2,
+        }
+    }
+    anyhow::bail!("NAT-PMP gateway did not respond")
+}
+
+/// Find an Internet Gateway Device on the local network, and return the control URL of its port
+/// mapping service.
+async fn upnp_discover() -> anyhow::Result<(String, &'static str)> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .context("bind SSDP socket")?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         HOST: {SSDP_ADDR}\r\n\
+         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+         MAN: \"ssdp:discover\"\r\n\
+         MX: 2\r\n\r\n"
+    );
+    socket
+        .send_to(search.as_bytes(), SSDP_ADDR)
+        .await
+        .context("send SSDP search")?;
+
+    let mut response = vec![0u8; 2048];
+    let n = tokio::time::timeout(Duration::from_secs(3), socket.recv(&mut response))
+        .await
+        .context("no UPnP gateway responded")?
+        .context("receive SSDP response")?;
+    let response = String::from_utf8_lossy(&response[..n]);
+    let location = ssdp_location(&response).context("SSDP response has no location")?;
+
+    let description = reqwest::get(location)
+        .await
+        .context("fetch UPnP device description")?
+        .text()
+        .await
+        .context("read UPnP device description")?;
+    let (control_url, service) =
+        control_url(&description).context("gateway has no port mapping service")?;
+    let control_url = reqwest::Url::parse(location)
+        .and_then(|base| base.join(&control_url))
+        .context("resolve UPnP control URL")?;
+    Ok((control_url.into(), service))
+}
+
+fn ssdp_location(response: &str) -> Option<&str> {
+    response.lines().find_map(|line| {
+        let (header, value) = line.split_once(':')?;
+        header
+            .trim()
+            .eq_ignore_ascii_case("location")
+            .then(|| value.trim())
+    })
+}
+
+fn control_url(description: &str) -> Option<(String, &'static str)> {
+    let services = regex::Regex::new(r"(?s)<service>(.*?)</service>").expect("valid regex");
+    let service_type =
+        regex::Regex::new(r"<serviceType>\s*(.*?)\s*</serviceType>").expect("valid regex");
+    let control_url =
+        regex::Regex::new(r"<controlURL>\s*(.*?)\s*</controlURL>").expect("valid regex");
+    UPNP_SERVICES.into_iter().find_map(|wanted| {
+        services.captures_iter(description).find_map(|service| {
+            let service = &service[1];
+            if &service_type.captures(service)?[1] != wanted {
+                return None;
+            }
+            Some((control_url.captures(service)?[1].to_string(), wanted))
+        })
+    })
+}
+
+async fn upnp_add(control_url: &str, service: &'static str, port: u16) -> anyhow::Result<()> {
+    let local_ip = local_ip_towards(control_url).await?;
+    let args = format!(
+        "<NewRemoteHost></NewRemoteHost>\
+         <NewExternalPort>{port}</NewExternalPort>\
+         <NewProtocol>TCP</NewProtocol>\
+         <NewInternalPort>{port}</NewInternalPort>\
+         <NewInternalClient>{local_ip}</NewInternalClient>\
+         <NewEnabled>1</NewEnabled>\
+         <NewPortMappingDescription>{DESCRIPTION}</NewPortMappingDescription>\
+         <NewLeaseDuration>{LEASE}</NewLeaseDuration>"
+    );
+    soap(control_url, service, "AddPortMapping", &args).await
+}
+
+/// The address of the local interface we'd use to talk to the host in `url`.
+async fn local_ip_towards(url: &str) -> anyhow::Result<std::net::IpAddr> {
+    let url = reqwest::Url::parse(url).context("parse UPnP control URL")?;
+    let host = url.host_str().context("UPnP control URL has no host")?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    // connecting a UDP socket sends nothing, but does make the kernel pick a route
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .context("bind probe socket")?;
+    socket
+        .connect((host, port))
+        .await
+        .context("route to UPnP gateway")?;
+    Ok(socket.local_addr().context("probe socket address")?.ip())
+}
+
+async fn soap(
+    control_url: &str,
+    service: &'static str,
+    action: &str,
+    args: &str,
+) -> anyhow::Result<()> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body>\
+         </s:Envelope>"
+    );
+    let response = reqwest::Client::new()
+        .post(control_url)
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header("SOAPAction", format!("\"{service}#{action}\""))
+        .body(body)
+        .send()
+        .await
+        .with_context(|| format!("send UPnP {action}"))?;
+    anyhow::ensure!(
+        response.status().is_success(),
+        "UPnP {action} failed with {}",
+        response.status()
+    );
+    Ok(())
+}
+
+#[test]
+fn upnp_parsing() {
+    let gateway = u32::from_ne_bytes([192, 168, 1, 1]);
+    let routes = format!(
+        "Iface\tDestination\tGateway \tFlags\n\
+         eth0\t0000A8C0\t00000000\t0001\n\
+         eth0\t00000000\t{gateway:08X}\t0003\n"
+    );
+    assert_eq!(
+        parse_default_gateway(&routes),
+        Some(Ipv4Addr::new(192, 168, 1, 1))
+    );
+
+    let ssdp = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+                Location: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+    assert_eq!(
+        ssdp_location(ssdp),
+        Some("http://192.168.1.1:5000/rootDesc.xml")
+    );
+
+    let description = "<root><service>\
+         <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+         <controlURL>/ctl/L3F</controlURL></service>\
+         <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+         <controlURL>/ctl/IPConn</controlURL></service></root>";
+    assert_eq!(
+        control_url(description),
+        Some((
+            "/ctl/IPConn".to_string(),
+            "urn:schemas-upnp-org:service:WANIPConnection:1"
+        ))
+    );
+}
@@ -0,0 +1,105 @@
+use crate::torrent::Torrent;
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// How eagerly a piece (or file) should be downloaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Don't download at all.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// The priorities the user has assigned to files and pieces of a torrent.
+#[derive(Debug, Clone, Default)]
+pub struct Priorities {
+    files: HashMap<usize, Priority>,
+    pieces: HashMap<usize, Priority>,
+}
+
+impl Priorities {
+    pub(crate) fn set_file(&mut self, file_i: usize, priority: Priority) {
+        self.files.insert(file_i, priority);
+    }
+
+    pub(crate) fn set_piece(&mut self, piece_i: usize, priority: Priority) {
+        self.pieces.insert(piece_i, priority);
+    }
+
+    /// The effective priority of `piece_i`.
+    ///
+    /// An explicit piece priority wins. Otherwise, a piece gets the highest priority of the files
+    /// it overlaps, so that a piece is only skipped if _every_ file it contains is skipped.
+    pub(crate) fn of_piece(&self, t: &Torrent, piece_i: usize) -> Priority {
+        if let Some(&priority) = self.pieces.get(&piece_i) {
+            return priority;
+        }
+        piece_files(t, piece_i)
+            .map(|file_i| self.files.get(&file_i).copied().unwrap_or_default())
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// The indices of the files that (at least partially) lie within `piece_i`.
+pub(crate) fn piece_files(t: &Torrent, piece_i: usize) -> Range<usize> {
+    let start = piece_i * t.info.plength;
+    let end = start + t.info.plength;
+    let mut first = None;
+    let mut last = 0;
+    let mut offset = 0;
+    for (file_i, length) in t.file_lengths().enumerate() {
+        let (file_start, file_end) = (offset, offset + length);
+        offset = file_end;
+        // empty files (and directories) have no data in any piece
+        if length > 0 && file_start < end && file_end > start {
+            first.get_or_insert(file_i);
+            last = file_i;
+        }
+    }
+    match first {
+        Some(first) => first..last + 1,
+        None => 0..0,
+    }
+}
+
+#[test]
+fn skip_only_unique_pieces() {
+    use crate::torrent::{File, Hashes, Info, Keys};
+    let file = |length, name: &str| File {
+        length,
+        path: vec![name.into()],
+        path_utf8: None,
+    };
+    // pieces of 10 bytes: [a a a a a a b b b b] [b b b b b b b b b b] [b b c c c]
+    let t = Torrent::new(
+        String::new(),
+        Info {
+            name: "dir".into(),
+            name_utf8: None,
+            plength: 10,
+            pieces: Hashes(vec![[0; 20]; 3]),
+            private: None,
+            keys: Keys::MultiFile {
+                files: vec![file(6, "a"), file(16, "b"), file(3, "c")],
+            },
+        },
+    );
+    assert_eq!(piece_files(&t, 0), 0..2);
+    assert_eq!(piece_files(&t, 1), 1..2);
+    assert_eq!(piece_files(&t, 2), 1..3);
+
+    let mut priorities = Priorities::default();
+    priorities.set_file(1, Priority::Skip);
+    assert_eq!(priorities.of_piece(&t, 0), Priority::Normal);
+    assert_eq!(priorities.of_piece(&t, 1), Priority::Skip);
+    assert_eq!(priorities.of_piece(&t, 2), Priority::Normal);
+
+    priorities.set_file(2, Priority::High);
+    priorities.set_piece(0, Priority::Low);
+    assert_eq!(priorities.of_piece(&t, 0), Priority::Low);
+    assert_eq!(priorities.of_piece(&t, 2), Priority::High);
+}
@@ -0,0 +1,182 @@
+//! Limiting how fast we transfer piece data, and how much of it we wait for at once.
+//!
+//! A limit can be shared between any number of peers (and torrents), in which case they all draw
+//! from the same budget.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
+
+/// A token bucket that refills at a fixed number of bytes per second.
+#[derive(Debug)]
+pub struct RateLimit {
+    bytes_per_sec: usize,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    available: f64,
+    refilled: Instant,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_sec: usize) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            bucket: Mutex::new(Bucket {
+                available: 0.0,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> usize {
+        self.bytes_per_sec
+    }
+
+    /// Wait until we're allowed to transfer another `n` bytes.
+    pub async fn acquire(&self, n: usize) {
+        while let Some(wait) = self.take(n, Instant::now()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take `n` bytes from the bucket if they're available at `now`, or otherwise return how long
+    /// until they will be.
+    fn take(&self, n: usize, now: Instant) -> Option<Duration> {
+        let rate = self.bytes_per_sec as f64;
+        let n = n as f64;
+        let mut bucket = self.bucket.lock().expect("rate limit lock poisoned");
+        let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+        // allow bursts of up to a second's worth of data, but always enough for a single transfer
+        // so that large ones don't wait forever
+        bucket.available = (bucket.available + elapsed * rate).min(rate.max(n));
+        bucket.refilled = now;
+        if bucket.available >= n {
+            bucket.available -= n;
+            None
+        } else {
+            Some(Duration::from_secs_f64((n - bucket.available) / rate))
+        }
+    }
+}
+
+/// A cap on how many bytes of blocks may be requested but not yet received at any one time.
+///
+/// Requests beyond the cap wait for earlier blocks to arrive, so the memory used by blocks in
+/// flight stays bounded no matter how large the pieces are or how many peers and torrents are
+/// downloading at once.
+#[derive(Debug)]
+pub struct InFlightLimit {
+    bytes: usize,
+    budget: Arc<Semaphore>,
+}
+
+impl InFlightLimit {
+    pub fn new(bytes: usize) -> Self {
+        // a semaphore counts in u32s, and must always have room for at least one block
+        let bytes = bytes.clamp(crate::BLOCK_MAX, u32::MAX as usize);
+        Self {
+            bytes,
+            budget: Arc::new(Semaphore::new(bytes)),
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Wait until another `n` bytes may be requested.
+    ///
+    /// The bytes count as in flight until the returned permit is dropped.
+    pub async fn acquire(&self, n: usize) -> OwnedSemaphorePermit {
+        // a block larger than the whole budget may still go out, just not alongside any other
+        let n = n.min(self.bytes) as u32;
+        Arc::clone(&self.budget)
+            .acquire_many_owned(n)
+            .await
+            .expect("in-flight budget is never closed")
+    }
+
+    /// Like [`acquire`](Self::acquire), but only if the bytes are available right away.
+    pub fn try_acquire(&self, n: usize) -> Result<OwnedSemaphorePermit, TryAcquireError> {
+        let n = n.min(self.bytes) as u32;
+        Arc::clone(&self.budget).try_acquire_many_owned(n)
+    }
+}
+
+/// Limits on the total transfer rates of everything that shares them.
+#[derive(Debug, Default)]
+pub struct RateLimits {
+    pub download: Option<RateLimit>,
+    pub upload: Option<RateLimit>,
+    /// How many bytes of blocks may be in flight across everything that shares these limits.
+    pub in_flight: Option<InFlightLimit>,
+}
+
+impl RateLimits {
+    pub(crate) async fn download(&self, n: usize) {
+        if let Some(limit) = &self.download {
+            limit.acquire(n).await;
+        }
+    }
+
+    pub(crate) async fn upload(&self, n: usize) {
+        if let Some(limit) = &self.upload {
+            limit.acquire(n).await;
+        }
+    }
+
+    /// Wait until a block of `n` bytes may be requested, and return what holds its place in the
+    /// in-flight budget until it arrives (or is given up on).
+    pub(crate) async fn request(&self, n: usize) -> Option<OwnedSemaphorePermit> {
+        match &self.in_flight {
+            Some(limit) => Some(limit.acquire(n).await),
+            None => None,
+        }
+    }
+
+    /// Like [`request`](Self::request), but fails rather than waiting if there's no room for the
+    /// block right now.
+    pub(crate) fn try_request(
+        &self,
+        n: usize,
+    ) -> Result<Option<OwnedSemaphorePermit>, TryAcquireError> {
+        self.in_flight
+            .as_ref()
+            .map(|limit| limit.try_acquire(n))
+            .transpose()
+    }
+}
+
+#[test]
+fn token_bucket() {
+    let limit = RateLimit::new(1024);
+    let start = limit.bucket.lock().unwrap().refilled;
+    // the bucket starts out empty
+    assert_eq!(limit.take(512, start), Some(Duration::from_millis(500)));
+    assert_eq!(limit.take(512, start + Duration::from_millis(500)), None);
+    // it only ever fills up to a second's worth
+    let later = start + Duration::from_secs(10);
+    assert_eq!(limit.take(1024, later), None);
+    assert_eq!(limit.take(256, later), Some(Duration::from_millis(250)));
+    // but a transfer larger than that still goes through eventually
+    let much_later = later + Duration::from_secs(10);
+    assert_eq!(limit.take(3000, much_later), None);
+}
+
+#[tokio::test]
+async fn in_flight_budget() {
+    let limit = InFlightLimit::new(2 * crate::BLOCK_MAX);
+    let first = limit.acquire(crate::BLOCK_MAX).await;
+    let _second = limit.acquire(crate::BLOCK_MAX).await;
+    // the budget is spent, so the next block has to wait for one to arrive
+    let third = limit.acquire(crate::BLOCK_MAX);
+    tokio::pin!(third);
+    assert!(futures_util::poll!(&mut third).is_pending());
+    drop(first);
+    assert!(futures_util::poll!(&mut third).is_ready());
+    // a block larger than the whole budget doesn't wait forever
+    assert_eq!(InFlightLimit::new(0).bytes(), crate::BLOCK_MAX);
+}
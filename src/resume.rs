@@ -0,0 +1,195 @@
+//! Remembering which pieces of a download are safely stored, so that a later run can pick up
+//! where it left off rather than start over, even after a crash.
+//!
+//! The record is only ever brought up to date after the pieces it lists have been written out
+//! (and, unless the [`FsyncPolicy`](crate::storage::FsyncPolicy) says otherwise, synced to disk),
+//! and it's replaced as a whole by renaming a new copy over it. So however a run ends, the record
+//! never claims a piece that didn't make it to disk; at worst, it's missing the last few that
+//! did.
+//!
+//! The blocks we got of pieces that weren't finished are kept in the record itself, data and all,
+//! so that a later run can finish those pieces rather than fetch them all over again. Like any
+//! piece, they're checked against their hashes once they're complete.
+
+use crate::bitfield::Bitfield;
+use crate::storage::PartialPiece;
+use anyhow::Context;
+use serde_bencode::value::Value;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// What a resume record says an earlier run got of a torrent.
+#[derive(Debug)]
+pub(crate) struct Resumed {
+    /// The pieces that are stored.
+    pub(crate) have: Bitfield,
+    /// The blocks we got of pieces that weren't finished.
+    pub(crate) partial: Vec<PartialPiece>,
+}
+
+/// The record of which pieces of a torrent are stored, kept in a file of its own.
+#[derive(Debug)]
+pub(crate) struct ResumeFile {
+    path: PathBuf,
+    info_hash: [u8; 20],
+    npieces: usize,
+}
+
+impl ResumeFile {
+    /// The record for the torrent with `info_hash` and `npieces` pieces, kept at `path`.
+    pub(crate) fn new(path: PathBuf, info_hash: [u8; 20], npieces: usize) -> Self {
+        Self {
+            path,
+            info_hash,
+            npieces,
+        }
+    }
+
+    /// What the record says is stored, if there is a record for this torrent.
+    ///
+    /// A record for another torrent (or one we can't make sense of) is ignored, and will be
+    /// replaced.
+    pub(crate) fn load(&self) -> anyhow::Result<Option<Resumed>> {
+        let bytes = match std::fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("read {}", self.path.display())),
+        };
+        match self.parse(bytes) {
+            Ok(resumed) => Ok(Some(resumed)),
+            Err(e) => {
+                eprintln!("ignoring resume file {}: {e:#}", self.path.display());
+                Ok(None)
+            }
+        }
+    }
+
+    fn parse(&self, bytes: Vec<u8>) -> anyhow::Result<Resumed> {
+        let Value::Dict(mut dict) =
+            serde_bencode::from_bytes(&bytes).context("parse resume file")?
+        else {
+            anyhow::bail!("resume file is not a dictionary");
+        };
+        let (Some(Value::Bytes(info_hash)), Some(Value::Bytes(pieces))) =
+            (dict.remove(&b"info hash"[..]), dict.remove(&b"pieces"[..]))
+        else {
+            anyhow::bail!("resume file is missing its info hash or pieces");
+        };
+        anyhow::ensure!(
+            info_hash == self.info_hash,
+            "it's for another torrent ({})",
+            hex::encode(info_hash)
+        );
+        let have = Bitfield::from_payload(pieces, self.npieces)
+            .context("pieces do not match the torrent's")?;
+        // records from before partial pieces were kept don't have any
+        let partial = match dict.remove(&b"partial"[..]) {
+            Some(Value::List(partial)) => partial
+                .into_iter()
+                .map(PartialPiece::from_value)
+                .collect::<anyhow::Result<Vec<_>>>()
+                .context("parse partial pieces")?,
+            Some(_) => anyhow::bail!("partial pieces are not a list"),
+            None => Vec::new(),
+        };
+        for partial in &partial {
+            anyhow::ensure!(
+                partial.index() < self.npieces,
+                "partial piece {} is past the end of the torrent",
+                partial.index()
+            );
+        }
+        Ok(Resumed { have, partial })
+    }
+
+    /// Replace the record with one that says the pieces in `have` are stored, and that we got
+    /// some of the blocks of the `partial` ones.
+    ///
+    /// With `sync`, the new record is synced to disk before it replaces the old one, and the
+    /// replacement is synced too, so that it survives the machine crashing.
+    pub(crate) fn save(
+        &self,
+        have: &Bitfield,
+        partial: &[PartialPiece],
+        sync: bool,
+    ) -> anyhow::Result<()> {
+        let dict = HashMap::from([
+            (b"info hash".to_vec(), Value::Bytes(self.info_hash.to_vec())),
+            (
+                b"pieces".to_vec(),
+                Value::Bytes(have.to_payload(self.npieces)),
+            ),
+            (
+                b"partial".to_vec(),
+                Value::List(partial.iter().map(PartialPiece::to_value).collect()),
+            ),
+        ]);
+        let bytes =
+            serde_bencode::to_bytes(&Value::Dict(dict)).expect("bencoding a value can't fail");
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file =
+            std::fs::File::create(&tmp).with_context(|| format!("create {}", tmp.display()))?;
+        file.write_all(&bytes)
+            .with_context(|| format!("write {}", tmp.display()))?;
+        if sync {
+            file.sync_all()
+                .with_context(|| format!("sync {}", tmp.display()))?;
+        }
+        drop(file);
+        std::fs::rename(&tmp, &self.path)
+            .with_context(|| format!("replace {}", self.path.display()))?;
+        #[cfg(unix)]
+        if sync {
+            // the rename only survives a crash once the directory it's in has been synced
+            if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                std::fs::File::open(dir)
+                    .and_then(|dir| dir.sync_all())
+                    .with_context(|| format!("sync {}", dir.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the record, such as once the download is complete and there's nothing left to
+    /// resume.
+    pub(crate) fn remove(&self) -> anyhow::Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("remove {}", self.path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn resume_file_round_trip() {
+    let dir = tempfile::tempdir().expect("create temporary directory");
+    let path = dir.path().join("t.resume");
+    let file = ResumeFile::new(path.clone(), [1; 20], 10);
+    assert!(file.load().unwrap().is_none());
+
+    let mut have = Bitfield::empty();
+    have.set_piece(0);
+    have.set_piece(9);
+    let partial = vec![PartialPiece::new(3, 2, vec![false, true], vec![0, 0, 7, 7])];
+    file.save(&have, &partial, true).unwrap();
+    let loaded = file.load().unwrap().expect("saved");
+    assert_eq!(loaded.have.pieces().collect::<Vec<_>>(), [0, 9]);
+    assert_eq!(loaded.partial, partial);
+
+    // the same file means nothing for another torrent
+    assert!(ResumeFile::new(path.clone(), [2; 20], 10)
+        .load()
+        .unwrap()
+        .is_none());
+    assert!(ResumeFile::new(path, [1; 20], 4).load().unwrap().is_none());
+
+    file.remove().unwrap();
+    assert!(file.load().unwrap().is_none());
+    file.remove().unwrap();
+}
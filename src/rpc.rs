@@ -0,0 +1,426 @@
+//! A small HTTP API for controlling a long-running [`Client`].
+//!
+//! Every response is a JSON document. The endpoints are:
+//!
+//! - `GET /torrents`: the state of every torrent.
+//! - `POST /torrents`: start downloading the torrent whose `.torrent` file is the request body.
+//! - `GET /torrents/<id>`: the state of a single torrent.
+//! - `DELETE /torrents/<id>`: stop a torrent and forget about it.
+//! - `POST /torrents/<id>/pause` and `POST /torrents/<id>/resume`.
+//! - `POST /torrents/<id>/start`: start a queued torrent without waiting for its turn.
+//! - `GET /stats`: transfer totals across all torrents.
+//! - `GET /metrics`: the stats of every torrent, in the Prometheus text format (see
+//!   [`crate::metrics`]).
+//!
+//! There is no authentication, so the server should only ever listen on a local address.
+
+use crate::client::Client;
+use crate::download::DownloadHandle;
+use crate::metrics;
+use crate::stats::Stats;
+use crate::torrent::Torrent;
+use anyhow::Context;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// The most bytes of request line and headers we'll read.
+const MAX_HEAD: usize = 16 << 10;
+/// The largest request body (that is, torrent file) we'll accept.
+const MAX_BODY: usize = 16 << 20;
+
+/// A [`Client`] whose torrents can be controlled over HTTP.
+pub struct Daemon {
+    client: Client,
+    /// Where to write the files of completed torrents.
+    output: PathBuf,
+    torrents: Mutex<Torrents>,
+}
+
+#[derive(Default)]
+struct Torrents {
+    next_id: u64,
+    entries: BTreeMap<u64, Entry>,
+}
+
+struct Entry {
+    name: String,
+    length: usize,
+    stats: Arc<Stats>,
+    state: State,
+}
+
+enum State {
+    Running(DownloadHandle),
+    /// The download ended, either with `verified` of `total` pieces, or with an error.
+    Done {
+        verified: usize,
+        total: usize,
+        error: Option<String>,
+    },
+}
+
+impl Entry {
+    fn to_json(&self, id: u64) -> Value {
+        let progress = if self.length == 0 {
+            1.0
+        } else {
+            1.0 - self.stats.left() as f64 / self.length as f64
+        };
+        let (state, error) = match &self.state {
+            State::Running(handle) if handle.is_queued() => ("queued", None),
+            State::Running(handle) if handle.is_paused() => ("paused", None),
+            State::Running(_) => ("downloading", None),
+            State::Done { error: Some(e), .. } => ("failed", Some(e.clone())),
+            State::Done {
+                verified, total, ..
+            } if verified == total => ("finished", None),
+            State::Done { .. } => ("stopped", None),
+        };
+        json!({
+            "id": id,
+            "name": self.name,
+            "state": state,
+            "error": error,
+            "length": self.length,
+            "progress": progress,
+            "downloaded": self.stats.downloaded(),
+            "uploaded": self.stats.uploaded(),
+        })
+    }
+}
+
+impl Daemon {
+    /// A daemon that runs its torrents on `client`, and writes them to `output` once complete.
+    pub fn new(client: Client, output: PathBuf) -> Arc<Self> {
+        Arc::new(Self {
+            client,
+            output,
+            torrents: Mutex::new(Torrents::default()),
+        })
+    }
+
+    /// Start downloading `t`, and return the id it can be controlled with.
+    pub fn add(&self, t: &Torrent) -> u64 {
+        let handle = self.client.add(t);
+        let mut torrents = self.torrents.lock().expect("daemon lock poisoned");
+        let id = torrents.next_id;
+        torrents.next_id += 1;
+        torrents.entries.insert(
+            id,
+            Entry {
+                name: t.info.display_name().into_owned(),
+                length: handle.length(),
+                stats: handle.stats(),
+                state: State::Running(handle),
+            },
+        );
+        id
+    }
+
+    /// Gracefully stop every torrent.
+    pub fn stop(&self) {
+        self.client.stop();
+    }
+
+    /// Answer requests on `listener` forever.
+    ///
+    /// Completed torrents are written out to the output directory as they finish.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> anyhow::Result<()> {
+        let mut reap = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                conn = listener.accept() => {
+                    let (stream, addr) = conn.context("accept RPC connection")?;
+                    let this = Arc::clone(&self);
+                    tokio::spawn(async move {
+                        if let Err(e) = this.handle_connection(stream).await {
+                            eprintln!("RPC request from {addr} failed: {e:?}");
+                        }
+                    });
+                }
+                _ = reap.tick() => self.reap().await,
+            }
+        }
+    }
+
+    /// Collect the results of any downloads that have finished.
+    pub async fn reap(&self) {
+        let finished: Vec<_> = {
+            let mut torrents = self.torrents.lock().expect("daemon lock poisoned");
+            torrents
+                .entries
+                .iter_mut()
+                .filter(|(_, entry)| matches!(&entry.state, State::Running(h) if h.is_finished()))
+                .map(|(&id, entry)| {
+                    let placeholder = State::Done {
+                        verified: 0,
+                        total: 0,
+                        error: None,
+                    };
+                    (id, std::mem::replace(&mut entry.state, placeholder))
+                })
+                .collect()
+        };
+        for (id, state) in finished {
+            let State::Running(mut handle) = state else {
+                unreachable!("only running downloads are reaped");
+            };
+            let state = match handle.wait().await {
+                Ok(files) => {
+                    let error = if files.is_complete() {
+                        files
+                            .write_to_dir(&self.output)
+                            .await
+                            .err()
+                            .map(|e| format!("{e:#}"))
+                    } else {
+                        None
+                    };
+                    State::Done {
+                        verified: files.verified_pieces(),
+                        total: files.total_pieces(),
+                        error,
+                    }
+                }
+                Err(e) => State::Done {
+                    verified: 0,
+                    total: 0,
+                    error: Some(format!("{e:#}")),
+                },
+            };
+            if let Some(entry) = self
+                .torrents
+                .lock()
+                .expect("daemon lock poisoned")
+                .entries
+                .get_mut(&id)
+            {
+                entry.state = state;
+            }
+        }
+    }
+
+    async fn handle_connection(&self, mut stream: TcpStream) -> anyhow::Result<()> {
+        let (status, content_type, body) = match read_request(&mut stream).await {
+            Ok(request) if request.method == "GET" && request.path == "/metrics" => {
+                (200, metrics::CONTENT_TYPE, self.metrics())
+            }
+            Ok(request) => {
+                let (status, body) = self.handle(request).await;
+                (status, "application/json", body.to_string())
+            }
+            Err(e) => (
+                400,
+                "application/json",
+                json!({ "error": format!("{e:#}") }).to_string(),
+            ),
+        };
+        let reason = match status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            _ => "Error",
+        };
+        let response = format!(
+            "HTTP/1.1 {status} {reason}\r\n\
+             Content-Type: {content_type}\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream
+            .write_all(response.as_bytes())
+            .await
+            .context("write RPC response")?;
+        Ok(())
+    }
+
+    fn metrics(&self) -> String {
+        let torrents = self.torrents.lock().expect("daemon lock poisoned");
+        metrics::render(
+            torrents
+                .entries
+                .values()
+                .map(|entry| (entry.name.as_str(), &*entry.stats)),
+        )
+    }
+
+    async fn handle(&self, request: Request) -> (u16, Value) {
+        let segments: Vec<_> = request
+            .path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let id = |segment: &str| segment.parse::<u64>().ok();
+        match (request.method.as_str(), &segments[..]) {
+            ("GET", ["torrents"]) => {
+                let torrents = self.torrents.lock().expect("daemon lock poisoned");
+                let list: Vec<_> = torrents
+                    .entries
+                    .iter()
+                    .map(|(&id, entry)| entry.to_json(id))
+                    .collect();
+                (200, Value::Array(list))
+            }
+            ("POST", ["torrents"]) => match Torrent::from_bytes(&request.body) {
+                Ok(t) => match t.validate() {
+                    Ok(()) => (200, json!({ "id": self.add(&t) })),
+                    Err(e) => (400, json!({ "error": format!("invalid torrent: {e}") })),
+                },
+                Err(e) => (400, json!({ "error": format!("{e:#}") })),
+            },
+            ("GET", ["torrents", i]) => {
+                let torrents = self.torrents.lock().expect("daemon lock poisoned");
+                match id(i).and_then(|id| Some((id, torrents.entries.get(&id)?))) {
+                    Some((id, entry)) => (200, entry.to_json(id)),
+                    None => not_found(),
+                }
+            }
+            ("DELETE", ["torrents", i]) => {
+                let Some(id) = id(i) else {
+                    return not_found();
+                };
+                let removed = self
+                    .torrents
+                    .lock()
+                    .expect("daemon lock poisoned")
+                    .entries
+                    .remove(&id);
+                let Some(entry) = removed else {
+                    return not_found();
+                };
+                if let State::Running(mut handle) = entry.state {
+                    handle.stop();
+                    let _ = handle.wait().await;
+                }
+                (200, json!({ "removed": id }))
+            }
+            ("POST", ["torrents", i, action @ ("pause" | "resume")]) => {
+                let torrents = self.torrents.lock().expect("daemon lock poisoned");
+                match id(i).and_then(|id| torrents.entries.get(&id)) {
+                    Some(Entry {
+                        state: State::Running(handle),
+                        ..
+                    }) => {
+                        if *action == "pause" {
+                            handle.pause();
+                        } else {
+                            handle.resume();
+                        }
+                        (200, json!({ "paused": handle.is_paused() }))
+                    }
+                    Some(_) => (400, json!({ "error": "download has already ended" })),
+                    None => not_found(),
+                }
+            }
+            ("POST", ["torrents", i, "start"]) => {
+                let torrents = self.torrents.lock().expect("daemon lock poisoned");
+                match id(i).and_then(|id| torrents.entries.get(&id)) {
+                    Some(Entry {
+                        state: State::Running(handle),
+                        ..
+                    }) => {
+                        handle.force_start();
+                        (200, json!({ "started": true }))
+                    }
+                    Some(_) => (400, json!({ "error": "download has already ended" })),
+                    None => not_found(),
+                }
+            }
+            ("GET", ["stats"]) => {
+                let torrents = self.torrents.lock().expect("daemon lock poisoned");
+                let (downloaded, uploaded) =
+                    torrents.entries.values().fold((0, 0), |(down, up), entry| {
+                        (down + entry.stats.downloaded(), up + entry.stats.uploaded())
+                    });
+                (
+                    200,
+                    json!({
+                        "torrents": torrents.entries.len(),
+                        "downloaded": downloaded,
+                        "uploaded": uploaded,
+                    }),
+                )
+            }
+            _ => not_found(),
+        }
+    }
+}
+
+fn not_found() -> (u16, Value) {
+    (404, json!({ "error": "not found" }))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) body: Vec<u8>,
+}
+
+pub(crate) async fn read_request(stream: &mut TcpStream) -> anyhow::Result<Request> {
+    let mut buf = Vec::new();
+    let head_end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        anyhow::ensure!(buf.len() <= MAX_HEAD, "request head is too large");
+        let mut chunk = [0; 1024];
+        let n = stream.read(&mut chunk).await.context("read request")?;
+        anyhow::ensure!(n != 0, "connection closed mid-request");
+        buf.extend(&chunk[..n]);
+    };
+    let head = std::str::from_utf8(&buf[..head_end]).context("request head is not UTF-8")?;
+    let (method, path, content_length) = parse_head(head)?;
+    anyhow::ensure!(content_length <= MAX_BODY, "request body is too large");
+
+    let mut body = buf.split_off(head_end + 4);
+    if body.len() < content_length {
+        let have = body.len();
+        body.resize(content_length, 0);
+        stream
+            .read_exact(&mut body[have..])
+            .await
+            .context("read request body")?;
+    }
+    body.truncate(content_length);
+    Ok(Request { method, path, body })
+}
+
+/// Parse the request line and headers of an HTTP request into its method, path, and body length.
+fn parse_head(head: &str) -> anyhow::Result<(String, String, usize)> {
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().context("request is empty")?;
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(path), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        anyhow::bail!("malformed request line {request_line:?}");
+    };
+    let mut content_length = 0;
+    for line in lines {
+        let (name, value) = line.split_once(':').context("malformed header")?;
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            content_length = value.trim().parse().context("malformed content length")?;
+        }
+    }
+    Ok((method.to_string(), path.to_string(), content_length))
+}
+
+#[test]
+fn request_head() {
+    assert_eq!(
+        parse_head("POST /torrents HTTP/1.1\r\nHost: localhost\r\nContent-length: 42").unwrap(),
+        ("POST".to_string(), "/torrents".to_string(), 42)
+    );
+    assert_eq!(
+        parse_head("GET /stats HTTP/1.1").unwrap(),
+        ("GET".to_string(), "/stats".to_string(), 0)
+    );
+    assert!(parse_head("GET /stats").is_err());
+    assert!(parse_head("GET / HTTP/1.1\r\nContent-Length: lots").is_err());
+}
@@ -0,0 +1,886 @@
+//! Deciding which piece to download next, and which blocks of it each peer should fetch.
+//!
+//! None of this touches the network: the download tells the [`Scheduler`] what happened (a block
+//! arrived, a peer went away, a piece checked out), and the scheduler tells it what to do next.
+
+use crate::bitfield::Bitfield;
+use crate::picker::{Candidate, PiecePicker};
+use crate::piece::{self, Piece, PiecePolicy};
+use crate::priority::{Priorities, Priority};
+use crate::storage::PartialPiece;
+use crate::torrent::Torrent;
+use crate::BLOCK_MAX;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::Notify;
+
+/// A block of a piece for a peer to fetch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Block {
+    pub(crate) piece_i: usize,
+    pub(crate) begin: usize,
+    pub(crate) length: usize,
+}
+
+/// What to do after a piece has been dealt with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Next {
+    /// Download piece `piece_i` from the peers that have it.
+    Download { piece_i: usize },
+    /// None of our peers has any of the pieces we still need. `missing` is one of them, and there
+    /// are `others` more.
+    Wait { missing: usize, others: usize },
+    /// Every piece we want has been downloaded.
+    Done,
+}
+
+/// What a peer should do next while downloading the current piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Assignment {
+    Fetch(Block),
+    /// Every block is either received or being fetched by another peer, but those peers may yet
+    /// give theirs back.
+    Wait,
+    /// Every block of the piece has been received.
+    Done,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    Pending,
+    Fetching { peer_i: usize },
+    Received,
+}
+
+/// The piece that's being downloaded.
+#[derive(Debug)]
+struct Current {
+    piece: Piece,
+    blocks: Vec<BlockState>,
+    data: Vec<u8>,
+    received: usize,
+    /// The length of every block but the last.
+    block_size: usize,
+    /// The peers that have been given blocks of the piece, and haven't been lost since.
+    contributors: Vec<usize>,
+    /// The peer each block was received from, if it was received during this attempt at the
+    /// piece.
+    sources: Vec<Option<usize>>,
+}
+
+/// What became of a block a peer sent us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Received {
+    /// It had blocks we still needed.
+    Needed,
+    /// We already had all of it, typically because another peer sent it first in endgame.
+    Redundant,
+    /// It was of no use: a block of a piece we aren't fetching, or one that doesn't line up with
+    /// our blocks.
+    Unusable,
+}
+
+impl Current {
+    fn block(&self, block_i: usize) -> Block {
+        Block {
+            piece_i: self.piece.index(),
+            begin: block_i * self.block_size,
+            length: self
+                .block_size
+                .min(self.piece.length() - block_i * self.block_size),
+        }
+    }
+}
+
+/// Keeps track of which pieces of a download are still needed, and of the progress of the piece
+/// that's currently being downloaded.
+///
+/// Peers are referred to by their index in the download's list of peers, which only changes
+/// between pieces.
+#[derive(Debug)]
+pub(crate) struct Scheduler {
+    /// Pieces that at least one of our peers has.
+    need_pieces: Vec<Piece>,
+    picker: Arc<dyn PiecePicker>,
+    /// Pieces that none of our peers has (yet).
+    no_peers: Vec<Piece>,
+    skipped: Vec<Piece>,
+    current: Option<Current>,
+    /// The blocks we got of pieces that were set aside before they were complete.
+    partial: HashMap<usize, PartialPiece>,
+    verified: Vec<bool>,
+    /// Pieces that were [taken](Self::take_piece) to be checked against their hashes, and haven't
+    /// been found to match or not yet.
+    verifying: HashSet<usize>,
+    /// Whether to take blocks that span several of the blocks we asked for.
+    accept_oversized: bool,
+    /// The most peers to fetch blocks of the same piece from, if limited.
+    affinity: Option<usize>,
+    /// How many bytes to ask for at a time.
+    block_size: usize,
+    /// How many pieces at the front had been verified when last [asked](Self::verified_in_order).
+    in_order: usize,
+}
+
+impl Scheduler {
+    /// Schedule the download of `t`, with `picker` choosing the order of pieces, and `policy`
+    /// deciding which round each piece is in.
+    ///
+    /// Pieces are fetched in blocks of `block_size` bytes (at most [`BLOCK_MAX`]). If
+    /// `accept_oversized` is set, a peer may answer a request with a larger block than it was
+    /// asked for, as long as that block covers whole blocks of ours. With an `affinity`, the
+    /// blocks of each piece are fetched from at most that many peers (see
+    /// [`assign_block`](Self::assign_block)).
+    pub(crate) fn new(
+        t: &Torrent,
+        policy: PiecePolicy,
+        picker: Arc<dyn PiecePicker>,
+        block_size: usize,
+        accept_oversized: bool,
+        affinity: Option<usize>,
+    ) -> Self {
+        assert!(
+            (1..=BLOCK_MAX).contains(&block_size),
+            "blocks must be between 1 and {BLOCK_MAX} bytes"
+        );
+        let rounds = match policy {
+            PiecePolicy::Availability => vec![0; t.info.pieces.0.len()],
+            PiecePolicy::FileRoundRobin => piece::file_rounds(t),
+        };
+        let no_peers = rounds
+            .into_iter()
+            .enumerate()
+            .map(|(piece_i, round)| {
+                let mut piece = Piece::new(piece_i, t);
+                piece.set_round(round);
+                piece
+            })
+            .collect();
+        Self {
+            need_pieces: Vec::new(),
+            picker,
+            no_peers,
+            skipped: Vec::new(),
+            current: None,
+            partial: HashMap::new(),
+            verified: vec![false; t.info.pieces.0.len()],
+            verifying: HashSet::new(),
+            accept_oversized,
+            affinity,
+            block_size,
+            in_order: 0,
+        }
+    }
+
+    /// Re-prioritize the pieces that haven't been downloaded yet.
+    ///
+    /// Pieces that become [`Priority::Skip`] are set aside until their priority changes again.
+    pub(crate) fn set_priorities(&mut self, t: &Torrent, priorities: &Priorities) {
+        let mut pieces: Vec<_> = self
+            .need_pieces
+            .drain(..)
+            .chain(self.no_peers.drain(..))
+            .chain(self.skipped.drain(..))
+            .collect();
+        for piece in &mut pieces {
+            piece.set_priority(priorities.of_piece(t, piece.index()));
+        }
+        let (skip, keep): (Vec<_>, Vec<_>) = pieces
+            .into_iter()
+            .partition(|piece| piece.priority() == Priority::Skip);
+        self.skipped = skip;
+        self.no_peers = keep;
+    }
+
+    /// Pick the next piece to download, given the bitfield of each of our peers.
+    ///
+    /// Peers' bitfields change as they tell us about pieces they've finished, and some clients
+    /// only send part of their bitfield up front and the rest as a burst of haves, so which peers
+    /// have each piece (and so the order of the pieces) is brought up to date first.
+    pub(crate) fn next_piece(&mut self, peers: &[&Bitfield]) -> Next {
+        assert!(
+            self.current.is_none(),
+            "the current piece must be finished before picking the next one"
+        );
+        let pieces: Vec<_> = self
+            .need_pieces
+            .drain(..)
+            .chain(self.no_peers.drain(..))
+            .collect();
+        for mut piece in pieces {
+            piece.refresh_peers(peers);
+            if piece.peers().is_empty() {
+                self.no_peers.push(piece);
+            } else {
+                self.need_pieces.push(piece);
+            }
+        }
+
+        if self.need_pieces.is_empty() {
+            return match self.no_peers.first() {
+                Some(missing) => Next::Wait {
+                    missing: missing.index(),
+                    others: self.no_peers.len() - 1,
+                },
+                None => Next::Done,
+            };
+        }
+        let candidates: Vec<_> = self
+            .need_pieces
+            .iter()
+            .map(|piece| Candidate {
+                piece_i: piece.index(),
+                priority: piece.priority(),
+                round: piece.round(),
+                peers: piece.peers().len(),
+            })
+            .collect();
+        let pick = self.picker.pick(&candidates, self.verified());
+        let piece = self.need_pieces.swap_remove(pick);
+        let piece_i = piece.index();
+        let nblocks = piece.length().div_ceil(self.block_size);
+        let mut current = Current {
+            data: vec![0; piece.length()],
+            blocks: vec![BlockState::Pending; nblocks],
+            sources: vec![None; nblocks],
+            received: 0,
+            block_size: self.block_size,
+            contributors: Vec::new(),
+            piece,
+        };
+        if let Some(partial) = self.partial.remove(&piece_i) {
+            // blocks of another size don't line up with ours, so those are fetched again
+            if partial.data().len() == current.data.len() && partial.block_size() == self.block_size
+            {
+                for (block_i, state) in current.blocks.iter_mut().enumerate() {
+                    if partial.has_block(block_i) {
+                        *state = BlockState::Received;
+                        current.received += 1;
+                    }
+                }
+                current.data.copy_from_slice(partial.data());
+            }
+        }
+        self.current = Some(current);
+        Next::Download { piece_i }
+    }
+
+    /// The peers that have the current piece.
+    pub(crate) fn current_peers(&self) -> impl Iterator<Item = usize> + '_ {
+        self.current
+            .iter()
+            .flat_map(|current| current.piece.peers().iter().copied())
+    }
+
+    /// Give peer `peer_i` a block of the current piece to fetch.
+    ///
+    /// A piece is only as fast as the slowest peer fetching blocks of it, so with an affinity, the
+    /// first peers to ask are the only ones that get any of its blocks. The others wait until one
+    /// of those is [lost](Self::peer_lost), and then take its place.
+    pub(crate) fn assign_block(&mut self, peer_i: usize) -> Assignment {
+        let Some(current) = &mut self.current else {
+            return Assignment::Done;
+        };
+        if current.received == current.blocks.len() {
+            return Assignment::Done;
+        }
+        let Some(block_i) = current
+            .blocks
+            .iter()
+            .position(|&state| state == BlockState::Pending)
+        else {
+            return Assignment::Wait;
+        };
+        if !current.contributors.contains(&peer_i) {
+            if self
+                .affinity
+                .is_some_and(|affinity| current.contributors.len() >= affinity)
+            {
+                return Assignment::Wait;
+            }
+            current.contributors.push(peer_i);
+        }
+        current.blocks[block_i] = BlockState::Fetching { peer_i };
+        Assignment::Fetch(current.block(block_i))
+    }
+
+    /// Record that peer `peer_i` sent us `data` for the block of piece `piece_i` at `begin`.
+    ///
+    /// Returns whether that had any blocks we still needed, or if not, why. Blocks of other
+    /// pieces, blocks we already have, and blocks of the wrong length are ignored. Data that covers
+    /// several of our blocks is only taken if the scheduler accepts oversized blocks.
+    pub(crate) fn block_received(
+        &mut self,
+        peer_i: usize,
+        piece_i: usize,
+        begin: usize,
+        data: &[u8],
+    ) -> Received {
+        if self.verified.get(piece_i) == Some(&true) {
+            return Received::Redundant;
+        }
+        let Some(current) = &mut self.current else {
+            return Received::Unusable;
+        };
+        let block_size = current.block_size;
+        if current.piece.index() != piece_i || !begin.is_multiple_of(block_size) {
+            return Received::Unusable;
+        }
+        let first = begin / block_size;
+        let Some(first_block) = (first < current.blocks.len()).then(|| current.block(first)) else {
+            return Received::Unusable;
+        };
+        let end = begin + data.len();
+        let covers_whole_blocks = end <= current.piece.length()
+            && (end.is_multiple_of(block_size) || end == current.piece.length());
+        let blocks = if data.len() == first_block.length {
+            first..first + 1
+        } else if self.accept_oversized && data.len() > first_block.length && covers_whole_blocks {
+            first..end.div_ceil(block_size)
+        } else {
+            return Received::Unusable;
+        };
+
+        let mut needed = false;
+        for block_i in blocks {
+            // a block that was handed back (and maybe on to another peer) is still just as good
+            if current.blocks[block_i] != BlockState::Received {
+                current.blocks[block_i] = BlockState::Received;
+                current.sources[block_i] = Some(peer_i);
+                current.received += 1;
+                needed = true;
+            }
+        }
+        if !needed {
+            return Received::Redundant;
+        }
+        current.data[begin..end].copy_from_slice(data);
+        Received::Needed
+    }
+
+    /// Peer `peer_i` won't deliver the blocks it's fetching, so let other peers have them.
+    ///
+    /// This is the case when the peer disconnects, chokes us, or snubs us.
+    pub(crate) fn peer_lost(&mut self, peer_i: usize) {
+        let Some(current) = &mut self.current else {
+            return;
+        };
+        current
+            .contributors
+            .retain(|&contributor| contributor != peer_i);
+        for state in &mut current.blocks {
+            if *state == (BlockState::Fetching { peer_i }) {
+                *state = BlockState::Pending;
+            }
+        }
+    }
+
+    /// Take the contents of the current piece once all its blocks have arrived, along with the
+    /// peer each block came from (`None` for blocks kept from an earlier attempt at the piece).
+    ///
+    /// If blocks are still missing, the piece goes back to the pieces we need, and `None` is
+    /// returned. The blocks we did get are kept for when the piece is picked again.
+    ///
+    /// A piece that's taken is being verified until it's [verified](Self::piece_verified) or
+    /// [failed](Self::piece_failed).
+    pub(crate) fn take_piece(&mut self) -> Option<(Piece, Vec<u8>, Vec<Option<usize>>)> {
+        let current = self.current.take()?;
+        if current.received == current.blocks.len() {
+            self.verifying.insert(current.piece.index());
+            return Some((current.piece, current.data, current.sources));
+        }
+        let piece_i = current.piece.index();
+        if current.received > 0 {
+            let blocks = current
+                .blocks
+                .iter()
+                .map(|&state| state == BlockState::Received)
+                .collect();
+            self.partial.insert(
+                piece_i,
+                PartialPiece::new(piece_i, current.block_size, blocks, current.data),
+            );
+        }
+        self.no_peers.push(current.piece);
+        None
+    }
+
+    /// Put a piece that was [taken](Self::take_piece) back among the pieces we need, because its
+    /// contents didn't match its hash.
+    ///
+    /// None of its blocks can be trusted, so it's downloaded again from scratch.
+    pub(crate) fn piece_failed(&mut self, piece: Piece) {
+        self.verifying.remove(&piece.index());
+        self.partial.remove(&piece.index());
+        self.no_peers.push(piece);
+    }
+
+    /// The pieces we have some, but not all, of the blocks of.
+    pub(crate) fn partial_pieces(&self) -> impl Iterator<Item = &PartialPiece> {
+        self.partial.values()
+    }
+
+    /// Pick up the blocks an earlier run got of a piece, so that only the rest are fetched.
+    pub(crate) fn restore_partial(&mut self, partial: PartialPiece) {
+        if !self.verified[partial.index()] {
+            self.partial.insert(partial.index(), partial);
+        }
+    }
+
+    /// Record that piece `piece_i` was stored without being downloaded, so it no longer needs to
+    /// be.
+    ///
+    /// Returns whether that's news, which it isn't if the piece was verified already. Nor is it
+    /// for a piece that's being verified: the check will tell.
+    pub(crate) fn piece_stored(&mut self, piece_i: usize) -> bool {
+        if self.verified[piece_i] || self.verifying.contains(&piece_i) {
+            return false;
+        }
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|current| current.piece.index() == piece_i)
+        {
+            self.current = None;
+        }
+        for pieces in [&mut self.need_pieces, &mut self.no_peers, &mut self.skipped] {
+            pieces.retain(|piece| piece.index() != piece_i);
+        }
+        self.partial.remove(&piece_i);
+        self.verified[piece_i] = true;
+        true
+    }
+
+    /// Record that piece `piece_i` matched its hash and has been stored.
+    ///
+    /// Returns whether that's news, which it isn't if the piece was already verified.
+    pub(crate) fn piece_verified(&mut self, piece_i: usize) -> bool {
+        self.verifying.remove(&piece_i);
+        !std::mem::replace(&mut self.verified[piece_i], true)
+    }
+
+    /// How many pieces have been verified.
+    pub(crate) fn verified(&self) -> usize {
+        self.verified.iter().filter(|&&verified| verified).count()
+    }
+
+    /// How many pieces at the front of the torrent have all been verified, if that's more than
+    /// the last time this was called.
+    ///
+    /// Pieces are verified in whatever order they happen to complete in, which is no use to
+    /// something that consumes the torrent's data front to back, such as a player streaming it.
+    /// This puts them back in order: a piece only counts once every piece before it does too.
+    pub(crate) fn verified_in_order(&mut self) -> Option<usize> {
+        let previous = self.in_order;
+        while self.verified.get(self.in_order) == Some(&true) {
+            self.in_order += 1;
+        }
+        (self.in_order > previous).then_some(self.in_order)
+    }
+}
+
+/// A [`Scheduler`] that the peers downloading the current piece share.
+#[derive(Debug)]
+pub(crate) struct SharedScheduler {
+    scheduler: Mutex<Scheduler>,
+    /// Woken whenever blocks are handed back or the piece completes, for peers that are waiting
+    /// for something to fetch.
+    changed: Notify,
+}
+
+impl SharedScheduler {
+    pub(crate) fn new(scheduler: Scheduler) -> Self {
+        Self {
+            scheduler: Mutex::new(scheduler),
+            changed: Notify::new(),
+        }
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Scheduler> {
+        self.scheduler.lock().expect("scheduler lock poisoned")
+    }
+
+    /// The next block of the current piece for peer `peer_i` to fetch.
+    ///
+    /// Returns `None` once every block of the piece has been received.
+    pub(crate) async fn assign_block(&self, peer_i: usize) -> Option<Block> {
+        loop {
+            // register for wakeups before looking, so that we can't miss one in between
+            let changed = self.changed.notified();
+            let assignment = self.lock().assign_block(peer_i);
+            match assignment {
+                Assignment::Fetch(block) => return Some(block),
+                Assignment::Wait => changed.await,
+                Assignment::Done => return None,
+            }
+        }
+    }
+
+    /// The next block of the current piece for peer `peer_i` to fetch, without waiting for one
+    /// to be handed back if every block is taken.
+    pub(crate) fn try_assign_block(&self, peer_i: usize) -> Assignment {
+        self.lock().assign_block(peer_i)
+    }
+
+    /// See [`Scheduler::block_received`].
+    pub(crate) fn block_received(
+        &self,
+        peer_i: usize,
+        piece_i: usize,
+        begin: usize,
+        data: &[u8],
+    ) -> Received {
+        let received = self.lock().block_received(peer_i, piece_i, begin, data);
+        if received == Received::Needed {
+            self.changed.notify_waiters();
+        }
+        received
+    }
+
+    /// See [`Scheduler::peer_lost`].
+    pub(crate) fn peer_lost(&self, peer_i: usize) {
+        self.lock().peer_lost(peer_i);
+        self.changed.notify_waiters();
+    }
+}
+
+#[test]
+fn blocks_move_between_peers() {
+    use crate::picker::{ByPriority, MostAvailable};
+    use crate::torrent::{Hashes, Info, Keys};
+    // two pieces: a full one of two blocks, and a short one of a single block
+    let t = Torrent::new(
+        String::new(),
+        Info {
+            name: "f".into(),
+            name_utf8: None,
+            plength: 2 * BLOCK_MAX,
+            pieces: Hashes(vec![[0; 20]; 2]),
+            private: None,
+            keys: Keys::SingleFile {
+                length: 2 * BLOCK_MAX + 10,
+            },
+        },
+    );
+    let mut s = Scheduler::new(
+        &t,
+        PiecePolicy::Availability,
+        Arc::new(ByPriority(MostAvailable)),
+        BLOCK_MAX,
+        false,
+        None,
+    );
+    assert_eq!(
+        s.next_piece(&[]),
+        Next::Wait {
+            missing: 0,
+            others: 1
+        }
+    );
+
+    // peer 0 only has piece 0, and peer 1 has both; the piece more peers have goes first
+    let (mut only_first, mut both) = (Bitfield::empty(), Bitfield::empty());
+    only_first.set_piece(0);
+    both.set_piece(0);
+    both.set_piece(1);
+    assert_eq!(
+        s.next_piece(&[&only_first, &both]),
+        Next::Download { piece_i: 0 }
+    );
+    let mut have = s.current_peers().collect::<Vec<_>>();
+    have.sort();
+    assert_eq!(have, [0, 1]);
+
+    let first = Block {
+        piece_i: 0,
+        begin: 0,
+        length: BLOCK_MAX,
+    };
+    let second = Block {
+        begin: BLOCK_MAX,
+        ..first
+    };
+    assert_eq!(s.assign_block(1), Assignment::Fetch(first));
+    assert_eq!(s.assign_block(2), Assignment::Fetch(second));
+    assert_eq!(s.assign_block(1), Assignment::Wait);
+
+    // peer 2 goes away, so its block is up for grabs again
+    s.peer_lost(2);
+    assert_eq!(s.assign_block(1), Assignment::Fetch(second));
+    assert_eq!(
+        s.block_received(1, 0, BLOCK_MAX, &[2; BLOCK_MAX]),
+        Received::Needed
+    );
+    assert_eq!(
+        s.block_received(1, 0, BLOCK_MAX, &[2; BLOCK_MAX]),
+        Received::Redundant
+    );
+    assert_eq!(s.block_received(0, 1, 0, &[1; 10]), Received::Unusable);
+    assert_eq!(s.block_received(0, 0, 0, &[1; 10]), Received::Unusable);
+    assert_eq!(s.assign_block(1), Assignment::Wait);
+    // a block that arrives from a peer other than the one fetching it is just as good
+    assert_eq!(s.block_received(2, 0, 0, &[1; BLOCK_MAX]), Received::Needed);
+    assert_eq!(s.assign_block(1), Assignment::Done);
+
+    let (piece, data, sources) = s.take_piece().unwrap();
+    assert_eq!(piece.index(), 0);
+    assert_eq!(sources, [Some(2), Some(1)]);
+    assert_eq!(&data[..BLOCK_MAX], &[1; BLOCK_MAX]);
+    assert_eq!(&data[BLOCK_MAX..], &[2; BLOCK_MAX]);
+    assert!(s.piece_verified(0));
+    assert_eq!(s.verified(), 1);
+
+    // an unfinished piece goes back into the queue
+    assert_eq!(
+        s.next_piece(&[&only_first, &both]),
+        Next::Download { piece_i: 1 }
+    );
+    assert_eq!(
+        s.assign_block(1),
+        Assignment::Fetch(Block {
+            piece_i: 1,
+            begin: 0,
+            length: 10
+        })
+    );
+    assert!(s.take_piece().is_none());
+    assert_eq!(
+        s.next_piece(&[&only_first, &both]),
+        Next::Download { piece_i: 1 }
+    );
+
+    let mut priorities = Priorities::default();
+    priorities.set_piece(1, Priority::Skip);
+    s.take_piece();
+    s.set_priorities(&t, &priorities);
+    assert_eq!(s.next_piece(&[&only_first, &both]), Next::Done);
+}
+
+#[test]
+fn oversized_blocks() {
+    use crate::picker::{ByPriority, MostAvailable};
+    use crate::torrent::{Hashes, Info, Keys};
+    // a single piece of three blocks, the last of them short
+    let length = 2 * BLOCK_MAX + 100;
+    let t = Torrent::new(
+        String::new(),
+        Info {
+            name: "f".into(),
+            name_utf8: None,
+            plength: 4 * BLOCK_MAX,
+            pieces: Hashes(vec![[0; 20]]),
+            private: None,
+            keys: Keys::SingleFile { length },
+        },
+    );
+    let everyone = Bitfield::from_payload(vec![0x80], 1).unwrap();
+    for accept in [false, true] {
+        let mut s = Scheduler::new(
+            &t,
+            PiecePolicy::Availability,
+            Arc::new(ByPriority(MostAvailable)),
+            BLOCK_MAX,
+            accept,
+            None,
+        );
+        assert_eq!(s.next_piece(&[&everyone]), Next::Download { piece_i: 0 });
+        // half a block past the first one doesn't line up with our blocks either way
+        let needed = |received| received == Received::Needed;
+        assert!(!needed(s.block_received(
+            0,
+            0,
+            0,
+            &[1; BLOCK_MAX + BLOCK_MAX / 2]
+        )));
+        assert_eq!(
+            needed(s.block_received(0, 0, 0, &[1; 2 * BLOCK_MAX])),
+            accept
+        );
+        assert!(!needed(s.block_received(
+            0,
+            0,
+            BLOCK_MAX,
+            &[2; BLOCK_MAX + 200]
+        )));
+        assert!(needed(s.block_received(0, 0, 2 * BLOCK_MAX, &[3; 100])));
+        if accept {
+            assert_eq!(s.assign_block(0), Assignment::Done);
+            let (_, data, _) = s.take_piece().unwrap();
+            assert_eq!(data.len(), length);
+            assert!(data[..2 * BLOCK_MAX].iter().all(|&b| b == 1));
+        } else {
+            assert!(s.take_piece().is_none());
+        }
+    }
+}
+
+#[test]
+fn partial_pieces_are_kept() {
+    use crate::picker::{ByPriority, MostAvailable};
+    use crate::torrent::{Hashes, Info, Keys};
+    let t = Torrent::new(
+        String::new(),
+        Info {
+            name: "f".into(),
+            name_utf8: None,
+            plength: 3 * BLOCK_MAX,
+            pieces: Hashes(vec![[0; 20]]),
+            private: None,
+            keys: Keys::SingleFile {
+                length: 3 * BLOCK_MAX,
+            },
+        },
+    );
+    let everyone = Bitfield::from_payload(vec![0x80], 1).unwrap();
+    let mut s = Scheduler::new(
+        &t,
+        PiecePolicy::Availability,
+        Arc::new(ByPriority(MostAvailable)),
+        BLOCK_MAX,
+        false,
+        None,
+    );
+    assert_eq!(s.next_piece(&[&everyone]), Next::Download { piece_i: 0 });
+    assert_eq!(
+        s.block_received(0, 0, BLOCK_MAX, &[7; BLOCK_MAX]),
+        Received::Needed
+    );
+    assert!(s.take_piece().is_none());
+
+    let partial: Vec<_> = s.partial_pieces().cloned().collect();
+    assert_eq!(partial.len(), 1);
+    assert!(!partial[0].has_block(0) && partial[0].has_block(1) && !partial[0].has_block(2));
+    assert_eq!(
+        PartialPiece::from_bytes(&partial[0].to_bytes()).unwrap(),
+        partial[0]
+    );
+
+    // when the piece comes around again, only the blocks we're missing are fetched
+    assert_eq!(s.next_piece(&[&everyone]), Next::Download { piece_i: 0 });
+    let Assignment::Fetch(first) = s.assign_block(0) else {
+        panic!("block 0 is still missing");
+    };
+    let Assignment::Fetch(last) = s.assign_block(0) else {
+        panic!("block 2 is still missing");
+    };
+    assert_eq!((first.begin, last.begin), (0, 2 * BLOCK_MAX));
+    assert_eq!(s.block_received(0, 0, 0, &[1; BLOCK_MAX]), Received::Needed);
+    assert_eq!(
+        s.block_received(0, 0, 2 * BLOCK_MAX, &[3; BLOCK_MAX]),
+        Received::Needed
+    );
+    let (_, data, _) = s.take_piece().unwrap();
+    assert_eq!(data[BLOCK_MAX], 7);
+    assert_eq!(s.partial_pieces().count(), 0);
+}
+
+#[test]
+fn piece_affinity() {
+    use crate::picker::{ByPriority, MostAvailable};
+    use crate::torrent::{Hashes, Info, Keys};
+    let t = Torrent::new(
+        String::new(),
+        Info {
+            name: "f".into(),
+            name_utf8: None,
+            plength: 3 * BLOCK_MAX,
+            pieces: Hashes(vec![[0; 20]]),
+            private: None,
+            keys: Keys::SingleFile {
+                length: 3 * BLOCK_MAX,
+            },
+        },
+    );
+    let everyone = Bitfield::from_payload(vec![0x80], 1).unwrap();
+    let mut s = Scheduler::new(
+        &t,
+        PiecePolicy::Availability,
+        Arc::new(ByPriority(MostAvailable)),
+        BLOCK_MAX,
+        false,
+        Some(1),
+    );
+    assert_eq!(
+        s.next_piece(&[&everyone, &everyone]),
+        Next::Download { piece_i: 0 }
+    );
+
+    // the first peer to ask gets every block, while the other waits
+    assert_eq!(
+        s.assign_block(0),
+        Assignment::Fetch(s.current.as_ref().unwrap().block(0))
+    );
+    assert_eq!(s.assign_block(1), Assignment::Wait);
+    assert!(matches!(s.assign_block(0), Assignment::Fetch(_)));
+
+    // until the first one goes away, and the other takes over its blocks
+    s.peer_lost(0);
+    assert!(matches!(s.assign_block(1), Assignment::Fetch(_)));
+    assert_eq!(s.assign_block(0), Assignment::Wait);
+}
+
+#[test]
+fn verified_in_order() {
+    use crate::picker::{ByPriority, MostAvailable};
+    let (t, _) = crate::testing::generate(40, 10);
+    let mut s = Scheduler::new(
+        &t,
+        PiecePolicy::Availability,
+        Arc::new(ByPriority(MostAvailable)),
+        BLOCK_MAX,
+        false,
+        None,
+    );
+    assert_eq!(s.verified_in_order(), None);
+    assert!(s.piece_verified(2));
+    assert_eq!(s.verified_in_order(), None);
+    assert!(s.piece_stored(0));
+    assert_eq!(s.verified_in_order(), Some(1));
+    assert_eq!(s.verified_in_order(), None);
+    // piece 1 fills the gap, and so releases piece 2 along with it
+    assert!(s.piece_verified(1));
+    assert_eq!(s.verified_in_order(), Some(3));
+    assert!(s.piece_verified(3));
+    assert_eq!(s.verified_in_order(), Some(4));
+}
+
+#[test]
+fn stored_while_verifying() {
+    use crate::picker::{ByPriority, MostAvailable};
+    let (t, _) = crate::testing::generate(20, 10);
+    let mut s = Scheduler::new(
+        &t,
+        PiecePolicy::Availability,
+        Arc::new(ByPriority(MostAvailable)),
+        BLOCK_MAX,
+        false,
+        None,
+    );
+    let all = Bitfield::full(2);
+    let take = |s: &mut Scheduler| {
+        let Next::Download { piece_i } = s.next_piece(&[&all]) else {
+            panic!("a piece is needed");
+        };
+        let Assignment::Fetch(block) = s.assign_block(0) else {
+            panic!("the piece has a block to fetch");
+        };
+        s.block_received(0, piece_i, block.begin, &vec![0; block.length]);
+        s.take_piece().expect("the piece is complete")
+    };
+
+    // the piece is filled in while it's being checked, which leaves it to the check
+    let (piece, ..) = take(&mut s);
+    assert!(!s.piece_stored(piece.index()));
+    assert!(s.piece_verified(piece.index()));
+    assert!(!s.piece_verified(piece.index()));
+    assert!(!s.piece_stored(piece.index()));
+
+    // and if the check fails after all, the piece is fetched again
+    let (piece, ..) = take(&mut s);
+    let piece_i = piece.index();
+    assert!(!s.piece_stored(piece_i));
+    s.piece_failed(piece);
+    assert_eq!(s.next_piece(&[&all]), Next::Download { piece_i });
+    assert!(s.piece_stored(piece_i));
+    assert_eq!(s.verified(), 2);
+}
@@ -0,0 +1,400 @@
+//! Seeding torrents whose data we already have.
+//!
+//! Seeding only ever waits for peers to connect to us: it announces to each torrent's tracker so
+//! that peers learn about us, but never connects out to the peers the tracker hands back.
+
+use crate::download::DownloadConfig;
+use crate::peer::{self, Connection, Handshake, Peer};
+use crate::stats::{DownloadEvent, Stats};
+use crate::storage::{Layout, MemoryStorage, Pieces};
+use crate::swarm::{Swarm, SwarmState};
+use crate::torrent::Torrent;
+use crate::tracker::{AnnounceClient, AnnounceSession, Event, TrackerConfig};
+use anyhow::Context;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+
+/// How long a peer that connects to us gets to send its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to check whether a torrent has reached its seed ratio limit.
+const RATIO_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A torrent whose local data has been verified, and which is ready to be seeded.
+pub struct Seed {
+    torrent: Torrent,
+    info_hash: [u8; 20],
+    metadata: Arc<[u8]>,
+    swarm: Arc<Swarm>,
+    stats: Arc<Stats>,
+    /// Whether we only have some of the pieces (see [`Seed::verify_partial`]).
+    partial: bool,
+    /// Cancelled once the torrent has reached its seed limits, at which point its peers are
+    /// disconnected and no new ones are let in.
+    finished: CancellationToken,
+}
+
+impl Seed {
+    /// Check the data for `t` in `dir` against the torrent's piece hashes.
+    ///
+    /// The data is expected where a download into `dir` would have put it: a single-file torrent
+    /// is the file `dir/<name>`, and a multi-file torrent is the directory `dir/<name>`. Seeding
+    /// requires every piece to match.
+    pub async fn verify(t: &Torrent, dir: &Path, config: &DownloadConfig) -> anyhow::Result<Self> {
+        Self::check(t, dir, config, false).await
+    }
+
+    /// Like [`verify`](Self::verify), but for the data of a download that only fetched some of
+    /// the torrent's files, which we then seed as a partial seed (BEP 21).
+    ///
+    /// Files that are missing, or not their full length, are left out, and we only serve the
+    /// pieces that lie entirely within the others. Those must all match, and there must be at
+    /// least one of them.
+    pub async fn verify_partial(
+        t: &Torrent,
+        dir: &Path,
+        config: &DownloadConfig,
+    ) -> anyhow::Result<Self> {
+        Self::check(t, dir, config, true).await
+    }
+
+    async fn check(
+        t: &Torrent,
+        dir: &Path,
+        config: &DownloadConfig,
+        partial: bool,
+    ) -> anyhow::Result<Self> {
+        t.validate().context("invalid torrent")?;
+        let metadata: Arc<[u8]> = t.info_bytes().into();
+
+        let mut data = Vec::with_capacity(t.length());
+        // the byte ranges of the files we don't have, if partial
+        let mut missing: Vec<Range<usize>> = Vec::new();
+        for (path, length) in Layout::default().files(t, dir)? {
+            let bytes = match tokio::fs::read(&path).await {
+                Ok(bytes) if partial && bytes.len() != length => None,
+                Err(e) if partial && e.kind() == std::io::ErrorKind::NotFound => None,
+                result => Some(result.with_context(|| format!("read {}", path.display()))?),
+            };
+            let Some(bytes) = bytes else {
+                missing.push(data.len()..data.len() + length);
+                data.resize(data.len() + length, 0);
+                continue;
+            };
+            anyhow::ensure!(
+                bytes.len() == length,
+                "{} is {} bytes, but the torrent says it should be {length}",
+                path.display(),
+                bytes.len()
+            );
+            data.extend(bytes);
+        }
+
+        // TODO: like downloads, this keeps the whole torrent in memory.
+        let stats = Arc::new(config.torrent_stats(t, t.length()));
+        let pieces = Arc::new(Pieces::new(
+            Arc::new(MemoryStorage::default()),
+            config.read_cache_size,
+        ));
+        let (mut mismatched, mut have) = (0, 0);
+        for (piece_i, (piece, hash)) in data
+            .chunks(t.info.plength)
+            .zip(&t.info.pieces.0)
+            .enumerate()
+        {
+            let start = piece_i * t.info.plength;
+            let end = start + piece.len();
+            if missing
+                .iter()
+                .any(|file| file.start < end && file.end > start)
+            {
+                continue;
+            }
+            if config.hasher.hash(piece) != *hash {
+                stats.add_hash_failure(piece_i);
+                mismatched += 1;
+                continue;
+            }
+            pieces.write_verified(piece_i, piece)?;
+            stats.piece_verified(piece_i, piece.len());
+            have += 1;
+        }
+        let npieces = t.info.pieces.0.len();
+        anyhow::ensure!(
+            mismatched == 0,
+            "{mismatched} of {npieces} pieces of {} don't match the torrent",
+            t.info.display_name()
+        );
+        anyhow::ensure!(
+            have > 0 || npieces == 0,
+            "we have none of the pieces of {}",
+            t.info.display_name()
+        );
+
+        let (swarm, _candidates) = Swarm::new(
+            Arc::clone(&stats),
+            t.is_private(),
+            pieces,
+            npieces,
+            config.torrent_peer_id(),
+            config,
+        );
+        // we never download while seeding, even the pieces we're missing
+        swarm.set_upload_only();
+        Ok(Self {
+            torrent: t.clone(),
+            info_hash: t.info_hash(),
+            metadata,
+            swarm,
+            stats,
+            partial: have < npieces,
+            finished: CancellationToken::new(),
+        })
+    }
+
+    pub fn torrent(&self) -> &Torrent {
+        &self.torrent
+    }
+
+    /// The transfer counters of the torrent.
+    pub fn stats(&self) -> Arc<Stats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Whether we only have some of the torrent's pieces, and so seed it as a partial seed.
+    pub fn is_partial(&self) -> bool {
+        self.partial
+    }
+
+    /// Whether we've stopped seeding the torrent, having reached its seed ratio or time limit.
+    pub fn is_finished(&self) -> bool {
+        self.finished.is_cancelled()
+    }
+
+    /// How many times over we've uploaded what we downloaded of the torrent, or its whole length
+    /// if we downloaded less than that (see [`DownloadConfig::seed_ratio_limit`]).
+    pub fn ratio(&self) -> f64 {
+        let totals = self.stats.totals();
+        totals.uploaded as f64 / totals.downloaded.max(self.torrent.length()).max(1) as f64
+    }
+
+    /// Wait until the torrent has been seeded for `time_limit` since `started`, or reached a
+    /// ratio of `ratio_limit`, whichever comes first.
+    async fn limit_reached(
+        &self,
+        ratio_limit: Option<f64>,
+        time_limit: Option<Duration>,
+        started: tokio::time::Instant,
+    ) {
+        let time = async {
+            match time_limit {
+                Some(limit) => tokio::time::sleep_until(started + limit).await,
+                None => std::future::pending().await,
+            }
+        };
+        let ratio = async {
+            match ratio_limit {
+                Some(limit) => {
+                    while self.ratio() < limit {
+                        tokio::time::sleep(RATIO_CHECK_INTERVAL).await;
+                    }
+                }
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = time => {}
+            _ = ratio => {}
+        }
+    }
+
+    /// The peers that are currently connected to us for this torrent.
+    pub fn swarm_state(&self) -> SwarmState {
+        self.swarm.state(self.torrent.info.pieces.0.len())
+    }
+}
+
+/// Seed `seeds` to peers that connect to `listener`, until `stop` is cancelled, or until every
+/// torrent has reached the seed limits in `config`.
+///
+/// Each torrent is announced to its tracker when seeding starts, again as often as the tracker
+/// asks, and one last time when seeding stops.
+pub async fn run(
+    seeds: Vec<Arc<Seed>>,
+    listener: TcpListener,
+    config: &DownloadConfig,
+    stop: CancellationToken,
+) -> anyhow::Result<()> {
+    let tracker_config = config.tracker_config();
+    let tracker = tracker_config.announcer()?;
+    let portmap = config.map_port();
+
+    let mut announcers = tokio::task::JoinSet::new();
+    for seed in &seeds {
+        announcers.spawn(announce(
+            Arc::clone(seed),
+            Arc::clone(&tracker),
+            tracker_config.clone(),
+            config.seed_ratio_limit,
+            config.seed_time_limit,
+            stop.clone(),
+        ));
+    }
+
+    let seeds: Arc<HashMap<_, _>> = Arc::new(
+        seeds
+            .into_iter()
+            .map(|seed| (seed.info_hash, seed))
+            .collect(),
+    );
+    let mut peers = tokio::task::JoinSet::new();
+    loop {
+        tokio::select! {
+            conn = listener.accept() => {
+                let (stream, addr) = match conn {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        // most likely we're out of file descriptors, which will pass as peers
+                        // disconnect
+                        eprintln!("failed to accept peer connection: {e:?}");
+                        continue;
+                    }
+                };
+                let seeds = Arc::clone(&seeds);
+                peers.spawn(async move {
+                    if let Err(e) = serve(stream, addr, &seeds).await {
+                        eprintln!("peer {addr} disconnected: {e:#}");
+                    }
+                });
+            }
+            Some(_) = peers.join_next() => {}
+            announcer = announcers.join_next() => {
+                if announcer.is_none() {
+                    eprintln!("every torrent has reached its seed limits");
+                    break;
+                }
+            }
+            _ = stop.cancelled() => break,
+        }
+    }
+
+    peers.abort_all();
+    while announcers.join_next().await.is_some() {}
+    for seed in seeds.values() {
+        config.record_stats(&seed.torrent, &seed.stats);
+    }
+    if let Some(portmap) = portmap {
+        portmap.shutdown().await;
+    }
+    Ok(())
+}
+
+/// Keep the tracker of `seed` up to date until `stop` is cancelled, or until it reaches its
+/// `ratio_limit` or `time_limit`, at which point it's [finished](Seed::is_finished).
+async fn announce(
+    seed: Arc<Seed>,
+    tracker: Arc<dyn AnnounceClient>,
+    config: TrackerConfig,
+    ratio_limit: Option<f64>,
+    time_limit: Option<Duration>,
+    stop: CancellationToken,
+) {
+    let started = tokio::time::Instant::now();
+    let t = &seed.torrent;
+    let mut session = AnnounceSession::new(
+        tracker,
+        t,
+        seed.swarm.peer_id(),
+        Arc::clone(&seed.stats),
+        config,
+    );
+    // a partial seed says so with every announce, rather than just once like the other events
+    let ongoing = seed.partial.then_some(Event::Paused);
+    let mut event = ongoing.or(Some(Event::Started));
+    loop {
+        match session.announce(event).await {
+            Ok(_) => event = ongoing,
+            Err(e) => {
+                eprintln!(
+                    "failed to announce {} to tracker: {e:?}",
+                    t.info.display_name()
+                );
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(session.next_announce()) => {}
+            _ = seed.limit_reached(ratio_limit, time_limit, started) => {
+                eprintln!(
+                    "done seeding {} (ratio {:.2})",
+                    t.info.display_name(),
+                    seed.ratio()
+                );
+                seed.finished.cancel();
+                seed.stats.emit(DownloadEvent::SeedingFinished);
+                break;
+            }
+            _ = stop.cancelled() => break,
+        }
+    }
+
+    if let Err(e) = session.stop().await {
+        eprintln!(
+            "failed to tell tracker we're no longer seeding {}: {e:?}",
+            t.info.display_name()
+        );
+    }
+}
+
+/// Handshake with a peer that connected to us, and then serve it whichever of `seeds` it asked
+/// for until it disconnects.
+async fn serve(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    seeds: &HashMap<[u8; 20], Arc<Seed>>,
+) -> anyhow::Result<()> {
+    let SocketAddr::V4(addr) = addr else {
+        anyhow::bail!("only IPv4 peers are supported");
+    };
+    let theirs = tokio::time::timeout(HANDSHAKE_TIMEOUT, peer::read_handshake(&mut stream))
+        .await
+        .context("peer took too long to send its handshake")??;
+    let info_hash = theirs.info_hash;
+    let seed = seeds
+        .get(&info_hash)
+        .with_context(|| format!("peer asked for unknown torrent {}", hex::encode(info_hash)))?;
+    anyhow::ensure!(
+        !seed.is_finished(),
+        "peer asked for {}, which we're done seeding",
+        seed.torrent.info.display_name()
+    );
+
+    let mut ours = Handshake::new(seed.info_hash, seed.swarm.peer_id());
+    ours.set_extension_protocol();
+    if seed.swarm.dht_port().is_some() {
+        ours.set_dht();
+    }
+    let npieces = seed.torrent.info.pieces.0.len();
+    let conn = Connection::accept(stream, addr, theirs, ours, npieces).await?;
+    let mut peer = Peer::accept(
+        conn,
+        Arc::clone(&seed.metadata),
+        Arc::clone(&seed.swarm),
+        seed.torrent.info.pieces.0.len(),
+    )
+    .await?;
+    eprintln!(
+        "peer {addr} ({}) connected for {}",
+        peer.client().as_deref().unwrap_or("unknown client"),
+        seed.torrent.info.display_name()
+    );
+    tokio::select! {
+        result = peer.serve() => result,
+        _ = seed.finished.cancelled() => Ok(()),
+    }
+}
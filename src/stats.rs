@@ -0,0 +1,316 @@
+use futures_util::Stream;
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::broadcast;
+
+/// How many events a subscriber may fall behind by before it starts missing some.
+const EVENT_BACKLOG: usize = 1024;
+
+/// Something that happened to a torrent, as reported by [`Stats::events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadEvent {
+    PieceVerified {
+        piece_i: usize,
+    },
+    HashFailed {
+        piece_i: usize,
+    },
+    /// The first `pieces` pieces have now all been verified.
+    ///
+    /// [`PieceVerified`](Self::PieceVerified) comes in whatever order pieces complete in, so this
+    /// is for consumers of the data that need it in order, such as a player streaming it. It
+    /// follows the `PieceVerified` of the piece that filled the gap.
+    VerifiedInOrder {
+        pieces: usize,
+    },
+    PeerConnected {
+        addr: SocketAddrV4,
+    },
+    PeerDropped {
+        addr: SocketAddrV4,
+    },
+    /// An announce went through, and the tracker gave us `peers` peers.
+    TrackerAnnounced {
+        peers: usize,
+    },
+    /// Every piece we wanted has been verified.
+    Completed,
+    /// We stopped seeding the torrent, having reached the ratio or seeding time we were to stop
+    /// at.
+    SeedingFinished,
+}
+
+/// A block of piece data that arrived from a peer, as reported by [`Stats::blocks`].
+///
+/// The block hasn't been checked yet: that happens once its whole piece is in, and is reported as
+/// a [`DownloadEvent`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockReceived {
+    pub piece_i: usize,
+    /// The offset of the block into its piece.
+    pub begin: usize,
+    pub length: usize,
+    /// The peer that sent it.
+    pub peer: SocketAddrV4,
+}
+
+/// Transfer counters for a single torrent, shared between the download and its peer connections.
+///
+/// The byte counts are what we report to the tracker on every announce; the rest are for
+/// monitoring (see [`crate::metrics`]). Whatever changes them is also published as a
+/// [`DownloadEvent`].
+#[derive(Debug)]
+pub struct Stats {
+    uploaded: AtomicUsize,
+    downloaded: AtomicUsize,
+    left: AtomicUsize,
+    snubs: AtomicUsize,
+    pieces_verified: AtomicUsize,
+    hash_failures: AtomicUsize,
+    corrupt: AtomicUsize,
+    redundant: AtomicUsize,
+    discarded: AtomicUsize,
+    tracker_errors: AtomicUsize,
+    peers: AtomicUsize,
+    events: broadcast::Sender<DownloadEvent>,
+    /// Kept apart from `events`, as there are so many more of them that they'd crowd those out of
+    /// the backlog of a subscriber that only cares about pieces.
+    blocks: broadcast::Sender<BlockReceived>,
+    /// What was transferred of the torrent in earlier runs.
+    previous: Totals,
+}
+
+/// The bytes of a torrent's data we've transferred, over however many runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Totals {
+    pub uploaded: usize,
+    pub downloaded: usize,
+}
+
+impl Totals {
+    /// How many times over we've uploaded what we downloaded, or `None` if we haven't downloaded
+    /// anything.
+    pub fn ratio(&self) -> Option<f64> {
+        (self.downloaded > 0).then(|| self.uploaded as f64 / self.downloaded as f64)
+    }
+}
+
+impl std::ops::Add for Totals {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            uploaded: self.uploaded + other.uploaded,
+            downloaded: self.downloaded + other.downloaded,
+        }
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl Stats {
+    /// Counters for a torrent that still needs `left` bytes to be complete.
+    ///
+    /// For a fresh download this is the length of the torrent, but for a resumed download it only
+    /// counts the pieces we don't already have.
+    pub fn new(left: usize) -> Self {
+        Self {
+            uploaded: AtomicUsize::new(0),
+            downloaded: AtomicUsize::new(0),
+            left: AtomicUsize::new(left),
+            snubs: AtomicUsize::new(0),
+            pieces_verified: AtomicUsize::new(0),
+            hash_failures: AtomicUsize::new(0),
+            corrupt: AtomicUsize::new(0),
+            redundant: AtomicUsize::new(0),
+            discarded: AtomicUsize::new(0),
+            tracker_errors: AtomicUsize::new(0),
+            peers: AtomicUsize::new(0),
+            events: broadcast::channel(EVENT_BACKLOG).0,
+            blocks: broadcast::channel(EVENT_BACKLOG).0,
+            previous: Totals::default(),
+        }
+    }
+
+    /// Carry on from the `previous` totals of earlier runs.
+    ///
+    /// Only [`totals`](Self::totals) include them: the other counters are just for this run.
+    pub fn with_previous(mut self, previous: Totals) -> Self {
+        self.previous = previous;
+        self
+    }
+
+    /// Every event from now on.
+    ///
+    /// A subscriber that falls more than a thousand or so events behind skips the ones it missed.
+    /// The stream ends once the torrent is done with, and nothing can happen to it anymore.
+    pub fn events(&self) -> impl Stream<Item = DownloadEvent> {
+        subscribe(&self.events)
+    }
+
+    /// Every block that arrives from now on, for progress displays finer-grained than pieces.
+    ///
+    /// Like with [`events`](Self::events), a subscriber that falls too far behind skips blocks.
+    pub fn blocks(&self) -> impl Stream<Item = BlockReceived> {
+        subscribe(&self.blocks)
+    }
+
+    pub(crate) fn emit(&self, event: DownloadEvent) {
+        // nobody listening is just fine
+        let _ = self.events.send(event);
+    }
+
+    /// The number of bytes of piece data we've sent to peers.
+    pub fn uploaded(&self) -> usize {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    /// The number of bytes of piece data we've received from peers.
+    pub fn downloaded(&self) -> usize {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// What we've transferred in this run and every earlier one we were told about (see
+    /// [`with_previous`](Self::with_previous)).
+    pub fn totals(&self) -> Totals {
+        self.previous
+            + Totals {
+                uploaded: self.uploaded(),
+                downloaded: self.downloaded(),
+            }
+    }
+
+    /// The number of bytes we still need before we have the whole torrent.
+    pub fn left(&self) -> usize {
+        self.left.load(Ordering::Relaxed)
+    }
+
+    /// The number of times a peer has snubbed us by not sending a block we requested in time.
+    pub fn snubs(&self) -> usize {
+        self.snubs.load(Ordering::Relaxed)
+    }
+
+    /// The number of pieces we've verified, whether downloaded or already on disk.
+    pub fn pieces_verified(&self) -> usize {
+        self.pieces_verified.load(Ordering::Relaxed)
+    }
+
+    /// The number of pieces whose data didn't match their hash.
+    pub fn hash_failures(&self) -> usize {
+        self.hash_failures.load(Ordering::Relaxed)
+    }
+
+    /// The number of bytes we downloaded of pieces that then didn't match their hash.
+    pub fn corrupt(&self) -> usize {
+        self.corrupt.load(Ordering::Relaxed)
+    }
+
+    /// The number of bytes of blocks that arrived after we already had them, such as when several
+    /// peers are asked for the same block in endgame.
+    pub fn redundant(&self) -> usize {
+        self.redundant.load(Ordering::Relaxed)
+    }
+
+    /// The number of bytes of blocks we had no use for, because we weren't fetching their piece
+    /// anymore, or they didn't line up with the blocks we ask for.
+    pub fn discarded(&self) -> usize {
+        self.discarded.load(Ordering::Relaxed)
+    }
+
+    /// The number of bytes of piece data that peers sent us for nothing: the
+    /// [corrupt](Self::corrupt), [redundant](Self::redundant), and [discarded](Self::discarded)
+    /// bytes together.
+    ///
+    /// A swarm that wastes a lot has peers sending bad data, or is being asked for the same
+    /// blocks more than it needs to be.
+    pub fn wasted(&self) -> usize {
+        self.corrupt() + self.redundant() + self.discarded()
+    }
+
+    /// The number of announces that failed, whether the tracker couldn't be reached or refused us.
+    pub fn tracker_errors(&self) -> usize {
+        self.tracker_errors.load(Ordering::Relaxed)
+    }
+
+    /// The number of peers we're currently connected to.
+    pub fn peers(&self) -> usize {
+        self.peers.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add_snub(&self) {
+        self.snubs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_uploaded(&self, n: usize) {
+        self.uploaded.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_downloaded(&self, n: usize) {
+        self.downloaded.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Record that `block` arrived, and count its bytes as downloaded.
+    pub(crate) fn block_received(&self, block: BlockReceived) {
+        self.add_downloaded(block.length);
+        let _ = self.blocks.send(block);
+    }
+
+    pub(crate) fn add_hash_failure(&self, piece_i: usize) {
+        self.hash_failures.fetch_add(1, Ordering::Relaxed);
+        self.emit(DownloadEvent::HashFailed { piece_i });
+    }
+
+    pub(crate) fn add_corrupt(&self, n: usize) {
+        self.corrupt.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_redundant(&self, n: usize) {
+        self.redundant.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_discarded(&self, n: usize) {
+        self.discarded.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_tracker_error(&self) {
+        self.tracker_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn peer_joined(&self, addr: SocketAddrV4) {
+        self.peers.fetch_add(1, Ordering::Relaxed);
+        self.emit(DownloadEvent::PeerConnected { addr });
+    }
+
+    pub(crate) fn peer_left(&self, addr: SocketAddrV4) {
+        self.peers.fetch_sub(1, Ordering::Relaxed);
+        self.emit(DownloadEvent::PeerDropped { addr });
+    }
+
+    /// Record that piece `piece_i`, of length `n`, has been verified, and so is no longer left.
+    pub(crate) fn piece_verified(&self, piece_i: usize, n: usize) {
+        self.pieces_verified.fetch_add(1, Ordering::Relaxed);
+        self.emit(DownloadEvent::PieceVerified { piece_i });
+        // saturate rather than wrap in case a piece is somehow verified twice
+        let _ = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(n))
+            });
+    }
+}
+
+fn subscribe<T: Clone + Send + 'static>(sender: &broadcast::Sender<T>) -> impl Stream<Item = T> {
+    futures_util::stream::unfold(sender.subscribe(), |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(item) => return Some((item, rx)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+}
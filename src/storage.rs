@@ -0,0 +1,695 @@
+//! Where the pieces of a torrent live once they've been downloaded, and how we read them back to
+//! serve them to other peers.
+
+use crate::bitfield::Bitfield;
+use crate::cache::PieceCache;
+use crate::disk::DiskStorage;
+use crate::download::DownloadConfig;
+use crate::hash::PieceHasher;
+use crate::mmap::MmapStorage;
+use crate::peer::InvalidRequest;
+use crate::resume::ResumeFile;
+use crate::torrent::{is_single_name, Keys, Torrent};
+use crate::BLOCK_MAX;
+use anyhow::Context;
+use serde_bencode::value::Value;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// How often to bring the resume record up to date under [`FsyncPolicy::Periodic`] (and
+/// [`FsyncPolicy::Never`]).
+const RESUME_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A place to keep verified pieces.
+pub trait Storage: Send + Sync {
+    /// Store the (verified) contents of piece `piece_i`.
+    fn write_piece(&self, piece_i: usize, data: &[u8]) -> anyhow::Result<()>;
+
+    /// Read back the contents of piece `piece_i`, which must have been written before.
+    fn read_piece(&self, piece_i: usize) -> anyhow::Result<Arc<[u8]>>;
+
+    /// Make sure every piece written so far has actually been stored, for storage that holds
+    /// on to writes to batch them up.
+    fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// [Flush](Self::flush) every piece written so far, and then make sure it's on the disk
+    /// itself, so that it survives the machine crashing and not only this process.
+    fn sync(&self) -> anyhow::Result<()> {
+        self.flush()
+    }
+}
+
+/// When to sync the pieces of a download to disk, and so how much of the download's resume
+/// record a crash can cost.
+///
+/// Pieces are only ever recorded as stored once they've been written out, and synced if the
+/// policy syncs at all, so the record never claims a piece that isn't there.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Never sync, and leave it to the operating system to write pieces to disk in its own time.
+    ///
+    /// Pieces are recorded every so often once they've been handed to the operating system, which
+    /// survives this process crashing, but not the machine.
+    Never,
+    /// Every so often, sync the pieces written so far and record them.
+    #[default]
+    Periodic,
+    /// Sync every piece, and record it, before moving on. The safest, but also the slowest, since
+    /// it leaves no writes to batch up.
+    Piece,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "never" => Ok(Self::Never),
+            "periodic" => Ok(Self::Periodic),
+            "piece" => Ok(Self::Piece),
+            _ => anyhow::bail!("unknown fsync policy {s:?} (expected never, periodic, or piece)"),
+        }
+    }
+}
+
+/// Which [`Storage`] a download keeps its pieces in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum StorageBackend {
+    /// A [`MemoryStorage`].
+    #[default]
+    Memory,
+    /// A [`MmapStorage`](crate::mmap::MmapStorage), with the torrent's files in this directory.
+    Mmap(PathBuf),
+    /// A [`DiskStorage`](crate::disk::DiskStorage), with the torrent's files in this directory.
+    Disk(PathBuf),
+}
+
+impl StorageBackend {
+    /// Open the storage for `t`. With `keep`, the files already there are kept as they are,
+    /// rather than truncated, for a download that's resuming.
+    pub(crate) fn open(
+        &self,
+        t: &Torrent,
+        config: &DownloadConfig,
+        keep: bool,
+    ) -> anyhow::Result<Arc<dyn Storage>> {
+        Ok(match self {
+            Self::Memory => Arc::new(MemoryStorage::default()),
+            Self::Mmap(dir) if keep => Arc::new(MmapStorage::open(t, dir, &config.layout)?),
+            Self::Mmap(dir) => Arc::new(MmapStorage::create(t, dir, &config.layout)?),
+            Self::Disk(dir) if keep => Arc::new(DiskStorage::open(
+                t,
+                dir,
+                &config.layout,
+                config.write_buffer_size,
+            )?),
+            Self::Disk(dir) => Arc::new(DiskStorage::create(
+                t,
+                dir,
+                &config.layout,
+                config.write_buffer_size,
+            )?),
+        })
+    }
+
+    /// Where the resume record of `t` goes, for storage that outlives the download.
+    pub(crate) fn resume_path(&self, t: &Torrent) -> Option<PathBuf> {
+        match self {
+            Self::Memory => None,
+            Self::Mmap(dir) | Self::Disk(dir) => {
+                Some(dir.join(format!(".{}.resume", hex::encode(t.info_hash()))))
+            }
+        }
+    }
+}
+
+/// Where the files of a torrent go inside the directory they're saved to.
+///
+/// By default, a single-file torrent's file and a multi-file torrent's directory are both named
+/// after the torrent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Layout {
+    /// The name to save a single-file torrent's file as instead.
+    pub file_name: Option<String>,
+    /// Where to put a multi-file torrent's files instead, relative to the directory they're saved
+    /// to (unless it's absolute). An empty path puts them straight into that directory.
+    pub dir: Option<PathBuf>,
+}
+
+impl Layout {
+    /// Where `t` goes in `output`: the file of a single-file torrent, or the directory of a
+    /// multi-file one.
+    pub fn root(&self, t: &Torrent, output: &Path) -> anyhow::Result<PathBuf> {
+        match &t.info.keys {
+            Keys::SingleFile { .. } => {
+                let name = self.file_name(t);
+                anyhow::ensure!(
+                    is_single_name(Path::new(&name)),
+                    "{} is not a file name",
+                    Path::new(&name).display()
+                );
+                Ok(output.join(name))
+            }
+            Keys::MultiFile { .. } => match &self.dir {
+                Some(dir) => Ok(output.join(dir)),
+                None => {
+                    let name = t.info.local_name();
+                    anyhow::ensure!(
+                        is_single_name(Path::new(&name)),
+                        "{} is not a directory name",
+                        Path::new(&name).display()
+                    );
+                    Ok(output.join(name))
+                }
+            },
+        }
+    }
+
+    /// The name of a single-file torrent's file.
+    pub(crate) fn file_name(&self, t: &Torrent) -> OsString {
+        match &self.file_name {
+            Some(name) => name.into(),
+            None => t.info.local_name(),
+        }
+    }
+
+    /// Where each file of `t` goes in `output`, along with how long it should be.
+    ///
+    /// Entries that stand for empty directories are left out.
+    pub fn files(&self, t: &Torrent, output: &Path) -> anyhow::Result<Vec<(PathBuf, usize)>> {
+        let root = self.root(t, output)?;
+        match &t.info.keys {
+            Keys::SingleFile { length } => Ok(vec![(root, *length)]),
+            Keys::MultiFile { files } => files
+                .iter()
+                .filter(|file| !file.is_dir())
+                .map(|file| {
+                    let relative = file.relative_path();
+                    anyhow::ensure!(
+                        relative
+                            .components()
+                            .all(|c| matches!(c, Component::Normal(_))),
+                        "refusing to place {} outside of {}",
+                        relative.display(),
+                        root.display()
+                    );
+                    Ok((root.join(relative), file.length))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The files a torrent's data is spread over on disk, for the storage that keeps pieces in them.
+///
+/// Each file is held as an `F` of the storage's choosing, such as the open file or a mapping of
+/// it.
+pub(crate) struct FileLayout<F> {
+    plength: usize,
+    length: usize,
+    /// Each (non-empty) file, along with where it starts in the torrent's data and its length.
+    files: Vec<(usize, usize, F)>,
+}
+
+impl<F> FileLayout<F> {
+    /// Open the files of `t` in `dir`, laid out by `layout`, creating them at their full length
+    /// if need be, and hold each (non-empty) one as what `hold` makes of it.
+    ///
+    /// With `truncate`, whatever the files already held is thrown away.
+    pub(crate) fn open(
+        t: &Torrent,
+        dir: &Path,
+        layout: &Layout,
+        truncate: bool,
+        mut hold: impl FnMut(PathBuf, File, usize) -> anyhow::Result<F>,
+    ) -> anyhow::Result<Self> {
+        let mut files = Vec::new();
+        let mut offset = 0;
+        for (path, length) in layout.files(t, dir)? {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("create {}", parent.display()))?;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(truncate)
+                .open(&path)
+                .with_context(|| format!("create {}", path.display()))?;
+            file.set_len(length as u64)
+                .with_context(|| format!("allocate {}", path.display()))?;
+            if length > 0 {
+                files.push((offset, length, hold(path, file, length)?));
+            }
+            offset += length;
+        }
+        Ok(Self {
+            plength: t.info.plength,
+            length: offset,
+            files,
+        })
+    }
+
+    /// The parts of the files that the `len` bytes at `offset` of the torrent's data are in: for
+    /// each, the file, where in it the part starts, where in the range it starts, and its length.
+    pub(crate) fn spans(
+        &self,
+        offset: usize,
+        len: usize,
+    ) -> impl Iterator<Item = (&F, usize, usize, usize)> + '_ {
+        let end = offset + len;
+        self.files.iter().filter_map(move |(start, length, file)| {
+            let from = offset.max(*start);
+            let to = end.min(start + length);
+            (from < to).then(|| (file, from - start, from - offset, to - from))
+        })
+    }
+
+    /// Where piece `piece_i` starts in the torrent's data, and how long it is.
+    pub(crate) fn piece_range(&self, piece_i: usize) -> anyhow::Result<(usize, usize)> {
+        let offset = piece_i * self.plength;
+        anyhow::ensure!(offset < self.length, "piece {piece_i} is past the end");
+        Ok((offset, self.plength.min(self.length - offset)))
+    }
+
+    pub(crate) fn plength(&self) -> usize {
+        self.plength
+    }
+
+    /// Every (non-empty) file.
+    pub(crate) fn files(&self) -> impl Iterator<Item = &F> + '_ {
+        self.files.iter().map(|(_, _, file)| file)
+    }
+}
+
+/// Keeps every piece in memory.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    pieces: Mutex<HashMap<usize, Arc<[u8]>>>,
+}
+
+impl Storage for MemoryStorage {
+    fn write_piece(&self, piece_i: usize, data: &[u8]) -> anyhow::Result<()> {
+        self.pieces
+            .lock()
+            .expect("storage lock poisoned")
+            .insert(piece_i, data.into());
+        Ok(())
+    }
+
+    fn read_piece(&self, piece_i: usize) -> anyhow::Result<Arc<[u8]>> {
+        self.pieces
+            .lock()
+            .expect("storage lock poisoned")
+            .get(&piece_i)
+            .cloned()
+            .with_context(|| format!("piece {piece_i} was never written"))
+    }
+}
+
+/// The blocks we have of a piece that wasn't finished, so that a later download of the piece can
+/// pick up where this one left off rather than start over.
+///
+/// With pieces of 16 MiB or more, throwing away the blocks of an unfinished piece can cost a lot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialPiece {
+    piece_i: usize,
+    /// The length of every block but the last.
+    block_size: usize,
+    /// Which of the piece's blocks we have.
+    blocks: Vec<bool>,
+    /// The whole piece, with zeroes for the blocks we don't have.
+    data: Vec<u8>,
+}
+
+impl PartialPiece {
+    pub(crate) fn new(piece_i: usize, block_size: usize, blocks: Vec<bool>, data: Vec<u8>) -> Self {
+        Self {
+            piece_i,
+            block_size,
+            blocks,
+            data,
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.piece_i
+    }
+
+    /// The size of the blocks the piece was fetched in.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Whether we have block `block_i` of the piece.
+    pub fn has_block(&self, block_i: usize) -> bool {
+        self.blocks.get(block_i).copied().unwrap_or(false)
+    }
+
+    /// The contents of the piece, with zeroes for the blocks we don't have.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Encode the partial piece for a resume file, as a bencoded dictionary of the piece index,
+    /// its block size, its block bitmap (in the same layout as a peer's bitfield), and its data.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(&self.to_value()).expect("bencoding a value can't fail")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        Self::from_value(serde_bencode::from_bytes(bytes).context("parse partial piece")?)
+    }
+
+    /// The dictionary [`to_bytes`](Self::to_bytes) encodes, for a resume file to embed.
+    pub(crate) fn to_value(&self) -> Value {
+        let mut blocks = Bitfield::empty();
+        for (block_i, _) in self.blocks.iter().enumerate().filter(|(_, &have)| have) {
+            blocks.set_piece(block_i);
+        }
+        let dict = HashMap::from([
+            (b"piece".to_vec(), Value::Int(self.piece_i as i64)),
+            (b"block".to_vec(), Value::Int(self.block_size as i64)),
+            (
+                b"blocks".to_vec(),
+                Value::Bytes(blocks.to_payload(self.blocks.len())),
+            ),
+            (b"data".to_vec(), Value::Bytes(self.data.clone())),
+        ]);
+        Value::Dict(dict)
+    }
+
+    pub(crate) fn from_value(value: Value) -> anyhow::Result<Self> {
+        let Value::Dict(mut dict) = value else {
+            anyhow::bail!("partial piece is not a dictionary");
+        };
+        let (Some(Value::Int(piece_i)), Some(Value::Bytes(blocks)), Some(Value::Bytes(data))) = (
+            dict.remove(&b"piece"[..]),
+            dict.remove(&b"blocks"[..]),
+            dict.remove(&b"data"[..]),
+        ) else {
+            anyhow::bail!("partial piece is missing its index, blocks, or data");
+        };
+        let piece_i = usize::try_from(piece_i).context("negative piece index")?;
+        // resume files from before the block size could be changed don't say
+        let block_size = match dict.remove(&b"block"[..]) {
+            Some(Value::Int(size)) => usize::try_from(size)
+                .ok()
+                .filter(|size| (1..=BLOCK_MAX).contains(size))
+                .context("invalid block size")?,
+            Some(_) => anyhow::bail!("block size is not an integer"),
+            None => BLOCK_MAX,
+        };
+        let nblocks = data.len().div_ceil(block_size);
+        let blocks = Bitfield::from_payload(blocks, nblocks)
+            .context("block bitmap does not match the piece length")?;
+        let blocks = (0..nblocks)
+            .map(|block_i| blocks.has_piece(block_i))
+            .collect();
+        Ok(Self::new(piece_i, block_size, blocks, data))
+    }
+}
+
+/// The pieces we have, and can therefore serve to peers that request them.
+pub(crate) struct Pieces {
+    storage: Arc<dyn Storage>,
+    cache: PieceCache,
+    have: Mutex<Bitfield>,
+    /// Pieces that were [filled in](Self::fill) from elsewhere, which the download hasn't heard
+    /// about yet.
+    filled: Mutex<Vec<usize>>,
+    /// Woken when a piece is filled in.
+    filled_changed: Notify,
+    /// The record of which pieces are safely stored, if the download keeps one.
+    resume: Option<Mutex<Resume>>,
+}
+
+/// A resume record, and when to bring it up to date.
+struct Resume {
+    file: ResumeFile,
+    policy: FsyncPolicy,
+    /// When the record was last brought up to date.
+    saved: Instant,
+    /// The unfinished pieces to keep in the record, as of when the download last handed them over.
+    partial: Vec<PartialPiece>,
+}
+
+impl Pieces {
+    pub(crate) fn new(storage: Arc<dyn Storage>, cache_size: usize) -> Self {
+        Self {
+            storage,
+            cache: PieceCache::new(cache_size),
+            have: Mutex::new(Bitfield::empty()),
+            filled: Mutex::new(Vec::new()),
+            filled_changed: Notify::new(),
+            resume: None,
+        }
+    }
+
+    /// Keep `file` up to date with which pieces are stored, syncing them to disk as `policy`
+    /// says.
+    pub(crate) fn with_resume(mut self, file: ResumeFile, policy: FsyncPolicy) -> Self {
+        self.resume = Some(Mutex::new(Resume {
+            file,
+            policy,
+            saved: Instant::now(),
+            partial: Vec::new(),
+        }));
+        self
+    }
+
+    /// Start serving the pieces in `have`, which a resume record says are already in storage.
+    ///
+    /// Like [filled](Self::fill) pieces, the download hears about them through
+    /// [`take_filled`](Self::take_filled).
+    pub(crate) fn restore(&self, have: &Bitfield) {
+        let mut ours = self.have.lock().expect("pieces lock poisoned");
+        let mut filled = self.filled.lock().expect("pieces lock poisoned");
+        for piece_i in have.pieces() {
+            ours.set_piece(piece_i);
+            filled.push(piece_i);
+        }
+        self.filled_changed.notify_one();
+    }
+
+    /// Keep the blocks we got of the `partial` pieces in the resume record (if any) the next time
+    /// it's brought up to date, so that a later run can finish those pieces rather than start
+    /// them over.
+    pub(crate) fn keep_partial(&self, partial: Vec<PartialPiece>) {
+        if let Some(resume) = &self.resume {
+            resume.lock().expect("pieces lock poisoned").partial = partial;
+        }
+    }
+
+    /// Store a piece that has passed its hash check, and start serving it.
+    pub(crate) fn write_verified(&self, piece_i: usize, data: &[u8]) -> anyhow::Result<()> {
+        self.storage
+            .write_piece(piece_i, data)
+            .with_context(|| format!("write piece {piece_i}"))?;
+        self.have
+            .lock()
+            .expect("pieces lock poisoned")
+            .set_piece(piece_i);
+        self.record(false)
+    }
+
+    /// Bring the resume record (if any) up to date, if it's time to under its policy or `now`.
+    ///
+    /// The pieces to record are taken before syncing, since only the ones written by then are
+    /// sure to be covered by the sync.
+    fn record(&self, now: bool) -> anyhow::Result<()> {
+        let Some(resume) = &self.resume else {
+            return Ok(());
+        };
+        let mut resume = resume.lock().expect("pieces lock poisoned");
+        if !now && resume.policy != FsyncPolicy::Piece && resume.saved.elapsed() < RESUME_INTERVAL {
+            return Ok(());
+        }
+        let have = self.bitfield();
+        resume
+            .partial
+            .retain(|partial| !have.has_piece(partial.index()));
+        let sync = resume.policy != FsyncPolicy::Never;
+        if sync {
+            self.storage.sync().context("sync pieces to disk")?;
+        } else {
+            self.storage.flush().context("write out pieces")?;
+        }
+        resume
+            .file
+            .save(&have, &resume.partial, sync)
+            .context("update resume record")?;
+        resume.saved = Instant::now();
+        Ok(())
+    }
+
+    /// Remove the resume record, if any, once there's nothing left to resume.
+    pub(crate) fn discard_resume(&self) -> anyhow::Result<()> {
+        match &self.resume {
+            Some(resume) => resume.lock().expect("pieces lock poisoned").file.remove(),
+            None => Ok(()),
+        }
+    }
+
+    /// Store a verified piece that came from somewhere other than the download itself, such as
+    /// another torrent with the same file, so that the download no longer needs to fetch it.
+    pub(crate) fn fill(&self, piece_i: usize, data: &[u8]) -> anyhow::Result<()> {
+        self.write_verified(piece_i, data)?;
+        self.filled
+            .lock()
+            .expect("pieces lock poisoned")
+            .push(piece_i);
+        self.filled_changed.notify_one();
+        Ok(())
+    }
+
+    /// The pieces that have been [filled in](Self::fill) since the last call.
+    pub(crate) fn take_filled(&self) -> Vec<usize> {
+        std::mem::take(&mut *self.filled.lock().expect("pieces lock poisoned"))
+    }
+
+    /// Wait until a piece is [filled in](Self::fill), if none has been since the last wait.
+    pub(crate) async fn filled(&self) {
+        self.filled_changed.notified().await;
+    }
+
+    /// Read all of piece `piece_i`, which we must have.
+    pub(crate) fn read_piece(&self, piece_i: usize) -> anyhow::Result<Arc<[u8]>> {
+        anyhow::ensure!(self.has_piece(piece_i), "we don't have piece {piece_i}");
+        self.cache
+            .get_or_insert_with(piece_i, || self.storage.read_piece(piece_i))
+    }
+
+    /// See [`Storage::flush`]. The resume record, if any, is brought up to date too.
+    pub(crate) fn flush(&self) -> anyhow::Result<()> {
+        if self.resume.is_some() {
+            return self.record(true);
+        }
+        self.storage.flush().context("write out pieces")
+    }
+
+    /// Read piece `piece_i` straight from storage (not the cache), and check that `hasher` gives
+    /// it the given hash.
+    ///
+    /// Storage that batches up writes is flushed first, so that the piece is read back from
+    /// where it ended up rather than from the batch.
+    pub(crate) fn verify_stored(
+        &self,
+        piece_i: usize,
+        hash: [u8; 20],
+        hasher: &dyn PieceHasher,
+    ) -> anyhow::Result<()> {
+        self.flush()?;
+        let stored = self
+            .storage
+            .read_piece(piece_i)
+            .with_context(|| format!("read back piece {piece_i}"))?;
+        let stored_hash = hasher.hash(&stored);
+        anyhow::ensure!(
+            stored_hash == hash,
+            "piece {piece_i} no longer matches its hash after being written to storage"
+        );
+        Ok(())
+    }
+
+    /// Concatenate the first `npieces` pieces, with any we don't have left as zeroes.
+    pub(crate) fn to_bytes(
+        &self,
+        npieces: usize,
+        plength: usize,
+        length: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = vec![0; length];
+        for piece_i in self.bitfield().pieces().filter(|&i| i < npieces) {
+            let piece = self
+                .storage
+                .read_piece(piece_i)
+                .with_context(|| format!("read piece {piece_i}"))?;
+            bytes[piece_i * plength..][..piece.len()].copy_from_slice(&piece);
+        }
+        Ok(bytes)
+    }
+
+    pub(crate) fn bitfield(&self) -> Bitfield {
+        self.have.lock().expect("pieces lock poisoned").clone()
+    }
+
+    pub(crate) fn has_piece(&self, piece_i: usize) -> bool {
+        self.have
+            .lock()
+            .expect("pieces lock poisoned")
+            .has_piece(piece_i)
+    }
+
+    /// Read `length` bytes starting at `begin` of piece `piece_i`.
+    ///
+    /// A block of a piece we don't have, or that doesn't fit in the piece, is an
+    /// [`InvalidRequest`].
+    ///
+    /// Popular pieces tend to be requested by many peers in a row, so whole pieces are kept in a
+    /// cache rather than going back to storage for every block.
+    pub(crate) fn read_block(
+        &self,
+        piece_i: usize,
+        begin: usize,
+        length: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        // we only ever tell peers about pieces we have, and never lose one once we have it, so a
+        // peer has no business asking for any other
+        if !self.has_piece(piece_i) {
+            return Err(InvalidRequest::Missing { index: piece_i }.into());
+        }
+        let piece = self
+            .cache
+            .get_or_insert_with(piece_i, || self.storage.read_piece(piece_i))
+            .with_context(|| format!("read piece {piece_i}"))?;
+        let block = begin
+            .checked_add(length)
+            .and_then(|end| piece.get(begin..end))
+            .ok_or(InvalidRequest::OutOfBounds {
+                index: piece_i,
+                begin,
+                length,
+                piece_length: piece.len(),
+            })?;
+        Ok(block.to_vec())
+    }
+}
+
+#[test]
+fn read_block_bounds() {
+    let pieces = Pieces::new(Arc::new(MemoryStorage::default()), 0);
+    pieces.write_verified(0, &[1, 2, 3, 4]).unwrap();
+    assert_eq!(pieces.read_block(0, 1, 3).unwrap(), [2, 3, 4]);
+
+    let invalid = |begin, length, piece_i| {
+        pieces
+            .read_block(piece_i, begin, length)
+            .unwrap_err()
+            .downcast::<InvalidRequest>()
+            .unwrap()
+    };
+    assert_eq!(invalid(0, 4, 1), InvalidRequest::Missing { index: 1 });
+    assert_eq!(
+        invalid(2, 3, 0),
+        InvalidRequest::OutOfBounds {
+            index: 0,
+            begin: 2,
+            length: 3,
+            piece_length: 4
+        }
+    );
+    assert!(matches!(
+        invalid(usize::MAX, 1, 0),
+        InvalidRequest::OutOfBounds { .. }
+    ));
+}
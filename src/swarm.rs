@@ -0,0 +1,693 @@
+use crate::bitfield::Bitfield;
+use crate::download::{self, DownloadConfig};
+use crate::holepunch::{HolepunchError, HolepunchMessage};
+use crate::peer::{Message, MessageTag, PeerTimeouts};
+use crate::peer_id;
+use crate::proxy::Proxy;
+use crate::ratelimit::RateLimits;
+use crate::stats::Stats;
+use crate::storage::{MemoryStorage, Pieces};
+use crate::torrent::Torrent;
+use crate::tracker::{TrackerConfig, TrackerResponse};
+use anyhow::Context;
+use std::collections::HashMap;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// The state shared between all the peer connections of a single download.
+///
+/// Each connected peer registers an outbox here so that other connections (and the download
+/// itself) can get messages to it, and anything that learns about new peers to connect to pushes
+/// them as candidates.
+pub(crate) struct Swarm {
+    peers: Mutex<HashMap<SocketAddrV4, PeerHandle>>,
+    candidates: mpsc::UnboundedSender<SocketAddrV4>,
+    stats: Arc<Stats>,
+    /// The pieces we have, for serving to peers.
+    pieces: Arc<Pieces>,
+    /// Whether the torrent is private (BEP 27), in which case we may only use peers from the
+    /// tracker.
+    private: bool,
+    /// The number of pieces in the torrent.
+    npieces: usize,
+    /// The peer id we identify ourselves with.
+    peer_id: [u8; 20],
+    limits: Arc<RateLimits>,
+    /// How many bytes we ask peers for at a time.
+    block_size: usize,
+    /// The largest block we accept from peers.
+    max_block: usize,
+    /// What to connect to peers through, if anything.
+    proxy: Option<Proxy>,
+    /// How long to wait for peers to get through each step of talking to us.
+    timeouts: PeerTimeouts,
+    /// The port our DHT node listens on, which we tell peers about, unless the torrent is private.
+    dht_port: Option<u16>,
+    /// Whether we're only uploading, and so have no use for peers that are too (BEP 21).
+    upload_only: AtomicBool,
+}
+
+/// We're already connected to this peer, at the given address, and the existing connection is
+/// the one to keep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("already connected to this peer at {0}")]
+pub struct DuplicateConnection(pub SocketAddrV4);
+
+/// Where we learned about a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PeerSource {
+    Tracker,
+    /// A peer we're connected to brokered a connection using `ut_holepunch`.
+    Holepunch,
+}
+
+struct PeerHandle {
+    outbox: mpsc::UnboundedSender<Message>,
+    /// Whether we opened the connection, rather than the peer connecting to us.
+    outgoing: bool,
+    /// The extended message id the peer wants `ut_holepunch` messages on, if it supports it.
+    holepunch_id: Option<u8>,
+    state: PeerState,
+}
+
+/// What we know about a peer we're connected to.
+#[derive(Debug, Clone)]
+pub struct PeerState {
+    pub addr: SocketAddrV4,
+    pub peer_id: [u8; 20],
+    /// The client name and version the peer reported in its extension handshake.
+    pub client: Option<String>,
+    /// The pieces the peer has told us it has.
+    pub bitfield: Bitfield,
+    /// Whether the peer is choking us.
+    pub choked: bool,
+    /// Whether the peer has unchoked us but then left our requests unanswered for a long time.
+    pub snubbed: bool,
+    /// Bytes of piece data we've received from the peer.
+    pub downloaded: usize,
+    /// Bytes of piece data we've sent to the peer.
+    pub uploaded: usize,
+    /// The smoothed round-trip time of our block requests to the peer, once we've timed one.
+    pub rtt: Option<Duration>,
+    /// How many block requests we keep outstanding with the peer.
+    pub pipeline_depth: usize,
+    /// The port the peer's DHT node listens on, if it told us (BEP 5).
+    pub dht_port: Option<u16>,
+    /// Whether the peer told us it isn't going to download anything (BEP 21).
+    pub upload_only: bool,
+    pub connected_at: Instant,
+}
+
+impl PeerState {
+    /// The name of the client the peer is running, as reported in its extension handshake, or
+    /// otherwise as [identified](peer_id::identify) from its peer id.
+    pub fn client_name(&self) -> Option<String> {
+        self.client
+            .clone()
+            .or_else(|| peer_id::identify(&self.peer_id).map(|client| client.to_string()))
+    }
+
+    /// Whether the peer has every one of the torrent's `npieces` pieces.
+    pub fn is_seed(&self, npieces: usize) -> bool {
+        self.bitfield.is_complete(npieces)
+    }
+
+    /// Whether the peer only uploads, either because it's a seed or because it said so.
+    pub fn is_upload_only(&self, npieces: usize) -> bool {
+        self.upload_only || self.is_seed(npieces)
+    }
+
+    /// The average rate (in bytes per second) at which we've downloaded from this peer.
+    pub fn download_rate(&self) -> f64 {
+        self.downloaded as f64 / self.connected_at.elapsed().as_secs_f64()
+    }
+
+    /// The average rate (in bytes per second) at which we've uploaded to this peer.
+    pub fn upload_rate(&self) -> f64 {
+        self.uploaded as f64 / self.connected_at.elapsed().as_secs_f64()
+    }
+}
+
+/// A snapshot of the state of every peer we're connected to for a torrent.
+#[derive(Debug, Clone)]
+pub struct SwarmState {
+    pub npieces: usize,
+    pub peers: Vec<PeerState>,
+}
+
+impl SwarmState {
+    /// The number of connected peers that have each piece.
+    pub fn availability(&self) -> Vec<usize> {
+        let mut availability = vec![0; self.npieces];
+        for peer in &self.peers {
+            for piece_i in peer.bitfield.pieces() {
+                if let Some(n) = availability.get_mut(piece_i) {
+                    *n += 1;
+                }
+            }
+        }
+        availability
+    }
+
+    /// How many pieces are available from each number of peers: entry `n` counts the pieces that
+    /// exactly `n` connected peers have.
+    pub fn availability_histogram(&self) -> Vec<usize> {
+        let mut histogram = vec![0; self.peers.len() + 1];
+        for n in self.availability() {
+            histogram[n] += 1;
+        }
+        histogram
+    }
+
+    /// The pieces none of the connected peers have, which a download can't finish without.
+    pub fn missing_pieces(&self) -> Vec<usize> {
+        self.availability()
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, n)| n == 0)
+            .map(|(piece_i, _)| piece_i)
+            .collect()
+    }
+
+    /// The number of connected peers that have the rarest piece.
+    pub fn min_availability(&self) -> usize {
+        self.availability().into_iter().min().unwrap_or(0)
+    }
+
+    /// How many full copies of the torrent the connected peers have between them.
+    ///
+    /// The whole part is the number of peers that have the rarest piece, and the fraction is how
+    /// many of the pieces have at least one more copy than that, the way most clients report it.
+    pub fn distributed_copies(&self) -> f64 {
+        let availability = self.availability();
+        let Some(&min) = availability.iter().min() else {
+            return 0.0;
+        };
+        let above = availability.iter().filter(|&&n| n > min).count();
+        min as f64 + above as f64 / availability.len() as f64
+    }
+
+    /// The DHT nodes of the connected peers that told us about theirs, for bootstrapping a DHT
+    /// from.
+    pub fn dht_nodes(&self) -> Vec<SocketAddrV4> {
+        self.peers
+            .iter()
+            .filter_map(|peer| Some(SocketAddrV4::new(*peer.addr.ip(), peer.dht_port?)))
+            .collect()
+    }
+
+    /// Whether the connected peers have every piece between them, and so the torrent can be
+    /// completed without anyone else joining.
+    pub fn has_full_copy(&self) -> bool {
+        self.min_availability() > 0
+    }
+
+    /// Render the availability of pieces as a single line of at most `width` characters.
+    ///
+    /// Each character covers a range of pieces, and shows how many peers have the least available
+    /// piece in that range: a space if none do, a digit for up to nine, and `+` for more.
+    pub fn availability_map(&self, width: usize) -> String {
+        let availability = self.availability();
+        if availability.is_empty() || width == 0 {
+            return String::new();
+        }
+        let per_char = availability.len().div_ceil(width);
+        availability
+            .chunks(per_char)
+            .map(|range| match range.iter().min().copied().unwrap_or(0) {
+                0 => ' ',
+                n @ 1..=9 => char::from_digit(n as u32, 10).expect("n is a single digit"),
+                _ => '+',
+            })
+            .collect()
+    }
+}
+
+impl Swarm {
+    pub(crate) fn new(
+        stats: Arc<Stats>,
+        private: bool,
+        pieces: Arc<Pieces>,
+        npieces: usize,
+        peer_id: [u8; 20],
+        config: &DownloadConfig,
+    ) -> (Arc<Self>, mpsc::UnboundedReceiver<SocketAddrV4>) {
+        let (candidates, candidates_rx) = mpsc::unbounded_channel();
+        let swarm = Self {
+            peers: Mutex::new(HashMap::new()),
+            candidates,
+            stats,
+            pieces,
+            private,
+            npieces,
+            peer_id,
+            limits: Arc::clone(&config.limits),
+            block_size: config.block_size,
+            max_block: config.max_block_size,
+            proxy: config.proxy.clone(),
+            timeouts: config.peer_timeouts,
+            // private torrents must stay out of the DHT (BEP 27)
+            dht_port: config.dht_port.filter(|_| !private),
+            upload_only: AtomicBool::new(false),
+        };
+        (Arc::new(swarm), candidates_rx)
+    }
+
+    pub(crate) fn is_private(&self) -> bool {
+        self.private
+    }
+
+    /// Whether we're allowed to use peers we learn about from `source`.
+    ///
+    /// Private torrents must only ever use peers handed out by the tracker.
+    pub(crate) fn allows(&self, source: PeerSource) -> bool {
+        !self.private || source == PeerSource::Tracker
+    }
+
+    pub(crate) fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub(crate) fn pieces(&self) -> &Arc<Pieces> {
+        &self.pieces
+    }
+
+    pub(crate) fn npieces(&self) -> usize {
+        self.npieces
+    }
+
+    pub(crate) fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    pub(crate) fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    pub(crate) fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub(crate) fn max_block(&self) -> usize {
+        self.max_block
+    }
+
+    pub(crate) fn proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+
+    pub(crate) fn dht_port(&self) -> Option<u16> {
+        self.dht_port
+    }
+
+    /// Whether we're only uploading, which we tell peers in our extension handshake.
+    pub(crate) fn is_upload_only(&self) -> bool {
+        self.upload_only.load(Ordering::Relaxed)
+    }
+
+    /// Note that we're only uploading from here on, such as because we're seeding.
+    pub(crate) fn set_upload_only(&self) {
+        self.upload_only.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn peer_timeouts(&self) -> PeerTimeouts {
+        self.timeouts
+    }
+
+    /// Register a newly connected peer, and return the receiving end of its outbox.
+    ///
+    /// We may learn about the same peer from several places and end up connected to it twice,
+    /// such as when we connect out to it just as it connects in to us. The two connections come
+    /// from the same IP with the same peer id, and only one of them may stay: the one opened by
+    /// whichever of us has the lower peer id, so that both ends pick the same one. If both were
+    /// opened the same way, the first one stays. A connection that loses to an existing one is
+    /// refused with [`DuplicateConnection`], and an existing one that loses has its outbox
+    /// closed, which ends it.
+    pub(crate) fn join(
+        &self,
+        addr: SocketAddrV4,
+        peer_id: [u8; 20],
+        outgoing: bool,
+    ) -> Result<mpsc::UnboundedReceiver<Message>, DuplicateConnection> {
+        let mut peers = self.peers.lock().expect("swarm lock poisoned");
+        let existing = peers
+            .iter()
+            .find(|(&other, peer)| {
+                other != addr && other.ip() == addr.ip() && peer.state.peer_id == peer_id
+            })
+            .map(|(&other, peer)| (other, peer.outgoing));
+        if let Some((other, other_outgoing)) = existing {
+            let ours_wins = self.peer_id < peer_id;
+            if other_outgoing == outgoing || other_outgoing == ours_wins {
+                return Err(DuplicateConnection(other));
+            }
+            peers.remove(&other);
+            self.stats.peer_left(other);
+        }
+
+        let (outbox, outbox_rx) = mpsc::unbounded_channel();
+        let previous = peers.insert(
+            addr,
+            PeerHandle {
+                outbox,
+                outgoing,
+                holepunch_id: None,
+                state: PeerState {
+                    addr,
+                    peer_id,
+                    client: None,
+                    bitfield: Bitfield::empty(),
+                    choked: true,
+                    snubbed: false,
+                    downloaded: 0,
+                    uploaded: 0,
+                    rtt: None,
+                    pipeline_depth: crate::pipeline::INITIAL_DEPTH,
+                    dht_port: None,
+                    upload_only: false,
+                    connected_at: Instant::now(),
+                },
+            },
+        );
+        if previous.is_none() {
+            self.stats.peer_joined(addr);
+        }
+        Ok(outbox_rx)
+    }
+
+    /// Update what we know about the peer at `addr`.
+    pub(crate) fn update(&self, addr: SocketAddrV4, f: impl FnOnce(&mut PeerState)) {
+        if let Some(peer) = self
+            .peers
+            .lock()
+            .expect("swarm lock poisoned")
+            .get_mut(&addr)
+        {
+            f(&mut peer.state);
+        }
+    }
+
+    pub(crate) fn state(&self, npieces: usize) -> SwarmState {
+        let mut peers: Vec<_> = self
+            .peers
+            .lock()
+            .expect("swarm lock poisoned")
+            .values()
+            .map(|peer| peer.state.clone())
+            .collect();
+        peers.sort_by_key(|peer| peer.addr);
+        SwarmState { npieces, peers }
+    }
+
+    /// Whether the peer at `addr` only uploads (see [`PeerState::is_upload_only`]).
+    pub(crate) fn is_upload_only_peer(&self, addr: SocketAddrV4) -> bool {
+        self.peers
+            .lock()
+            .expect("swarm lock poisoned")
+            .get(&addr)
+            .is_some_and(|peer| peer.state.is_upload_only(self.npieces))
+    }
+
+    pub(crate) fn leave(&self, addr: SocketAddrV4) {
+        let removed = self
+            .peers
+            .lock()
+            .expect("swarm lock poisoned")
+            .remove(&addr);
+        if removed.is_some() {
+            self.stats.peer_left(addr);
+        }
+    }
+
+    /// How many peers we're connected to.
+    pub(crate) fn peer_count(&self) -> usize {
+        self.peers.lock().expect("swarm lock poisoned").len()
+    }
+
+    pub(crate) fn is_connected(&self, addr: SocketAddrV4) -> bool {
+        self.peers
+            .lock()
+            .expect("swarm lock poisoned")
+            .contains_key(&addr)
+    }
+
+    pub(crate) fn set_holepunch_id(&self, addr: SocketAddrV4, id: Option<u8>) {
+        if let Some(peer) = self
+            .peers
+            .lock()
+            .expect("swarm lock poisoned")
+            .get_mut(&addr)
+        {
+            peer.holepunch_id = id;
+        }
+    }
+
+    /// Note that we've learned about a peer we may want to connect to.
+    pub(crate) fn add_candidate(&self, addr: SocketAddrV4, source: PeerSource) {
+        if !self.allows(source) {
+            return;
+        }
+        // the download may have finished and dropped the receiver, which is fine
+        let _ = self.candidates.send(addr);
+    }
+
+    /// Tell every connected peer that we now have `piece_i`, so that they can ask us for it.
+    ///
+    /// Only call this once the piece has been verified and stored, since that's when we start
+    /// serving it. Peers that already have the piece themselves aren't told, since they'll never
+    /// ask us for it.
+    pub(crate) fn announce_have(&self, piece_i: usize) {
+        let peers = self.peers.lock().expect("swarm lock poisoned");
+        for peer in peers
+            .values()
+            .filter(|peer| !peer.state.bitfield.has_piece(piece_i))
+        {
+            // a peer that's going away has dropped its outbox, and doesn't need to know
+            let _ = peer.outbox.send(Message {
+                tag: MessageTag::Have,
+                payload: (piece_i as u32).to_be_bytes().to_vec(),
+            });
+        }
+    }
+
+    /// Ask a connected peer that supports holepunching to broker a connection to `target`.
+    ///
+    /// Returns `false` if no connected peer can act as a relay.
+    pub(crate) fn request_holepunch(&self, target: SocketAddrV4) -> bool {
+        if !self.allows(PeerSource::Holepunch) {
+            return false;
+        }
+        let peers = self.peers.lock().expect("swarm lock poisoned");
+        let rendezvous = HolepunchMessage::rendezvous(SocketAddr::V4(target));
+        peers
+            .iter()
+            .filter(|&(&addr, _)| addr != target)
+            .filter_map(|(_, peer)| Some((peer, peer.holepunch_id?)))
+            .any(|(peer, id)| peer.outbox.send(holepunch(id, &rendezvous)).is_ok())
+    }
+
+    /// Act as the relay for a rendezvous request from `initiator` to `target`.
+    ///
+    /// On success, the target is told to connect to the initiator, and the returned message tells
+    /// the initiator to connect to the target. Otherwise the returned message carries the error.
+    pub(crate) fn relay_holepunch(
+        &self,
+        initiator: SocketAddrV4,
+        target: SocketAddr,
+    ) -> HolepunchMessage {
+        if !self.allows(PeerSource::Holepunch) {
+            return HolepunchMessage::error(target, HolepunchError::NoSupport);
+        }
+        let SocketAddr::V4(target_v4) = target else {
+            // we only ever connect to peers over IPv4, so can't be connected to this one
+            return HolepunchMessage::error(target, HolepunchError::NotConnected);
+        };
+        let peers = self.peers.lock().expect("swarm lock poisoned");
+        let Some(peer) = peers.get(&target_v4) else {
+            return HolepunchMessage::error(target, HolepunchError::NotConnected);
+        };
+        let Some(id) = peer.holepunch_id else {
+            return HolepunchMessage::error(target, HolepunchError::NoSupport);
+        };
+        let connect = HolepunchMessage::connect(SocketAddr::V4(initiator));
+        if peer.outbox.send(holepunch(id, &connect)).is_err() {
+            return HolepunchMessage::error(target, HolepunchError::NotConnected);
+        }
+        HolepunchMessage::connect(target)
+    }
+}
+
+fn holepunch(id: u8, msg: &HolepunchMessage) -> Message {
+    let mut payload = vec![id];
+    payload.extend(msg.to_bytes());
+    Message {
+        tag: MessageTag::Extended,
+        payload,
+    }
+}
+
+/// Connect to the peers the tracker knows about for `t`, and watch what they tell us.
+///
+/// `report` is called with the state of the swarm every `interval`, and the observation stops
+/// (returning the final state) once it returns `false`.
+pub(crate) async fn observe(
+    t: &Torrent,
+    interval: Duration,
+    mut report: impl FnMut(&SwarmState) -> bool,
+) -> anyhow::Result<SwarmState> {
+    t.validate().context("invalid torrent")?;
+    let info_hash = t.info_hash();
+    let metadata: Arc<[u8]> = t.info_bytes().into();
+    let stats = Arc::new(Stats::new(t.length()));
+    let tracker_config = TrackerConfig::default();
+    let tracker = tracker_config.announcer()?;
+    let peer_id = peer_id::generate();
+    let peer_info = TrackerResponse::query(
+        &*tracker,
+        t,
+        info_hash,
+        peer_id,
+        &stats,
+        None,
+        &tracker_config,
+    )
+    .await
+    .context("query tracker for peer info")?;
+
+    // we're only watching, so we never have any pieces to serve
+    let pieces = Arc::new(Pieces::new(Arc::new(MemoryStorage::default()), 0));
+    let npieces = t.info.pieces.0.len();
+    let config = DownloadConfig::default();
+    let (swarm, _candidates) = Swarm::new(stats, t.is_private(), pieces, npieces, peer_id, &config);
+    let peers = download::connect(
+        &peer_info.peers.0,
+        info_hash,
+        &metadata,
+        &swarm,
+        &config,
+        peer_info.peers.0.len(),
+    )
+    .await;
+    let mut observers = tokio::task::JoinSet::new();
+    for mut peer in peers {
+        observers.spawn(async move { peer.observe().await });
+    }
+
+    loop {
+        tokio::time::sleep(interval).await;
+        let state = swarm.state(npieces);
+        if !report(&state) {
+            observers.abort_all();
+            return Ok(state);
+        }
+    }
+}
+
+#[test]
+fn availability() {
+    let peer = |payload: Vec<u8>| PeerState {
+        addr: SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, 6881),
+        peer_id: [0; 20],
+        client: None,
+        bitfield: Bitfield::from_payload(payload, 10).unwrap(),
+        choked: true,
+        snubbed: false,
+        downloaded: 0,
+        uploaded: 0,
+        rtt: None,
+        pipeline_depth: crate::pipeline::INITIAL_DEPTH,
+        dht_port: None,
+        upload_only: false,
+        connected_at: Instant::now(),
+    };
+    let state = SwarmState {
+        npieces: 10,
+        peers: vec![
+            peer(vec![0b11110000, 0b11000000]),
+            peer(vec![0b11000000, 0b01000000]),
+        ],
+    };
+    assert_eq!(state.availability(), [2, 2, 1, 1, 0, 0, 0, 0, 1, 2]);
+    assert_eq!(state.availability_map(10), "2211    12");
+    assert_eq!(state.availability_map(5), "21  1");
+    assert_eq!(state.availability_histogram(), [4, 3, 3]);
+    assert_eq!(state.min_availability(), 0);
+    assert_eq!(state.missing_pieces(), [4, 5, 6, 7]);
+    assert_eq!(state.distributed_copies(), 0.6);
+    assert!(!state.has_full_copy());
+
+    let state = SwarmState {
+        npieces: 10,
+        peers: vec![peer(vec![0xff, 0xc0]), peer(vec![0xf0, 0x00])],
+    };
+    assert_eq!(state.availability_histogram(), [0, 6, 4]);
+    assert_eq!(state.distributed_copies(), 1.4);
+    assert!(state.missing_pieces().is_empty());
+    assert!(state.has_full_copy());
+}
+
+#[test]
+fn announce_have() {
+    let (swarm, _candidates) = Swarm::new(
+        Arc::new(Stats::new(0)),
+        false,
+        Arc::new(Pieces::new(Arc::new(MemoryStorage::default()), 0)),
+        10,
+        [0; 20],
+        &DownloadConfig::default(),
+    );
+    let peer = |port| SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, port);
+    let mut outboxes = [
+        swarm.join(peer(1), [1; 20], true).unwrap(),
+        swarm.join(peer(2), [2; 20], true).unwrap(),
+    ];
+    swarm.update(peer(2), |state| state.bitfield.set_piece(5));
+    swarm.announce_have(3);
+    for outbox in &mut outboxes {
+        let msg = outbox.try_recv().expect("peer is told about the piece");
+        assert_eq!(msg.tag, MessageTag::Have);
+        assert_eq!(msg.payload, [0, 0, 0, 3]);
+    }
+    // the second peer has the piece already
+    swarm.announce_have(5);
+    assert!(outboxes[0].try_recv().is_ok());
+    assert!(outboxes[1].try_recv().is_err());
+}
+
+#[test]
+fn duplicate_connections() {
+    let (swarm, _candidates) = Swarm::new(
+        Arc::new(Stats::new(0)),
+        false,
+        Arc::new(Pieces::new(Arc::new(MemoryStorage::default()), 0)),
+        10,
+        [5; 20],
+        &DownloadConfig::default(),
+    );
+    let peer = |port| SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, port);
+
+    // a peer with a higher id than ours: our outgoing connection is the one to keep
+    let _incoming = swarm.join(peer(50000), [9; 20], false).unwrap();
+    let outgoing = swarm.join(peer(6881), [9; 20], true).unwrap();
+    assert!(!swarm.is_connected(peer(50000)));
+    assert!(swarm.is_connected(peer(6881)));
+    assert_eq!(
+        swarm.join(peer(50001), [9; 20], false).unwrap_err(),
+        DuplicateConnection(peer(6881))
+    );
+    // connecting out again doesn't replace the connection we already opened
+    assert!(swarm.join(peer(6882), [9; 20], true).is_err());
+    drop(outgoing);
+
+    // a peer with a lower id than ours: its connection to us is the one to keep
+    let _incoming = swarm.join(peer(50002), [1; 20], false).unwrap();
+    assert_eq!(
+        swarm.join(peer(6883), [1; 20], true).unwrap_err(),
+        DuplicateConnection(peer(50002))
+    );
+    // a different peer behind the same IP is no duplicate
+    assert!(swarm.join(peer(6884), [2; 20], true).is_ok());
+}
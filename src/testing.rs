@@ -0,0 +1,1241 @@
+//! A miniature seeder and tracker for testing downloads end to end, without any other BitTorrent
+//! software or network access.
+//!
+//! The seeder speaks just enough of the peer wire protocol to hand out a torrent: it answers the
+//! handshake, announces its pieces, unchokes anyone who's interested, and answers requests. It
+//! deliberately doesn't use [`crate::peer`], so that tests exercise our framing against an
+//! independent implementation. Both listen on loopback TCP, since that's what downloads connect
+//! over.
+
+use crate::faults::{Faults, FaultyStream};
+use crate::storage::Layout;
+use crate::torrent::{Info, Keys, Torrent};
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A single-file torrent of `length` bytes of made-up data, along with that data.
+///
+/// The data only depends on `length`, so the same torrent can be generated again.
+pub(crate) fn generate(length: usize, plength: usize) -> (Torrent, Vec<u8>) {
+    // xorshift, which is plenty random for telling pieces apart
+    let mut state = 0x2545_f491_4f6c_dd1d_u64 ^ length as u64;
+    let data: Vec<u8> = (0..length)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    let pieces = data
+        .chunks(plength)
+        .map(|piece| Sha1::digest(piece).into())
+        .collect();
+    // the announce URL is filled in once there's a tracker to point at
+    let t = Torrent::new(
+        String::new(),
+        Info::single_file("generated.bin", plength, pieces, length),
+    );
+    (t, data)
+}
+
+/// A multi-file torrent of made-up data, with files of the given `lengths` spread over a few
+/// directories, along with that data.
+pub(crate) fn generate_files(lengths: &[usize], plength: usize) -> (Torrent, Vec<u8>) {
+    let (mut t, data) = generate(lengths.iter().sum(), plength);
+    let files = lengths
+        .iter()
+        .enumerate()
+        .map(|(i, &length)| crate::torrent::File {
+            length,
+            path: match i % 3 {
+                0 => vec![format!("f{i}").into()],
+                1 => vec!["sub".into(), format!("f{i}").into()],
+                _ => vec!["sub".into(), "deeper".into(), format!("f{i}").into()],
+            },
+            path_utf8: None,
+        })
+        .collect();
+    t.info.keys = Keys::MultiFile { files };
+    (t, data)
+}
+
+/// File lengths that put the piece and block boundaries of pieces of `plength` bytes (at least
+/// two blocks) in awkward places.
+pub(crate) fn adversarial_layouts(plength: usize) -> Vec<Vec<usize>> {
+    use crate::BLOCK_MAX;
+    vec![
+        // files smaller than a block
+        vec![1, 100, BLOCK_MAX - 1, 3],
+        // a file that spans many pieces, between tiny ones
+        vec![1, 10 * plength + 7, 1],
+        // piece boundaries inside, and right after, files of a byte or two
+        vec![plength - 1, 2, plength - 2, 1, 1, 1],
+        // empty files all over, including right on piece boundaries
+        vec![0, plength, 0, 0, 5, 0, plength - 5, 0],
+        // a piece per file
+        vec![plength, plength, plength],
+        // block boundaries that line up with file boundaries, but piece boundaries that don't
+        vec![
+            plength - BLOCK_MAX,
+            BLOCK_MAX,
+            BLOCK_MAX,
+            plength - BLOCK_MAX,
+            1,
+        ],
+    ]
+}
+
+/// `n` layouts of random file lengths for pieces of `plength` bytes, always from the same seed so
+/// that a failure can be reproduced.
+pub(crate) fn random_layouts(n: usize, plength: usize) -> Vec<Vec<usize>> {
+    let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+    let mut next = move |below: usize| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as usize % below
+    };
+    (0..n)
+        .map(|_| {
+            let mut lengths: Vec<usize> = (0..1 + next(12))
+                .map(|_| match next(5) {
+                    0 => 0,
+                    1 => 1 + next(16),
+                    2 => crate::BLOCK_MAX - 2 + next(5),
+                    3 => plength - 2 + next(5),
+                    _ => next(4 * plength),
+                })
+                .collect();
+            if lengths.iter().all(|&length| length == 0) {
+                lengths.push(1);
+            }
+            lengths
+        })
+        .collect()
+}
+
+/// Check that each of the files of `t` under `dir`, laid out by `layout`, holds its part of `data`.
+fn assert_files_match(t: &Torrent, data: &[u8], dir: &std::path::Path, layout: &Layout) {
+    let mut offset = 0;
+    for (path, length) in layout.files(t, dir).expect("paths are safe") {
+        let bytes = std::fs::read(&path).expect("file was written");
+        assert!(
+            bytes == data[offset..][..length],
+            "{} doesn't match in layout {:?}",
+            path.display(),
+            t.file_lengths().collect::<Vec<_>>()
+        );
+        offset += length;
+    }
+    assert_eq!(offset, data.len());
+}
+
+/// A peer that has every piece of a torrent.
+pub(crate) struct Seeder {
+    info_hash: [u8; 20],
+    /// A peer id of its own, so that seeders on the same loopback address are told apart.
+    peer_id: [u8; 20],
+    plength: usize,
+    data: Vec<u8>,
+    /// Send an empty bitfield, and then announce every piece with a have instead.
+    pub(crate) lazy_bitfield: bool,
+    /// Claim to have none of the pieces, like a peer that has only just joined.
+    pub(crate) empty: bool,
+    /// Choke the downloader after every this many blocks, the way real clients do when they
+    /// rotate who they upload to.
+    ///
+    /// The last block goes out after the choke, as if it had been on its way already. Requests
+    /// that arrive while choked are dropped, and the downloader is unchoked again shortly after.
+    pub(crate) choke_every: Option<usize>,
+    /// Ignore the first this many interested messages on each connection, rather than
+    /// unchoking the downloader.
+    pub(crate) ignore_interested: usize,
+    /// Answer only this many requests (across all connections), and ignore the rest, like a peer
+    /// that's gone quiet.
+    pub(crate) serve_only: Option<usize>,
+    /// How many requests were answered.
+    pub(crate) served: Arc<AtomicUsize>,
+    /// Faults to inject into what the seeder sends, on each connection.
+    pub(crate) faults: Faults,
+}
+
+impl Seeder {
+    pub(crate) fn new(t: &Torrent, data: Vec<u8>) -> Self {
+        static SEEDERS: AtomicUsize = AtomicUsize::new(0);
+        let mut peer_id = *b"-TS0001-seeder000000";
+        let n = SEEDERS.fetch_add(1, Ordering::Relaxed) % 1_000_000;
+        peer_id[14..].copy_from_slice(format!("{n:06}").as_bytes());
+        Self {
+            info_hash: t.info_hash(),
+            peer_id,
+            plength: t.info.plength,
+            data,
+            lazy_bitfield: false,
+            empty: false,
+            choke_every: None,
+            ignore_interested: 0,
+            serve_only: None,
+            served: Arc::default(),
+            faults: Faults::default(),
+        }
+    }
+
+    /// Serve the torrent on a loopback port until the runtime shuts down, and return its address.
+    pub(crate) async fn spawn(self) -> SocketAddrV4 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .expect("bind seeder to loopback");
+        let addr = local_addr(&listener);
+        let this = Arc::new(self);
+        tokio::spawn(async move {
+            let mut connections = 0;
+            while let Ok((stream, _)) = listener.accept().await {
+                let this = Arc::clone(&this);
+                // every connection gets faults of its own
+                let faults = Faults {
+                    seed: this.faults.seed.wrapping_add(connections),
+                    ..this.faults.clone()
+                };
+                connections += 1;
+                tokio::spawn(async move {
+                    // the downloader hanging up once it's done is an error too, so stay quiet
+                    let _ = this.serve(FaultyStream::new(stream, faults)).await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn serve(&self, mut stream: FaultyStream<TcpStream>) -> anyhow::Result<()> {
+        let mut handshake = [0; 68];
+        stream
+            .read_exact(&mut handshake)
+            .await
+            .context("read handshake")?;
+        anyhow::ensure!(&handshake[..20] == b"\x13BitTorrent protocol");
+        anyhow::ensure!(handshake[28..48] == self.info_hash, "wrong info hash");
+        // no extensions, and a peer id of our own
+        handshake[20..28].fill(0);
+        handshake[48..].copy_from_slice(&self.peer_id);
+        stream
+            .write_all(&handshake)
+            .await
+            .context("send handshake")?;
+
+        let npieces = self.data.len().div_ceil(self.plength);
+        let mut bitfield = vec![0u8; npieces.div_ceil(8)];
+        if !self.lazy_bitfield && !self.empty {
+            for piece_i in 0..npieces {
+                bitfield[piece_i / 8] |= 0x80 >> (piece_i % 8);
+            }
+        }
+        send(&mut stream, 5, &bitfield).await?;
+        if self.lazy_bitfield && !self.empty {
+            for piece_i in 0..npieces {
+                send(&mut stream, 4, &(piece_i as u32).to_be_bytes()).await?;
+            }
+        }
+
+        let mut choking = true;
+        let mut ignored = 0;
+        loop {
+            let mut length = [0; 4];
+            stream
+                .read_exact(&mut length)
+                .await
+                .context("read message length")?;
+            let mut msg = vec![0; u32::from_be_bytes(length) as usize];
+            stream.read_exact(&mut msg).await.context("read message")?;
+            match msg.first() {
+                // interested
+                Some(2) if ignored < self.ignore_interested => ignored += 1,
+                Some(2) if choking => {
+                    choking = false;
+                    send(&mut stream, 1, &[]).await?;
+                }
+                // request
+                Some(6) if choking => {}
+                Some(6)
+                    if self
+                        .serve_only
+                        .is_some_and(|n| self.served.load(Ordering::Relaxed) >= n) => {}
+                Some(6) => {
+                    anyhow::ensure!(msg.len() == 13, "request of {} bytes", msg.len());
+                    let field = |i: usize| {
+                        u32::from_be_bytes(msg[i..i + 4].try_into().expect("4 bytes")) as usize
+                    };
+                    let (index, begin, length) = (field(1), field(5), field(9));
+                    let start = index * self.plength + begin;
+                    let block = self
+                        .data
+                        .get(start..start + length)
+                        .context("request for data past the end of the torrent")?;
+                    let mut payload = msg[1..9].to_vec();
+                    payload.extend(block);
+                    let served = self.served.fetch_add(1, Ordering::Relaxed) + 1;
+                    let choke = self.choke_every.is_some_and(|n| served.is_multiple_of(n));
+                    if choke {
+                        send(&mut stream, 0, &[]).await?;
+                    }
+                    send(&mut stream, 7, &payload).await?;
+                    if choke {
+                        // whatever the downloader had asked for by the time it heard of the
+                        // choke is dropped, and after that it asks for nothing until unchoked
+                        let until = tokio::time::Instant::now() + Duration::from_millis(50);
+                        while let Ok(peeked) =
+                            tokio::time::timeout_at(until, stream.get_ref().peek(&mut [0; 1])).await
+                        {
+                            anyhow::ensure!(peeked.context("peek")? > 0, "downloader hung up");
+                            let mut length = [0; 4];
+                            stream
+                                .read_exact(&mut length)
+                                .await
+                                .context("read length")?;
+                            let mut msg = vec![0; u32::from_be_bytes(length) as usize];
+                            stream.read_exact(&mut msg).await.context("read message")?;
+                        }
+                        send(&mut stream, 1, &[]).await?;
+                    }
+                }
+                // keep-alives, and everything else a seeder can ignore
+                _ => {}
+            }
+        }
+    }
+}
+
+async fn send(
+    stream: &mut (impl AsyncWrite + Unpin),
+    tag: u8,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    let mut msg = Vec::with_capacity(5 + payload.len());
+    msg.extend((1 + payload.len() as u32).to_be_bytes());
+    msg.push(tag);
+    msg.extend(payload);
+    stream
+        .write_all(&msg)
+        .await
+        .with_context(|| format!("send message {tag}"))
+}
+
+/// Answer every announce with `peers`, and return the URL to announce to.
+pub(crate) async fn tracker(peers: Vec<SocketAddrV4>) -> String {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .expect("bind tracker to loopback");
+    let addr = local_addr(&listener);
+    let mut compact = Vec::new();
+    for peer in &peers {
+        compact.extend(peer.ip().octets());
+        compact.extend(peer.port().to_be_bytes());
+    }
+    let mut body = format!("d8:intervali60e5:peers{}:", compact.len()).into_bytes();
+    body.extend(compact);
+    body.push(b'e');
+    let body: Arc<[u8]> = body.into();
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let body = Arc::clone(&body);
+            tokio::spawn(async move {
+                let mut head: Vec<u8> = Vec::new();
+                let mut buf = [0; 1024];
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => head.extend(&buf[..n]),
+                    }
+                }
+                let mut response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .into_bytes();
+                response.extend(&*body);
+                let _ = stream.write_all(&response).await;
+            });
+        }
+    });
+    format!("http://{addr}/announce")
+}
+
+fn local_addr(listener: &TcpListener) -> SocketAddrV4 {
+    match listener
+        .local_addr()
+        .expect("bound listener has an address")
+    {
+        std::net::SocketAddr::V4(addr) => addr,
+        std::net::SocketAddr::V6(_) => unreachable!("bound to an IPv4 address"),
+    }
+}
+
+async fn download_from(seeder: Seeder, mut t: Torrent) -> crate::download::Downloaded {
+    use crate::client::Client;
+    use crate::download::DownloadConfig;
+    let addr = seeder.spawn().await;
+    t.announce = tracker(vec![addr]).await;
+    let client = Client::new(DownloadConfig {
+        bootstrap_peers: 1,
+        ..Default::default()
+    });
+    let mut handle = client.add(&t);
+    tokio::time::timeout(std::time::Duration::from_secs(30), handle.wait())
+        .await
+        .expect("download finishes")
+        .expect("download succeeds")
+}
+
+#[tokio::test]
+async fn download_from_seeder() {
+    // a short last piece, and pieces of several blocks
+    let (t, data) = generate(5 * (1 << 15) + 1000, 1 << 15);
+    let downloaded = download_from(Seeder::new(&t, data.clone()), t).await;
+    assert!(downloaded.is_complete());
+    let file = (&downloaded).into_iter().next().expect("one file");
+    assert!(file.bytes().unwrap() == data);
+    let mut sink = Vec::new();
+    file.write_to(&mut sink).await.expect("write to a Vec");
+    assert!(sink == data);
+}
+
+#[tokio::test]
+async fn download_events() {
+    use crate::download::DownloadEvent;
+    use futures_util::StreamExt;
+
+    let (mut t, data) = generate(3 * (1 << 14), 1 << 14);
+    let addr = Seeder::new(&t, data).spawn().await;
+    t.announce = tracker(vec![addr]).await;
+    let client = crate::client::Client::new(crate::download::DownloadConfig {
+        bootstrap_peers: 1,
+        ..Default::default()
+    });
+    let mut handle = client.add(&t);
+    let events = handle.events();
+    handle.wait().await.expect("download succeeds");
+    drop(handle);
+    drop(client);
+
+    let events: Vec<_> = events.collect().await;
+    assert_eq!(
+        events.first(),
+        Some(&DownloadEvent::TrackerAnnounced { peers: 1 })
+    );
+    assert!(events.contains(&DownloadEvent::PeerConnected { addr }));
+    let mut verified: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            DownloadEvent::PieceVerified { piece_i } => Some(*piece_i),
+            _ => None,
+        })
+        .collect();
+    verified.sort();
+    assert_eq!(verified, [0, 1, 2]);
+    // however the pieces came in, the in-order count ends up covering all of them, after the last
+    // piece to be verified
+    let in_order: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            DownloadEvent::VerifiedInOrder { pieces } => Some(*pieces),
+            _ => None,
+        })
+        .collect();
+    assert!(in_order.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(in_order.last(), Some(&3));
+    assert!(events.contains(&DownloadEvent::Completed));
+}
+
+#[tokio::test]
+async fn block_progress() {
+    use futures_util::StreamExt;
+
+    // pieces of two blocks, the last of them short
+    let (mut t, data) = generate(5 * (1 << 14) + 100, 1 << 15);
+    let addr = Seeder::new(&t, data).spawn().await;
+    t.announce = tracker(vec![addr]).await;
+    let client = crate::client::Client::new(crate::download::DownloadConfig {
+        bootstrap_peers: 1,
+        ..Default::default()
+    });
+    let mut handle = client.add(&t);
+    let blocks = handle.blocks();
+    handle.wait().await.expect("download succeeds");
+    drop(handle);
+    drop(client);
+
+    let mut blocks: Vec<_> = blocks
+        .map(|block| {
+            assert_eq!(block.peer, addr);
+            (block.piece_i, block.begin, block.length)
+        })
+        .collect()
+        .await;
+    blocks.sort();
+    assert_eq!(
+        blocks,
+        [
+            (0, 0, 1 << 14),
+            (0, 1 << 14, 1 << 14),
+            (1, 0, 1 << 14),
+            (1, 1 << 14, 1 << 14),
+            (2, 0, 1 << 14),
+            (2, 1 << 14, 100),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn download_from_lazy_seeder() {
+    let (t, data) = generate(3 * (1 << 14), 1 << 14);
+    let mut seeder = Seeder::new(&t, data.clone());
+    seeder.lazy_bitfield = true;
+    let downloaded = download_from(seeder, t).await;
+    assert!(downloaded.is_complete());
+    let file = (&downloaded).into_iter().next().expect("one file");
+    assert!(file.bytes().unwrap() == data);
+}
+
+#[tokio::test]
+async fn download_through_chokes() {
+    // pieces of four blocks, so that requests are always outstanding when the seeder chokes
+    let (t, data) = generate(6 * (1 << 16) + 1000, 1 << 16);
+    let mut seeder = Seeder::new(&t, data.clone());
+    seeder.choke_every = Some(5);
+    let served = Arc::clone(&seeder.served);
+    let downloaded = download_from(seeder, t).await;
+    assert!(downloaded.is_complete());
+    let file = (&downloaded).into_iter().next().expect("one file");
+    assert!(file.bytes().unwrap() == data);
+    // the block that arrived after each choke was kept rather than asked for again, and only
+    // the requests the seeder dropped were sent again
+    assert_eq!(served.load(Ordering::Relaxed), data.len().div_ceil(1 << 14));
+}
+
+#[tokio::test]
+async fn sideline_peers_that_never_unchoke() {
+    use crate::peer::PeerTimeouts;
+    let (mut t, data) = generate(3 * (1 << 14) + 10, 1 << 14);
+    // one seeder that never unchokes us, and one that only does once we ask again
+    let mut never = Seeder::new(&t, data.clone());
+    never.ignore_interested = usize::MAX;
+    let mut later = Seeder::new(&t, data.clone());
+    later.ignore_interested = 1;
+    t.announce = tracker(vec![never.spawn().await, later.spawn().await]).await;
+    let client = crate::client::Client::new(crate::download::DownloadConfig {
+        bootstrap_peers: 2,
+        peer_timeouts: PeerTimeouts {
+            unchoke: Duration::from_millis(200),
+            unchoke_retry: Duration::from_millis(300),
+            ..PeerTimeouts::default()
+        },
+        ..Default::default()
+    });
+    let downloaded = tokio::time::timeout(Duration::from_secs(10), client.add(&t).wait())
+        .await
+        .expect("choking peers don't hold up the download")
+        .expect("download succeeds");
+    let file = (&downloaded).into_iter().next().expect("one file");
+    assert!(file.bytes().unwrap() == data);
+}
+
+#[tokio::test]
+async fn download_with_empty_files() {
+    use crate::torrent::File;
+    let file = |length, path: &[&str]| File {
+        length,
+        path: path.iter().map(|&name| name.into()).collect(),
+        path_utf8: None,
+    };
+    let (mut t, data) = generate(3 * (1 << 14) + 10, 1 << 14);
+    t.info.keys = Keys::MultiFile {
+        files: vec![
+            file(0, &["empty"]),
+            file(1 << 14, &["a"]),
+            file(0, &["sub", "empty"]),
+            file(0, &["sub", "nested", ""]),
+            file(2 * (1 << 14) + 10, &["b"]),
+            file(0, &["last"]),
+        ],
+    };
+    t.validate().expect("valid torrent");
+    let downloaded = download_from(Seeder::new(&t, data.clone()), t).await;
+    assert!(downloaded.is_complete());
+    let lengths: Vec<_> = (&downloaded)
+        .into_iter()
+        .map(|file| file.bytes().unwrap().len())
+        .collect();
+    assert_eq!(lengths, [0, 1 << 14, 0, 0, 2 * (1 << 14) + 10, 0]);
+
+    let output = tempfile::tempdir().expect("create temporary directory");
+    downloaded
+        .write_to_dir(output.path())
+        .await
+        .expect("write files");
+    let base = output.path().join("generated.bin");
+    assert_eq!(std::fs::read(base.join("empty")).unwrap(), b"");
+    assert_eq!(std::fs::read(base.join("sub/empty")).unwrap(), b"");
+    assert!(base.join("sub/nested").is_dir());
+    assert_eq!(std::fs::read(base.join("b")).unwrap(), data[1 << 14..]);
+    assert_eq!(std::fs::read(base.join("last")).unwrap(), b"");
+}
+
+/// A tracker that's only ever asked in-process, and remembers what it was told.
+#[derive(Debug)]
+struct FakeTracker {
+    peers: Vec<SocketAddrV4>,
+    events: std::sync::Mutex<Vec<Option<crate::tracker::Event>>>,
+    /// How much was left at each announce.
+    left: std::sync::Mutex<Vec<usize>>,
+}
+
+impl crate::tracker::AnnounceClient for FakeTracker {
+    fn announce<'a>(
+        &'a self,
+        _: &'a str,
+        _: [u8; 20],
+        request: &'a crate::tracker::TrackerRequest,
+    ) -> futures_util::future::BoxFuture<'a, anyhow::Result<crate::tracker::TrackerResponse>> {
+        self.events.lock().unwrap().push(request.event);
+        self.left.lock().unwrap().push(request.left);
+        let response = crate::tracker::TrackerResponse {
+            interval: 60,
+            min_interval: None,
+            peers: crate::tracker::Peers(self.peers.clone()),
+            warning_message: None,
+        };
+        Box::pin(async move { Ok(response) })
+    }
+}
+
+#[tokio::test]
+async fn download_with_fake_tracker() {
+    use crate::tracker::{Event, TrackerConfig};
+    let (t, data) = generate(2 * (1 << 14), 1 << 14);
+    let addr = Seeder::new(&t, data.clone()).spawn().await;
+    let tracker = Arc::new(FakeTracker {
+        peers: vec![addr],
+        events: Default::default(),
+        left: Default::default(),
+    });
+    let client = crate::client::Client::new(crate::download::DownloadConfig {
+        bootstrap_peers: 1,
+        tracker: TrackerConfig {
+            announce_client: Some(tracker.clone()),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    let downloaded = client.add(&t).wait().await.expect("download succeeds");
+    let file = (&downloaded).into_iter().next().expect("one file");
+    assert!(file.bytes().unwrap() == data);
+    assert_eq!(
+        *tracker.events.lock().unwrap(),
+        [
+            Some(Event::Started),
+            Some(Event::Completed),
+            Some(Event::Stopped)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn failed_download_tells_tracker_it_stopped() {
+    use crate::tracker::{Event, TrackerConfig};
+    use std::time::Duration;
+    let (t, _) = generate(1 << 14, 1 << 14);
+    let tracker = Arc::new(FakeTracker {
+        peers: Vec::new(),
+        events: Default::default(),
+        left: Default::default(),
+    });
+    let client = crate::client::Client::new(crate::download::DownloadConfig {
+        tracker: TrackerConfig {
+            announce_client: Some(tracker.clone()),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    let Err(e) = client.add(&t).wait().await else {
+        panic!("there are no peers to download from");
+    };
+    assert!(format!("{e:#}").contains("could not connect to any peers"));
+    // the stopped announce goes out in the background once the download gives up
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !tracker
+            .events
+            .lock()
+            .unwrap()
+            .contains(&Some(Event::Stopped))
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("tracker hears that we stopped");
+    assert_eq!(tracker.events.lock().unwrap()[0], Some(Event::Started));
+}
+
+#[tokio::test]
+async fn seeding_stops_at_ratio_limit() {
+    use crate::download::{DownloadConfig, DownloadEvent};
+    use crate::seed::{self, Seed};
+    use crate::tracker::{Event, TrackerConfig};
+    use futures_util::StreamExt;
+
+    let (t, data) = generate(3 * (1 << 14), 1 << 14);
+    let dir = tempfile::tempdir().expect("create temporary directory");
+    std::fs::write(dir.path().join(t.info.local_name()), &data).unwrap();
+    let seed_tracker = Arc::new(FakeTracker {
+        peers: Vec::new(),
+        events: Default::default(),
+        left: Default::default(),
+    });
+    let config = DownloadConfig {
+        seed_ratio_limit: Some(1.0),
+        tracker: TrackerConfig {
+            announce_client: Some(seed_tracker.clone()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let seed = Arc::new(Seed::verify(&t, dir.path(), &config).await.unwrap());
+    let events = seed.stats().events();
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = local_addr(&listener);
+    let seeding = tokio::spawn({
+        let seed = Arc::clone(&seed);
+        async move { seed::run(vec![seed], listener, &config, Default::default()).await }
+    });
+
+    // one whole copy makes for a ratio of 1, since the seed never downloaded anything
+    let client = crate::client::Client::new(DownloadConfig {
+        bootstrap_peers: 1,
+        tracker: TrackerConfig {
+            announce_client: Some(Arc::new(FakeTracker {
+                peers: vec![addr],
+                events: Default::default(),
+                left: Default::default(),
+            })),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    client.add(&t).wait().await.expect("download succeeds");
+    tokio::time::timeout(std::time::Duration::from_secs(10), seeding)
+        .await
+        .expect("seeding stops by itself")
+        .unwrap()
+        .unwrap();
+
+    assert!(seed.is_finished());
+    assert_eq!(seed.ratio(), 1.0);
+    let announces = seed_tracker.events.lock().unwrap().clone();
+    assert_eq!(announces.first(), Some(&Some(Event::Started)));
+    assert_eq!(announces.last(), Some(&Some(Event::Stopped)));
+    let finished = events.any(|event| std::future::ready(event == DownloadEvent::SeedingFinished));
+    tokio::time::timeout(std::time::Duration::from_secs(1), finished)
+        .await
+        .expect("seed says it's finished");
+}
+
+#[tokio::test]
+async fn download_into_mmap_storage() {
+    use crate::storage::StorageBackend;
+    let (mut t, data) = generate(3 * (1 << 14) + 10, 1 << 14);
+    let addr = Seeder::new(&t, data.clone()).spawn().await;
+    t.announce = tracker(vec![addr]).await;
+    let dir = tempfile::tempdir().expect("create temporary directory");
+    let client = crate::client::Client::new(crate::download::DownloadConfig {
+        bootstrap_peers: 1,
+        storage: StorageBackend::Mmap(dir.path().to_path_buf()),
+        ..Default::default()
+    });
+    let downloaded = client.add(&t).wait().await.expect("download succeeds");
+    let file = (&downloaded).into_iter().next().expect("one file");
+    assert!(file.bytes().unwrap() == data);
+    // the pieces went straight into the file
+    assert!(std::fs::read(dir.path().join("generated.bin")).unwrap() == data);
+
+    // and for disk storage, the ones still waiting to be written went in once the download was
+    // done
+    let dir = tempfile::tempdir().expect("create temporary directory");
+    let client = crate::client::Client::new(crate::download::DownloadConfig {
+        bootstrap_peers: 1,
+        storage: StorageBackend::Disk(dir.path().to_path_buf()),
+        write_buffer_size: 2 * (1 << 14),
+        ..Default::default()
+    });
+    client.add(&t).wait().await.expect("download succeeds");
+    assert!(std::fs::read(dir.path().join("generated.bin")).unwrap() == data);
+}
+
+#[tokio::test]
+async fn resume_from_record() {
+    use crate::bitfield::Bitfield;
+    use crate::resume::ResumeFile;
+    use crate::storage::StorageBackend;
+    let (t, data) = generate(3 * (1 << 14) + 10, 1 << 14);
+    let seeder = Seeder::new(&t, data.clone());
+    let served = Arc::clone(&seeder.served);
+    let addr = seeder.spawn().await;
+
+    // an earlier run got as far as the first two pieces (and part of the third, which it didn't
+    // get to record) before it was cut short
+    let dir = tempfile::tempdir().expect("create temporary directory");
+    let mut stored = data.clone();
+    stored[2 * (1 << 14)..].fill(0);
+    stored[2 * (1 << 14)..][..100].copy_from_slice(&data[2 * (1 << 14)..][..100]);
+    std::fs::write(dir.path().join("generated.bin"), &stored).unwrap();
+    let record = dir
+        .path()
+        .join(format!(".{}.resume", hex::encode(t.info_hash())));
+    let mut have = Bitfield::empty();
+    have.set_piece(0);
+    have.set_piece(1);
+    ResumeFile::new(record.clone(), t.info_hash(), 4)
+        .save(&have, &[], false)
+        .unwrap();
+
+    let tracker = Arc::new(FakeTracker {
+        peers: vec![addr],
+        events: Default::default(),
+        left: Default::default(),
+    });
+    let client = crate::client::Client::new(crate::download::DownloadConfig {
+        bootstrap_peers: 1,
+        storage: StorageBackend::Disk(dir.path().to_path_buf()),
+        tracker: crate::tracker::TrackerConfig {
+            announce_client: Some(tracker.clone()),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    let downloaded = client.add(&t).wait().await.expect("download succeeds");
+    assert!(downloaded.is_complete());
+    assert_eq!(served.load(Ordering::Relaxed), 2, "only the missing pieces");
+    // the tracker heard from the start that only the last two pieces were left
+    assert_eq!(tracker.left.lock().unwrap()[0], (1 << 14) + 10);
+    assert!(std::fs::read(dir.path().join("generated.bin")).unwrap() == data);
+    assert!(!record.exists(), "nothing left to resume");
+}
+
+#[tokio::test]
+async fn resume_mid_piece() {
+    use crate::resume::ResumeFile;
+    use crate::storage::StorageBackend;
+    use std::time::Duration;
+    // a single piece of four blocks
+    let (mut t, data) = generate(4 * (1 << 14), 4 * (1 << 14));
+    let dir = tempfile::tempdir().expect("create temporary directory");
+    let config = || crate::download::DownloadConfig {
+        bootstrap_peers: 1,
+        storage: StorageBackend::Disk(dir.path().to_path_buf()),
+        ..Default::default()
+    };
+
+    // the first run gets two of the blocks before its only seeder goes quiet, and is stopped
+    let mut quiet = Seeder::new(&t, data.clone());
+    quiet.serve_only = Some(2);
+    let served = Arc::clone(&quiet.served);
+    t.announce = tracker(vec![quiet.spawn().await]).await;
+    let mut download = crate::client::Client::new(config()).add(&t);
+    while served.load(Ordering::Relaxed) < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // give the blocks time to arrive
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    download.stop();
+    let downloaded = download.wait().await.expect("stopping isn't an error");
+    assert_eq!(downloaded.verified_pieces(), 0);
+    assert_eq!(downloaded.partial_pieces().len(), 1);
+    let record = dir
+        .path()
+        .join(format!(".{}.resume", hex::encode(t.info_hash())));
+    let resumed = ResumeFile::new(record, t.info_hash(), 1)
+        .load()
+        .unwrap()
+        .expect("the record is kept");
+    assert_eq!(resumed.partial, downloaded.partial_pieces());
+
+    // the next run picks up the piece where the first one left off
+    let seeder = Seeder::new(&t, data.clone());
+    let served = Arc::clone(&seeder.served);
+    t.announce = tracker(vec![seeder.spawn().await]).await;
+    let downloaded = crate::client::Client::new(config())
+        .add(&t)
+        .wait()
+        .await
+        .expect("download succeeds");
+    assert!(downloaded.is_complete());
+    assert_eq!(served.load(Ordering::Relaxed), 2, "only the missing blocks");
+    assert!(std::fs::read(dir.path().join("generated.bin")).unwrap() == data);
+}
+
+#[tokio::test]
+async fn dedupe_across_downloads() {
+    use std::time::Duration;
+    let (mut a, data) = generate(3 * (1 << 14) + 10, 1 << 14);
+    // the same file under another name, and so another info hash
+    let mut b = a.clone();
+    b.info.name = "copy.bin".into();
+    a.announce = tracker(vec![Seeder::new(&a, data.clone()).spawn().await]).await;
+    let mut empty = Seeder::new(&b, data.clone());
+    empty.empty = true;
+    b.announce = tracker(vec![empty.spawn().await]).await;
+
+    let client = crate::client::Client::new(crate::download::DownloadConfig {
+        bootstrap_peers: 1,
+        ..Default::default()
+    });
+    let mut first = client.add(&a);
+    first.wait().await.expect("download succeeds");
+    let mut second = client.add(&b);
+    // the copy's storage opens before it announces, and it has no peers to get anything from
+    let duplicates = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let duplicates = client.dedupe_scan().expect("scan succeeds");
+            if !duplicates.is_empty() {
+                break duplicates;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("downloads share a file");
+    assert_eq!(duplicates.len(), 1);
+    assert_eq!(duplicates[0].reused, 4);
+    assert_eq!(duplicates[0].info_hashes, [a.info_hash(), b.info_hash()]);
+
+    let downloaded = tokio::time::timeout(Duration::from_secs(5), second.wait())
+        .await
+        .expect("copy doesn't wait for peers")
+        .expect("download succeeds");
+    let file = (&downloaded).into_iter().next().expect("one file");
+    assert!(file.bytes().unwrap() == data);
+    assert!(client.dedupe_scan().unwrap()[0].reused == 0);
+}
+
+#[tokio::test]
+async fn queued_downloads() {
+    use std::time::Duration;
+    let torrent = |name: &str, empty| {
+        let (mut t, data) = generate(2 * (1 << 14) + 10, 1 << 14);
+        t.info.name = name.into();
+        async move {
+            let mut seeder = Seeder::new(&t, data);
+            seeder.empty = empty;
+            t.announce = tracker(vec![seeder.spawn().await]).await;
+            t
+        }
+    };
+    // a download that never gets anywhere, and so keeps its turn until it's stopped
+    let stuck = torrent("stuck.bin", true).await;
+    let (forced, queued) = (
+        torrent("forced.bin", false).await,
+        torrent("queued.bin", false).await,
+    );
+
+    let client = crate::client::Client::new(crate::download::DownloadConfig {
+        bootstrap_peers: 1,
+        ..Default::default()
+    })
+    .with_max_active_downloads(1);
+    let mut stuck = client.add(&stuck);
+    let mut forced = client.add(&forced);
+    let mut queued = client.add(&queued);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!stuck.is_queued());
+    assert!(forced.is_queued() && queued.is_queued());
+
+    forced.force_start();
+    tokio::time::timeout(Duration::from_secs(5), forced.wait())
+        .await
+        .expect("forced download doesn't wait its turn")
+        .expect("download succeeds");
+    assert!(queued.is_queued());
+
+    stuck.stop();
+    let _ = stuck.wait().await;
+    tokio::time::timeout(Duration::from_secs(5), queued.wait())
+        .await
+        .expect("queued download starts once there's room")
+        .expect("download succeeds");
+}
+
+#[test]
+fn mmap_storage_layouts() {
+    use crate::storage::Storage;
+    let plength = 2 * crate::BLOCK_MAX;
+    for lengths in adversarial_layouts(plength)
+        .into_iter()
+        .chain(random_layouts(50, plength))
+    {
+        let (t, data) = generate_files(&lengths, plength);
+        t.validate().expect("valid torrent");
+        let dir = tempfile::tempdir().expect("create temporary directory");
+        let storage = crate::mmap::MmapStorage::create(&t, dir.path(), &Layout::default())
+            .expect("map files");
+        // the last piece first, and then every other one, so that writes don't just append
+        let npieces = t.info.pieces.0.len();
+        let order = std::iter::once(npieces - 1)
+            .chain((0..npieces - 1).step_by(2))
+            .chain((1..npieces - 1).step_by(2));
+        for piece_i in order {
+            let piece = &data[piece_i * plength..][..plength.min(data.len() - piece_i * plength)];
+            storage.write_piece(piece_i, piece).expect("write piece");
+        }
+        drop(storage);
+        assert_files_match(&t, &data, dir.path(), &Layout::default());
+    }
+}
+
+#[tokio::test]
+async fn downloaded_layouts() {
+    let plength = 2 * crate::BLOCK_MAX;
+    for lengths in adversarial_layouts(plength) {
+        let (t, data) = generate_files(&lengths, plength);
+        let downloaded = download_from(Seeder::new(&t, data.clone()), t.clone()).await;
+        assert!(downloaded.is_complete());
+        let dir = tempfile::tempdir().expect("create temporary directory");
+        downloaded
+            .write_to_dir(dir.path())
+            .await
+            .expect("write files");
+        assert_files_match(&t, &data, dir.path(), &Layout::default());
+    }
+}
+
+#[tokio::test]
+async fn custom_layouts() {
+    use crate::storage::StorageBackend;
+    let plength = 2 * crate::BLOCK_MAX;
+    let single = generate(3 * plength + 10, plength);
+    let multi = generate_files(&[plength + 5, 2 * plength], plength);
+    let layouts = [
+        Layout {
+            file_name: Some("renamed.bin".into()),
+            dir: None,
+        },
+        Layout {
+            file_name: None,
+            dir: Some("".into()),
+        },
+        Layout {
+            file_name: None,
+            dir: Some("nested/elsewhere".into()),
+        },
+    ];
+    for ((mut t, data), layout) in [single, multi.clone(), multi].into_iter().zip(layouts) {
+        let addr = Seeder::new(&t, data.clone()).spawn().await;
+        t.announce = tracker(vec![addr]).await;
+        let storage = tempfile::tempdir().expect("create temporary directory");
+        let client = crate::client::Client::new(crate::download::DownloadConfig {
+            bootstrap_peers: 1,
+            storage: StorageBackend::Mmap(storage.path().to_path_buf()),
+            layout: layout.clone(),
+            ..Default::default()
+        });
+        let downloaded = client.add(&t).wait().await.expect("download succeeds");
+        // the pieces went straight into the renamed files
+        assert_files_match(&t, &data, storage.path(), &layout);
+
+        let dir = tempfile::tempdir().expect("create temporary directory");
+        downloaded
+            .write_to_dir(dir.path())
+            .await
+            .expect("write files");
+        assert_files_match(&t, &data, dir.path(), &layout);
+        assert_eq!(
+            downloaded.root(dir.path()),
+            layout.root(&t, dir.path()).unwrap()
+        );
+    }
+    assert!(Layout {
+        file_name: Some("../escape.bin".into()),
+        dir: None,
+    }
+    .files(&generate(10, plength).0, std::path::Path::new("out"))
+    .is_err());
+    // nor may a multi-file torrent's name take its directory out of the output
+    let (mut t, _) = generate_files(&[10, 20], plength);
+    t.info.name = "../escape".into();
+    assert!(Layout::default()
+        .files(&t, std::path::Path::new("out"))
+        .is_err());
+}
+
+#[tokio::test]
+async fn partial_seed() {
+    use crate::download::DownloadConfig;
+    use crate::seed::{self, Seed};
+    use crate::tracker::{Event, TrackerConfig};
+
+    // the short middle file shares piece 2 with the last file
+    let plength = 1 << 14;
+    let (t, data) = generate_files(&[2 * plength, 10, 2 * plength], plength);
+    let dir = tempfile::tempdir().expect("create temporary directory");
+    let files = Layout::default().files(&t, dir.path()).unwrap();
+    let mut offset = 0;
+    for (file_i, (path, length)) in files.into_iter().enumerate() {
+        // the middle file was skipped
+        if file_i != 1 {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, &data[offset..][..length]).unwrap();
+        }
+        offset += length;
+    }
+    let tracker = Arc::new(FakeTracker {
+        peers: Vec::new(),
+        events: Default::default(),
+        left: Default::default(),
+    });
+    let config = DownloadConfig {
+        tracker: TrackerConfig {
+            announce_client: Some(tracker.clone()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    assert!(Seed::verify(&t, dir.path(), &config).await.is_err());
+    let seed = Arc::new(Seed::verify_partial(&t, dir.path(), &config).await.unwrap());
+    assert!(seed.is_partial());
+    assert_eq!(seed.stats().left(), plength);
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let stop = tokio_util::sync::CancellationToken::new();
+    let seeding = tokio::spawn({
+        let stop = stop.clone();
+        async move { seed::run(vec![seed], listener, &config, stop).await }
+    });
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while tracker.events.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("seed announces itself");
+    stop.cancel();
+    seeding.await.unwrap().unwrap();
+    assert_eq!(
+        *tracker.events.lock().unwrap(),
+        [Some(Event::Paused), Some(Event::Stopped)]
+    );
+}
+
+#[tokio::test]
+async fn failed_peers_are_dropped() {
+    use crate::download::DownloadEvent;
+    use futures_util::StreamExt;
+
+    let (mut t, data) = generate(16 * (1 << 15), 1 << 15);
+    let mut flaky = Seeder::new(&t, data.clone());
+    flaky.faults = Faults {
+        seed: 3,
+        drop: 0.05,
+        ..Faults::default()
+    };
+    let flaky_addr = flaky.spawn().await;
+    t.announce = tracker(vec![flaky_addr, Seeder::new(&t, data).spawn().await]).await;
+    let client = crate::client::Client::new(crate::download::DownloadConfig {
+        bootstrap_peers: 2,
+        ..Default::default()
+    });
+    let mut handle = client.add(&t);
+    let events = handle.events();
+    handle.wait().await.expect("download succeeds");
+    drop(handle);
+    drop(client);
+
+    // the flaky seeder was let go of as soon as it hung up on us, not just once we were done
+    let events: Vec<_> = events.collect().await;
+    let dropped = events
+        .iter()
+        .position(|event| *event == DownloadEvent::PeerDropped { addr: flaky_addr })
+        .expect("the flaky seeder hung up");
+    let completed = events
+        .iter()
+        .position(|event| *event == DownloadEvent::Completed)
+        .expect("download completed");
+    assert!(dropped < completed);
+}
+
+#[tokio::test]
+async fn download_through_faults() {
+    // one seeder drops connections, cuts messages short, and holds them back, and the other
+    // corrupts blocks, so every piece is likely to need more than one attempt
+    let (mut t, data) = generate(4 * (1 << 17) + 1000, 1 << 17);
+    let mut flaky = Seeder::new(&t, data.clone());
+    flaky.faults = Faults {
+        seed: 1,
+        drop: 0.1,
+        truncate: 0.1,
+        delay: Some(Duration::from_millis(5)),
+        ..Faults::default()
+    };
+    let mut corrupting = Seeder::new(&t, data.clone());
+    corrupting.faults = Faults {
+        seed: 2,
+        corrupt: 0.1,
+        ..Faults::default()
+    };
+    let corrupting_addr = corrupting.spawn().await;
+    t.announce = tracker(vec![flaky.spawn().await, corrupting_addr]).await;
+    let dumps = tempfile::tempdir().expect("create temporary directory");
+    let client = crate::client::Client::new(crate::download::DownloadConfig {
+        bootstrap_peers: 2,
+        hash_failure_dir: Some(dumps.path().into()),
+        ..Default::default()
+    });
+    let mut download = client.add(&t);
+    let stats = download.stats();
+    let downloaded = tokio::time::timeout(Duration::from_secs(20), download.wait())
+        .await
+        .expect("faults don't hold up the download for good")
+        .expect("download succeeds");
+    let file = (&downloaded).into_iter().next().expect("one file");
+    assert!(file.bytes().unwrap() == data);
+    assert!(stats.hash_failures() > 0);
+    // each bad piece was downloaded for nothing
+    assert!(stats.corrupt() >= stats.hash_failures());
+    assert!(stats.wasted() >= stats.corrupt());
+
+    // every bad piece was kept, and has a block from the seeder that corrupts them
+    let mut maps = 0;
+    for entry in std::fs::read_dir(dumps.path()).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "blocks") {
+            let map = std::fs::read_to_string(&path).unwrap();
+            assert!(map.contains(&corrupting_addr.to_string()));
+            assert!(path.with_extension("piece").exists());
+            maps += 1;
+        }
+    }
+    assert_eq!(maps, stats.hash_failures());
+}
+
+#[tokio::test]
+async fn download_in_smaller_blocks() {
+    // pieces that don't divide evenly into blocks, and a short last piece
+    let plength = 3 * (1 << 13) + 100;
+    let (mut t, data) = generate(3 * plength + 10, plength);
+    let seeder = Seeder::new(&t, data.clone());
+    let served = Arc::clone(&seeder.served);
+    t.announce = tracker(vec![seeder.spawn().await]).await;
+    let client = crate::client::Client::new(crate::download::DownloadConfig {
+        bootstrap_peers: 1,
+        block_size: 1 << 13,
+        ..Default::default()
+    });
+    let downloaded = client.add(&t).wait().await.expect("download succeeds");
+    let file = (&downloaded).into_iter().next().expect("one file");
+    assert!(file.bytes().unwrap() == data);
+    assert_eq!(served.load(Ordering::Relaxed), 3 * 4 + 1);
+}
@@ -1,51 +1,444 @@
-use crate::download::Downloaded;
+use crate::download::{DownloadConfig, DownloadHandle, Downloaded};
 
 use super::download;
+use crate::bencode::{self, Mode};
+use crate::swarm::{self, SwarmState};
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
-use std::path::Path;
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
+pub use bytestring::ByteString;
 pub use hashes::Hashes;
 
+/// The largest piece length we're willing to handle.
+///
+/// Real torrents rarely use pieces larger than 16 MiB, and we hold (at least) a whole piece in
+/// memory while downloading it.
+pub const MAX_PIECE_LENGTH: usize = 1 << 28;
+
+/// Ways in which the metadata in a .torrent file can be inconsistent.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidTorrent {
+    #[error("piece length is zero")]
+    ZeroPieceLength,
+    #[error("piece length {0} is larger than the maximum of {MAX_PIECE_LENGTH}")]
+    PieceLengthTooLarge(usize),
+    #[error("multi-file torrent has no files")]
+    NoFiles,
+    #[error("name {0:?} is not a single file or directory name")]
+    UnsafeName(String),
+    #[error("file {0} has an empty path")]
+    EmptyPath(usize),
+    #[error("file {0} is a directory, but has a length")]
+    DirectoryLength(usize),
+    #[error("total length of all files overflows")]
+    LengthOverflow,
+    #[error("torrent has {actual} piece hashes, but {length} bytes need {expected}")]
+    PieceCountMismatch {
+        length: usize,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+/// Ways in which re-encoding a torrent's info dictionary doesn't reproduce the original.
+///
+/// The info hash is computed from the re-encoded dictionary, so any of these make it wrong, and
+/// peers and trackers won't recognise the torrent.
+#[derive(Debug, Clone, Default, PartialEq, Eq, thiserror::Error)]
+#[error("re-encoded info dictionary differs from the original: {}", self.describe())]
+pub struct InfoMismatch {
+    /// Keys of the original that we don't know about, and so leave out.
+    pub dropped: Vec<String>,
+    /// Keys whose values come out differently.
+    pub changed: Vec<String>,
+    /// Keys we add that the original didn't have.
+    pub added: Vec<String>,
+    /// Whether the original's keys weren't in sorted order, as bencode requires.
+    pub unsorted: bool,
+}
+
+impl InfoMismatch {
+    fn is_empty(&self) -> bool {
+        self.dropped.is_empty()
+            && self.changed.is_empty()
+            && self.added.is_empty()
+            && !self.unsorted
+    }
+
+    fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        for (what, keys) in [
+            ("drops", &self.dropped),
+            ("changes", &self.changed),
+            ("adds", &self.added),
+        ] {
+            if !keys.is_empty() {
+                parts.push(format!("{what} {}", keys.join(", ")));
+            }
+        }
+        if self.unsorted {
+            parts.push("the original's keys aren't sorted".to_string());
+        }
+        parts.join("; ")
+    }
+}
+
 /// A Metainfo file (also known as .torrent files).
+///
+/// Parse one with [`Torrent::from_bytes`] (or [`Torrent::read`]), or build one with
+/// [`Torrent::new`]. A torrent that was parsed keeps its info dictionary exactly as it was
+/// encoded, so that its info hash comes out right even if we'd encode the dictionary differently.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Torrent {
     /// The URL of the tracker.
     pub announce: String,
 
     pub info: Info,
+
+    #[serde(skip)]
+    original_info: Option<Arc<OriginalInfo>>,
+}
+
+/// An info dictionary as it was encoded where we got it from.
+struct OriginalInfo {
+    bytes: Vec<u8>,
+    hash: [u8; 20],
+    /// The hash of the dictionary as we re-encode it, to tell whether the [`Info`] has been
+    /// changed since it was parsed.
+    reencoded_hash: [u8; 20],
+}
+
+impl fmt::Debug for OriginalInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OriginalInfo")
+            .field("bytes", &self.bytes.len())
+            .field("hash", &hex::encode(self.hash))
+            .finish()
+    }
 }
 
 impl Torrent {
+    /// A torrent built from scratch, such as for a torrent file that's being created.
+    pub fn new(announce: impl Into<String>, info: Info) -> Self {
+        Self {
+            announce: announce.into(),
+            info,
+            original_info: None,
+        }
+    }
+
+    /// A torrent with the info dictionary `info`, as it was encoded, such as the metadata of a
+    /// magnet link once it's been fetched from peers.
+    pub fn from_info_bytes(announce: impl Into<String>, bytes: &[u8]) -> anyhow::Result<Self> {
+        let length = bencode::value_length(bytes).context("parse info dictionary")?;
+        anyhow::ensure!(
+            length == bytes.len() && bytes.first() == Some(&b'd'),
+            "info is not a single dictionary"
+        );
+        let info = serde_bencode::from_bytes(bytes).context("parse info dictionary")?;
+        let mut t = Self::new(announce, info);
+        t.keep_original_info(bytes)?;
+        Ok(t)
+    }
+
+    /// Parse the contents of a torrent file.
+    pub fn from_bytes(dot_torrent: &[u8]) -> anyhow::Result<Self> {
+        Self::from_bytes_with(dot_torrent, Mode::Lenient)
+    }
+
+    /// Parse the contents of a torrent file, which has to be encoded as `mode` requires.
+    pub fn from_bytes_with(dot_torrent: &[u8], mode: Mode) -> anyhow::Result<Self> {
+        if mode == Mode::Strict {
+            bencode::check(dot_torrent, mode).context("parse torrent file")?;
+        }
+        let (mut t, pieces, _) = Self::split_pieces(dot_torrent).context("parse torrent file")?;
+        t.info.pieces = pieces;
+        let length = bencode::value_length(dot_torrent)?;
+        let info = bencode::dict_entries(&dot_torrent[..length])?
+            .into_iter()
+            .find_map(|(key, value)| (key == b"info").then_some(value))
+            .context("torrent has no info dictionary")?;
+        t.keep_original_info(info)?;
+        Ok(t)
+    }
+
+    /// Remember `info` as the encoding of our info dictionary, as it stands.
+    fn keep_original_info(&mut self, info: &[u8]) -> anyhow::Result<()> {
+        let reencoded = serde_bencode::to_bytes(&self.info).context("re-encode info dictionary")?;
+        self.original_info = Some(Arc::new(OriginalInfo {
+            bytes: info.to_vec(),
+            hash: Sha1::digest(info).into(),
+            reencoded_hash: Sha1::digest(&reencoded).into(),
+        }));
+        Ok(())
+    }
+
+    /// The encoded info dictionary, which is what the info hash is the hash of, and what peers
+    /// that ask for the torrent's metadata are sent.
+    ///
+    /// For a parsed torrent, that's the dictionary as it was encoded, unless the [`Info`] has
+    /// been changed since.
+    pub fn info_bytes(&self) -> Cow<'_, [u8]> {
+        let reencoded =
+            serde_bencode::to_bytes(&self.info).expect("re-encode info section should be fine");
+        match self.unchanged_original(&reencoded) {
+            Some(original) => Cow::Borrowed(&original.bytes),
+            None => Cow::Owned(reencoded),
+        }
+    }
+
     pub fn info_hash(&self) -> [u8; 20] {
         let info_encoded =
             serde_bencode::to_bytes(&self.info).expect("re-encode info section should be fine");
-        let mut hasher = Sha1::new();
-        hasher.update(&info_encoded);
-        hasher
-            .finalize()
-            .try_into()
-            .expect("GenericArray<_, 20> == [_; 20]")
+        match self.unchanged_original(&info_encoded) {
+            Some(original) => original.hash,
+            None => Sha1::digest(&info_encoded).into(),
+        }
+    }
+
+    /// The info dictionary as it was parsed, if `reencoded` (the [`Info`] as it is now) still
+    /// matches it.
+    fn unchanged_original(&self, reencoded: &[u8]) -> Option<&OriginalInfo> {
+        self.original_info
+            .as_deref()
+            .filter(|original| original.reencoded_hash == <[u8; 20]>::from(Sha1::digest(reencoded)))
+    }
+
+    /// Encode the torrent as the contents of a torrent file.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut dot_torrent = b"d8:announce".to_vec();
+        bencode::encode_bytes(self.announce.as_bytes(), &mut dot_torrent);
+        dot_torrent.extend(b"4:info");
+        dot_torrent.extend(&*self.info_bytes());
+        dot_torrent.push(b'e');
+        dot_torrent
+    }
+
+    /// Check that the metadata is internally consistent, so that computing piece sizes and
+    /// offsets from it can't overflow, divide by zero, or index out of bounds.
+    pub fn validate(&self) -> Result<(), InvalidTorrent> {
+        let plength = self.info.plength;
+        if plength == 0 {
+            return Err(InvalidTorrent::ZeroPieceLength);
+        }
+        if plength > MAX_PIECE_LENGTH {
+            return Err(InvalidTorrent::PieceLengthTooLarge(plength));
+        }
+        // the torrent's files (or directory) are saved under its name, which mustn't lead anywhere
+        // else
+        let name = self.info.local_name();
+        if !is_single_name(Path::new(&name)) {
+            return Err(InvalidTorrent::UnsafeName(
+                name.to_string_lossy().into_owned(),
+            ));
+        }
+        if let Keys::MultiFile { files } = &self.info.keys {
+            if files.is_empty() {
+                return Err(InvalidTorrent::NoFiles);
+            }
+            if let Some(file_i) = files.iter().position(|file| file.path.is_empty()) {
+                return Err(InvalidTorrent::EmptyPath(file_i));
+            }
+            if let Some(file_i) = files
+                .iter()
+                .position(|file| file.is_dir() && file.length != 0)
+            {
+                return Err(InvalidTorrent::DirectoryLength(file_i));
+            }
+        }
+        let length = self
+            .file_lengths()
+            .try_fold(0usize, usize::checked_add)
+            .ok_or(InvalidTorrent::LengthOverflow)?;
+        let expected = length.div_ceil(plength);
+        let actual = self.info.pieces.0.len();
+        if expected != actual {
+            return Err(InvalidTorrent::PieceCountMismatch {
+                length,
+                expected,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check that re-encoding the info dictionary gives back exactly what `dot_torrent`, the
+    /// file this torrent was parsed from, has.
+    ///
+    /// If it doesn't, the error is an [`InfoMismatch`] that says how. The torrent's own info hash
+    /// is still right, since it comes from the dictionary as it was encoded, but any change to
+    /// its [`Info`] gives it a new one, and other clients that parse it as we do may get it wrong.
+    pub fn verify_roundtrip(&self, dot_torrent: &[u8]) -> anyhow::Result<()> {
+        let original = bencode::dict_entries(dot_torrent)
+            .context("parse torrent file")?
+            .into_iter()
+            .find_map(|(key, value)| (key == b"info").then_some(value))
+            .context("torrent has no info dictionary")?;
+        let reencoded = serde_bencode::to_bytes(&self.info).context("re-encode info dictionary")?;
+        if original == reencoded {
+            return Ok(());
+        }
+
+        let original = bencode::dict_entries(original).context("parse info dictionary")?;
+        let reencoded = bencode::dict_entries(&reencoded).context("parse re-encoded info")?;
+        let name = |key: &[u8]| String::from_utf8_lossy(key).into_owned();
+        let mut mismatch = InfoMismatch {
+            unsorted: original.windows(2).any(|pair| pair[0].0 >= pair[1].0),
+            ..InfoMismatch::default()
+        };
+        for (key, value) in &original {
+            match reencoded.iter().find(|(k, _)| k == key) {
+                None => mismatch.dropped.push(name(key)),
+                Some((_, v)) if v != value => mismatch.changed.push(name(key)),
+                Some(_) => {}
+            }
+        }
+        for (key, _) in &reencoded {
+            if !original.iter().any(|(k, _)| k == key) {
+                mismatch.added.push(name(key));
+            }
+        }
+        if mismatch.is_empty() {
+            // the same entries, so the difference must be in how the dictionary itself is
+            // written, which only sorting can change
+            mismatch.unsorted = true;
+        }
+        Err(mismatch.into())
     }
 
     pub async fn read(file: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::read_with(file, Mode::Lenient).await
+    }
+
+    /// Read a torrent file, which has to be encoded as `mode` requires.
+    pub async fn read_with(file: impl AsRef<Path>, mode: Mode) -> anyhow::Result<Self> {
         let dot_torrent = tokio::fs::read(file).await.context("read torrent file")?;
-        let t: Torrent = serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
-        Ok(t)
+        Self::from_bytes_with(&dot_torrent, mode)
+    }
+
+    /// Parse `dot_torrent` with its piece hashes taken out, and the piece hashes on their own.
+    ///
+    /// The hashes make up nearly all of a large torrent file (a torrent with a million pieces has
+    /// 20 MB of them), and serde would copy them into a string of their own before we split that
+    /// up into hashes. So they're split up straight from `dot_torrent` instead, and the rest of
+    /// the file is parsed as it would be with no pieces at all, which is returned as the third
+    /// value.
+    fn split_pieces(dot_torrent: &[u8]) -> anyhow::Result<(Self, Hashes, Vec<u8>)> {
+        let length = bencode::value_length(dot_torrent)?;
+        let dot_torrent = &dot_torrent[..length];
+        let pieces = bencode::dict_entries(dot_torrent)?
+            .into_iter()
+            .find_map(|(key, value)| (key == b"info").then_some(value))
+            .filter(|info| info.first() == Some(&b'd'))
+            .map(bencode::dict_entries)
+            .transpose()
+            .context("parse info dictionary")?
+            .and_then(|info| {
+                info.into_iter()
+                    .find_map(|(key, value)| (key == b"pieces").then_some(value))
+            })
+            .filter(|pieces| pieces.first().is_some_and(u8::is_ascii_digit));
+        let Some(pieces) = pieces else {
+            // let serde say what's missing
+            let t = serde_bencode::from_bytes(dot_torrent)?;
+            return Ok((t, Hashes(Vec::new()), dot_torrent.to_vec()));
+        };
+
+        let start = pieces.as_ptr() as usize - dot_torrent.as_ptr() as usize;
+        let end = start + pieces.len();
+        let colon = pieces
+            .iter()
+            .position(|&b| b == b':')
+            .expect("strings have a colon");
+        let hashes = Hashes::from_bytes(&pieces[colon + 1..])?;
+        let mut rest = Vec::with_capacity(dot_torrent.len() - pieces.len() + 2);
+        rest.extend(&dot_torrent[..start]);
+        rest.extend(b"0:");
+        rest.extend(&dot_torrent[end..]);
+        let t = serde_bencode::from_bytes(&rest)?;
+        Ok((t, hashes, rest))
     }
 
+    /// Print the [file tree](Self::file_tree) to stderr.
     pub fn print_tree(&self) {
-        match &self.info.keys {
-            Keys::SingleFile { .. } => {
-                eprintln!("{}", self.info.name);
+        eprint!("{}", self.file_tree());
+    }
+
+    /// The torrent's files as a tree, with the files of a multi-file torrent under its directory,
+    /// one line for each file (with its length) and directory.
+    pub fn file_tree(&self) -> String {
+        let files = match &self.info.keys {
+            Keys::SingleFile { length } => {
+                return format!("{} ({length} bytes)\n", self.info.display_name());
             }
-            Keys::MultiFile { files } => {
-                for file in files {
-                    eprintln!("{}", file.path.join(std::path::MAIN_SEPARATOR_STR));
-                }
+            Keys::MultiFile { files } => files,
+        };
+        let mut root = TreeNode::default();
+        for file in files {
+            let path = file.relative_path();
+            let mut node = &mut root;
+            for component in &path {
+                let name = component.to_string_lossy();
+                let i = match node.children.iter().position(|(n, _)| *n == name) {
+                    Some(i) => i,
+                    None => {
+                        node.children.push((name.into_owned(), TreeNode::default()));
+                        node.children.len() - 1
+                    }
+                };
+                node = &mut node.children[i].1;
+            }
+            if !file.is_dir() {
+                node.length = Some(file.length);
             }
         }
+        let mut tree = format!("{}/\n", self.info.display_name());
+        root.render("", &mut tree);
+        tree
+    }
+
+    /// A `magnet:` link for the torrent, for sharing it without the torrent file.
+    ///
+    /// It has the info hash, the name, the total length, and the tracker, which is enough for a
+    /// client that supports fetching the metadata from peers (BEP 9) to get the rest.
+    pub fn to_magnet(&self) -> String {
+        let length = self.length().to_string();
+        let mut params = vec![
+            ("dn", self.info.display_name().into_owned()),
+            ("xl", length),
+        ];
+        if !self.announce.is_empty() {
+            params.push(("tr", self.announce.clone()));
+        }
+        format!(
+            "magnet:?xt=urn:btih:{}&{}",
+            hex::encode(self.info_hash()),
+            serde_urlencoded::to_string(params).expect("strings always encode")
+        )
+    }
+
+    /// Whether this is a private torrent (BEP 27).
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+
+    /// The length of each file in the torrent, in the order they appear in the torrent.
+    pub fn file_lengths(&self) -> impl Iterator<Item = usize> + '_ {
+        let (single, multi) = match &self.info.keys {
+            Keys::SingleFile { length } => (Some(*length), &[][..]),
+            Keys::MultiFile { files } => (None, &files[..]),
+        };
+        single
+            .into_iter()
+            .chain(multi.iter().map(|file| file.length))
     }
 
     pub fn length(&self) -> usize {
@@ -55,8 +448,67 @@ impl Torrent {
         }
     }
 
+    /// Start downloading the torrent in the background.
+    pub fn download(&self) -> DownloadHandle {
+        self.download_with(DownloadConfig::default())
+    }
+
+    /// Like [`Torrent::download`], but with a non-default configuration.
+    pub fn download_with(&self, config: DownloadConfig) -> DownloadHandle {
+        download::start(self.clone(), config, CancellationToken::new(), None)
+    }
+
     pub async fn download_all(&self) -> anyhow::Result<Downloaded> {
-        download::all(self).await
+        self.download().wait().await
+    }
+
+    /// Connect to the swarm for this torrent, and report what pieces each peer has, whether they
+    /// are choking us, and so on.
+    ///
+    /// Peers are given a couple of seconds to tell us about themselves before the snapshot is
+    /// taken.
+    pub async fn swarm_state(&self) -> anyhow::Result<SwarmState> {
+        swarm::observe(self, Duration::from_secs(2), |_| false).await
+    }
+
+    /// Like [`Torrent::swarm_state`], but keeps watching the swarm, calling `report` every
+    /// `interval` until it returns `false`.
+    pub async fn watch_swarm(
+        &self,
+        interval: Duration,
+        report: impl FnMut(&SwarmState) -> bool,
+    ) -> anyhow::Result<SwarmState> {
+        swarm::observe(self, interval, report).await
+    }
+}
+
+/// A directory (or file) in a [file tree](Torrent::file_tree), with its entries in the order the
+/// torrent first mentions them.
+#[derive(Default)]
+struct TreeNode {
+    children: Vec<(String, TreeNode)>,
+    /// The length of a file, which directories don't have.
+    length: Option<usize>,
+}
+
+impl TreeNode {
+    /// Add a line for each entry to `out`, below the line for this node, behind `prefix`.
+    fn render(&self, prefix: &str, out: &mut String) {
+        for (i, (name, child)) in self.children.iter().enumerate() {
+            let last = i + 1 == self.children.len();
+            out.push_str(prefix);
+            out.push_str(if last { "└── " } else { "├── " });
+            match child.length {
+                Some(length) if child.children.is_empty() => {
+                    out.push_str(&format!("{name} ({length} bytes)\n"));
+                }
+                _ => out.push_str(&format!("{name}/\n")),
+            }
+            child.render(
+                &format!("{prefix}{}", if last { "    " } else { "│   " }),
+                out,
+            );
+        }
     }
 }
 
@@ -66,7 +518,17 @@ pub struct Info {
     ///
     /// In the single file case, the name key is the name of a file, in the muliple file case, it's
     /// the name of a directory.
-    pub name: String,
+    ///
+    /// This is whatever bytes the torrent's creator used, which aren't necessarily UTF-8.
+    pub name: ByteString,
+
+    /// The same name as `name`, but in UTF-8, for torrents whose `name` is in some other encoding.
+    #[serde(
+        rename = "name.utf-8",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub name_utf8: Option<String>,
 
     /// The number of bytes in each piece the file is split into.
     ///
@@ -80,10 +542,64 @@ pub struct Info {
     /// Each entry of `pieces` is the SHA1 hash of the piece at the corresponding index.
     pub pieces: Hashes,
 
+    /// If set to 1, the torrent is private (BEP 27): peers may only be obtained from the tracker,
+    /// and not through DHT, PEX, or other peer discovery mechanisms.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+
     #[serde(flatten)]
     pub keys: Keys,
 }
 
+impl Info {
+    /// The info of a public torrent of the single file `name`, of `length` bytes, in pieces of
+    /// `plength` bytes that hash to `pieces`.
+    pub fn single_file(name: &str, plength: usize, pieces: Vec<[u8; 20]>, length: usize) -> Self {
+        Self {
+            name: name.into(),
+            name_utf8: None,
+            plength,
+            pieces: Hashes(pieces),
+            private: None,
+            keys: Keys::SingleFile { length },
+        }
+    }
+
+    /// The info of a public torrent of the directory `name` with `files` in it, whose data
+    /// (all the files, one after the other) is in pieces of `plength` bytes that hash to
+    /// `pieces`.
+    pub fn multi_file(name: &str, plength: usize, pieces: Vec<[u8; 20]>, files: Vec<File>) -> Self {
+        Self {
+            keys: Keys::MultiFile { files },
+            ..Self::single_file(name, plength, pieces, 0)
+        }
+    }
+
+    /// The name of the torrent, for showing to the user.
+    pub fn display_name(&self) -> Cow<'_, str> {
+        match &self.name_utf8 {
+            Some(name) => Cow::Borrowed(name),
+            None => self.name.to_string_lossy(),
+        }
+    }
+
+    /// The name to save the file (or directory) as.
+    pub fn local_name(&self) -> OsString {
+        match &self.name_utf8 {
+            Some(name) => name.into(),
+            None => self.name.to_os_string(),
+        }
+    }
+}
+
+/// Whether `path` is the name of a single file or directory, and so stays inside whatever
+/// directory it's joined onto.
+pub(crate) fn is_single_name(path: &Path) -> bool {
+    let mut components = path.components();
+    matches!(components.next(), Some(std::path::Component::Normal(_)))
+        && components.next().is_none()
+}
+
 /// There is a key `length` or a key `files`, but not both or neither.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(untagged)]
@@ -107,7 +623,144 @@ pub struct File {
 
     /// Subdirectory names for this file, the last of which is the actual file name
     /// (a zero length list is an error case).
-    pub path: Vec<String>,
+    ///
+    /// Some torrents include empty directories as entries whose last name is empty, such as
+    /// `["docs", ""]`. Those always have a length of zero.
+    pub path: Vec<ByteString>,
+
+    /// The same path as `path`, but in UTF-8, for torrents whose `path` is in some other encoding.
+    #[serde(
+        rename = "path.utf-8",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub path_utf8: Option<Vec<String>>,
+}
+
+impl File {
+    /// Whether this entry stands for an empty directory rather than a file.
+    pub fn is_dir(&self) -> bool {
+        self.path.last().is_some_and(|name| name.is_empty())
+    }
+
+    /// Where the file goes, relative to the torrent's directory.
+    ///
+    /// This is not checked to stay inside that directory.
+    pub fn relative_path(&self) -> PathBuf {
+        match &self.path_utf8 {
+            Some(path) => path.iter().collect(),
+            None => self.path.iter().map(ByteString::to_os_string).collect(),
+        }
+    }
+}
+
+mod bytestring {
+    use serde::de::{self, Deserialize, Deserializer, Visitor};
+    use serde::ser::{Serialize, Serializer};
+    use std::borrow::Cow;
+    use std::ffi::OsString;
+    use std::fmt;
+
+    /// A bencoded string, which may or may not be valid UTF-8.
+    #[derive(Clone, Default, PartialEq, Eq, Hash)]
+    pub struct ByteString(pub Vec<u8>);
+
+    impl ByteString {
+        pub fn as_bytes(&self) -> &[u8] {
+            &self.0
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+
+        /// The string as UTF-8, with any invalid sequences replaced by U+FFFD.
+        pub fn to_string_lossy(&self) -> Cow<'_, str> {
+            String::from_utf8_lossy(&self.0)
+        }
+
+        /// The string as a file name.
+        ///
+        /// On Unix, file names are bytes too, so they're used as is. Elsewhere, the name has to
+        /// be converted lossily.
+        pub fn to_os_string(&self) -> OsString {
+            #[cfg(unix)]
+            {
+                use std::os::unix::ffi::OsStringExt;
+                OsString::from_vec(self.0.clone())
+            }
+            #[cfg(not(unix))]
+            {
+                self.to_string_lossy().into_owned().into()
+            }
+        }
+    }
+
+    impl From<&str> for ByteString {
+        fn from(s: &str) -> Self {
+            Self(s.as_bytes().to_vec())
+        }
+    }
+
+    impl From<String> for ByteString {
+        fn from(s: String) -> Self {
+            Self(s.into_bytes())
+        }
+    }
+
+    impl fmt::Debug for ByteString {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt::Debug::fmt(&self.to_string_lossy(), f)
+        }
+    }
+
+    impl fmt::Display for ByteString {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(&self.to_string_lossy())
+        }
+    }
+
+    struct ByteStringVisitor;
+
+    impl<'de> Visitor<'de> for ByteStringVisitor {
+        type Value = ByteString;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a byte string")
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(ByteString(v.to_vec()))
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(v.into())
+        }
+    }
+
+    impl<'de> Deserialize<'de> for ByteString {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_bytes(ByteStringVisitor)
+        }
+    }
+
+    impl Serialize for ByteString {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
 }
 
 mod hashes {
@@ -119,6 +772,23 @@ mod hashes {
     pub struct Hashes(pub Vec<[u8; 20]>);
     struct HashesVisitor;
 
+    impl Hashes {
+        /// Split up the concatenated hashes of a torrent's `pieces`.
+        pub fn from_bytes(v: &[u8]) -> anyhow::Result<Self> {
+            anyhow::ensure!(
+                v.len().is_multiple_of(20),
+                "piece hashes are {} bytes long, which is not a multiple of 20",
+                v.len()
+            );
+            // TODO: use array_chunks when stable
+            Ok(Hashes(
+                v.chunks_exact(20)
+                    .map(|slice_20| slice_20.try_into().expect("guaranteed to be length 20"))
+                    .collect(),
+            ))
+        }
+    }
+
     impl<'de> Visitor<'de> for HashesVisitor {
         type Value = Hashes;
 
@@ -130,15 +800,7 @@ mod hashes {
         where
             E: de::Error,
         {
-            if v.len() % 20 != 0 {
-                return Err(E::custom(format!("length is {}", v.len())));
-            }
-            // TODO: use array_chunks when stable
-            Ok(Hashes(
-                v.chunks_exact(20)
-                    .map(|slice_20| slice_20.try_into().expect("guaranteed to be length 20"))
-                    .collect(),
-            ))
+            Hashes::from_bytes(v).map_err(E::custom)
         }
     }
 
@@ -161,3 +823,241 @@ mod hashes {
         }
     }
 }
+
+#[test]
+fn validate() {
+    let t = |plength, length, npieces| {
+        Torrent::new(
+            String::new(),
+            Info {
+                name: "file".into(),
+                name_utf8: None,
+                plength,
+                pieces: Hashes(vec![[0; 20]; npieces]),
+                private: None,
+                keys: Keys::SingleFile { length },
+            },
+        )
+    };
+    assert_eq!(t(10, 25, 3).validate(), Ok(()));
+    assert_eq!(t(10, 30, 3).validate(), Ok(()));
+    assert_eq!(t(0, 25, 3).validate(), Err(InvalidTorrent::ZeroPieceLength));
+    assert_eq!(
+        t(10, 31, 3).validate(),
+        Err(InvalidTorrent::PieceCountMismatch {
+            length: 31,
+            expected: 4,
+            actual: 3
+        })
+    );
+
+    let mut overflow = t(10, 0, 0);
+    overflow.info.keys = Keys::MultiFile {
+        files: vec![
+            File {
+                length: usize::MAX,
+                path: vec!["a".into()],
+                path_utf8: None,
+            },
+            File {
+                length: 1,
+                path: vec!["b".into()],
+                path_utf8: None,
+            },
+        ],
+    };
+    assert_eq!(overflow.validate(), Err(InvalidTorrent::LengthOverflow));
+
+    let mut directory = t(10, 0, 0);
+    directory.info.keys = Keys::MultiFile {
+        files: vec![File {
+            length: 1,
+            path: vec!["a".into(), "".into()],
+            path_utf8: None,
+        }],
+    };
+    assert_eq!(
+        directory.validate(),
+        Err(InvalidTorrent::DirectoryLength(0))
+    );
+
+    // a name that would put the files outside the directory they're saved to
+    for name in ["../../x", "/abs/path", "a/b", "..", ""] {
+        let mut escape = t(10, 25, 3);
+        escape.info.name = name.into();
+        assert_eq!(
+            escape.validate(),
+            Err(InvalidTorrent::UnsafeName(name.into())),
+            "{name:?}"
+        );
+    }
+}
+
+#[test]
+fn non_utf8_names() {
+    let mut info = Vec::new();
+    info.extend(b"d5:filesl");
+    info.extend(b"d6:lengthi3e4:pathl3:sub2:\xe9te10:path.utf-8l3:sub3:\xc3\xa9tee");
+    info.extend(b"d6:lengthi2e4:pathl2:\xff!eee");
+    info.extend(b"4:name3:d\xefr10:name.utf-84:d\xc3\xafr");
+    info.extend(b"12:piece lengthi10e6:pieces20:");
+    info.extend([0; 20]);
+    info.extend(b"e");
+    let mut dot_torrent = b"d8:announce0:4:info".to_vec();
+    dot_torrent.extend(&info);
+    dot_torrent.push(b'e');
+
+    let t: Torrent = serde_bencode::from_bytes(&dot_torrent).expect("parse torrent");
+    assert_eq!(t.validate(), Ok(()));
+    assert_eq!(t.info.display_name(), "dïr");
+    let Keys::MultiFile { files } = &t.info.keys else {
+        panic!("multi-file torrent");
+    };
+    assert_eq!(files[0].relative_path(), Path::new("sub/ét"));
+    assert_eq!(files[1].path[0].as_bytes(), b"\xff!");
+    assert_eq!(files[1].path[0].to_string_lossy(), "\u{fffd}!");
+    // nothing is lost in re-encoding, or the info hash would be wrong
+    assert_eq!(serde_bencode::to_bytes(&t.info).unwrap(), info);
+}
+
+#[test]
+fn roundtrip() {
+    let info = b"d6:lengthi1e4:name1:a12:piece lengthi1e6:pieces0:e";
+    let dot_torrent = |info: &[u8]| {
+        let mut dot_torrent = b"d8:announce0:4:info".to_vec();
+        dot_torrent.extend(info);
+        dot_torrent.push(b'e');
+        dot_torrent
+    };
+    let check = |info: &[u8]| {
+        let dot_torrent = dot_torrent(info);
+        let t: Torrent = serde_bencode::from_bytes(&dot_torrent).unwrap();
+        t.verify_roundtrip(&dot_torrent)
+            .map_err(|e| e.downcast::<InfoMismatch>().unwrap())
+    };
+    assert_eq!(check(info), Ok(()));
+    assert_eq!(
+        check(b"d6:lengthi1e4:name1:a12:piece lengthi1e6:pieces0:6:source3:abce"),
+        Err(InfoMismatch {
+            dropped: vec!["source".to_string()],
+            ..InfoMismatch::default()
+        })
+    );
+    assert_eq!(
+        check(b"d4:name1:a6:lengthi01e12:piece lengthi1e6:pieces0:e"),
+        Err(InfoMismatch {
+            changed: vec!["length".to_string()],
+            unsorted: true,
+            ..InfoMismatch::default()
+        })
+    );
+}
+
+#[test]
+fn preserved_info() {
+    // a key we don't know about, which re-encoding would drop
+    let info =
+        b"d6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source3:abce";
+    let mut dot_torrent = b"d8:announce4:http4:info".to_vec();
+    dot_torrent.extend(info);
+    dot_torrent.push(b'e');
+    let hash: [u8; 20] = Sha1::digest(info).into();
+
+    let t = Torrent::from_bytes(&dot_torrent).unwrap();
+    assert_eq!(t.announce, "http");
+    assert_eq!(t.info_hash(), hash);
+    assert_eq!(&*t.info_bytes(), info);
+    assert_eq!(t.to_bytes(), dot_torrent);
+    assert_eq!(
+        Torrent::from_info_bytes("http", info).unwrap().info_hash(),
+        hash
+    );
+    assert!(Torrent::from_info_bytes("http", &dot_torrent[..dot_torrent.len() - 1]).is_err());
+
+    // changing the info gives the torrent the info hash of what it's changed to
+    let mut changed = t.clone();
+    changed.info.plength = 2;
+    let rebuilt = Torrent::new("http", changed.info.clone());
+    assert_ne!(changed.info_hash(), hash);
+    assert_eq!(changed.info_hash(), rebuilt.info_hash());
+    assert_eq!(changed.to_bytes(), rebuilt.to_bytes());
+    assert_eq!(
+        Torrent::from_bytes(&rebuilt.to_bytes())
+            .unwrap()
+            .info_hash(),
+        rebuilt.info_hash()
+    );
+}
+
+#[test]
+fn split_out_pieces() {
+    let npieces: usize = 100_000;
+    let t = Torrent::new(
+        "http://tracker/",
+        Info {
+            name: "big".into(),
+            name_utf8: None,
+            plength: 1 << 14,
+            pieces: Hashes((0..npieces).map(|i| [i as u8; 20]).collect()),
+            private: Some(1),
+            keys: Keys::SingleFile {
+                length: npieces << 14,
+            },
+        },
+    );
+    let dot_torrent = serde_bencode::to_bytes(&t).unwrap();
+    let (parsed, pieces, rest) = Torrent::split_pieces(&dot_torrent).unwrap();
+    assert!(parsed.info.pieces.0.is_empty());
+    assert_eq!(parsed.verify_roundtrip(&rest).ok(), Some(()));
+    // only `2000000:` and the hashes themselves became `0:`
+    assert_eq!(
+        rest.len(),
+        dot_torrent.len() - "2000000:".len() - 20 * npieces + 2
+    );
+    assert!(pieces.0 == t.info.pieces.0);
+    let mut parsed = parsed;
+    parsed.info.pieces = pieces;
+    assert_eq!(parsed.info_hash(), t.info_hash());
+
+    // anything missing is still reported by serde
+    let e =
+        Torrent::split_pieces(b"d8:announce0:4:infod4:name1:a12:piece lengthi1eee").unwrap_err();
+    assert!(format!("{e:#}").contains("pieces"), "{e:#}");
+}
+
+#[test]
+fn tree_and_magnet() {
+    let (mut t, _) = crate::testing::generate_files(&[3, 5, 7], 4);
+    let Keys::MultiFile { files } = &mut t.info.keys else {
+        unreachable!("generated with several files");
+    };
+    files[0].path = vec!["docs".into(), "a b.txt".into()];
+    files[1].path = vec!["c".into()];
+    files[2].path = vec!["docs".into(), "sub".into(), "d".into()];
+    t.info.name = "dir".into();
+    t.announce = "http://tracker.example/announce?x=1".into();
+    assert_eq!(
+        t.file_tree(),
+        "dir/\n\
+         ├── docs/\n\
+         │   ├── a b.txt (3 bytes)\n\
+         │   └── sub/\n\
+         │       └── d (7 bytes)\n\
+         └── c (5 bytes)\n"
+    );
+    assert_eq!(
+        t.to_magnet(),
+        format!(
+            "magnet:?xt=urn:btih:{}&dn=dir&xl=15\
+             &tr=http%3A%2F%2Ftracker.example%2Fannounce%3Fx%3D1",
+            hex::encode(t.info_hash())
+        )
+    );
+
+    let (t, _) = crate::testing::generate(10, 4);
+    assert_eq!(
+        t.file_tree(),
+        format!("{} (10 bytes)\n", t.info.display_name())
+    );
+    assert!(!t.to_magnet().contains("&tr="));
+}
@@ -0,0 +1,196 @@
+//! Recording every message we exchange with peers, for debugging interop problems with specific
+//! clients.
+//!
+//! Tracing is off until [`enable`] is called, and from then on covers every peer connection in
+//! the process. It hooks in as a tap around the codec of each connection, so it sees messages
+//! exactly as they go onto and come off the wire (keep-alives aside, which carry nothing).
+
+use crate::peer::{Message, MessageFramer, MessageTag};
+use anyhow::Context;
+use bytes::BytesMut;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::Write;
+use std::net::SocketAddrV4;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_util::codec::{Decoder, Encoder};
+
+static TRACE: OnceLock<Sink> = OnceLock::new();
+
+enum Sink {
+    /// One human-readable line per message.
+    Stderr,
+    /// One JSON object per line.
+    Jsonl(Mutex<File>),
+}
+
+/// Start tracing peer messages, either to stderr or, if `path` is given, as JSON lines to that
+/// file.
+///
+/// Tracing can only be enabled once.
+pub fn enable(path: Option<&Path>) -> anyhow::Result<()> {
+    let sink = match path {
+        None => Sink::Stderr,
+        Some(path) => Sink::Jsonl(Mutex::new(
+            File::create(path).with_context(|| format!("create {}", path.display()))?,
+        )),
+    };
+    TRACE
+        .set(sink)
+        .map_err(|_| anyhow::anyhow!("wire tracing is already enabled"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Sent,
+    Received,
+}
+
+fn record(peer: SocketAddrV4, direction: Direction, msg: &Message) {
+    let Some(sink) = TRACE.get() else {
+        return;
+    };
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let entry = entry(time, peer, direction, msg);
+    match sink {
+        Sink::Stderr => {
+            let arrow = match direction {
+                Direction::Sent => "->",
+                Direction::Received => "<-",
+            };
+            let mut line = format!("[{time:.3}] {peer} {arrow} {:?}", msg.tag);
+            for field in ["length", "index", "begin", "block", "extension"] {
+                if let Some(value) = entry.get(field) {
+                    line.push_str(&format!(" {field}={value}"));
+                }
+            }
+            eprintln!("{line}");
+        }
+        Sink::Jsonl(file) => {
+            let mut file = file.lock().expect("trace lock poisoned");
+            // tracing is for debugging, so it shouldn't take the connection down with it
+            let _ = writeln!(file, "{entry}");
+        }
+    }
+}
+
+/// Describe `msg` as a JSON object: who it was exchanged with and how, its tag and length, and
+/// whichever piece indices and offsets it carries.
+fn entry(time: f64, peer: SocketAddrV4, direction: Direction, msg: &Message) -> Value {
+    let mut entry = json!({
+        "time": time,
+        "peer": peer.to_string(),
+        "direction": match direction {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        },
+        "tag": format!("{:?}", msg.tag),
+        "length": msg.payload.len(),
+    });
+    let u32_at = |i: usize| {
+        msg.payload
+            .get(i..i + 4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().expect("4 bytes")))
+    };
+    let fields = entry.as_object_mut().expect("entry is an object");
+    match msg.tag {
+        MessageTag::Have => {
+            fields.insert("index".into(), json!(u32_at(0)));
+        }
+        MessageTag::Request | MessageTag::Cancel => {
+            fields.insert("index".into(), json!(u32_at(0)));
+            fields.insert("begin".into(), json!(u32_at(4)));
+            fields.insert("block".into(), json!(u32_at(8)));
+        }
+        MessageTag::Piece => {
+            fields.insert("index".into(), json!(u32_at(0)));
+            fields.insert("begin".into(), json!(u32_at(4)));
+            fields.insert("block".into(), json!(msg.payload.len().saturating_sub(8)));
+        }
+        MessageTag::Port => {
+            let port = msg
+                .payload
+                .get(..2)
+                .map(|port| u16::from_be_bytes([port[0], port[1]]));
+            fields.insert("port".into(), json!(port));
+        }
+        MessageTag::Extended => {
+            fields.insert("extension".into(), json!(msg.payload.first()));
+        }
+        _ => {}
+    }
+    entry
+}
+
+/// A [`MessageFramer`] that records every message it frames for the peer at `peer`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Tap {
+    framer: MessageFramer,
+    peer: SocketAddrV4,
+}
+
+impl Tap {
+    pub(crate) fn new(framer: MessageFramer, peer: SocketAddrV4) -> Self {
+        Self { framer, peer }
+    }
+}
+
+impl Decoder for Tap {
+    type Item = Message;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let msg = self.framer.decode(src)?;
+        if let Some(msg) = &msg {
+            record(self.peer, Direction::Received, msg);
+        }
+        Ok(msg)
+    }
+}
+
+impl Encoder<Message> for Tap {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        record(self.peer, Direction::Sent, &item);
+        self.framer.encode(item, dst)
+    }
+}
+
+#[test]
+fn trace_entries() {
+    let peer = "10.0.0.1:6881".parse().unwrap();
+    let mut payload = 3u32.to_be_bytes().to_vec();
+    payload.extend(16384u32.to_be_bytes());
+    payload.extend([0; 100]);
+    let piece = Message {
+        tag: MessageTag::Piece,
+        payload,
+    };
+    assert_eq!(
+        entry(1.5, peer, Direction::Received, &piece),
+        json!({
+            "time": 1.5,
+            "peer": "10.0.0.1:6881",
+            "direction": "received",
+            "tag": "Piece",
+            "length": 108,
+            "index": 3,
+            "begin": 16384,
+            "block": 100,
+        })
+    );
+
+    let unchoke = Message {
+        tag: MessageTag::Unchoke,
+        payload: Vec::new(),
+    };
+    let entry = entry(2.0, peer, Direction::Sent, &unchoke);
+    assert_eq!(entry["direction"], "sent");
+    assert!(entry.get("index").is_none());
+}
@@ -1,16 +1,362 @@
+use crate::proxy::{Proxy, Unavailable};
+use crate::stats::{DownloadEvent, Stats};
 use crate::torrent::Torrent;
+use crate::udp_tracker::UdpTracker;
+use crate::PORT;
 use anyhow::Context;
+use futures_util::future::BoxFuture;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 pub use peers::Peers;
 
+/// The shortest time we wait between regular announces, whatever the tracker asks for.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The longest we back off for after announces fail, so that a tracker that's back up hears from
+/// us again soon enough.
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How far (as a fraction) each wait between announces is moved at random, so that clients that
+/// started together (such as after a tracker outage) don't keep announcing together.
+const ANNOUNCE_JITTER: f64 = 0.1;
+
+/// How we talk to trackers.
+#[derive(Debug, Clone, Default)]
+pub struct TrackerConfig {
+    /// The user agent to announce with, since some private trackers only allow clients they know.
+    ///
+    /// Defaults to the name and version of this crate.
+    pub user_agent: Option<String>,
+    /// PEM-encoded root certificates to trust for HTTPS trackers, in addition to the system ones.
+    pub root_certificates: Vec<Vec<u8>>,
+    /// The addresses to tell trackers that peers can reach us on.
+    ///
+    /// See [`TrackerConfig::announce_addrs`] for what's used for the ones left unset.
+    pub addrs: AnnounceAddrs,
+    /// The `key` we announce with.
+    ///
+    /// Configurations that share a key (such as clones of the same one) announce with the same
+    /// key.
+    pub key: AnnounceKey,
+    /// Announces to `udp://` trackers.
+    ///
+    /// Like the key, this is shared between clones, so that every torrent announces from the same
+    /// socket and reuses the connection ids the others got.
+    pub udp: UdpTracker,
+    /// What sends our announces, if not an [`HttpTracker`] built from the settings above (or
+    /// [`TrackerConfig::udp`] for `udp://` trackers).
+    ///
+    /// This is where trackers that speak some other protocol plug in, as do fake trackers in
+    /// tests.
+    pub announce_client: Option<Arc<dyn AnnounceClient>>,
+    /// Reach trackers through this proxy, and only over TCP.
+    ///
+    /// That rules out `udp://` and `https://` trackers (see [`Unavailable`]), and we don't detect
+    /// our own addresses to announce, which would give away what the proxy hides. Downloads and
+    /// seeds use their [`DownloadConfig::proxy`](crate::download::DownloadConfig::proxy).
+    pub proxy: Option<Proxy>,
+}
+
+/// Sends announces to a tracker, and returns what it answered.
+pub trait AnnounceClient: fmt::Debug + Send + Sync {
+    /// Send `request` about the torrent with `info_hash` to the tracker at `announce`.
+    ///
+    /// If the tracker refused the announce, the error should be a [`TrackerFailure`], since
+    /// that's not worth retrying.
+    fn announce<'a>(
+        &'a self,
+        announce: &'a str,
+        info_hash: [u8; 20],
+        request: &'a TrackerRequest,
+    ) -> BoxFuture<'a, anyhow::Result<TrackerResponse>>;
+}
+
+/// Announces to HTTP(S) trackers, as described in BEP 3.
+#[derive(Debug, Clone)]
+pub struct HttpTracker(reqwest::Client);
+
+impl HttpTracker {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self(client)
+    }
+}
+
+impl AnnounceClient for HttpTracker {
+    fn announce<'a>(
+        &'a self,
+        announce: &'a str,
+        info_hash: [u8; 20],
+        request: &'a TrackerRequest,
+    ) -> BoxFuture<'a, anyhow::Result<TrackerResponse>> {
+        Box::pin(async move {
+            let tracker_url = request.url(announce, &info_hash)?;
+            let response = self
+                .0
+                .get(tracker_url)
+                .send()
+                .await
+                .context("query tracker")?;
+            let response = response.bytes().await.context("fetch tracker response")?;
+            TrackerResponse::from_bytes(&response)
+        })
+    }
+}
+
+/// Announces to plain HTTP trackers through a [`Proxy`].
+///
+/// reqwest can't connect through a SOCKS proxy, so this sends the request itself.
+#[derive(Debug, Clone)]
+pub struct ProxiedHttpTracker {
+    proxy: Proxy,
+    user_agent: String,
+}
+
+impl ProxiedHttpTracker {
+    pub fn new(proxy: Proxy, user_agent: String) -> Self {
+        Self { proxy, user_agent }
+    }
+}
+
+impl AnnounceClient for ProxiedHttpTracker {
+    fn announce<'a>(
+        &'a self,
+        announce: &'a str,
+        info_hash: [u8; 20],
+        request: &'a TrackerRequest,
+    ) -> BoxFuture<'a, anyhow::Result<TrackerResponse>> {
+        Box::pin(async move {
+            let tracker_url = request.url(announce, &info_hash)?;
+            if tracker_url.scheme() == "https" {
+                return Err(Unavailable::HttpsTrackers.into());
+            }
+            let host = tracker_url.host_str().context("tracker URL has no host")?;
+            let port = tracker_url.port_or_known_default().unwrap_or(80);
+            let mut stream = self
+                .proxy
+                .connect(host, port)
+                .await
+                .context("connect to tracker")?;
+            let mut target = tracker_url.path().to_string();
+            if let Some(query) = tracker_url.query() {
+                target = format!("{target}?{query}");
+            }
+            // HTTP/1.0, so that the response isn't chunked and ends with the connection
+            let request = format!(
+                "GET {target} HTTP/1.0\r\nHost: {host}\r\nUser-Agent: {}\r\n\r\n",
+                self.user_agent
+            );
+            stream
+                .write_all(request.as_bytes())
+                .await
+                .context("query tracker")?;
+            let mut response = Vec::new();
+            stream
+                .read_to_end(&mut response)
+                .await
+                .context("fetch tracker response")?;
+            let body_start = response
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .context("tracker response has no body")?;
+            let status_line = response
+                .split(|&b| b == b'\r')
+                .next()
+                .expect("split always yields one");
+            let status = String::from_utf8_lossy(status_line);
+            anyhow::ensure!(
+                status.split(' ').nth(1) == Some("200"),
+                "tracker answered {status}"
+            );
+            TrackerResponse::from_bytes(&response[body_start + 4..])
+        })
+    }
+}
+
+/// Announces to whichever kind of tracker the announce URL is for.
+#[derive(Debug)]
+struct Announcer {
+    http: Arc<dyn AnnounceClient>,
+    /// `None` when going through a proxy.
+    udp: Option<UdpTracker>,
+}
+
+impl AnnounceClient for Announcer {
+    fn announce<'a>(
+        &'a self,
+        announce: &'a str,
+        info_hash: [u8; 20],
+        request: &'a TrackerRequest,
+    ) -> BoxFuture<'a, anyhow::Result<TrackerResponse>> {
+        if announce.starts_with("udp://") {
+            match &self.udp {
+                Some(udp) => udp.announce(announce, info_hash, request),
+                None => Box::pin(async { Err(Unavailable::UdpTrackers.into()) }),
+            }
+        } else {
+            self.http.announce(announce, info_hash, request)
+        }
+    }
+}
+
+/// Addresses to announce in addition to the one the tracker sees our request come from.
+///
+/// Dual-stack clients only ever reach a tracker over one of IPv4 and IPv6, so without these the
+/// tracker only lists us under that one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AnnounceAddrs {
+    /// The address peers should use instead of the one the tracker sees, for example when the
+    /// tracker is on the same private network as us.
+    pub ip: Option<IpAddr>,
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+/// The `key` parameter of our announces, which lets a tracker recognise us even if our IP address
+/// changes.
+///
+/// That also lets a tracker tie together everything we announce, so the key is made up afresh for
+/// every session, and again whenever our addresses change: a tracker can't link our announces
+/// from different networks by it.
+#[derive(Debug, Clone, Default)]
+pub struct AnnounceKey(Arc<Mutex<Option<(AnnounceAddrs, u32)>>>);
+
+impl AnnounceKey {
+    /// The key to announce from `addrs` with.
+    fn for_addrs(&self, addrs: AnnounceAddrs) -> u32 {
+        let mut current = self.0.lock().expect("announce key lock poisoned");
+        match *current {
+            Some((last, key)) if last == addrs => key,
+            _ => {
+                let [a, b, c, d, ..] = crate::peer_id::entropy();
+                let key = u32::from_be_bytes([a, b, c, d]);
+                *current = Some((addrs, key));
+                key
+            }
+        }
+    }
+}
+
+impl TrackerConfig {
+    /// Build an HTTP client with this configuration.
+    ///
+    /// The client keeps connections to trackers open between announces, so build it once and
+    /// reuse it.
+    pub fn client(&self) -> anyhow::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder().user_agent(self.user_agent());
+        for pem in &self.root_certificates {
+            let cert =
+                reqwest::Certificate::from_pem(pem).context("parse tracker root certificate")?;
+            builder = builder.add_root_certificate(cert);
+        }
+        builder.build().context("build tracker HTTP client")
+    }
+
+    fn user_agent(&self) -> &str {
+        self.user_agent.as_deref().unwrap_or(concat!(
+            env!("CARGO_PKG_NAME"),
+            "/",
+            env!("CARGO_PKG_VERSION")
+        ))
+    }
+
+    /// What to send announces with: [`TrackerConfig::announce_client`] if there is one, and
+    /// otherwise an [`HttpTracker`] with this configuration for HTTP(S) trackers, and
+    /// [`TrackerConfig::udp`] for UDP ones.
+    ///
+    /// With a [`proxy`](TrackerConfig::proxy), HTTP trackers are announced to through it with a
+    /// [`ProxiedHttpTracker`] instead, and UDP ones not at all.
+    ///
+    /// Like [`TrackerConfig::client`], build it once and reuse it.
+    pub fn announcer(&self) -> anyhow::Result<Arc<dyn AnnounceClient>> {
+        if let Some(client) = &self.announce_client {
+            return Ok(Arc::clone(client));
+        }
+        Ok(Arc::new(match &self.proxy {
+            Some(proxy) => Announcer {
+                http: Arc::new(ProxiedHttpTracker::new(
+                    proxy.clone(),
+                    self.user_agent().to_string(),
+                )),
+                udp: None,
+            },
+            None => Announcer {
+                http: Arc::new(HttpTracker::new(self.client()?)),
+                udp: Some(self.udp.clone()),
+            },
+        }))
+    }
+
+    /// The addresses to announce.
+    ///
+    /// Where `ipv4` or `ipv6` aren't configured, they're filled in with the address of this
+    /// machine's route to the internet, as long as that address is publicly routable. Behind a
+    /// NAT it isn't, and the tracker works out our public address by itself. Through a proxy,
+    /// nothing is filled in.
+    pub fn announce_addrs(&self) -> AnnounceAddrs {
+        if self.proxy.is_some() {
+            return self.addrs;
+        }
+        let detect = |probe: SocketAddr| {
+            // connecting a UDP socket sends nothing, but makes the kernel pick a source address
+            let socket = UdpSocket::bind(match probe {
+                SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            })
+            .ok()?;
+            socket.connect(probe).ok()?;
+            Some(socket.local_addr().ok()?.ip())
+        };
+        AnnounceAddrs {
+            ip: self.addrs.ip,
+            ipv4: self.addrs.ipv4.or_else(|| {
+                match detect(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 9)))? {
+                    IpAddr::V4(ip) if is_public_v4(ip) => Some(ip),
+                    _ => None,
+                }
+            }),
+            ipv6: self.addrs.ipv6.or_else(|| {
+                match detect(SocketAddr::from((
+                    Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+                    9,
+                )))? {
+                    IpAddr::V6(ip) if is_public_v6(ip) => Some(ip),
+                    _ => None,
+                }
+            }),
+        }
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let carrier_grade_nat = ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64;
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_documentation()
+        || carrier_grade_nat)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    // only global unicast (2000::/3), and not the documentation prefix
+    let [first, second, ..] = ip.segments();
+    first & 0xe000 == 0x2000 && !(first == 0x2001 && second == 0x0db8)
+}
+
 /// Note: the info hash field is _not_ included.
 #[derive(Debug, Clone, Serialize)]
 pub struct TrackerRequest {
     /// A unique identifier for your client.
     ///
-    /// A string of length 20 that you get to pick.
-    pub peer_id: String,
+    /// 20 bytes that you get to pick. They needn't be printable, so like the info hash, they're
+    /// only added to the URL by [`TrackerRequest::url`], and not serialized with the rest.
+    #[serde(skip)]
+    pub peer_id: [u8; 20],
 
     /// The port your client is listening on.
     pub port: u16,
@@ -24,11 +370,48 @@ pub struct TrackerRequest {
     /// The number of bytes left to download.
     pub left: usize,
 
+    /// Identifies us to the tracker across changes of IP address.
+    ///
+    /// Sent as 8 hex digits, like most clients do.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+
     /// Whether the peer list should use the compact representation
     ///
     /// The compact representation is more commonly used in the wild, the non-compact
     /// representation is mostly supported for backward-compatibility.
     pub compact: u8,
+
+    /// What happened to the download, if this isn't just a regular announce.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+
+    /// The address peers should connect to, if not the one the request comes from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv4: Option<Ipv4Addr>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+/// Changes in the state of a download that the tracker should be told about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    /// The first announce of a download.
+    Started,
+    /// The download finished.
+    Completed,
+    /// The download is being shut down, so the tracker should stop handing us out to peers.
+    Stopped,
+    /// We're a partial seed (BEP 21): we have all we mean to download, but not the whole torrent.
+    ///
+    /// Unlike the other events, this goes with every announce for as long as it's true, so the
+    /// tracker doesn't count us among the peers still downloading.
+    Paused,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -38,40 +421,252 @@ pub struct TrackerResponse {
     /// You can ignore this value for the purposes of this challenge.
     pub interval: usize,
 
+    /// The fewest seconds the tracker wants between our announces, however soon we'd like more
+    /// peers.
+    #[serde(rename = "min interval", default)]
+    pub min_interval: Option<usize>,
+
     /// A string, which contains list of peers that your client can connect to.
     ///
     /// Each peer is represented using 6 bytes. The first 4 bytes are the peer's IP address and the
     /// last 2 bytes are the peer's port number.
     pub peers: Peers,
+
+    /// Something the tracker wants us to know, even though the announce went through.
+    #[serde(rename = "warning message", default)]
+    pub warning_message: Option<String>,
+}
+
+/// The tracker refused an announce, and told us why.
+///
+/// Unlike not reaching the tracker at all, retrying the same announce won't help.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("tracker refused the announce: {0}")]
+pub struct TrackerFailure(pub String);
+
+/// The announce URL is for a kind of tracker we can't talk to.
+///
+/// In particular, WebTorrent's WebSocket trackers (`ws://` and `wss://`) only hand out browser
+/// peers, which need WebRTC to connect to.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{0}:// trackers are not supported")]
+pub struct UnsupportedTracker(pub String);
+
+/// What a tracker may answer with: either the usual response, or only a reason for failing.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Announce {
+    Failure {
+        #[serde(rename = "failure reason")]
+        failure_reason: String,
+    },
+    Success(TrackerResponse),
 }
 
 impl TrackerResponse {
-    pub(crate) async fn query(t: &Torrent, info_hash: [u8; 20]) -> anyhow::Result<Self> {
+    /// Parse the bencoded body of a tracker's response.
+    ///
+    /// If the tracker sent a failure reason instead of peers, the error is a [`TrackerFailure`].
+    pub fn from_bytes(response: &[u8]) -> anyhow::Result<Self> {
+        match serde_bencode::from_bytes(response).context("parse tracker response")? {
+            Announce::Failure { failure_reason } => Err(TrackerFailure(failure_reason).into()),
+            Announce::Success(response) => Ok(response),
+        }
+    }
+
+    /// Announce to the tracker of `t`, with the addresses and key `config` makes for.
+    ///
+    /// Our addresses are detected afresh every time, so that the key changes along with them.
+    pub(crate) async fn query(
+        client: &dyn AnnounceClient,
+        t: &Torrent,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        stats: &Stats,
+        event: Option<Event>,
+        config: &TrackerConfig,
+    ) -> anyhow::Result<Self> {
+        let addrs = config.announce_addrs();
         let request = TrackerRequest {
-            peer_id: String::from("00112233445566778899"),
-            port: 6881,
-            uploaded: 0,
-            downloaded: 0,
-            left: t.length(),
+            peer_id,
+            port: PORT,
+            // over every run, for trackers that keep track of our ratio
+            uploaded: stats.totals().uploaded,
+            downloaded: stats.totals().downloaded,
+            left: stats.left(),
+            key: Some(format!("{:08X}", config.key.for_addrs(addrs))),
             compact: 1,
+            event,
+            ip: addrs.ip,
+            ipv4: addrs.ipv4,
+            ipv6: addrs.ipv6,
         };
 
-        let url_params =
-            serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
-        let tracker_url = format!(
-            "{}?{}&info_hash={}",
-            t.announce,
-            url_params,
-            &urlencode(&info_hash)
-        );
-        let response = reqwest::get(tracker_url).await.context("query tracker")?;
-        let response = response.bytes().await.context("fetch tracker response")?;
-        let tracker_info: TrackerResponse =
-            serde_bencode::from_bytes(&response).context("parse tracker response")?;
+        let tracker_info = client
+            .announce(&t.announce, info_hash, &request)
+            .await
+            .inspect_err(|_| stats.add_tracker_error())?;
+        stats.emit(DownloadEvent::TrackerAnnounced {
+            peers: tracker_info.peers.0.len(),
+        });
+        if let Some(warning) = &tracker_info.warning_message {
+            eprintln!("tracker {} warns: {warning}", t.announce);
+        }
         Ok(tracker_info)
     }
 }
 
+/// The announces of a single download (or seed), which makes sure the tracker hears when we go
+/// away.
+///
+/// Once an announce has gone through, the tracker hands us out to other peers until we announce
+/// [`Event::Stopped`], or until it gives up on hearing from us, which can take an hour or more. A
+/// run that crashes without saying goodbye leaves a ghost peer behind, which private trackers in
+/// particular hold against us. So if the session is dropped while the tracker still lists us, it
+/// sends the stopped announce itself, in the background on the current tokio runtime. That is only
+/// best-effort: prefer [`stop`](Self::stop) where there's a chance to wait for it.
+pub struct AnnounceSession {
+    inner: Arc<SessionInner>,
+    /// Whether the tracker has us listed, and so needs to be told when we stop.
+    listed: bool,
+    /// How often the tracker last told us to announce.
+    interval: Duration,
+    /// How often the tracker last told us we may announce at most.
+    min_interval: Duration,
+    /// How many announces in a row have failed.
+    failures: u32,
+}
+
+struct SessionInner {
+    client: Arc<dyn AnnounceClient>,
+    torrent: Torrent,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    stats: Arc<Stats>,
+    config: TrackerConfig,
+}
+
+impl SessionInner {
+    async fn query(&self, event: Option<Event>) -> anyhow::Result<TrackerResponse> {
+        TrackerResponse::query(
+            &*self.client,
+            &self.torrent,
+            self.info_hash,
+            self.peer_id,
+            &self.stats,
+            event,
+            &self.config,
+        )
+        .await
+    }
+}
+
+impl AnnounceSession {
+    /// Start a session for announcing `t` through `client`. Nothing is sent until the first
+    /// [`announce`](Self::announce).
+    pub fn new(
+        client: Arc<dyn AnnounceClient>,
+        t: &Torrent,
+        peer_id: [u8; 20],
+        stats: Arc<Stats>,
+        config: TrackerConfig,
+    ) -> Self {
+        Self {
+            inner: Arc::new(SessionInner {
+                client,
+                torrent: t.clone(),
+                info_hash: t.info_hash(),
+                peer_id,
+                stats,
+                config,
+            }),
+            listed: false,
+            interval: MIN_ANNOUNCE_INTERVAL,
+            min_interval: Duration::ZERO,
+            failures: 0,
+        }
+    }
+
+    /// Announce `event` (or a regular announce if there's none) to the tracker.
+    pub async fn announce(&mut self, event: Option<Event>) -> anyhow::Result<TrackerResponse> {
+        let response = match self.inner.query(event).await {
+            Ok(response) => response,
+            Err(e) => {
+                self.failures += 1;
+                return Err(e);
+            }
+        };
+        self.failures = 0;
+        self.interval = Duration::from_secs(response.interval as u64);
+        self.min_interval = Duration::from_secs(response.min_interval.unwrap_or(0) as u64);
+        self.listed = event != Some(Event::Stopped);
+        Ok(response)
+    }
+
+    /// How long to wait before the next regular announce, given how the last ones went.
+    ///
+    /// That's the interval the tracker asked for, or, after failed announces, a wait that doubles
+    /// with each failure in a row. Either way, it's never shorter than the tracker's `min
+    /// interval`, and is moved a little at random.
+    pub fn next_announce(&self) -> Duration {
+        let [a, b, c, d, e, f, g, h, ..] = crate::peer_id::entropy();
+        let random =
+            (u64::from_le_bytes([a, b, c, d, e, f, g, h]) >> 11) as f64 / (1u64 << 53) as f64;
+        announce_delay(self.interval, self.min_interval, self.failures, random)
+    }
+
+    /// Tell the tracker we've stopped, unless it never heard from us in the first place.
+    ///
+    /// This is only tried once: if it fails, dropping the session won't try again.
+    pub async fn stop(&mut self) -> anyhow::Result<()> {
+        if !std::mem::take(&mut self.listed) {
+            return Ok(());
+        }
+        self.inner.query(Some(Event::Stopped)).await?;
+        Ok(())
+    }
+}
+
+impl Drop for AnnounceSession {
+    fn drop(&mut self) {
+        if !self.listed {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let inner = Arc::clone(&self.inner);
+        runtime.spawn(async move {
+            if let Err(e) = inner.query(Some(Event::Stopped)).await {
+                eprintln!(
+                    "failed to tell tracker we've stopped {}: {e:?}",
+                    inner.torrent.info.display_name()
+                );
+            }
+        });
+    }
+}
+
+/// How long to wait before announcing again, after `failures` failed announces in a row, with a
+/// tracker that asked for `interval` (and at least `min_interval`) between announces.
+///
+/// `random` (in `[0, 1)`) picks where in the jitter around that the wait ends up.
+fn announce_delay(
+    interval: Duration,
+    min_interval: Duration,
+    failures: u32,
+    random: f64,
+) -> Duration {
+    let wait = match failures {
+        0 => interval,
+        n => MIN_ANNOUNCE_INTERVAL
+            .saturating_mul(1 << (n - 1).min(16))
+            .min(MAX_RETRY_INTERVAL),
+    };
+    let wait = wait.mul_f64(1.0 + ANNOUNCE_JITTER * (2.0 * random - 1.0));
+    wait.max(MIN_ANNOUNCE_INTERVAL).max(min_interval)
+}
+
 mod peers {
     use serde::de::{self, Deserialize, Deserializer, Visitor};
     use serde::ser::{Serialize, Serializer};
@@ -93,7 +688,7 @@ mod peers {
         where
             E: de::Error,
         {
-            if v.len() % 6 != 0 {
+            if !v.len().is_multiple_of(6) {
                 return Err(E::custom(format!("length is {}", v.len())));
             }
             // TODO: use array_chunks when stable; then we can also pattern-match in closure args
@@ -134,11 +729,294 @@ mod peers {
     }
 }
 
-fn urlencode(t: &[u8; 20]) -> String {
-    let mut encoded = String::with_capacity(3 * t.len());
-    for &byte in t {
-        encoded.push('%');
-        encoded.push_str(&hex::encode(&[byte]));
+impl TrackerRequest {
+    /// The URL to send this request to the tracker at `announce` with, for the torrent with
+    /// `info_hash`.
+    ///
+    /// Whatever query the announce URL already has, such as a private tracker's passkey, is kept.
+    pub fn url(&self, announce: &str, info_hash: &[u8; 20]) -> anyhow::Result<reqwest::Url> {
+        let mut url = AnnounceUrl::parse(announce)?;
+        url.bytes("peer_id", &self.peer_id);
+        url.params(self)?;
+        url.bytes("info_hash", info_hash);
+        Ok(url.0)
+    }
+}
+
+/// An announce URL that query parameters are added to.
+struct AnnounceUrl(reqwest::Url);
+
+impl AnnounceUrl {
+    fn parse(announce: &str) -> anyhow::Result<Self> {
+        let url = reqwest::Url::parse(announce)
+            .with_context(|| format!("parse announce URL {announce:?}"))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(UnsupportedTracker(url.scheme().to_string()).into());
+        }
+        Ok(Self(url))
+    }
+
+    /// Add every field of `params` as a parameter.
+    fn params(&mut self, params: &impl Serialize) -> anyhow::Result<()> {
+        params
+            .serialize(serde_urlencoded::Serializer::new(
+                &mut self.0.query_pairs_mut(),
+            ))
+            .context("url-encode tracker parameters")?;
+        Ok(())
+    }
+
+    /// Add the parameter `name`, whose value is arbitrary bytes rather than a string.
+    fn bytes(&mut self, name: &str, value: &[u8]) {
+        let mut query = self.0.query().unwrap_or_default().to_string();
+        if !query.is_empty() {
+            query.push('&');
+        }
+        query.push_str(name);
+        query.push('=');
+        for &byte in value {
+            if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                query.push(char::from(byte));
+            } else {
+                query.push_str(&format!("%{byte:02X}"));
+            }
+        }
+        self.0.set_query(Some(&query));
+    }
+}
+
+#[test]
+fn public_addrs() {
+    assert!(is_public_v4(Ipv4Addr::new(8, 8, 8, 8)));
+    assert!(!is_public_v4(Ipv4Addr::new(192, 168, 1, 2)));
+    assert!(!is_public_v4(Ipv4Addr::new(100, 64, 0, 1)));
+    assert!(is_public_v4(Ipv4Addr::new(100, 128, 0, 1)));
+    assert!(is_public_v6("2606:4700::1111".parse().unwrap()));
+    assert!(!is_public_v6("fe80::1".parse().unwrap()));
+    assert!(!is_public_v6("fd00::1".parse().unwrap()));
+    assert!(!is_public_v6(Ipv6Addr::LOCALHOST));
+
+    let request = TrackerRequest {
+        peer_id: *b"00112233445566778899",
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 0,
+        key: None,
+        compact: 1,
+        event: None,
+        ip: None,
+        ipv4: Some(Ipv4Addr::new(8, 8, 8, 8)),
+        ipv6: Some("2606:4700::1111".parse().unwrap()),
+    };
+    assert_eq!(
+        serde_urlencoded::to_string(&request).unwrap(),
+        "port=6881&uploaded=0&downloaded=0&left=0&compact=1\
+         &ipv4=8.8.8.8&ipv6=2606%3A4700%3A%3A1111"
+    );
+}
+
+#[tokio::test]
+async fn announce_through_proxy() {
+    use tokio::net::TcpListener;
+
+    // a proxy that lets us in without credentials, and then answers as the tracker itself
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut greeting = [0; 3];
+        stream.read_exact(&mut greeting).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        let mut connect = [0; 5 + 19 + 2];
+        stream.read_exact(&mut connect).await.unwrap();
+        assert_eq!(&connect[5..24], b"tracker.example.org");
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("GET /announce?"), "{request}");
+        assert!(request.contains("\r\nHost: tracker.example.org\r\n"));
+        stream
+            .write_all(b"HTTP/1.0 200 OK\r\n\r\nd8:intervali60e5:peers6:\x7f\0\0\x01\x1a\xe1e")
+            .await
+            .unwrap();
+    });
+
+    let config = TrackerConfig {
+        proxy: Some(Proxy::new("127.0.0.1", port)),
+        ..TrackerConfig::default()
+    };
+    assert_eq!(config.announce_addrs(), AnnounceAddrs::default());
+    let announcer = config.announcer().unwrap();
+    let request = TrackerRequest {
+        peer_id: [0; 20],
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 0,
+        key: None,
+        compact: 1,
+        event: None,
+        ip: None,
+        ipv4: None,
+        ipv6: None,
+    };
+    let response = announcer
+        .announce("http://tracker.example.org/announce", [0; 20], &request)
+        .await
+        .unwrap();
+    assert_eq!(
+        response.peers.0,
+        [std::net::SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881)]
+    );
+    for (announce, unavailable) in [
+        ("udp://tracker.example.org:6969", Unavailable::UdpTrackers),
+        (
+            "https://tracker.example.org/announce",
+            Unavailable::HttpsTrackers,
+        ),
+    ] {
+        let e = announcer
+            .announce(announce, [0; 20], &request)
+            .await
+            .unwrap_err();
+        assert_eq!(e.downcast_ref::<Unavailable>(), Some(&unavailable));
     }
-    encoded
+}
+
+#[test]
+fn announce_key_rotates() {
+    let key = AnnounceKey::default();
+    let home = AnnounceAddrs {
+        ipv4: Some(Ipv4Addr::new(8, 8, 8, 8)),
+        ..AnnounceAddrs::default()
+    };
+    let away = AnnounceAddrs {
+        ipv4: Some(Ipv4Addr::new(9, 9, 9, 9)),
+        ..AnnounceAddrs::default()
+    };
+    let first = key.for_addrs(home);
+    assert_eq!(key.for_addrs(home), first);
+    assert_eq!(key.clone().for_addrs(home), first);
+    assert_ne!(key.for_addrs(away), first);
+    // going back doesn't bring back the old key either
+    assert_ne!(key.for_addrs(home), first);
+    assert_ne!(AnnounceKey::default().for_addrs(home), first);
+}
+
+#[test]
+fn announce_url() {
+    let request = TrackerRequest {
+        peer_id: *b"-BS0001-abcdefghij\xe9\x80",
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 10,
+        key: None,
+        compact: 1,
+        event: Some(Event::Started),
+        ip: None,
+        ipv4: None,
+        ipv6: None,
+    };
+    let mut info_hash = [b'a'; 20];
+    info_hash[..4].copy_from_slice(&[0x00, 0xff, b'&', b'~']);
+    let url = request
+        .url(
+            "https://tracker.example/announce?passkey=s3cr%2Ft",
+            &info_hash,
+        )
+        .unwrap();
+    assert_eq!(
+        url.as_str(),
+        "https://tracker.example/announce?passkey=s3cr%2Ft&peer_id=-BS0001-abcdefghij%E9%80\
+         &port=6881&uploaded=0&downloaded=0&left=10&compact=1&event=started\
+         &info_hash=%00%FF%26~aaaaaaaaaaaaaaaa"
+    );
+
+    let url = request
+        .url("http://tracker.example/announce", &info_hash)
+        .unwrap();
+    assert!(url
+        .as_str()
+        .starts_with("http://tracker.example/announce?peer_id="));
+    assert!(request.url("not a url", &info_hash).is_err());
+    let e = request
+        .url("wss://tracker.example", &info_hash)
+        .unwrap_err();
+    assert_eq!(
+        e.downcast_ref::<UnsupportedTracker>(),
+        Some(&UnsupportedTracker("wss".to_string()))
+    );
+}
+
+#[test]
+fn failure_and_warning() {
+    let e = TrackerResponse::from_bytes(b"d14:failure reason17:torrent not founde").unwrap_err();
+    assert_eq!(
+        e.downcast_ref::<TrackerFailure>(),
+        Some(&TrackerFailure("torrent not found".to_string()))
+    );
+
+    let response = TrackerResponse::from_bytes(
+        b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe115:warning message4:slowe",
+    )
+    .unwrap();
+    assert_eq!(response.interval, 60);
+    assert_eq!(response.min_interval, None);
+    assert_eq!(response.peers.0, ["127.0.0.1:6881".parse().unwrap()]);
+    assert_eq!(response.warning_message.as_deref(), Some("slow"));
+
+    assert!(TrackerResponse::from_bytes(b"d8:intervali60ee").is_err());
+}
+
+#[test]
+fn announce_timing() {
+    let response =
+        TrackerResponse::from_bytes(b"d8:intervali1800e12:min intervali900e5:peers0:e").unwrap();
+    assert_eq!(response.min_interval, Some(900));
+
+    let minutes = |n: u64| Duration::from_secs(n * 60);
+    // the interval, give or take the jitter
+    assert_eq!(
+        announce_delay(minutes(30), minutes(15), 0, 0.5),
+        minutes(30)
+    );
+    assert_eq!(
+        announce_delay(minutes(30), minutes(15), 0, 0.0),
+        minutes(27)
+    );
+    assert!(announce_delay(minutes(30), minutes(15), 0, 0.99) < minutes(33));
+    // but never sooner than the tracker (or we) allow
+    assert_eq!(
+        announce_delay(minutes(10), minutes(15), 0, 0.0),
+        minutes(15)
+    );
+    assert_eq!(
+        announce_delay(Duration::ZERO, Duration::ZERO, 0, 0.5),
+        minutes(1)
+    );
+    // failures back off, up to a point
+    assert_eq!(
+        announce_delay(minutes(30), Duration::ZERO, 1, 0.5),
+        minutes(1)
+    );
+    assert_eq!(
+        announce_delay(minutes(30), Duration::ZERO, 3, 0.5),
+        minutes(4)
+    );
+    assert_eq!(
+        announce_delay(minutes(30), Duration::ZERO, 100, 0.5),
+        minutes(60)
+    );
+    assert_eq!(
+        announce_delay(minutes(30), minutes(15), 2, 0.5),
+        minutes(15)
+    );
 }
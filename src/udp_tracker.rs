@@ -0,0 +1,564 @@
+//! Announcing to `udp://` trackers (BEP 15).
+//!
+//! A UDP tracker first hands out a connection id, which proves that we really receive at the
+//! address we send from, and only then takes announces that carry it. Connection ids are good for
+//! a minute, so we keep each tracker's around for that long rather than connecting before every
+//! announce.
+//!
+//! Nothing guarantees that a datagram arrives, so a request that goes unanswered is sent again
+//! after 15 * 2^n seconds, for n from 0 up to 8, as the spec asks. Every tracker is talked to from
+//! the same socket (one for each of IPv4 and IPv6), which keeps us down to one NAT mapping;
+//! responses are matched up with their requests by transaction id.
+//!
+//! Over IPv6, the tracker lists peers in 18 bytes rather than 6. We only listen for peers on IPv4,
+//! though, so a tracker that has both kinds of address is announced to over IPv4: announcing over
+//! IPv6 would list us under an address no-one can reach us at.
+
+use crate::tracker::{
+    AnnounceClient, Event, Peers, TrackerFailure, TrackerRequest, TrackerResponse,
+};
+use anyhow::Context;
+use futures_util::future::BoxFuture;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{oneshot, OnceCell};
+
+/// The magic number that every connect request starts with.
+const PROTOCOL_ID: u64 = 0x417_2710_1980;
+
+/// How long a tracker accepts a connection id for.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+/// How many times an unanswered request is sent again before we give up on the tracker.
+///
+/// BEP 15 allows for 8, but with the wait doubling every time that's over two hours of waiting on
+/// a tracker that's gone, and the announce gets retried later anyway. With 2, a silent tracker is
+/// given up on after 7 times the first [timeout](Inner::timeout).
+const MAX_RETRANSMITS: u32 = 2;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+
+/// Announces to UDP trackers.
+///
+/// Clones share the socket and the cached connection ids.
+#[derive(Debug, Clone, Default)]
+pub struct UdpTracker(Arc<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    /// Bound the first time they're needed, since that has to happen inside the runtime.
+    socket_v4: OnceCell<Socket>,
+    socket_v6: OnceCell<Socket>,
+    /// The requests waiting for a response, by transaction id.
+    pending: Mutex<HashMap<u32, Pending>>,
+    /// The connection id we have with each tracker, and when we got it.
+    connections: Mutex<HashMap<SocketAddr, (u64, Instant)>>,
+    /// How long to wait for the first response; this doubles with every retransmit.
+    timeout: Duration,
+}
+
+#[derive(Debug)]
+struct Socket {
+    socket: Arc<UdpSocket>,
+    receiver: tokio::task::JoinHandle<()>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    tracker: SocketAddr,
+    response: oneshot::Sender<Vec<u8>>,
+}
+
+impl Default for Inner {
+    fn default() -> Self {
+        Self {
+            socket_v4: OnceCell::new(),
+            socket_v6: OnceCell::new(),
+            pending: Mutex::new(HashMap::new()),
+            connections: Mutex::new(HashMap::new()),
+            timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+impl UdpTracker {
+    /// The socket to talk to `tracker` from, which is of the same address family.
+    async fn socket(&self, tracker: SocketAddr) -> anyhow::Result<Arc<UdpSocket>> {
+        let (cell, local) = match tracker {
+            SocketAddr::V4(_) => (
+                &self.0.socket_v4,
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            ),
+            SocketAddr::V6(_) => (
+                &self.0.socket_v6,
+                SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            ),
+        };
+        let socket = cell
+            .get_or_try_init(|| async {
+                let socket = UdpSocket::bind(local)
+                    .await
+                    .context("bind UDP tracker socket")?;
+                let socket = Arc::new(socket);
+                let receiver = tokio::spawn(receive(Arc::clone(&socket), Arc::downgrade(&self.0)));
+                anyhow::Ok(Socket { socket, receiver })
+            })
+            .await?;
+        Ok(Arc::clone(&socket.socket))
+    }
+
+    /// Send `request` to `tracker` as attempt `n`, and wait for its response.
+    ///
+    /// The transaction id in `request` is replaced with a fresh one. Returns `None` if no response
+    /// came in time.
+    async fn send(
+        &self,
+        tracker: SocketAddr,
+        request: &mut [u8],
+        n: u32,
+        action: u32,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let socket = self.socket(tracker).await?;
+        let [a, b, c, d, ..] = crate::peer_id::entropy();
+        let transaction_id = u32::from_be_bytes([a, b, c, d]);
+        request[12..16].copy_from_slice(&transaction_id.to_be_bytes());
+
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .pending
+            .lock()
+            .expect("udp tracker lock poisoned")
+            .insert(
+                transaction_id,
+                Pending {
+                    tracker,
+                    response: tx,
+                },
+            );
+        let response = async {
+            socket
+                .send_to(request, tracker)
+                .await
+                .with_context(|| format!("send to UDP tracker {tracker}"))?;
+            match tokio::time::timeout(self.0.timeout * 2u32.pow(n), rx).await {
+                Ok(Ok(response)) => parse_response(response, action).map(Some),
+                Ok(Err(_)) => anyhow::bail!("UDP tracker socket closed"),
+                Err(_) => Ok(None),
+            }
+        }
+        .await;
+        self.0
+            .pending
+            .lock()
+            .expect("udp tracker lock poisoned")
+            .remove(&transaction_id);
+        response
+    }
+
+    /// The connection id to use with `tracker`, connecting again if we don't have a current one.
+    async fn connection_id(&self, tracker: SocketAddr) -> anyhow::Result<u64> {
+        if let Some(&(connection_id, since)) = self
+            .0
+            .connections
+            .lock()
+            .expect("udp tracker lock poisoned")
+            .get(&tracker)
+        {
+            if since.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(connection_id);
+            }
+        }
+
+        let mut request = [0; 16];
+        request[..8].copy_from_slice(&PROTOCOL_ID.to_be_bytes());
+        request[8..12].copy_from_slice(&ACTION_CONNECT.to_be_bytes());
+        for n in 0..=MAX_RETRANSMITS {
+            let Some(response) = self
+                .send(tracker, &mut request, n, ACTION_CONNECT)
+                .await
+                .context("connect to UDP tracker")?
+            else {
+                continue;
+            };
+            let connection_id: [u8; 8] = response
+                .get(8..16)
+                .and_then(|id| id.try_into().ok())
+                .context("connect response is too short")?;
+            let connection_id = u64::from_be_bytes(connection_id);
+            self.0
+                .connections
+                .lock()
+                .expect("udp tracker lock poisoned")
+                .insert(tracker, (connection_id, Instant::now()));
+            return Ok(connection_id);
+        }
+        anyhow::bail!("UDP tracker {tracker} did not answer our connect")
+    }
+
+    async fn announce_to(
+        &self,
+        announce: &str,
+        info_hash: [u8; 20],
+        request: &TrackerRequest,
+    ) -> anyhow::Result<TrackerResponse> {
+        let tracker = resolve(announce).await?;
+
+        let mut packet = Vec::with_capacity(98);
+        packet.extend([0; 8]); // connection id, filled in below
+        packet.extend(ACTION_ANNOUNCE.to_be_bytes());
+        packet.extend([0; 4]); // transaction id, filled in when sent
+        packet.extend(info_hash);
+        packet.extend(request.peer_id);
+        packet.extend((request.downloaded as u64).to_be_bytes());
+        packet.extend((request.left as u64).to_be_bytes());
+        packet.extend((request.uploaded as u64).to_be_bytes());
+        let event: u32 = match request.event {
+            None => 0,
+            Some(Event::Completed) => 1,
+            Some(Event::Started) => 2,
+            Some(Event::Stopped) => 3,
+            // BEP 15 has no event for partial seeds, so this is just a regular announce
+            Some(Event::Paused) => 0,
+        };
+        packet.extend(event.to_be_bytes());
+        let ip = match request.ip {
+            Some(IpAddr::V4(ip)) => Some(ip),
+            _ => request.ipv4,
+        };
+        packet.extend(ip.unwrap_or(Ipv4Addr::UNSPECIFIED).octets());
+        let key = match &request.key {
+            Some(key) => u32::from_str_radix(key, 16).context("announce key is not hex")?,
+            None => 0,
+        };
+        packet.extend(key.to_be_bytes());
+        packet.extend((-1i32).to_be_bytes()); // as many peers as the tracker likes
+        packet.extend(request.port.to_be_bytes());
+
+        for n in 0..=MAX_RETRANSMITS {
+            // the wait may have outlived the connection id, in which case we get a new one
+            let connection_id = self.connection_id(tracker).await?;
+            packet[..8].copy_from_slice(&connection_id.to_be_bytes());
+            let Some(response) = self.send(tracker, &mut packet, n, ACTION_ANNOUNCE).await? else {
+                continue;
+            };
+
+            anyhow::ensure!(response.len() >= 20, "announce response is too short");
+            let interval = u32::from_be_bytes(response[8..12].try_into().expect("4 bytes"));
+            let peers = parse_peers(&response[20..], tracker.is_ipv6());
+            return Ok(TrackerResponse {
+                interval: interval as usize,
+                min_interval: None,
+                peers: Peers(peers),
+                warning_message: None,
+            });
+        }
+        anyhow::bail!("UDP tracker {tracker} did not answer our announce")
+    }
+}
+
+impl AnnounceClient for UdpTracker {
+    fn announce<'a>(
+        &'a self,
+        announce: &'a str,
+        info_hash: [u8; 20],
+        request: &'a TrackerRequest,
+    ) -> BoxFuture<'a, anyhow::Result<TrackerResponse>> {
+        Box::pin(self.announce_to(announce, info_hash, request))
+    }
+}
+
+/// Hand every response that arrives on `socket` to the request it answers.
+async fn receive(socket: Arc<UdpSocket>, tracker: Weak<Inner>) {
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let Ok((n, from)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Some(tracker) = tracker.upgrade() else {
+            return;
+        };
+        if n < 8 {
+            continue;
+        }
+        let transaction_id = u32::from_be_bytes(buf[4..8].try_into().expect("4 bytes"));
+        let mut pending = tracker.pending.lock().expect("udp tracker lock poisoned");
+        // only the tracker we asked gets to answer
+        if pending
+            .get(&transaction_id)
+            .is_some_and(|pending| pending.tracker == from)
+        {
+            let pending = pending.remove(&transaction_id).expect("just checked");
+            let _ = pending.response.send(buf[..n].to_vec());
+        }
+    }
+}
+
+/// Check that `response` is for `action`, and turn an error response into a [`TrackerFailure`].
+fn parse_response(response: Vec<u8>, action: u32) -> anyhow::Result<Vec<u8>> {
+    let got = u32::from_be_bytes(response[..4].try_into().expect("4 bytes"));
+    if got == ACTION_ERROR {
+        return Err(TrackerFailure(String::from_utf8_lossy(&response[8..]).into_owned()).into());
+    }
+    anyhow::ensure!(
+        got == action,
+        "UDP tracker answered with action {got} instead of {action}"
+    );
+    Ok(response)
+}
+
+/// The peers listed in an announce response, which take 18 bytes each if it came over IPv6, and
+/// 6 otherwise.
+///
+/// Peers with an IPv6 address can't be connected to (yet), so only those whose address is an
+/// IPv4 address in disguise are kept.
+fn parse_peers(peers: &[u8], ipv6: bool) -> Vec<SocketAddrV4> {
+    if !ipv6 {
+        return peers
+            .chunks_exact(6)
+            .map(|peer| {
+                SocketAddrV4::new(
+                    Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]),
+                    u16::from_be_bytes([peer[4], peer[5]]),
+                )
+            })
+            .collect();
+    }
+    let all = peers.len() / 18;
+    let peers: Vec<_> = peers
+        .chunks_exact(18)
+        .filter_map(|peer| {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&peer[..16]).expect("16 bytes"));
+            let port = u16::from_be_bytes([peer[16], peer[17]]);
+            Some(SocketAddrV4::new(ip.to_ipv4_mapped()?, port))
+        })
+        .collect();
+    if peers.len() < all {
+        eprintln!(
+            "skipping {} peers that are only reachable over IPv6",
+            all - peers.len()
+        );
+    }
+    peers
+}
+
+/// The address of the tracker at the `udp://` URL `announce`, preferring an IPv4 one.
+async fn resolve(announce: &str) -> anyhow::Result<SocketAddr> {
+    let url = reqwest::Url::parse(announce)
+        .with_context(|| format!("parse announce URL {announce:?}"))?;
+    let host = url.host_str().context("UDP tracker URL has no host")?;
+    // IPv6 addresses come in brackets, which the lookup doesn't take
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port().context("UDP tracker URL has no port")?;
+    let addrs = tokio::net::lookup_host((host, port))
+        .await
+        .with_context(|| format!("resolve {host}"))?;
+    pick_addr(addrs).with_context(|| format!("{host} has no addresses"))
+}
+
+/// The address to reach a tracker at, out of all of `addrs`: the first IPv4 one, since that's
+/// what we listen for peers on, or failing that the first IPv6 one.
+fn pick_addr(addrs: impl IntoIterator<Item = SocketAddr>) -> Option<SocketAddr> {
+    let mut ipv6 = None;
+    for addr in addrs {
+        match addr {
+            SocketAddr::V4(_) => return Some(addr),
+            SocketAddr::V6(_) => {
+                ipv6.get_or_insert(addr);
+            }
+        }
+    }
+    ipv6
+}
+
+#[tokio::test]
+async fn connection_ids_and_retransmits() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let announce = format!("udp://{}/announce", server.local_addr().unwrap());
+    let connects = Arc::new(AtomicUsize::new(0));
+    let server = tokio::spawn({
+        let connects = Arc::clone(&connects);
+        async move {
+            let mut buf = [0; 1024];
+            let mut announces = 0;
+            loop {
+                let (n, from) = server.recv_from(&mut buf).await.unwrap();
+                let mut response = Vec::new();
+                if buf[..8] == PROTOCOL_ID.to_be_bytes() {
+                    connects.fetch_add(1, Ordering::SeqCst);
+                    assert_eq!(n, 16);
+                    response.extend(ACTION_CONNECT.to_be_bytes());
+                    response.extend(&buf[12..16]);
+                    response.extend(42u64.to_be_bytes());
+                } else {
+                    assert_eq!(n, 98);
+                    assert_eq!(buf[..8], 42u64.to_be_bytes());
+                    announces += 1;
+                    // lose the first announce, so that it has to be sent again
+                    if announces == 1 {
+                        continue;
+                    }
+                    if buf[16..36] == [0xff; 20] {
+                        response.extend(ACTION_ERROR.to_be_bytes());
+                        response.extend(&buf[12..16]);
+                        response.extend(b"unregistered torrent");
+                    } else {
+                        response.extend(ACTION_ANNOUNCE.to_be_bytes());
+                        response.extend(&buf[12..16]);
+                        response.extend(1800u32.to_be_bytes());
+                        response.extend([0; 8]);
+                        response.extend([127, 0, 0, 1, 0x1a, 0xe1]);
+                    }
+                }
+                server.send_to(&response, from).await.unwrap();
+            }
+        }
+    });
+
+    let tracker = UdpTracker(Arc::new(Inner {
+        timeout: Duration::from_millis(50),
+        ..Inner::default()
+    }));
+    let request = TrackerRequest {
+        peer_id: *b"-BS0001-abcdefghijkl",
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 10,
+        key: Some("0123ABCD".to_string()),
+        compact: 1,
+        event: Some(Event::Started),
+        ip: None,
+        ipv4: None,
+        ipv6: None,
+    };
+    let response = tracker
+        .announce(&announce, [0; 20], &request)
+        .await
+        .expect("announce is retransmitted");
+    assert_eq!(response.interval, 1800);
+    assert_eq!(response.peers.0, ["127.0.0.1:6881".parse().unwrap()]);
+
+    // the connection id is still good, and clones share it
+    let e = tracker
+        .clone()
+        .announce(&announce, [0xff; 20], &request)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        e.downcast_ref::<TrackerFailure>(),
+        Some(&TrackerFailure("unregistered torrent".to_string()))
+    );
+    assert_eq!(connects.load(Ordering::SeqCst), 1);
+    server.abort();
+}
+
+#[tokio::test]
+async fn silent_tracker_gives_up() {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let announce = format!("udp://{}/announce", server.local_addr().unwrap());
+    let tracker = UdpTracker(Arc::new(Inner {
+        timeout: Duration::from_millis(50),
+        ..Inner::default()
+    }));
+    let request = TrackerRequest {
+        peer_id: *b"-BS0001-abcdefghijkl",
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 10,
+        key: None,
+        compact: 1,
+        event: Some(Event::Started),
+        ip: None,
+        ipv4: None,
+        ipv6: None,
+    };
+    let start = Instant::now();
+    tracker
+        .announce(&announce, [0; 20], &request)
+        .await
+        .expect_err("nobody answers");
+    // 50 + 100 + 200ms of waiting for the connect, and not a lot more
+    let waited = start.elapsed();
+    assert!(waited >= Duration::from_millis(350), "{waited:?}");
+    assert!(waited < Duration::from_secs(2), "{waited:?}");
+
+    // it was asked exactly as many times as we said
+    let mut buf = [0; 1024];
+    let mut sent = 0;
+    while server.try_recv_from(&mut buf).is_ok() {
+        sent += 1;
+    }
+    assert_eq!(sent, MAX_RETRANSMITS + 1);
+}
+
+#[tokio::test]
+async fn announce_over_ipv6() {
+    let v4: SocketAddr = "127.0.0.1:6969".parse().unwrap();
+    let v6: SocketAddr = "[::1]:6969".parse().unwrap();
+    assert_eq!(pick_addr([v6, v4]), Some(v4));
+    assert_eq!(pick_addr([v6]), Some(v6));
+
+    let Ok(server) = UdpSocket::bind("[::1]:0").await else {
+        eprintln!("no IPv6 loopback, so not announcing over IPv6");
+        return;
+    };
+    let announce = format!("udp://{}/announce", server.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        let mut buf = [0; 1024];
+        loop {
+            let (_, from) = server.recv_from(&mut buf).await.unwrap();
+            let mut response = Vec::new();
+            if buf[..8] == PROTOCOL_ID.to_be_bytes() {
+                response.extend(ACTION_CONNECT.to_be_bytes());
+                response.extend(&buf[12..16]);
+                response.extend(42u64.to_be_bytes());
+            } else {
+                response.extend(ACTION_ANNOUNCE.to_be_bytes());
+                response.extend(&buf[12..16]);
+                response.extend(1800u32.to_be_bytes());
+                response.extend([0; 8]);
+                let mapped: Ipv6Addr = "::ffff:127.0.0.1".parse().unwrap();
+                response.extend(mapped.octets());
+                response.extend(6881u16.to_be_bytes());
+                response.extend(Ipv6Addr::LOCALHOST.octets());
+                response.extend(6882u16.to_be_bytes());
+            }
+            server.send_to(&response, from).await.unwrap();
+        }
+    });
+
+    let request = TrackerRequest {
+        peer_id: *b"-BS0001-abcdefghijkl",
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 10,
+        key: None,
+        compact: 1,
+        event: None,
+        ip: None,
+        ipv4: None,
+        ipv6: None,
+    };
+    let response = UdpTracker::default()
+        .announce(&announce, [0; 20], &request)
+        .await
+        .expect("announce over IPv6");
+    // the peer that's only on IPv6 can't be connected to
+    assert_eq!(response.peers.0, ["127.0.0.1:6881".parse().unwrap()]);
+    server.abort();
+}
@@ -0,0 +1,157 @@
+//! Checking downloaded pieces against their hashes, and storing the ones that match, off the
+//! download loop so that the next pieces can download meanwhile.
+
+use crate::download::DownloadEvent;
+use crate::forensics::{Contributor, HashFailure};
+use crate::hash::PieceHasher;
+use crate::piece::Piece;
+use crate::scheduler::SharedScheduler;
+use crate::stats::Stats;
+use crate::swarm::Swarm;
+use anyhow::Context;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use futures_util::FutureExt;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::task::{JoinError, JoinHandle};
+
+/// What checking a downloaded piece against its hash, off the download loop, needs.
+pub(crate) struct Checking {
+    pub(crate) info_hash: [u8; 20],
+    pub(crate) swarm: Arc<Swarm>,
+    pub(crate) stats: Arc<Stats>,
+    pub(crate) hasher: Arc<dyn PieceHasher>,
+    pub(crate) verify_writes: bool,
+    pub(crate) hash_failure_dir: Option<PathBuf>,
+    pub(crate) block_size: usize,
+}
+
+impl Checking {
+    /// Check `data` against the hash of `piece`, and store it if it matches. Returns whether it
+    /// did.
+    ///
+    /// A piece that doesn't match is kept in the hash failure directory if there is one, along
+    /// with who sent each of its `blocks`. This all blocks, on hashing and on storage.
+    fn check(
+        &self,
+        piece: &Piece,
+        data: Vec<u8>,
+        blocks: Option<Vec<Option<Contributor>>>,
+    ) -> anyhow::Result<bool> {
+        let piece_i = piece.index();
+        let hash = self.hasher.hash(&data);
+        if hash != piece.hash() {
+            eprintln!("piece {piece_i} failed its hash check");
+            if let (Some(dir), Some(blocks)) = (&self.hash_failure_dir, blocks) {
+                let failure = HashFailure {
+                    info_hash: self.info_hash,
+                    piece_i,
+                    expected: piece.hash(),
+                    actual: hash,
+                    data: &data,
+                    block_size: self.block_size,
+                    blocks,
+                };
+                match failure.dump(dir) {
+                    Ok(path) => eprintln!("wrote piece {piece_i} to {}", path.display()),
+                    Err(e) => eprintln!("failed to keep piece {piece_i}: {e:?}"),
+                }
+            }
+            return Ok(false);
+        }
+        self.stats.piece_verified(piece_i, piece.length());
+
+        let pieces = self.swarm.pieces();
+        pieces.write_verified(piece_i, &data)?;
+        if self.verify_writes {
+            pieces
+                .verify_stored(piece_i, piece.hash(), &*self.hasher)
+                .inspect_err(|_| self.stats.add_hash_failure(piece_i))?;
+        }
+        Ok(true)
+    }
+}
+
+/// The pieces being checked in the background.
+///
+/// Each holds on to a whole piece, so only as many are checked at once as there are CPUs to hash
+/// them on.
+pub(crate) struct VerifyQueue {
+    checking: Arc<Checking>,
+    verifying: FuturesUnordered<JoinHandle<(Piece, anyhow::Result<bool>)>>,
+    max: usize,
+}
+
+impl VerifyQueue {
+    pub(crate) fn new(checking: Checking) -> Self {
+        Self {
+            checking: Arc::new(checking),
+            verifying: FuturesUnordered::new(),
+            max: std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        }
+    }
+
+    /// Start checking `data` as the contents of `piece`, once there's room for another piece.
+    pub(crate) async fn push(
+        &mut self,
+        piece: Piece,
+        data: Vec<u8>,
+        blocks: Option<Vec<Option<Contributor>>>,
+        scheduler: &SharedScheduler,
+    ) -> anyhow::Result<()> {
+        if self.verifying.len() >= self.max {
+            self.next(scheduler).await?;
+        }
+        let checking = Arc::clone(&self.checking);
+        self.verifying.push(tokio::task::spawn_blocking(move || {
+            let matched = checking.check(&piece, data, blocks);
+            (piece, matched)
+        }));
+        Ok(())
+    }
+
+    /// Tell `scheduler` about the pieces that are done being checked, without waiting for the
+    /// rest.
+    pub(crate) fn ready(&mut self, scheduler: &SharedScheduler) -> anyhow::Result<()> {
+        while let Some(Some(checked)) = self.verifying.next().now_or_never() {
+            self.checked(checked, scheduler)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the next piece to be checked, and tell `scheduler` about it. Returns whether
+    /// there was a piece being checked.
+    pub(crate) async fn next(&mut self, scheduler: &SharedScheduler) -> anyhow::Result<bool> {
+        let Some(checked) = self.verifying.next().await else {
+            return Ok(false);
+        };
+        self.checked(checked, scheduler)?;
+        Ok(true)
+    }
+
+    fn checked(
+        &self,
+        checked: Result<(Piece, anyhow::Result<bool>), JoinError>,
+        scheduler: &SharedScheduler,
+    ) -> anyhow::Result<()> {
+        let (piece, matched) = checked.context("check piece")?;
+        let piece_i = piece.index();
+        let Checking { stats, swarm, .. } = &*self.checking;
+        let mut scheduler = scheduler.lock();
+        if !matched? {
+            // some peer sent us bad data, so the whole piece has to be fetched again
+            stats.add_hash_failure(piece_i);
+            stats.add_corrupt(piece.length());
+            scheduler.piece_failed(piece);
+            return Ok(());
+        }
+        if scheduler.piece_verified(piece_i) {
+            swarm.announce_have(piece_i);
+            if let Some(pieces) = scheduler.verified_in_order() {
+                stats.emit(DownloadEvent::VerifiedInOrder { pieces });
+            }
+        }
+        Ok(())
+    }
+}